@@ -348,7 +348,7 @@ fn main() {
                                     panic!("Got the same result_token twice!");
                                 };
                             }
-                            crust::Event::BootstrapConnect(peer_id, addr) => {
+                            crust::Event::BootstrapConnect(peer_id, addr, _) => {
                                 println!(
                                     "\nBootstrapConnect with peer {:?} (address: <{:?}>)",
                                     peer_id, addr
@@ -360,7 +360,7 @@ fn main() {
                                 );
                                 let _ = bs_sender.send(peer_index);
                             }
-                            crust::Event::BootstrapAccept(peer_id, _) => {
+                            crust::Event::BootstrapAccept(peer_id, _, _) => {
                                 println!("\nBootstrapAccept with peer {:?}", peer_id);
                                 let peer_index = handle_new_peer(
                                     &unwrap!(service.lock()),
@@ -369,7 +369,7 @@ fn main() {
                                 );
                                 let _ = bs_sender.send(peer_index);
                             }
-                            crust::Event::ConnectSuccess(peer_id) => {
+                            crust::Event::ConnectSuccess(peer_id, _) => {
                                 println!("\nConnected to peer {:?}", peer_id);
                                 let _ = handle_new_peer(
                                     &unwrap!(service.lock()),
@@ -377,7 +377,7 @@ fn main() {
                                     peer_id,
                                 );
                             }
-                            crust::Event::LostPeer(peer_id) => {
+                            crust::Event::LostPeer(peer_id, _) => {
                                 println!("\nLost connection to peer {:?}", peer_id);
                                 let mut index = None;
                                 {
@@ -0,0 +1,93 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CommonError, Result};
+use lz4;
+
+/// Upper bound for decompressed payloads, so that a malicious peer can't make us allocate
+/// arbitrary amounts of memory. Bigger payloads are never compressed.
+pub const MAX_DECOMPRESSED_SIZE: usize = 2 * 1024 * 1024;
+
+/// Compression algorithms that can be applied to data message payloads.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Compression {
+    Lz4,
+}
+
+/// Compression algorithms supported by this build, in order of preference.
+pub const SUPPORTED_COMPRESSION: &[Compression] = &[Compression::Lz4];
+
+impl Compression {
+    /// Returns `None` if the data can't be compressed.
+    pub fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Compression::Lz4 => lz4::block::compress(data, None, true).ok(),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Lz4 => {
+                // Decompressed size is prepended as little endian u32.
+                if data.len() < 4 {
+                    return Err(CommonError::Decompression);
+                }
+                let size = data[..4]
+                    .iter()
+                    .rev()
+                    .fold(0usize, |size, byte| (size << 8) | *byte as usize);
+                if size > MAX_DECOMPRESSED_SIZE {
+                    return Err(CommonError::PayloadSizeProhibitive);
+                }
+                lz4::block::decompress(data, None).map_err(|_| CommonError::Decompression)
+            }
+        }
+    }
+}
+
+/// Picks the most preferred algorithm of ours that the peer supports as well.
+pub fn negotiate_compression(ours: &[Compression], theirs: &[Compression]) -> Option<Compression> {
+    ours.iter().find(|algo| theirs.contains(algo)).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lz4_roundtrip() {
+        let data = b"routing message routing message routing message".to_vec();
+
+        let compressed = unwrap!(Compression::Lz4.compress(&data));
+
+        assert!(compressed.len() < data.len());
+        assert_eq!(unwrap!(Compression::Lz4.decompress(&compressed)), data);
+    }
+
+    #[test]
+    fn decompression_rejects_oversized_payloads() {
+        let mut data = vec![0xff, 0xff, 0xff, 0x7f];
+        data.extend_from_slice(&[0; 16]);
+
+        match Compression::Lz4.decompress(&data) {
+            Err(CommonError::PayloadSizeProhibitive) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn negotiation_falls_back_to_no_compression() {
+        assert_eq!(
+            negotiate_compression(SUPPORTED_COMPRESSION, &[Compression::Lz4]),
+            Some(Compression::Lz4)
+        );
+        assert_eq!(negotiate_compression(SUPPORTED_COMPRESSION, &[]), None);
+        assert_eq!(negotiate_compression(&[], SUPPORTED_COMPRESSION), None);
+    }
+}
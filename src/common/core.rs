@@ -237,6 +237,11 @@ impl<T> Core<T> {
         self.states.get(&key).cloned()
     }
 
+    /// Tokens of all registered states.
+    pub fn tokens(&self) -> Vec<Token> {
+        self.states.keys().cloned().collect()
+    }
+
     /// Returns an immutable reference to user data stored in `Core`.
     pub fn user_data(&self) -> &T {
         &self.user_data
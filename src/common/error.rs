@@ -37,6 +37,15 @@ quick_error! {
             cause(e)
             from()
         }
+        /// Compressed payload could not be decompressed
+        Decompression {
+            description("Failed to decompress payload")
+        }
+        /// Message is encoded in the format of a protocol version we don't speak
+        UnsupportedVersion(version: u32) {
+            description("Unsupported protocol version")
+            display("Unsupported protocol version: {}", version)
+        }
         /// A zero byte socket read - means EOF
         ZeroByteRead {
             description("Read zero bytes from the socket - indicates EOF")
@@ -0,0 +1,212 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::Uid;
+use maidsafe_utilities::serialisation::serialise;
+use safe_crypto::{PublicEncryptKey, PublicSignKey, SecretSignKey, Signature};
+use std::sync::Arc;
+
+/// Decides whether a peer is allowed to use a `UID` along with a signing key.
+///
+/// Applications implement this to require that peer UIDs are derived from, or certified for, the
+/// signing keys peers prove to own during handshake.
+pub trait IdentityVerifier<UID>: Send + Sync {
+    /// Returns `true` if `uid` may be used by the owner of `sign_pk`.
+    fn verify(&self, uid: &UID, sign_pk: &PublicSignKey) -> bool;
+}
+
+impl<UID, F> IdentityVerifier<UID> for F
+where
+    F: Fn(&UID, &PublicSignKey) -> bool + Send + Sync,
+{
+    fn verify(&self, uid: &UID, sign_pk: &PublicSignKey) -> bool {
+        self(uid, sign_pk)
+    }
+}
+
+/// Signing keypair of a `Service` along with the verifier peer identities are checked with.
+///
+/// When a `Service` has an identity, it proves the ownership of its `UID` in every handshake and
+/// rejects peers that don't do the same.
+pub struct Identity<UID> {
+    sign_pk: PublicSignKey,
+    sign_sk: SecretSignKey,
+    verifier: Box<IdentityVerifier<UID>>,
+}
+
+impl<UID: Uid> Identity<UID> {
+    /// Constructs identity from the given signing keypair and verifier.
+    pub fn new(
+        sign_pk: PublicSignKey,
+        sign_sk: SecretSignKey,
+        verifier: Box<IdentityVerifier<UID>>,
+    ) -> Self {
+        Identity {
+            sign_pk,
+            sign_sk,
+            verifier,
+        }
+    }
+
+    /// Our public signing key.
+    pub fn sign_pk(&self) -> &PublicSignKey {
+        &self.sign_pk
+    }
+
+    /// Signs our UID along with the session keys of both sides of the handshake, so that the proof
+    /// can't be reused for other sessions.
+    fn prove(
+        &self,
+        our_uid: &UID,
+        our_pk: &PublicEncryptKey,
+        their_pk: &PublicEncryptKey,
+    ) -> IdentityProof {
+        let data = unwrap!(serialise(&(our_uid, our_pk, their_pk)));
+        IdentityProof {
+            sign_pk: self.sign_pk,
+            signature: self.sign_sk.sign_detached(&data),
+        }
+    }
+
+    fn verify(
+        &self,
+        proof: &IdentityProof,
+        their_uid: &UID,
+        their_pk: &PublicEncryptKey,
+        our_pk: &PublicEncryptKey,
+    ) -> bool {
+        let data = unwrap!(serialise(&(their_uid, their_pk, our_pk)));
+        proof.sign_pk.verify_detached(&proof.signature, &data)
+            && self.verifier.verify(their_uid, &proof.sign_pk)
+    }
+}
+
+/// Proof that the sender of a handshake message owns the signing key its `UID` is bound to.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct IdentityProof {
+    sign_pk: PublicSignKey,
+    signature: Signature,
+}
+
+/// Returns the proof of our identity to include in a handshake message, if we have an identity.
+///
+/// `our_pk` is our ephemeral key. `their_pk` is the peer's ephemeral key, or its static key when the
+/// ephemeral one is not known yet.
+pub fn prove_identity<UID: Uid>(
+    identity: &Option<Arc<Identity<UID>>>,
+    our_uid: &UID,
+    our_pk: &PublicEncryptKey,
+    their_pk: &PublicEncryptKey,
+) -> Option<IdentityProof> {
+    identity
+        .as_ref()
+        .map(|identity| identity.prove(our_uid, our_pk, their_pk))
+}
+
+/// Checks the identity proof the peer sent. Without an identity of our own we don't require peers
+/// to have one either, so anything goes.
+pub fn verify_identity<UID: Uid>(
+    identity: &Option<Arc<Identity<UID>>>,
+    proof: &Option<IdentityProof>,
+    their_uid: &UID,
+    their_pk: &PublicEncryptKey,
+    our_pk: &PublicEncryptKey,
+) -> bool {
+    match (identity, proof) {
+        (None, _) => true,
+        (Some(identity), Some(proof)) => identity.verify(proof, their_uid, their_pk, our_pk),
+        (Some(_), None) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::UniqueId;
+    use rand;
+    use safe_crypto::{gen_encrypt_keypair, gen_sign_keypair};
+
+    fn identity(verifier: Box<IdentityVerifier<UniqueId>>) -> Option<Arc<Identity<UniqueId>>> {
+        let (sign_pk, sign_sk) = gen_sign_keypair();
+        Some(Arc::new(Identity::new(sign_pk, sign_sk, verifier)))
+    }
+
+    fn accept_all() -> Box<IdentityVerifier<UniqueId>> {
+        Box::new(|_: &UniqueId, _: &PublicSignKey| true)
+    }
+
+    #[test]
+    fn valid_proof_is_accepted() {
+        let (our_pk, _) = gen_encrypt_keypair();
+        let (their_pk, _) = gen_encrypt_keypair();
+        let their_uid: UniqueId = rand::random();
+        let ours = identity(accept_all());
+        let theirs = identity(accept_all());
+
+        let proof = prove_identity(&theirs, &their_uid, &their_pk, &our_pk);
+
+        assert!(verify_identity(
+            &ours, &proof, &their_uid, &their_pk, &our_pk
+        ));
+    }
+
+    #[test]
+    fn proof_for_other_session_or_uid_is_rejected() {
+        let (our_pk, _) = gen_encrypt_keypair();
+        let (their_pk, _) = gen_encrypt_keypair();
+        let (other_pk, _) = gen_encrypt_keypair();
+        let their_uid: UniqueId = rand::random();
+        let ours = identity(accept_all());
+        let theirs = identity(accept_all());
+
+        let proof = prove_identity(&theirs, &their_uid, &their_pk, &other_pk);
+        assert!(!verify_identity(
+            &ours, &proof, &their_uid, &their_pk, &our_pk
+        ));
+
+        let proof = prove_identity(&theirs, &their_uid, &their_pk, &our_pk);
+        let other_uid: UniqueId = rand::random();
+        assert!(!verify_identity(
+            &ours, &proof, &other_uid, &their_pk, &our_pk
+        ));
+    }
+
+    #[test]
+    fn verifier_decides_which_keys_uids_belong_to() {
+        let (our_pk, _) = gen_encrypt_keypair();
+        let (their_pk, _) = gen_encrypt_keypair();
+        let their_uid: UniqueId = rand::random();
+        let ours = identity(Box::new(|_: &UniqueId, _: &PublicSignKey| false));
+        let theirs = identity(accept_all());
+
+        let proof = prove_identity(&theirs, &their_uid, &their_pk, &our_pk);
+
+        assert!(!verify_identity(
+            &ours, &proof, &their_uid, &their_pk, &our_pk
+        ));
+    }
+
+    #[test]
+    fn missing_proof_is_rejected_only_in_identity_mode() {
+        let (our_pk, _) = gen_encrypt_keypair();
+        let (their_pk, _) = gen_encrypt_keypair();
+        let their_uid: UniqueId = rand::random();
+
+        assert!(!verify_identity(
+            &identity(accept_all()),
+            &None,
+            &their_uid,
+            &their_pk,
+            &our_pk
+        ));
+        assert!(verify_identity::<UniqueId>(
+            &None, &None, &their_uid, &their_pk, &our_pk
+        ));
+    }
+}
@@ -7,26 +7,278 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{self, ExternalReachability, NameHash};
+use crate::common::{
+    self, CommonError, Compression, CrustUser, ExternalReachability, IdentityProof, NameHash,
+    PeerInfo, ProtocolInfo, Result, Uid, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use rand;
 use safe_crypto::PublicEncryptKey;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Handshake messages travel wrapped in a `Handshake`, everything else as is.
+///
+/// Handshake messages carry an ephemeral public key, which the session key is derived from. `Rekey*` messages replace the session key of an established connection.
+/// Handshake requests carry `Freshness`, so that the listener can recognise replayed ones.
+/// `BootstrapDenied` may point the bootstrapper to other nodes to try instead. `ConnectDenied`
+/// answers a `Connect` the listener turns down.
+/// `PeerExchange*` messages are only sent if both peers support `Capabilities::PEER_EXCHANGE`.
+/// `Relay` asks a relay to pass a message on to the given peer, which receives it as `Relayed`
+/// naming the sender and the kind of peer it is to the relay instead. `RelayClosed` tells a peer
+/// the relay ended or refused its session with the given peer. `Goodbye` is the last message of a
+/// connection its sender closes, if both peers support `Capabilities::GOODBYE`.
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Message<UID> {
     Heartbeat,
-    BootstrapRequest(UID, NameHash, ExternalReachability, PublicEncryptKey),
-    BootstrapGranted(UID),
-    BootstrapDenied(BootstrapDenyReason),
-    EchoAddrReq(PublicEncryptKey),
+    BootstrapRequest(
+        Freshness,
+        UID,
+        NameHash,
+        ExternalReachability,
+        PublicEncryptKey,
+        Option<IdentityProof>,
+    ),
+    BootstrapGranted(UID, PublicEncryptKey, Option<IdentityProof>),
+    BootstrapDenied(BootstrapDenyReason, Vec<PeerInfo>),
+    EchoAddrReq,
     EchoAddrResp(common::SocketAddr),
     ChooseConnection,
-    Connect(UID, NameHash, PublicEncryptKey),
+    Connect(
+        Freshness,
+        UID,
+        NameHash,
+        PublicEncryptKey,
+        Option<IdentityProof>,
+    ),
     Data(Vec<u8>),
+    CompressedData(Compression, Vec<u8>),
+    RekeyRequest(PublicEncryptKey),
+    RekeyResponse(PublicEncryptKey),
+    RekeyAck,
+    PeerExchangeRequest,
+    PeerExchange(Vec<PeerInfo>),
+    Relay(UID, RelayMessage),
+    Relayed(UID, CrustUser, RelayMessage),
+    Goodbye(GoodbyeReason),
+    ConnectDenied(ConnectDenyReason),
+    RelayClosed(UID),
 }
 
+/// Frame of every handshake message. Its serialised form must never change, so that peers can
+/// always read each other's protocol info and tell whether they are compatible before decoding
+/// the message itself.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Handshake {
+    /// Protocol info of the sender.
+    pub protocol: ProtocolInfo,
+    /// Static public key of the sender, which the reply is encrypted for.
+    pub pub_key: PublicEncryptKey,
+    /// The serialised `Message`. Requests are encoded in the format of the sender's
+    /// `min_version`, as the peer's protocol isn't known yet, replies in the format of the
+    /// negotiated version. Empty in replies to peers we share no version with.
+    body: Vec<u8>,
+}
+
+impl Handshake {
+    /// Wraps a message encoded in the format of the given protocol version.
+    pub fn new<UID: Uid>(
+        protocol: ProtocolInfo,
+        pub_key: PublicEncryptKey,
+        version: u32,
+        msg: &Message<UID>,
+    ) -> Result<Self> {
+        let body = match version {
+            // All versions we speak share one message format so far.
+            MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION => serialise(msg)?,
+            _ => return Err(CommonError::UnsupportedVersion(version)),
+        };
+        Ok(Handshake {
+            protocol,
+            pub_key,
+            body,
+        })
+    }
+
+    /// Wraps a request, which starts a handshake.
+    pub fn request<UID: Uid>(
+        protocol: ProtocolInfo,
+        pub_key: PublicEncryptKey,
+        msg: &Message<UID>,
+    ) -> Result<Self> {
+        Self::new(protocol, pub_key, protocol.min_version, msg)
+    }
+
+    /// Reply to a peer we share no protocol version with. It can tell so from our protocol info
+    /// alone.
+    pub fn incompatible(protocol: ProtocolInfo, pub_key: PublicEncryptKey) -> Self {
+        Handshake {
+            protocol,
+            pub_key,
+            body: Vec::new(),
+        }
+    }
+
+    /// Decodes the message, encoded in the format of the given protocol version.
+    pub fn message<UID: Uid>(&self, version: u32) -> Result<Message<UID>> {
+        match version {
+            MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION => Ok(deserialise(&self.body)?),
+            _ => Err(CommonError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// Decodes a request, see `Handshake::request`.
+    pub fn request_message<UID: Uid>(&self) -> Result<Message<UID>> {
+        self.message(self.protocol.min_version)
+    }
+}
+
+/// Makes every handshake request unique and tells when it was made.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct Freshness {
+    /// Seconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub nonce: u64,
+}
+
+impl Freshness {
+    /// Freshness for a request made right now.
+    pub fn now() -> Self {
+        Freshness {
+            timestamp: unix_timestamp(),
+            nonce: rand::random(),
+        }
+    }
+}
+
+/// Current time in seconds since the UNIX epoch.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+/// Messages of a session relayed through a node both ends are connected to. Payloads are encrypted
+/// end to end, so the relay can't read them.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum RelayMessage {
+    /// Opens a session. Carries the sender's static public key and the `Handshake` with its
+    /// `Message::Connect`, encrypted with the key shared by the static keys of both ends.
+    Connect(PublicEncryptKey, Vec<u8>),
+    /// Accepts a session with the `Handshake` of the accepting end's `Message::Connect`, encrypted
+    /// like the request.
+    Accept(Vec<u8>),
+    /// Data encrypted with the session key, along with the priority it was sent at, which the
+    /// relay passes it on at too.
+    Data(u8, Vec<u8>),
+    /// Closes a session or refuses to open it.
+    Close,
+    /// Our listener addresses, encrypted with the session key, for the peer to try connecting to
+    /// directly.
+    Upgrade(Vec<u8>),
+    /// Tells the peer we moved the session onto a direct connection.
+    Upgraded,
+    /// Keeps the session alive while no data is sent.
+    Heartbeat,
+}
+
+impl RelayMessage {
+    /// Priority the relay passes the message on at.
+    pub fn priority(&self) -> u8 {
+        match *self {
+            RelayMessage::Data(priority, _) => priority,
+            _ => 0,
+        }
+    }
+
+    /// Size of the payload, which counts towards the bandwidth limits of the relay.
+    pub fn payload_len(&self) -> usize {
+        match *self {
+            RelayMessage::Connect(_, ref payload)
+            | RelayMessage::Accept(ref payload)
+            | RelayMessage::Data(_, ref payload)
+            | RelayMessage::Upgrade(ref payload) => payload.len(),
+            RelayMessage::Close | RelayMessage::Upgraded | RelayMessage::Heartbeat => 0,
+        }
+    }
+}
+
+/// Why a peer closed its connection to us.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum GoodbyeReason {
+    /// The peer is shutting down.
+    Shutdown,
+}
+
+/// Why a peer refused our direct connection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ConnectDenyReason {
+    /// Our node is not on the peer's whitelist.
+    NodeNotWhitelisted,
+    /// The peer has no room for more connections.
+    TooManyConnections,
+}
+
+/// Why a peer refused to let us bootstrap off it.
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum BootstrapDenyReason {
+    /// We belong to a different network.
     InvalidNameHash,
+    /// The peer could not connect back to us, though we claim to be reachable.
     FailedExternalReachability,
+    /// Our node is not on the peer's whitelist.
     NodeNotWhitelisted,
+    /// Our client is not on the peer's whitelist.
     ClientNotWhitelisted,
+    /// We don't speak a protocol version in common.
+    IncompatibleVersion,
+    /// The peer did not accept our identity.
+    InvalidIdentity,
+    /// The peer has no room for more connections.
+    TooManyConnections,
+    /// We asked the peer to check our reachability too often lately. Others might still check
+    /// it.
+    ReachabilityCheckThrottled,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Capabilities;
+    use crate::tests::UniqueId;
+    use safe_crypto::gen_encrypt_keypair;
+
+    #[test]
+    fn handshake_of_unknown_version_reveals_protocol_info() {
+        let (pk, _) = gen_encrypt_keypair();
+        let theirs = ProtocolInfo {
+            version: PROTOCOL_VERSION + 2,
+            min_version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::empty(),
+        };
+        let handshake = Handshake {
+            protocol: theirs,
+            pub_key: pk,
+            body: vec![0xff; 64],
+        };
+
+        let handshake: Handshake = unwrap!(deserialise(&unwrap!(serialise(&handshake))));
+
+        assert_eq!(handshake.protocol, theirs);
+        assert!(ProtocolInfo::new(Capabilities::empty())
+            .negotiate(&handshake.protocol)
+            .is_none());
+        assert!(handshake.request_message::<UniqueId>().is_err());
+    }
+
+    #[test]
+    fn handshake_roundtrip() {
+        let (pk, _) = gen_encrypt_keypair();
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+        let msg = Message::EchoAddrReq::<UniqueId>;
+
+        let handshake = unwrap!(Handshake::request(protocol, pk, &msg));
+
+        assert_eq!(unwrap!(handshake.request_message::<UniqueId>()), msg);
+    }
 }
@@ -7,9 +7,17 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+pub use self::compression::{Compression, MAX_DECOMPRESSED_SIZE};
 pub use self::core::{spawn_event_loop, Core, CoreMessage, CoreTimer, EventLoop};
 pub use self::error::CommonError;
-pub use self::message::{BootstrapDenyReason, Message};
+pub use self::identity::{
+    prove_identity, verify_identity, Identity, IdentityProof, IdentityVerifier,
+};
+pub use self::message::{
+    unix_timestamp, BootstrapDenyReason, ConnectDenyReason, Freshness, GoodbyeReason, Handshake,
+    Message, RelayMessage,
+};
+pub use self::protocol::{Capabilities, ProtocolInfo, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 pub use self::state::State;
 use safe_crypto::PublicEncryptKey;
 use serde::de::DeserializeOwned;
@@ -25,7 +33,7 @@ pub type Result<T> = ::std::result::Result<T, CommonError>;
 /// Specify crust user. Behaviour (for example in bootstrap phase) will be different for different
 /// variants. Node will request the Bootstrapee to connect back to this crust failing which it
 /// would mean it's not reachable from outside and hence should be rejected bootstrap attempts.
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub enum CrustUser {
     /// Crust user is a Node and should not be allowed to bootstrap if it's not reachable from
     /// outside.
@@ -79,7 +87,10 @@ pub fn ipv4_addr(a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddr {
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port))
 }
 
+mod compression;
 mod core;
 mod error;
+mod identity;
 mod message;
+mod protocol;
 mod state;
@@ -0,0 +1,189 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::compression::{negotiate_compression, SUPPORTED_COMPRESSION};
+use crate::common::Compression;
+use std::ops::{BitAnd, BitOr};
+
+/// Wire protocol version spoken by this build. Bump it whenever the serialised form of
+/// `Message` changes. Version 11 added `Message::Goodbye`, see `Capabilities::GOODBYE`.
+pub const PROTOCOL_VERSION: u32 = 11;
+/// Oldest wire protocol version this build can still talk to. Version 10 introduced `Handshake`.
+pub const MIN_PROTOCOL_VERSION: u32 = 10;
+
+/// Set of optional protocol features.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// `Message::CompressedData` with LZ4 payloads is understood.
+    pub const LZ4_COMPRESSION: Capabilities = Capabilities(1);
+    /// `Message::PeerExchangeRequest` and `Message::PeerExchange` are understood.
+    pub const PEER_EXCHANGE: Capabilities = Capabilities(2);
+    /// The peer relays sessions between its peers. Unlike the other capabilities, this one is
+    /// kept for a connection if only the peer advertises it.
+    pub const RELAY: Capabilities = Capabilities(4);
+    /// Relayed sessions can be moved onto direct connections with `RelayMessage::Upgrade` and
+    /// `RelayMessage::Upgraded`.
+    pub const UPGRADE: Capabilities = Capabilities(8);
+    /// Sessions can be relayed to and from the peer with `Message::Relay` and `Message::Relayed`.
+    pub const RELAYED_SESSIONS: Capabilities = Capabilities(16);
+    /// A second connection may stand by for the active one instead of replacing it.
+    pub const STANDBY: Capabilities = Capabilities(32);
+    /// `Message::Goodbye` is understood.
+    pub const GOODBYE: Capabilities = Capabilities(64);
+
+    /// Set with no capabilities.
+    pub fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    /// Returns `true` if all capabilities in `other` are in this set too.
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the compression algorithms in this set.
+    pub fn compressions(self) -> Vec<Compression> {
+        let mut compressions = Vec::new();
+        if self.contains(Capabilities::LZ4_COMPRESSION) {
+            compressions.push(Compression::Lz4);
+        }
+        compressions
+    }
+
+    /// Returns our most preferred compression algorithm in this set, if any.
+    pub fn compression(self) -> Option<Compression> {
+        negotiate_compression(SUPPORTED_COMPRESSION, &self.compressions())
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Protocol version and capabilities, either advertised in a handshake or negotiated for a
+/// connection.
+///
+/// Part of `Handshake`, so its serialised form must never change.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct ProtocolInfo {
+    /// Protocol version. For a connection this is the highest version both peers speak.
+    pub version: u32,
+    /// Oldest protocol version the peer is willing to speak.
+    pub min_version: u32,
+    /// Supported optional features. For a connection these are the ones both peers support, plus
+    /// `RELAY` if the peer offers it.
+    pub capabilities: Capabilities,
+}
+
+impl ProtocolInfo {
+    /// Protocol info of this build with the given capabilities.
+    pub fn new(capabilities: Capabilities) -> Self {
+        ProtocolInfo {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// Agrees on the protocol to use with a peer. Returns `None` if the version ranges don't
+    /// overlap.
+    pub fn negotiate(&self, theirs: &ProtocolInfo) -> Option<ProtocolInfo> {
+        let version = self.version.min(theirs.version);
+        let min_version = self.min_version.max(theirs.min_version);
+        if version < min_version {
+            return None;
+        }
+        Some(ProtocolInfo {
+            version,
+            min_version,
+            capabilities: (self.capabilities & theirs.capabilities)
+                | (theirs.capabilities & Capabilities::RELAY),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protocol(version: u32, min_version: u32, capabilities: Capabilities) -> ProtocolInfo {
+        ProtocolInfo {
+            version,
+            min_version,
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn negotiation_picks_highest_common_version() {
+        let ours = protocol(3, 1, Capabilities::empty());
+        let theirs = protocol(2, 2, Capabilities::empty());
+
+        let negotiated = unwrap!(ours.negotiate(&theirs));
+
+        assert_eq!(negotiated.version, 2);
+        assert_eq!(unwrap!(theirs.negotiate(&ours)), negotiated);
+    }
+
+    #[test]
+    fn negotiation_fails_for_disjoint_versions() {
+        let ours = protocol(2, 2, Capabilities::empty());
+        let theirs = protocol(1, 1, Capabilities::empty());
+
+        assert!(ours.negotiate(&theirs).is_none());
+        assert!(theirs.negotiate(&ours).is_none());
+    }
+
+    #[test]
+    fn negotiation_keeps_relay_offered_by_peer() {
+        let relay = ProtocolInfo::new(Capabilities::RELAY | Capabilities::RELAYED_SESSIONS);
+        let peer = ProtocolInfo::new(Capabilities::RELAYED_SESSIONS);
+
+        let with_relay = unwrap!(peer.negotiate(&relay));
+        assert!(with_relay.capabilities.contains(Capabilities::RELAY));
+        assert!(with_relay
+            .capabilities
+            .contains(Capabilities::RELAYED_SESSIONS));
+
+        let with_peer = unwrap!(relay.negotiate(&peer));
+        assert!(!with_peer.capabilities.contains(Capabilities::RELAY));
+        assert!(with_peer
+            .capabilities
+            .contains(Capabilities::RELAYED_SESSIONS));
+    }
+
+    #[test]
+    fn negotiation_keeps_common_capabilities_only() {
+        let ours = ProtocolInfo::new(Capabilities::LZ4_COMPRESSION);
+        let theirs = ProtocolInfo::new(Capabilities::empty());
+
+        let negotiated = unwrap!(ours.negotiate(&theirs));
+        assert_eq!(negotiated.capabilities.compression(), None);
+
+        let negotiated = unwrap!(ours.negotiate(&ours));
+        assert_eq!(
+            negotiated.capabilities.compression(),
+            Some(Compression::Lz4)
+        );
+    }
+}
@@ -81,6 +81,7 @@ use config_file_handler;
 use crossbeam;
 use get_if_addrs;
 use igd;
+use lz4;
 use maidsafe_utilities;
 use mio;
 use mio_extras;
@@ -100,10 +101,16 @@ mod main;
 mod nat;
 mod service_discovery;
 
-pub use crate::common::{CrustUser, PeerInfo, Uid};
+pub use crate::common::{
+    BootstrapDenyReason, Capabilities, ConnectDenyReason, CrustUser, GoodbyeReason, Identity,
+    IdentityVerifier, PeerInfo, ProtocolInfo, Uid, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 pub use crate::main::{
-    read_config_file, Config, ConnectionInfoResult, CrustError, Event, PrivConnectionInfo,
-    PubConnectionInfo, Service,
+    read_config_file, AccessListEntry, AccessLists, AddrFailure, BandwidthLimit, BootstrapReport,
+    Config, ConnectFailureReason, ConnectionInfoResult, ConnectionLimits, ConnectionStats,
+    ContactResult, CrustError, DisconnectReason, DiscoveryMethod, Event, EvictionPolicy, IpRange,
+    ListenerLimits, ListenerStats, PeerHandle, PrivConnectionInfo, PubConnectionInfo, RateLimits,
+    RelayConfig, Service,
 };
 pub use socket_collection::Priority;
 
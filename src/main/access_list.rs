@@ -0,0 +1,330 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{unix_timestamp, CrustUser};
+use crate::main::CrustError;
+use config_file_handler::{self, FileHandler};
+use std::ffi::OsString;
+use std::net::IpAddr;
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy, Hash)]
+pub struct IpRange {
+    /// First address of the range. Bits past `prefix_len` are ignored.
+    pub addr: IpAddr,
+    /// Number of leading bits an address has to share with `addr` to be in the range.
+    pub prefix_len: u8,
+}
+
+impl IpRange {
+    /// Creates a range of addresses sharing the first `prefix_len` bits with `addr`. Fails if
+    /// `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> crate::Res<Self> {
+        if prefix_len > max_prefix_len(&addr) {
+            return Err(CrustError::InvalidIpRange(addr, prefix_len));
+        }
+        Ok(IpRange { addr, prefix_len })
+    }
+
+    /// Returns `true` if `ip` is within this range. IPv4-mapped IPv6 addresses are matched as the
+    /// IPv4 addresses they map.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let prefix_len = u32::from(self.prefix_len);
+        match (self.addr, unmap_ipv4(ip)) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                prefix_len <= 32
+                    && mask_u32(u32::from(range), prefix_len) == mask_u32(u32::from(ip), prefix_len)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                prefix_len <= 128
+                    && mask_u128(u128::from(range), prefix_len)
+                        == mask_u128(u128::from(ip), prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    /// A range holding just the given address.
+    fn from(addr: IpAddr) -> Self {
+        IpRange {
+            addr,
+            prefix_len: max_prefix_len(&addr),
+        }
+    }
+}
+
+/// An access list entry. Expired entries are ignored.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct AccessListEntry {
+    /// Addresses the entry applies to.
+    pub range: IpRange,
+    /// Unix timestamp (in seconds) the entry expires at. `None` means never.
+    pub expires_at: Option<u64>,
+}
+
+impl AccessListEntry {
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.map_or(true, |expires_at| expires_at > now)
+    }
+}
+
+/// IP ranges peers are allowed or refused from. They apply on top of `whitelisted_node_ips` and
+/// `whitelisted_client_ips`, to both incoming and outgoing connections.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AccessLists {
+    /// If given, only Nodes within these ranges are allowed.
+    pub node_allowlist: Option<Vec<AccessListEntry>>,
+    /// If given, only Clients within these ranges are allowed.
+    pub client_allowlist: Option<Vec<AccessListEntry>>,
+    /// Peers within these ranges are refused, whatever the allowlists say.
+    pub blocklist: Option<Vec<AccessListEntry>>,
+}
+
+impl AccessLists {
+    /// Default file runtime changes are persisted to is executable file + '.access_lists' suffix.
+    pub fn get_default_file_name() -> crate::Res<OsString> {
+        let mut name = config_file_handler::exe_file_stem()?;
+        name.push(".access_lists");
+        Ok(name)
+    }
+
+    /// Reads access lists written by `commit`. Returns `None` if none were persisted yet.
+    pub fn read_file(file_name: Option<&OsString>) -> crate::Res<Option<Self>> {
+        Ok(open_file(file_name)?.read_file()?)
+    }
+
+    /// Writes access lists to disk, so that runtime changes survive a restart.
+    pub fn commit(&self, file_name: Option<&OsString>) -> crate::Res<()> {
+        open_file(file_name)?.write_file(&Some(self.clone()))?;
+        Ok(())
+    }
+
+    /// Returns `true` if a peer of the given kind is allowed to use given IP.
+    pub fn allows(&self, ip: &IpAddr, peer_kind: CrustUser) -> bool {
+        self.allows_at(ip, peer_kind, unix_timestamp())
+    }
+
+    /// Returns `true` if given IP is within a blocked range.
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        self.is_blocked_at(ip, unix_timestamp())
+    }
+
+    /// Allows peers of given kind within `range`, replacing any previous entry for the same range.
+    pub fn allow(&mut self, peer_kind: CrustUser, range: IpRange, expires_at: Option<u64>) {
+        let list = self.allowlist_mut(peer_kind).get_or_insert_with(Vec::new);
+        upsert(list, AccessListEntry { range, expires_at });
+    }
+
+    /// Removes `range` from the allowlist of given peer kind. Returns whether it was there.
+    pub fn remove_allowed(&mut self, peer_kind: CrustUser, range: &IpRange) -> bool {
+        self.allowlist_mut(peer_kind)
+            .as_mut()
+            .map_or(false, |list| remove(list, range))
+    }
+
+    /// Refuses peers within `range`, replacing any previous entry for the same range.
+    pub fn block(&mut self, range: IpRange, expires_at: Option<u64>) {
+        let list = self.blocklist.get_or_insert_with(Vec::new);
+        upsert(list, AccessListEntry { range, expires_at });
+    }
+
+    /// Removes `range` from the blocklist. Returns whether it was there.
+    pub fn unblock(&mut self, range: &IpRange) -> bool {
+        self.blocklist
+            .as_mut()
+            .map_or(false, |list| remove(list, range))
+    }
+
+    /// Drops expired entries. Returns whether there were any.
+    pub fn remove_expired(&mut self) -> bool {
+        self.remove_expired_at(unix_timestamp())
+    }
+
+    fn allows_at(&self, ip: &IpAddr, peer_kind: CrustUser, now: u64) -> bool {
+        let allowlist = match peer_kind {
+            CrustUser::Node => &self.node_allowlist,
+            CrustUser::Client => &self.client_allowlist,
+        };
+        let allowed = allowlist.as_ref().map_or(true, |list| {
+            list.iter()
+                .any(|entry| entry.is_live(now) && entry.range.contains(ip))
+        });
+        allowed && !self.is_blocked_at(ip, now)
+    }
+
+    fn is_blocked_at(&self, ip: &IpAddr, now: u64) -> bool {
+        self.blocklist.as_ref().map_or(false, |list| {
+            list.iter()
+                .any(|entry| entry.is_live(now) && entry.range.contains(ip))
+        })
+    }
+
+    fn remove_expired_at(&mut self, now: u64) -> bool {
+        let mut removed = false;
+        for list in vec![
+            &mut self.node_allowlist,
+            &mut self.client_allowlist,
+            &mut self.blocklist,
+        ] {
+            if let Some(list) = list.as_mut() {
+                let len = list.len();
+                list.retain(|entry| entry.is_live(now));
+                removed |= list.len() != len;
+            }
+        }
+        removed
+    }
+
+    fn allowlist_mut(&mut self, peer_kind: CrustUser) -> &mut Option<Vec<AccessListEntry>> {
+        match peer_kind {
+            CrustUser::Node => &mut self.node_allowlist,
+            CrustUser::Client => &mut self.client_allowlist,
+        }
+    }
+}
+
+fn open_file(file_name: Option<&OsString>) -> crate::Res<FileHandler<Option<AccessLists>>> {
+    let fname = match file_name {
+        Some(file_name) => file_name.clone(),
+        None => AccessLists::get_default_file_name()?,
+    };
+    Ok(FileHandler::new(&fname, true)?)
+}
+
+fn unmap_ipv4(ip: &IpAddr) -> IpAddr {
+    match *ip {
+        IpAddr::V6(ip6) => match ip6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => IpAddr::V4(unwrap!(ip6.to_ipv4())),
+            _ => *ip,
+        },
+        IpAddr::V4(..) => *ip,
+    }
+}
+
+fn upsert(list: &mut Vec<AccessListEntry>, entry: AccessListEntry) {
+    let _ = remove(list, &entry.range);
+    list.push(entry);
+}
+
+fn remove(list: &mut Vec<AccessListEntry>, range: &IpRange) -> bool {
+    let len = list.len();
+    list.retain(|entry| entry.range != *range);
+    list.len() != len
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(..) => 32,
+        IpAddr::V6(..) => 128,
+    }
+}
+
+fn mask_u32(addr: u32, prefix_len: u32) -> u32 {
+    addr.checked_shr(32 - prefix_len).unwrap_or(0)
+}
+
+fn mask_u128(addr: u128, prefix_len: u32) -> u128 {
+    addr.checked_shr(128 - prefix_len).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::utils::access_lists_tmp_file;
+    use std::str::FromStr;
+
+    fn range(addr: &str, prefix_len: u8) -> IpRange {
+        unwrap!(IpRange::new(unwrap!(IpAddr::from_str(addr)), prefix_len))
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        unwrap!(IpAddr::from_str(addr))
+    }
+
+    #[test]
+    fn ranges_contain_addresses_sharing_prefix() {
+        let v4 = range("10.1.0.0", 16);
+        assert!(v4.contains(&ip("10.1.255.3")));
+        assert!(!v4.contains(&ip("10.2.0.1")));
+        assert!(v4.contains(&ip("::ffff:10.1.0.1")));
+        assert!(!v4.contains(&ip("::ffff:10.2.0.1")));
+        assert!(!v4.contains(&ip("::10.1.0.1")));
+
+        let v6 = range("2001:db8::", 32);
+        assert!(v6.contains(&ip("2001:db8:1::1")));
+        assert!(!v6.contains(&ip("2001:db9::1")));
+
+        assert!(range("0.0.0.0", 0).contains(&ip("1.2.3.4")));
+        assert!(IpRange::from(ip("1.2.3.4")).contains(&ip("1.2.3.4")));
+        assert!(!IpRange::from(ip("1.2.3.4")).contains(&ip("1.2.3.5")));
+        assert!(IpRange::new(ip("1.2.3.4"), 33).is_err());
+    }
+
+    #[test]
+    fn blocklist_overrides_allowlist() {
+        let mut lists = AccessLists::default();
+        assert!(lists.allows_at(&ip("10.0.0.1"), CrustUser::Node, 0));
+
+        lists.allow(CrustUser::Node, range("10.0.0.0", 8), None);
+        assert!(lists.allows_at(&ip("10.0.0.1"), CrustUser::Node, 0));
+        assert!(!lists.allows_at(&ip("11.0.0.1"), CrustUser::Node, 0));
+        assert!(lists.allows_at(&ip("11.0.0.1"), CrustUser::Client, 0));
+
+        lists.block(range("10.0.0.0", 24), None);
+        assert!(!lists.allows_at(&ip("10.0.0.1"), CrustUser::Node, 0));
+        assert!(lists.allows_at(&ip("10.0.1.1"), CrustUser::Node, 0));
+
+        assert!(lists.unblock(&range("10.0.0.0", 24)));
+        assert!(!lists.unblock(&range("10.0.0.0", 24)));
+        assert!(lists.allows_at(&ip("10.0.0.1"), CrustUser::Node, 0));
+
+        // An emptied allowlist still restricts, just like an empty whitelist does.
+        assert!(lists.remove_allowed(CrustUser::Node, &range("10.0.0.0", 8)));
+        assert!(!lists.allows_at(&ip("10.0.0.1"), CrustUser::Node, 0));
+    }
+
+    #[test]
+    fn mapped_ipv4_peers_are_matched_against_ipv4_ranges() {
+        let mut lists = AccessLists::default();
+        lists.block(range("10.0.0.0", 8), None);
+        assert!(lists.is_blocked_at(&ip("::ffff:10.0.0.1"), 0));
+        assert!(!lists.allows_at(&ip("::ffff:10.0.0.1"), CrustUser::Client, 0));
+        assert!(lists.allows_at(&ip("::ffff:11.0.0.1"), CrustUser::Client, 0));
+    }
+
+    #[test]
+    fn access_lists_survive_commit_and_read() {
+        let fname: OsString = access_lists_tmp_file().into();
+        assert_eq!(unwrap!(AccessLists::read_file(Some(&fname))), None);
+
+        let mut lists = AccessLists::default();
+        lists.block(range("10.0.0.0", 8), Some(100));
+        lists.allow(CrustUser::Client, range("2001:db8::", 32), None);
+        unwrap!(lists.commit(Some(&fname)));
+
+        assert_eq!(unwrap!(AccessLists::read_file(Some(&fname))), Some(lists));
+    }
+
+    #[test]
+    fn expired_entries_are_ignored() {
+        let mut lists = AccessLists::default();
+        lists.block(range("10.0.0.0", 8), Some(100));
+        lists.block(range("11.0.0.0", 8), None);
+
+        assert!(lists.is_blocked_at(&ip("10.0.0.1"), 99));
+        assert!(!lists.is_blocked_at(&ip("10.0.0.1"), 100));
+
+        assert!(!lists.remove_expired_at(99));
+        assert!(lists.remove_expired_at(100));
+        assert_eq!(unwrap!(lists.blocklist.as_ref()).len(), 1);
+        assert!(lists.is_blocked_at(&ip("11.0.0.1"), 100));
+    }
+}
@@ -7,18 +7,29 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{CoreTimer, CrustUser, Message, State, Uid};
+use crate::common::{
+    Capabilities, Compression, CoreTimer, CrustUser, GoodbyeReason, Message, PeerInfo,
+    ProtocolInfo, State, Uid, MAX_DECOMPRESSED_SIZE,
+};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{ConnectionId, ConnectionMap, Event, EventLoopCore};
+use crate::main::{
+    can_upgrade, handle_lost_peer, handle_relay, handle_relay_closed, handle_relayed, learn_peers,
+    peers_to_share, retire_session, ConnectionId, ConnectionMap, ConnectionStats, CrustConfig,
+    Direction, DisconnectReason, Event, EventLoopCore, PeerBandwidth, PeerHandle,
+    RelayedConnection,
+};
 use mio::{Poll, Ready, Token};
 use mio_extras::timer::Timeout;
-use socket_collection::{Priority, TcpSock};
+use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey, SharedSecretKey};
+use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::hash_map::Entry;
-use std::net::SocketAddr;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[cfg(not(test))]
 pub const INACTIVITY_TIMEOUT_MS: u64 = 120_000;
@@ -30,30 +41,87 @@ pub const INACTIVITY_TIMEOUT_MS: u64 = 900;
 #[cfg(test)]
 const HEARTBEAT_PERIOD_MS: u64 = 300;
 
+/// While a standby connection is ready to take over, or after our network interfaces changed, the
+/// active connection gives up on the peer once it missed a couple of heartbeats rather than after
+/// the full inactivity timeout.
+const SHORT_INACTIVITY_TIMEOUT_MS: u64 = HEARTBEAT_PERIOD_MS * 5 / 2;
+
+/// How often we ask the peer for contacts, so that we learn the peers it got to know since.
+#[cfg(not(test))]
+const PEER_EXCHANGE_PERIOD_MS: u64 = 10 * 60 * 1000;
+#[cfg(test)]
+const PEER_EXCHANGE_PERIOD_MS: u64 = 500;
+
+/// Heartbeat timer ids, which states using `Heartbeat` must not use for their other timers.
+pub const RECV_HEARTBEAT_TIMER_ID: u8 = 0;
+pub const SEND_HEARTBEAT_TIMER_ID: u8 = RECV_HEARTBEAT_TIMER_ID + 1;
+const WRITE_THROTTLE_TIMER_ID: u8 = SEND_HEARTBEAT_TIMER_ID + 1;
+const READ_THROTTLE_TIMER_ID: u8 = WRITE_THROTTLE_TIMER_ID + 1;
+const REKEY_TIMER_ID: u8 = READ_THROTTLE_TIMER_ID + 1;
+const PEER_EXCHANGE_TIMER_ID: u8 = REKEY_TIMER_ID + 1;
+
+/// Most data messages we hold back because of upload limits. Beyond it, the ones of the lowest
+/// priority are dropped.
+const MAX_PENDING_WRITES: usize = 1024;
+
+const DEFAULT_REKEY_AFTER_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_REKEY_INTERVAL_SECS: u64 = 60 * 60;
+
 pub struct ActiveConnection<UID: Uid> {
     token: Token,
     socket: TcpSock,
     cm: ConnectionMap<UID>,
+    config: CrustConfig,
     our_id: UID,
     their_id: UID,
     their_role: CrustUser,
     event_tx: crate::CrustEventSender<UID>,
     heartbeat: Heartbeat,
+    bandwidth: PeerBandwidth,
+    /// Data held back because we exceeded upload limits, in the order of its priority.
+    pending_writes: VecDeque<(Vec<u8>, Priority)>,
+    write_throttle: Option<Timeout>,
+    read_throttle: Option<Timeout>,
+    protocol: ProtocolInfo,
+    /// Negotiated compression algorithm and the payload size above which we apply it.
+    compression: Option<(Compression, usize)>,
+    rekey: Rekey<UID>,
+    /// When data was last sent or received.
+    last_activity: Instant,
+    /// Data bytes sent and received so far.
+    bytes_transferred: u64,
+    /// Whether we wait for the peer to share its contacts with us.
+    awaiting_peers: bool,
+    /// When we ask the peer for contacts next.
+    peer_exchange_timeout: Option<Timeout>,
+    /// When we last shared our contacts with the peer.
+    shared_peers_at: Option<Instant>,
+    /// Goodbye we say once our pending writes are out.
+    our_goodbye: Option<GoodbyeReason>,
+    /// Whether we said goodbye and only wait for the socket to flush before closing.
+    closing: bool,
+    /// Why the connection is being terminated, if known.
+    disconnect_reason: Option<DisconnectReason>,
 }
 
 impl<UID: Uid> ActiveConnection<UID> {
-    pub fn start(
+    /// Enters the state. `event` builds the event announcing the connection from its handle.
+    pub fn start<F>(
         core: &mut EventLoopCore,
         poll: &Poll,
         token: Token,
         socket: TcpSock,
         cm: ConnectionMap<UID>,
+        config: CrustConfig,
         our_id: UID,
         their_id: UID,
         their_role: CrustUser,
-        event: Event<UID>,
+        protocol: ProtocolInfo,
+        event: F,
         event_tx: crate::CrustEventSender<UID>,
-    ) {
+    ) where
+        F: FnOnce(PeerHandle<UID>) -> Event<UID>,
+    {
         trace!(
             "Entered state ActiveConnection: {:?} -> {:?}",
             our_id,
@@ -69,50 +137,185 @@ impl<UID: Uid> ActiveConnection<UID> {
                     our_id, e, their_id
                 );
                 let _ = poll.deregister(&socket);
-                let _ = event_tx.send(Event::LostPeer(their_id));
+                let reason = DisconnectReason::LocalError(format!("{:?}", e));
+                let _ = event_tx.send(Event::LostPeer(their_id, reason));
                 // TODO See if this plays well with ConnectionMap<UID> manipulation below
                 return;
             }
         };
 
+        let (bandwidth, compression, rekey) = {
+            let config = unwrap!(config.lock());
+            let threshold = config.cfg.compression_threshold_bytes;
+            (
+                PeerBandwidth::new(&config.bandwidth, their_role),
+                protocol
+                    .capabilities
+                    .compression()
+                    .and_then(|algo| threshold.map(|threshold| (algo, threshold))),
+                Rekey::new(
+                    core,
+                    token,
+                    config
+                        .cfg
+                        .rekey_after_bytes
+                        .unwrap_or(DEFAULT_REKEY_AFTER_BYTES),
+                    Duration::from_secs(
+                        config
+                            .cfg
+                            .rekey_interval_secs
+                            .unwrap_or(DEFAULT_REKEY_INTERVAL_SECS),
+                    ),
+                ),
+            )
+        };
+
         let state = Rc::new(RefCell::new(ActiveConnection {
             token,
             socket,
             cm,
+            config,
             our_id,
             their_id,
             their_role,
             event_tx,
             heartbeat,
+            bandwidth,
+            pending_writes: VecDeque::new(),
+            write_throttle: None,
+            read_throttle: None,
+            protocol,
+            compression,
+            rekey,
+            last_activity: Instant::now(),
+            bytes_transferred: 0,
+            awaiting_peers: false,
+            peer_exchange_timeout: None,
+            shared_peers_at: None,
+            our_goodbye: None,
+            closing: false,
+            disconnect_reason: None,
         }));
 
         let _ = core.insert_state(token, state.clone());
 
         let mut state_mut = state.borrow_mut();
-        {
+        // A direct connection may join one which is direct too as its standby.
+        let active = unwrap!(state_mut.cm.lock())
+            .get(&their_id)
+            .and_then(|cid| cid.active_connection);
+        let may_stand_by = protocol.capabilities.contains(Capabilities::STANDBY)
+            && active.map_or(false, |active| !can_upgrade::<UID>(core, active));
+        let (replaced, standby, has_standby) = {
             let mut guard = unwrap!(state_mut.cm.lock());
-            {
+            let res = {
                 let conn_id = guard.entry(their_id).or_insert(ConnectionId {
                     active_connection: None,
                     currently_handshaking: 1,
+                    standby: None,
+                    generation: 0,
                 });
                 conn_id.currently_handshaking -= 1;
-                conn_id.active_connection = Some(token);
-            }
+                if may_stand_by && conn_id.standby.is_none() {
+                    conn_id.standby = Some(token);
+                    (None, true, false)
+                } else {
+                    let replaced = conn_id.active_connection.replace(token);
+                    (replaced, false, conn_id.standby.is_some())
+                }
+            };
             trace!(
                 "Connection Map inserted: {:?} -> {:?}",
                 their_id,
                 guard.get(&their_id)
             );
+            res
+        };
+        if standby {
+            trace!("{:?} - Standby connection to {:?}", our_id, their_id);
+            if let Some(active) = active {
+                let timeout = Duration::from_millis(SHORT_INACTIVITY_TIMEOUT_MS);
+                set_inactivity_timeout::<UID>(core, poll, active, timeout);
+            }
+            return state_mut.read(core, poll);
+        }
+        // Taking a relayed session over is no news to the user. A connection we replace is
+        // dropped without failing over, and reported lost before the new one is announced.
+        let upgraded = replaced.map_or(false, |old| retire_session::<UID>(core, poll, old));
+        if let Some(old) = replaced.filter(|_| !upgraded) {
+            if let Some(state) = core.get_state(old) {
+                state.borrow_mut().terminate(core, poll);
+            }
+            let reason = DisconnectReason::Duplicate;
+            let _ = state_mut.event_tx.send(Event::LostPeer(their_id, reason));
+        }
+        if has_standby {
+            let timeout = Duration::from_millis(SHORT_INACTIVITY_TIMEOUT_MS);
+            let _ = state_mut.heartbeat.set_inactivity_timeout(core, timeout);
+        }
+        if !upgraded {
+            let generation = unwrap!(state_mut.cm.lock())
+                .get_mut(&their_id)
+                .map_or(0, |cid| cid.announce());
+            let handle = PeerHandle::new(core, their_id, generation, state_mut.cm.clone());
+            let _ = state_mut.event_tx.send(event(handle));
+        }
+        if !state_mut.request_peers(core, poll) {
+            return;
         }
-        let _ = state_mut.event_tx.send(event);
         state_mut.read(core, poll);
     }
 
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if self.read_throttle.is_some() {
+            return;
+        }
+
         loop {
+            if let Some(delay) = self.bandwidth_delay(Direction::Download) {
+                // Peer is alive, we are just not reading its data yet. Don't let the inactivity
+                // timer kill the connection in the meantime.
+                self.reset_receive_heartbeat(core, poll);
+                let timer = CoreTimer::new(self.token, READ_THROTTLE_TIMER_ID);
+                self.read_throttle = Some(core.set_timeout(delay, timer));
+                return;
+            }
+
             match self.socket.read::<Message<UID>>() {
                 Ok(Some(Message::Data(data))) => {
+                    self.consume_bandwidth(Direction::Download, data.len());
+                    self.record_traffic(data.len());
+                    let _ =
+                        self.event_tx
+                            .send(Event::NewMessage(self.their_id, self.their_role, data));
+                    self.reset_receive_heartbeat(core, poll);
+                }
+                Ok(Some(Message::CompressedData(compression, compressed))) => {
+                    self.consume_bandwidth(Direction::Download, compressed.len());
+                    self.record_traffic(compressed.len());
+                    if !self
+                        .protocol
+                        .capabilities
+                        .compressions()
+                        .contains(&compression)
+                    {
+                        debug!(
+                            "{:?} - Received data compressed with {:?}, which we didn't agree on",
+                            self.our_id, compression
+                        );
+                        return self.disconnect(core, poll, DisconnectReason::ProtocolViolation);
+                    }
+                    let data = match compression.decompress(&compressed) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            debug!("{:?} - Failed to decompress data: {:?}", self.our_id, e);
+                            return self.disconnect(
+                                core,
+                                poll,
+                                DisconnectReason::ProtocolViolation,
+                            );
+                        }
+                    };
                     let _ =
                         self.event_tx
                             .send(Event::NewMessage(self.their_id, self.their_role, data));
@@ -121,6 +324,79 @@ impl<UID: Uid> ActiveConnection<UID> {
                 Ok(Some(Message::Heartbeat)) => {
                     self.reset_receive_heartbeat(core, poll);
                 }
+                Ok(Some(Message::RekeyRequest(their_pk))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    if !self.handle_rekey_request(core, poll, their_pk) {
+                        return;
+                    }
+                }
+                Ok(Some(Message::RekeyResponse(their_pk))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    if !self.handle_rekey_response(core, poll, their_pk) {
+                        return;
+                    }
+                }
+                Ok(Some(Message::RekeyAck)) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    if !self.handle_rekey_ack(core, poll) {
+                        return;
+                    }
+                }
+                Ok(Some(Message::PeerExchangeRequest)) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    if !self.share_peers(core, poll) {
+                        return;
+                    }
+                }
+                Ok(Some(Message::PeerExchange(peers))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    self.handle_shared_peers(core, poll, peers);
+                }
+                Ok(Some(Message::Relay(target, msg))) => {
+                    self.consume_bandwidth(Direction::Download, msg.payload_len());
+                    self.reset_receive_heartbeat(core, poll);
+                    let reply = handle_relay(
+                        core,
+                        poll,
+                        &self.cm,
+                        &self.config,
+                        (self.their_id, self.their_role),
+                        target,
+                        msg,
+                    );
+                    if let Some(reply) = reply {
+                        if !self.write(core, poll, Some((reply, 0))) {
+                            return;
+                        }
+                    }
+                }
+                Ok(Some(Message::Relayed(source, source_kind, msg))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    let reply = handle_relayed(
+                        core,
+                        poll,
+                        &self.cm,
+                        (self.their_id, self.their_role),
+                        (source, source_kind),
+                        msg,
+                    );
+                    if let Some(reply) = reply {
+                        if !self.write(core, poll, Some((Message::Relay(source, reply), 0))) {
+                            return;
+                        }
+                    }
+                }
+                Ok(Some(Message::RelayClosed(peer))) => {
+                    self.reset_receive_heartbeat(core, poll);
+                    handle_relay_closed(core, poll, &self.cm, self.their_id, peer);
+                }
+                Ok(Some(Message::Goodbye(reason))) => {
+                    debug!(
+                        "{:?} - {:?} said goodbye: {:?}",
+                        self.our_id, self.their_id, reason
+                    );
+                    return self.disconnect(core, poll, DisconnectReason::Goodbye(reason));
+                }
                 Ok(Some(message)) => {
                     debug!("{:?} - Unexpected message: {:?}", self.our_id, message);
                     self.reset_receive_heartbeat(core, poll);
@@ -128,10 +404,224 @@ impl<UID: Uid> ActiveConnection<UID> {
                 Ok(None) => return,
                 Err(e) => {
                     debug!("{:?} - Failed to read from socket: {:?}", self.our_id, e);
-                    return self.terminate(core, poll);
+                    return self.disconnect(
+                        core,
+                        poll,
+                        DisconnectReason::ReadError(format!("{:?}", e)),
+                    );
                 }
             }
+
+            if !self.maybe_rekey(core, poll) {
+                return;
+            }
+        }
+    }
+
+    /// Starts rekeying if we transferred enough data under the current key. Returns false if the
+    /// connection was terminated.
+    fn maybe_rekey(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        if self.rekey.bytes < self.rekey.after_bytes {
+            return true;
+        }
+        self.start_rekey(core, poll)
+    }
+
+    /// Offers the peer a fresh ephemeral key to derive the next session key from. Returns false if
+    /// the connection was terminated.
+    fn start_rekey(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        if self.rekey.in_progress() {
+            return true;
+        }
+        trace!(
+            "{:?} - Rekeying connection to {:?}",
+            self.our_id,
+            self.their_id
+        );
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        self.rekey.requested = Some(our_sk);
+        // Like heartbeats, rekey messages bypass bandwidth limits.
+        self.write(core, poll, Some((Message::RekeyRequest(our_pk), 0)))
+    }
+
+    /// Answers the peer's rekey request and starts encrypting with the new key right after the
+    /// response. Returns false if the connection was terminated.
+    fn handle_rekey_request(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_pk: PublicEncryptKey,
+    ) -> bool {
+        if self.rekey.requested.is_some() {
+            // Both sides requested rekeying at the same time. The side with the higher UID wins
+            // and keeps waiting for the response to its own request.
+            if self.our_id > self.their_id {
+                return true;
+            }
+            self.rekey.requested = None;
+        }
+        if self.rekey.awaiting_ack.is_some() {
+            debug!("{:?} - Unexpected rekey request", self.our_id);
+            self.disconnect(core, poll, DisconnectReason::ProtocolViolation);
+            return false;
+        }
+
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let key = our_sk.shared_secret(&their_pk);
+        self.rekey.awaiting_ack = Some(key.clone());
+        self.switch_encrypt_key(core, poll, Message::RekeyResponse(our_pk), key)
+    }
+
+    /// Switches to the new key in both directions, letting the peer know with the ack it can
+    /// decrypt our messages with it too. Returns false if the connection was terminated.
+    fn handle_rekey_response(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_pk: PublicEncryptKey,
+    ) -> bool {
+        let key = match self.rekey.requested.take() {
+            Some(our_sk) => our_sk.shared_secret(&their_pk),
+            None => {
+                debug!("{:?} - Unexpected rekey response", self.our_id);
+                self.disconnect(core, poll, DisconnectReason::ProtocolViolation);
+                return false;
+            }
+        };
+        if !self.set_decrypt_key(core, poll, key.clone()) {
+            return false;
+        }
+        self.rekey.reset(core, self.token);
+        self.switch_encrypt_key(core, poll, Message::RekeyAck, key)
+    }
+
+    /// Returns false if the connection was terminated.
+    fn handle_rekey_ack(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        let key = match self.rekey.awaiting_ack.take() {
+            Some(key) => key,
+            None => {
+                debug!("{:?} - Unexpected rekey ack", self.our_id);
+                self.disconnect(core, poll, DisconnectReason::ProtocolViolation);
+                return false;
+            }
+        };
+        if !self.set_decrypt_key(core, poll, key) {
+            return false;
+        }
+        self.rekey.reset(core, self.token);
+        true
+    }
+
+    /// Sends `msg` as the last message encrypted with the old key and encrypts everything after it
+    /// with `key`. Returns false if the connection was terminated.
+    fn switch_encrypt_key(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        msg: Message<UID>,
+        key: SharedSecretKey,
+    ) -> bool {
+        self.rekey.switch = Some((msg, key));
+        self.try_switch_encrypt_key(core, poll)
+    }
+
+    /// Messages are encrypted when queued, but the socket sends them in priority order. So we
+    /// hold back our writes until the socket queue drains, then queue the switch message with the
+    /// highest priority, making sure the peer sees nothing encrypted with the new key before it.
+    /// Returns false if the connection was terminated.
+    fn try_switch_encrypt_key(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        let (msg, key) = match self.rekey.switch.take() {
+            Some(switch) => switch,
+            None => return true,
+        };
+        match self.socket.write(None) {
+            Ok(true) => (),
+            Ok(false) => {
+                self.rekey.switch = Some((msg, key));
+                return true;
+            }
+            Err(e) => {
+                debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
+                self.disconnect(core, poll, DisconnectReason::WriteError(format!("{:?}", e)));
+                return false;
+            }
+        }
+        if !self.write(core, poll, Some((msg, 0))) {
+            return false;
+        }
+        if let Err(e) = self
+            .socket
+            .set_encrypt_ctx(EncryptContext::authenticated(key))
+        {
+            debug!("{:?} - Failed to set encrypt context: {:?}", self.our_id, e);
+            self.disconnect(core, poll, DisconnectReason::LocalError(format!("{:?}", e)));
+            return false;
+        }
+        self.flush_pending_writes(core, poll);
+        true
+    }
+
+    /// Returns false if the connection was terminated.
+    fn set_decrypt_key(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        key: SharedSecretKey,
+    ) -> bool {
+        if let Err(e) = self
+            .socket
+            .set_decrypt_ctx(DecryptContext::authenticated(key))
+        {
+            debug!("{:?} - Failed to set decrypt context: {:?}", self.our_id, e);
+            self.disconnect(core, poll, DisconnectReason::LocalError(format!("{:?}", e)));
+            return false;
+        }
+        true
+    }
+
+    fn supports_peer_exchange(&self) -> bool {
+        self.protocol
+            .capabilities
+            .contains(Capabilities::PEER_EXCHANGE)
+    }
+
+    /// Asks the peer for contacts of other peers, if we both opted into peer exchange, and
+    /// schedules asking again. Returns false if the connection was terminated.
+    fn request_peers(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        if !self.supports_peer_exchange() {
+            return true;
+        }
+        let timer = CoreTimer::new(self.token, PEER_EXCHANGE_TIMER_ID);
+        let period = Duration::from_millis(PEER_EXCHANGE_PERIOD_MS);
+        self.peer_exchange_timeout = Some(core.set_timeout(period, timer));
+        self.awaiting_peers = true;
+        self.write(core, poll, Some((Message::PeerExchangeRequest, 0)))
+    }
+
+    /// Answers the peer's peer exchange request, ignoring the ones coming faster than we would
+    /// ask ourselves. Returns false if the connection was terminated.
+    fn share_peers(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        let min_interval = Duration::from_millis(PEER_EXCHANGE_PERIOD_MS / 2);
+        let too_soon = self
+            .shared_peers_at
+            .map_or(false, |shared_at| shared_at.elapsed() < min_interval);
+        if !self.supports_peer_exchange() || too_soon {
+            debug!("{:?} - Unexpected peer exchange request", self.our_id);
+            return true;
         }
+        self.shared_peers_at = Some(Instant::now());
+        let their_ip = self.peer_addr().ok().map(|addr| addr.ip());
+        let peers = peers_to_share(core, their_ip);
+        self.write(core, poll, Some((Message::PeerExchange(peers), 0)))
+    }
+
+    fn handle_shared_peers(&mut self, core: &mut EventLoopCore, poll: &Poll, peers: Vec<PeerInfo>) {
+        if !self.awaiting_peers {
+            debug!("{:?} - Unexpected peer exchange response", self.our_id);
+            return;
+        }
+        self.awaiting_peers = false;
+        learn_peers(core, poll, &self.config, &self.event_tx, peers);
     }
 
     #[cfg(not(test))]
@@ -152,37 +642,252 @@ impl<UID: Uid> ActiveConnection<UID> {
         self.their_role
     }
 
+    /// Returns whether a connection over `socket` takes a different path than this one, so that
+    /// it can stand by for it.
+    pub fn can_stand_by_with(&self, socket: &TcpSock) -> bool {
+        match (path(&self.socket), path(socket)) {
+            (Some(ours), Some(theirs)) => ours != theirs,
+            _ => false,
+        }
+    }
+
+    /// Checks the connection after our network interfaces changed. Drops it if its local address
+    /// is gone, otherwise sends a heartbeat and gives the peer less time than usual to answer,
+    /// so that it fails fast if its path broke.
+    pub fn revalidate(&mut self, core: &mut EventLoopCore, poll: &Poll, our_ips: &[IpAddr]) {
+        match self.socket.local_addr() {
+            Ok(addr) if addr.ip().is_loopback() || our_ips.contains(&addr.ip()) => (),
+            _ => {
+                debug!(
+                    "{:?} - Local address of connection to {:?} is gone.",
+                    self.our_id, self.their_id
+                );
+                return self.disconnect(core, poll, DisconnectReason::InterfaceGone);
+            }
+        }
+        if self.rekey.switch.is_none() && !self.write(core, poll, Some((Message::Heartbeat, 0))) {
+            return;
+        }
+        let timeout = Duration::from_millis(SHORT_INACTIVITY_TIMEOUT_MS);
+        self.heartbeat.probe(core, timeout);
+    }
+
+    /// Protocol version and capabilities negotiated with the peer.
+    pub fn protocol(&self) -> ProtocolInfo {
+        self.protocol
+    }
+
+    /// When data was last sent or received.
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    /// Data bytes sent and received over this connection so far.
+    pub fn bytes_transferred(&self) -> u64 {
+        self.bytes_transferred
+    }
+
+    /// Traffic statistics of this connection, see `PeerHandle::stats`.
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            bytes_transferred: self.bytes_transferred,
+            idle: self.last_activity.elapsed(),
+            pending_writes: self.pending_writes.len(),
+        }
+    }
+
+    /// Closes the connection gracefully: says goodbye to the peer once the data we have yet to send
+    /// is out, and terminates once the socket flushed it all. Data sent after the goodbye is dropped.
+    pub fn close(&mut self, core: &mut EventLoopCore, poll: &Poll, reason: GoodbyeReason) {
+        if self.closing || self.our_goodbye.is_some() {
+            return;
+        }
+        self.our_goodbye = Some(reason);
+        let _ = self.try_close(core, poll);
+    }
+
+    /// Says our goodbye if nothing is left to send before it and terminates once it's flushed.
+    /// Returns false if the connection was terminated.
+    fn try_close(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        if !self.pending_writes.is_empty() || self.rekey.switch.is_some() {
+            return true;
+        }
+        if let Some(reason) = self.our_goodbye.take() {
+            // Peers which don't know goodbyes only see the connection close.
+            if self.protocol.capabilities.contains(Capabilities::GOODBYE)
+                && !self.write(core, poll, Some((Message::Goodbye(reason), 0)))
+            {
+                return false;
+            }
+            self.closing = true;
+        }
+        if !self.closing {
+            return true;
+        }
+        match self.socket.write(None) {
+            Ok(false) => true,
+            Ok(true) => {
+                self.disconnect(core, poll, DisconnectReason::Shutdown);
+                false
+            }
+            Err(e) => {
+                debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
+                self.disconnect(core, poll, DisconnectReason::WriteError(format!("{:?}", e)));
+                false
+            }
+        }
+    }
+
+    /// Terminates the connection, telling the user why if the peer is lost with it.
+    pub fn disconnect(&mut self, core: &mut EventLoopCore, poll: &Poll, reason: DisconnectReason) {
+        self.disconnect_reason = Some(reason);
+        self.terminate(core, poll);
+    }
+
+    /// Takes over from the failed active connection, along with the data it had yet to send.
+    fn take_over(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        pending_writes: Vec<(Vec<u8>, Priority)>,
+    ) {
+        for (data, priority) in pending_writes {
+            self.queue_write(data, priority);
+        }
+        self.flush_pending_writes(core, poll);
+    }
+
+    /// Queues data behind the data of the same or a higher priority. When the queue is full, the
+    /// data of the lowest priority is dropped.
+    fn queue_write(&mut self, data: Vec<u8>, priority: Priority) {
+        if !queue_by_priority(&mut self.pending_writes, data, priority) {
+            debug!(
+                "{:?} - Too much data held back for {:?}, dropping some of it",
+                self.our_id, self.their_id
+            );
+        }
+    }
+
+    fn record_traffic(&mut self, bytes: usize) {
+        self.rekey.bytes += bytes as u64;
+        self.bytes_transferred += bytes as u64;
+        self.last_activity = Instant::now();
+    }
+
+    /// Sends a message other than data, bypassing bandwidth limits. Returns false if writing failed
+    /// and the connection was terminated.
+    pub fn send_message(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        msg: Message<UID>,
+        priority: Priority,
+    ) -> bool {
+        if !self.write(core, poll, Some((msg, priority))) {
+            return false;
+        }
+        self.reset_send_heartbeat(core, poll);
+        true
+    }
+
+    /// Returns false if writing failed and the connection was terminated.
     fn write(
         &mut self,
         core: &mut EventLoopCore,
         poll: &Poll,
         msg: Option<(Message<UID>, Priority)>,
-    ) {
+    ) -> bool {
         if let Err(e) = self.socket.write(msg) {
             debug!("{:?} - Failed to write socket: {:?}", self.our_id, e);
-            self.terminate(core, poll);
+            self.disconnect(core, poll, DisconnectReason::WriteError(format!("{:?}", e)));
+            return false;
         }
+        true
+    }
+
+    /// Writes data queued up due to upload limits for as long as limits allow it.
+    fn flush_pending_writes(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if self.write_throttle.is_some() || self.rekey.switch.is_some() {
+            return;
+        }
+
+        while !self.pending_writes.is_empty() {
+            if let Some(delay) = self.bandwidth_delay(Direction::Upload) {
+                let timer = CoreTimer::new(self.token, WRITE_THROTTLE_TIMER_ID);
+                self.write_throttle = Some(core.set_timeout(delay, timer));
+                return;
+            }
+
+            let (data, priority) = unwrap!(self.pending_writes.pop_front());
+            let (msg, len) = self.data_message(data);
+            self.consume_bandwidth(Direction::Upload, len);
+            self.record_traffic(len);
+            if !self.write(core, poll, Some((msg, priority))) {
+                return;
+            }
+            // Only data actually sent spares us the heartbeat.
+            self.reset_send_heartbeat(core, poll);
+            if !self.maybe_rekey(core, poll) {
+                return;
+            }
+        }
+        let _ = self.try_close(core, poll);
+    }
+
+    /// Wraps data into a message, compressing it if that was negotiated and pays off. Returns the
+    /// message along with the size of its payload.
+    fn data_message(&self, data: Vec<u8>) -> (Message<UID>, usize) {
+        if let Some((compression, threshold)) = self.compression {
+            if data.len() > threshold && data.len() <= MAX_DECOMPRESSED_SIZE {
+                if let Some(compressed) = compression
+                    .compress(&data)
+                    .filter(|compressed| compressed.len() < data.len())
+                {
+                    let len = compressed.len();
+                    return (Message::CompressedData(compression, compressed), len);
+                }
+            }
+        }
+        let len = data.len();
+        (Message::Data(data), len)
+    }
+
+    fn bandwidth_delay(&mut self, direction: Direction) -> Option<Duration> {
+        let mut config = unwrap!(self.config.lock());
+        self.bandwidth.delay(&mut config.bandwidth, direction)
+    }
+
+    /// Accounts for traffic against the bandwidth limits, e.g. for messages relayed through this
+    /// connection.
+    pub fn consume_bandwidth(&mut self, direction: Direction, bytes: usize) {
+        let mut config = unwrap!(self.config.lock());
+        self.bandwidth
+            .consume(&mut config.bandwidth, direction, bytes);
     }
 
     fn reset_receive_heartbeat(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         if let Err(e) = self.heartbeat.reset_receive(core) {
             debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
-            self.terminate(core, poll);
+            self.disconnect(core, poll, DisconnectReason::LocalError(format!("{:?}", e)));
         }
     }
 
     fn reset_send_heartbeat(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         if let Err(e) = self.heartbeat.reset_send(core) {
             debug!("{:?} - Failed to reset heartbeat: {:?}", self.our_id, e);
-            self.terminate(core, poll);
+            self.disconnect(core, poll, DisconnectReason::LocalError(format!("{:?}", e)));
         }
     }
 }
 
 impl<UID: Uid> State<BootstrapCache> for ActiveConnection<UID> {
     fn ready(&mut self, core: &mut EventLoopCore, poll: &Poll, kind: Ready) {
-        if kind.is_writable() {
-            self.write(core, poll, None);
+        if kind.is_writable()
+            && (!self.write(core, poll, None)
+                || !self.try_switch_encrypt_key(core, poll)
+                || !self.try_close(core, poll))
+        {
+            return;
         }
         if kind.is_readable() {
             self.read(core, poll);
@@ -190,42 +895,150 @@ impl<UID: Uid> State<BootstrapCache> for ActiveConnection<UID> {
     }
 
     fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, data: Vec<u8>, priority: Priority) {
-        self.write(core, poll, Some((Message::Data(data), priority)));
-        self.reset_send_heartbeat(core, poll);
+        if self.closing {
+            return;
+        }
+        self.queue_write(data, priority);
+        self.flush_pending_writes(core, poll);
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         self.heartbeat.terminate(core);
+        if let Some(timeout) = self.write_throttle.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        if let Some(timeout) = self.read_throttle.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
+        self.rekey.terminate(core);
+        if let Some(timeout) = self.peer_exchange_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
         let _ = poll.deregister(&self.socket);
         let _ = core.remove_state(self.token);
 
-        {
+        // Either we were the standby, or the standby takes our place if we were the active
+        // connection. A connection which was replaced leaves the peer alone.
+        let (role, promoted) = {
             let mut guard = unwrap!(self.cm.lock());
-            if let Entry::Occupied(mut oe) = guard.entry(self.their_id) {
-                oe.get_mut().active_connection = None;
-                if oe.get().currently_handshaking == 0 {
-                    let _ = oe.remove();
+            let res = match guard.entry(self.their_id) {
+                Entry::Occupied(mut oe) => {
+                    if oe.get().standby == Some(self.token) {
+                        oe.get_mut().standby = None;
+                        (Role::Standby(oe.get().active_connection), None)
+                    } else if oe.get().active_connection == Some(self.token) {
+                        let promoted = oe.get_mut().standby.take();
+                        oe.get_mut().active_connection = promoted;
+                        if promoted.is_none() && oe.get().currently_handshaking == 0 {
+                            let _ = oe.remove();
+                        }
+                        (Role::Active, promoted)
+                    } else {
+                        (Role::Replaced, None)
+                    }
                 }
-            }
+                Entry::Vacant(_) => (Role::Replaced, None),
+            };
             trace!(
                 "Connection Map removed: {:?} -> {:?}",
                 self.their_id,
                 guard.get(&self.their_id)
             );
+            res
+        };
+
+        match role {
+            Role::Standby(active) => {
+                trace!(
+                    "{:?} - Lost standby connection to {:?}",
+                    self.our_id,
+                    self.their_id
+                );
+                if let Some(active) = active {
+                    let timeout = Duration::from_millis(INACTIVITY_TIMEOUT_MS);
+                    set_inactivity_timeout::<UID>(core, poll, active, timeout);
+                }
+                return;
+            }
+            Role::Replaced => {
+                trace!(
+                    "{:?} - Dropped replaced connection to {:?}",
+                    self.our_id,
+                    self.their_id
+                );
+                return;
+            }
+            Role::Active => (),
+        }
+        if let Some(standby) = promoted {
+            debug!(
+                "{:?} - Failing over to standby connection to {:?}",
+                self.our_id, self.their_id
+            );
+            let pending_writes = self.pending_writes.drain(..).collect();
+            if let Some(state) = core.get_state(standby) {
+                if let Ok(mut state) = state.try_borrow_mut() {
+                    if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                        ac.take_over(core, poll, pending_writes);
+                    }
+                }
+            }
+            return;
         }
 
-        let _ = self.event_tx.send(Event::LostPeer(self.their_id));
+        // Terminated without a reason of its own, the connection was closed on request.
+        let reason = self
+            .disconnect_reason
+            .take()
+            .unwrap_or(DisconnectReason::Disconnected);
+        debug!(
+            "{:?} - Lost peer {:?}: {:?}",
+            self.our_id, self.their_id, reason
+        );
+        let _ = self.event_tx.send(Event::LostPeer(self.their_id, reason));
+        handle_lost_peer(core, poll, &self.cm, self.their_id);
     }
 
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, timer_id: u8) {
+        match timer_id {
+            WRITE_THROTTLE_TIMER_ID => {
+                self.write_throttle = None;
+                return self.flush_pending_writes(core, poll);
+            }
+            READ_THROTTLE_TIMER_ID => {
+                self.read_throttle = None;
+                return self.read(core, poll);
+            }
+            REKEY_TIMER_ID => {
+                // If rekeying is under way, its completion will rearm the timer.
+                let _ = self.start_rekey(core, poll);
+                return;
+            }
+            PEER_EXCHANGE_TIMER_ID => {
+                self.peer_exchange_timeout = None;
+                if !self.closing {
+                    let _ = self.request_peers(core, poll);
+                }
+                return;
+            }
+            _ => (),
+        }
+
         match self.heartbeat.timeout(core, timer_id) {
-            HeartbeatAction::Send => self.write(core, poll, Some((Message::Heartbeat, 0))),
+            HeartbeatAction::Send => {
+                // Heartbeats bypass bandwidth limits so that throttled peers don't time out. While
+                // switching keys the switch message will do as a heartbeat. Nothing follows our
+                // goodbye.
+                if self.rekey.switch.is_none() && !self.closing {
+                    let _ = self.write(core, poll, Some((Message::Heartbeat, 0)));
+                }
+            }
             HeartbeatAction::Terminate => {
                 debug!(
                     "Dropping connection to {:?} due to peer inactivity",
                     self.their_id
                 );
-                self.terminate(core, poll);
+                self.disconnect(core, poll, DisconnectReason::Inactivity);
             }
         }
     }
@@ -235,20 +1048,126 @@ impl<UID: Uid> State<BootstrapCache> for ActiveConnection<UID> {
     }
 }
 
-struct Heartbeat {
+/// Terminates the connection under `token` for given reason. Other states are just terminated.
+pub fn disconnect<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    token: Token,
+    reason: DisconnectReason,
+) {
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return,
+    };
+    let mut state = state.borrow_mut();
+    if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+        return ac.disconnect(core, poll, reason);
+    }
+    if let Some(session) = state.as_any().downcast_mut::<RelayedConnection<UID>>() {
+        return session.disconnect(core, poll, reason);
+    }
+    state.terminate(core, poll);
+}
+
+/// Sets how long the connection under `token` waits for the peer to show signs of life before
+/// giving up on it.
+pub fn set_inactivity_timeout<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    token: Token,
+    timeout: Duration,
+) {
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return,
+    };
+    let mut state = match state.try_borrow_mut() {
+        Ok(state) => state,
+        Err(_) => return,
+    };
+    if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+        if let Err(e) = ac.heartbeat.set_inactivity_timeout(core, timeout) {
+            debug!("{:?} - Failed to reset heartbeat: {:?}", ac.our_id, e);
+            ac.disconnect(core, poll, DisconnectReason::LocalError(format!("{:?}", e)));
+        }
+    }
+}
+
+/// Returns whether a connection over `socket` may stand by for the active connection under
+/// `active`, to take over if that one fails.
+pub fn can_stand_by<UID: Uid>(
+    core: &EventLoopCore,
+    config: &CrustConfig,
+    active: Token,
+    socket: &TcpSock,
+) -> bool {
+    if unwrap!(config.lock()).cfg.standby_connections != Some(true) {
+        return false;
+    }
+    let state = match core.get_state(active) {
+        Some(state) => state,
+        None => return false,
+    };
+    let mut state = match state.try_borrow_mut() {
+        Ok(state) => state,
+        Err(_) => return false,
+    };
+    state
+        .as_any()
+        .downcast_mut::<ActiveConnection<UID>>()
+        .map_or(false, |ac| {
+            ac.protocol.capabilities.contains(Capabilities::STANDBY) && ac.can_stand_by_with(socket)
+        })
+}
+
+/// Queues data held back because of upload limits in order of priority. Beyond
+/// `MAX_PENDING_WRITES`, the data of the lowest priority is dropped, in which case false is
+/// returned.
+pub fn queue_by_priority(
+    queue: &mut VecDeque<(Vec<u8>, Priority)>,
+    data: Vec<u8>,
+    priority: Priority,
+) -> bool {
+    let len = queue.len();
+    let pos = queue
+        .iter()
+        .position(|&(_, queued)| queued > priority)
+        .unwrap_or(len);
+    if len >= MAX_PENDING_WRITES {
+        if pos == len {
+            return false;
+        }
+        let _ = queue.pop_back();
+        queue.insert(pos, (data, priority));
+        return false;
+    }
+    queue.insert(pos, (data, priority));
+    true
+}
+
+/// Local and remote IPs of a connection. Connections sharing them would likely go down together.
+fn path(socket: &TcpSock) -> Option<(IpAddr, IpAddr)> {
+    let local_addr = socket.local_addr().ok()?;
+    let peer_addr = socket.peer_addr().ok()?;
+    Some((local_addr.ip(), peer_addr.ip()))
+}
+
+/// Sends heartbeats while we are silent and gives up on the peer once it is silent for too long.
+pub struct Heartbeat {
     recv_timeout: Timeout,
     recv_timer: CoreTimer,
     send_timeout: Timeout,
     send_timer: CoreTimer,
+    inactivity_timeout: Duration,
 }
 
 impl Heartbeat {
-    fn try_new(core: &mut EventLoopCore, state_id: Token) -> crate::Res<Self> {
-        let recv_timer = CoreTimer::new(state_id, 0);
-        let recv_timeout =
-            core.set_timeout(Duration::from_millis(INACTIVITY_TIMEOUT_MS), recv_timer);
+    pub fn try_new(core: &mut EventLoopCore, state_id: Token) -> crate::Res<Self> {
+        let inactivity_timeout = Duration::from_millis(INACTIVITY_TIMEOUT_MS);
+        let recv_timer = CoreTimer::new(state_id, RECV_HEARTBEAT_TIMER_ID);
+        let recv_timeout = core.set_timeout(inactivity_timeout, recv_timer);
 
-        let send_timer = CoreTimer::new(state_id, 1);
+        let send_timer = CoreTimer::new(state_id, SEND_HEARTBEAT_TIMER_ID);
         let send_timeout = core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), send_timer);
 
         Ok(Heartbeat {
@@ -256,10 +1175,11 @@ impl Heartbeat {
             recv_timer,
             send_timeout,
             send_timer,
+            inactivity_timeout,
         })
     }
 
-    fn timeout(&mut self, core: &mut EventLoopCore, timer_id: u8) -> HeartbeatAction {
+    pub fn timeout(&mut self, core: &mut EventLoopCore, timer_id: u8) -> HeartbeatAction {
         if timer_id == self.recv_timer.timer_id {
             HeartbeatAction::Terminate
         } else {
@@ -269,29 +1189,102 @@ impl Heartbeat {
         }
     }
 
-    fn reset_receive(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
+    pub fn reset_receive(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
         let _ = core.cancel_timeout(&self.recv_timeout);
-        self.recv_timeout = core.set_timeout(
-            Duration::from_millis(INACTIVITY_TIMEOUT_MS),
-            self.recv_timer,
-        );
+        self.recv_timeout = core.set_timeout(self.inactivity_timeout, self.recv_timer);
         Ok(())
     }
 
-    fn reset_send(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
+    /// Gives the peer at most the given time to show signs of life once, after which the regular
+    /// inactivity timeout applies again.
+    pub fn probe(&mut self, core: &mut EventLoopCore, timeout: Duration) {
+        let _ = core.cancel_timeout(&self.recv_timeout);
+        let timeout = cmp::min(timeout, self.inactivity_timeout);
+        self.recv_timeout = core.set_timeout(timeout, self.recv_timer);
+    }
+
+    /// Changes how long the peer may stay silent, counting from now.
+    pub fn set_inactivity_timeout(
+        &mut self,
+        core: &mut EventLoopCore,
+        timeout: Duration,
+    ) -> crate::Res<()> {
+        self.inactivity_timeout = timeout;
+        self.reset_receive(core)
+    }
+
+    pub fn reset_send(&mut self, core: &mut EventLoopCore) -> crate::Res<()> {
         let _ = core.cancel_timeout(&self.send_timeout);
         self.send_timeout =
             core.set_timeout(Duration::from_millis(HEARTBEAT_PERIOD_MS), self.send_timer);
         Ok(())
     }
 
-    fn terminate(&mut self, core: &mut EventLoopCore) {
+    pub fn terminate(&mut self, core: &mut EventLoopCore) {
         let _ = core.cancel_timeout(&self.recv_timeout);
         let _ = core.cancel_timeout(&self.send_timeout);
     }
 }
 
-enum HeartbeatAction {
+/// What a connection is to the peer.
+enum Role {
+    Active,
+    /// Standing by for the given active connection.
+    Standby(Option<Token>),
+    /// Neither, as another connection replaced it.
+    Replaced,
+}
+
+pub enum HeartbeatAction {
     Send,
     Terminate,
 }
+
+/// Keeps track of when the session key is due to be replaced and of the replacement in progress.
+struct Rekey<UID> {
+    after_bytes: u64,
+    interval: Duration,
+    /// Bytes transferred under the current key.
+    bytes: u64,
+    timeout: Timeout,
+    /// Our ephemeral key while we wait for the response to our rekey request.
+    requested: Option<SecretEncryptKey>,
+    /// The new key while we wait for the peer to acknowledge our rekey response.
+    awaiting_ack: Option<SharedSecretKey>,
+    /// Message to send once our writes drain, after which we encrypt with the new key.
+    switch: Option<(Message<UID>, SharedSecretKey)>,
+}
+
+impl<UID> Rekey<UID> {
+    fn new(
+        core: &mut EventLoopCore,
+        state_id: Token,
+        after_bytes: u64,
+        interval: Duration,
+    ) -> Self {
+        Rekey {
+            after_bytes,
+            interval,
+            bytes: 0,
+            timeout: core.set_timeout(interval, CoreTimer::new(state_id, REKEY_TIMER_ID)),
+            requested: None,
+            awaiting_ack: None,
+            switch: None,
+        }
+    }
+
+    fn in_progress(&self) -> bool {
+        self.requested.is_some() || self.awaiting_ack.is_some() || self.switch.is_some()
+    }
+
+    /// Starts counting towards the next rekey once the new key is in use.
+    fn reset(&mut self, core: &mut EventLoopCore, state_id: Token) {
+        self.bytes = 0;
+        let _ = core.cancel_timeout(&self.timeout);
+        self.timeout = core.set_timeout(self.interval, CoreTimer::new(state_id, REKEY_TIMER_ID));
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore) {
+        let _ = core.cancel_timeout(&self.timeout);
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::CrustUser;
+use crate::main::config_handler::{BandwidthLimit, RateLimits};
+use std::time::{Duration, Instant};
+
+/// Traffic direction as seen from our side of the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Token bucket which is refilled at a constant rate of bytes per second and can hold at most one
+/// second worth of tokens.
+///
+/// The bucket is allowed to go into debt, so that messages larger than its capacity still get
+/// through. Further traffic is then held back until the debt is paid off.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self::new_at(bytes_per_sec, Instant::now())
+    }
+
+    pub fn new_at(bytes_per_sec: u64, now: Instant) -> Self {
+        // Zero rate would never refill the bucket and block traffic forever.
+        let rate = bytes_per_sec.max(1);
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Takes given amount of tokens out of the bucket.
+    pub fn consume(&mut self, bytes: usize) {
+        self.consume_at(bytes, Instant::now())
+    }
+
+    /// Returns how long traffic should be held back, or `None` if it can go through right away.
+    pub fn delay(&mut self) -> Option<Duration> {
+        self.delay_at(Instant::now())
+    }
+
+    pub fn consume_at(&mut self, bytes: usize, now: Instant) {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+    }
+
+    pub fn delay_at(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            return None;
+        }
+        let wait_ms = (-self.tokens * 1000.0 / self.rate as f64).ceil() as u64;
+        Some(Duration::from_millis(wait_ms.max(1)))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+        let elapsed = now - self.last_refill;
+        let elapsed_sec = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed_sec * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+}
+
+/// Bandwidth limiter shared by all connections. Holds the currently effective limits and the
+/// global token buckets.
+#[derive(Debug, Default)]
+pub struct BandwidthLimiter {
+    limits: RateLimits,
+    generation: u64,
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        let mut limiter = BandwidthLimiter::default();
+        limiter.set_limits(limits);
+        limiter
+    }
+
+    /// Replaces the effective limits. Connections pick up the change on their next read or write.
+    pub fn set_limits(&mut self, limits: RateLimits) {
+        self.upload = limits.global.upload_bytes_per_sec.map(TokenBucket::new);
+        self.download = limits.global.download_bytes_per_sec.map(TokenBucket::new);
+        self.limits = limits;
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    fn bucket(&mut self, direction: Direction) -> Option<&mut TokenBucket> {
+        match direction {
+            Direction::Upload => self.upload.as_mut(),
+            Direction::Download => self.download.as_mut(),
+        }
+    }
+}
+
+/// Per connection token buckets. They are rebuilt whenever limits in the shared
+/// `BandwidthLimiter` change.
+#[derive(Debug)]
+pub struct PeerBandwidth {
+    peer_kind: CrustUser,
+    generation: u64,
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl PeerBandwidth {
+    pub fn new(global: &BandwidthLimiter, peer_kind: CrustUser) -> Self {
+        let mut peer_bandwidth = PeerBandwidth {
+            peer_kind,
+            generation: global.generation,
+            upload: None,
+            download: None,
+        };
+        peer_bandwidth.apply_limits(global);
+        peer_bandwidth
+    }
+
+    /// Accounts for the given amount of traffic both globally and for this connection.
+    pub fn consume(&mut self, global: &mut BandwidthLimiter, direction: Direction, bytes: usize) {
+        self.sync(global);
+        if let Some(bucket) = global.bucket(direction) {
+            bucket.consume(bytes);
+        }
+        if let Some(bucket) = self.bucket(direction) {
+            bucket.consume(bytes);
+        }
+    }
+
+    /// Returns how long traffic in the given direction should be held back, if at all.
+    pub fn delay(
+        &mut self,
+        global: &mut BandwidthLimiter,
+        direction: Direction,
+    ) -> Option<Duration> {
+        self.sync(global);
+        let global_delay = global.bucket(direction).and_then(TokenBucket::delay);
+        let peer_delay = self.bucket(direction).and_then(TokenBucket::delay);
+        match (global_delay, peer_delay) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn sync(&mut self, global: &BandwidthLimiter) {
+        if self.generation != global.generation {
+            self.generation = global.generation;
+            self.apply_limits(global);
+        }
+    }
+
+    fn apply_limits(&mut self, global: &BandwidthLimiter) {
+        let limits = peer_limit(&global.limits, self.peer_kind);
+        self.upload = limits.upload_bytes_per_sec.map(TokenBucket::new);
+        self.download = limits.download_bytes_per_sec.map(TokenBucket::new);
+    }
+
+    fn bucket(&mut self, direction: Direction) -> Option<&mut TokenBucket> {
+        match direction {
+            Direction::Upload => self.upload.as_mut(),
+            Direction::Download => self.download.as_mut(),
+        }
+    }
+}
+
+/// Picks per connection limits for the given kind of peer.
+fn peer_limit(limits: &RateLimits, peer_kind: CrustUser) -> BandwidthLimit {
+    let class_limit = match peer_kind {
+        CrustUser::Node => limits.per_node,
+        CrustUser::Client => limits.per_client,
+    };
+    class_limit.unwrap_or(limits.per_peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod token_bucket {
+        use super::*;
+
+        #[test]
+        fn it_lets_traffic_through_while_tokens_are_available() {
+            let now = Instant::now();
+            let mut bucket = TokenBucket::new_at(1000, now);
+
+            bucket.consume_at(600, now);
+
+            assert_eq!(bucket.delay_at(now), None);
+        }
+
+        #[test]
+        fn it_holds_traffic_back_until_debt_is_paid_off() {
+            let now = Instant::now();
+            let mut bucket = TokenBucket::new_at(1000, now);
+
+            bucket.consume_at(1500, now);
+
+            assert_eq!(bucket.delay_at(now), Some(Duration::from_millis(500)));
+            assert_eq!(bucket.delay_at(now + Duration::from_millis(500)), None);
+        }
+
+        #[test]
+        fn it_never_holds_more_than_one_second_worth_of_tokens() {
+            let now = Instant::now();
+            let mut bucket = TokenBucket::new_at(1000, now);
+
+            let later = now + Duration::from_secs(10);
+            bucket.consume_at(1001, later);
+
+            assert!(bucket.delay_at(later).is_some());
+        }
+    }
+
+    mod peer_bandwidth {
+        use super::*;
+
+        #[test]
+        fn class_limits_override_per_peer_limits() {
+            let mut limits = RateLimits::default();
+            limits.per_peer.upload_bytes_per_sec = Some(1000);
+            limits.per_client = Some(BandwidthLimit {
+                upload_bytes_per_sec: Some(10),
+                download_bytes_per_sec: None,
+            });
+            let mut global = BandwidthLimiter::new(limits);
+            let mut node = PeerBandwidth::new(&global, CrustUser::Node);
+            let mut client = PeerBandwidth::new(&global, CrustUser::Client);
+
+            node.consume(&mut global, Direction::Upload, 100);
+            client.consume(&mut global, Direction::Upload, 100);
+
+            assert!(node.delay(&mut global, Direction::Upload).is_none());
+            assert!(client.delay(&mut global, Direction::Upload).is_some());
+            assert!(client.delay(&mut global, Direction::Download).is_none());
+        }
+
+        #[test]
+        fn global_limits_are_shared_by_all_peers() {
+            let mut limits = RateLimits::default();
+            limits.global.download_bytes_per_sec = Some(1000);
+            let mut global = BandwidthLimiter::new(limits);
+            let mut peer1 = PeerBandwidth::new(&global, CrustUser::Node);
+            let mut peer2 = PeerBandwidth::new(&global, CrustUser::Node);
+
+            peer1.consume(&mut global, Direction::Download, 1500);
+
+            assert!(peer2.delay(&mut global, Direction::Download).is_some());
+        }
+
+        #[test]
+        fn it_picks_up_changed_limits() {
+            let mut limits = RateLimits::default();
+            limits.per_peer.upload_bytes_per_sec = Some(10);
+            let mut global = BandwidthLimiter::new(limits);
+            let mut peer = PeerBandwidth::new(&global, CrustUser::Node);
+            peer.consume(&mut global, Direction::Upload, 100);
+            assert!(peer.delay(&mut global, Direction::Upload).is_some());
+
+            global.set_limits(RateLimits::default());
+
+            assert!(peer.delay(&mut global, Direction::Upload).is_none());
+        }
+    }
+}
@@ -10,9 +10,10 @@
 use crate::common::PeerInfo;
 use config_file_handler::{self, FileHandler};
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 /// Reference-counted bootstrap cache - keeps log of known publicly accessible peers.
 #[derive(Clone)]
@@ -23,6 +24,8 @@ pub struct Cache {
 struct Inner {
     file_name: Option<OsString>,
     peers: HashSet<PeerInfo>,
+    /// When we last connected directly to peers during this run. Not written to disk.
+    verified: HashMap<PeerInfo, Instant>,
 }
 
 impl Cache {
@@ -33,6 +36,7 @@ impl Cache {
         let inner = Inner {
             file_name,
             peers: Default::default(),
+            verified: Default::default(),
         };
         Cache {
             inner: Rc::new(RefCell::new(inner)),
@@ -70,6 +74,22 @@ impl Cache {
     pub fn remove(&self, peer: &PeerInfo) {
         let mut inner = self.inner.borrow_mut();
         let _ = inner.peers.remove(peer);
+        let _ = inner.verified.remove(peer);
+    }
+
+    /// Records that we just connected directly to given peer, whether it's cached or not.
+    pub fn mark_verified(&self, peer: PeerInfo) {
+        let mut inner = self.inner.borrow_mut();
+        let _ = inner.verified.insert(peer, Instant::now());
+    }
+
+    /// Returns peers we connected directly to within `max_age`.
+    pub fn recently_verified(&self, max_age: Duration) -> Vec<PeerInfo> {
+        let mut inner = self.inner.borrow_mut();
+        inner
+            .verified
+            .retain(|_, verified_at| verified_at.elapsed() <= max_age);
+        inner.verified.keys().cloned().collect()
     }
 
     /// Writes bootstrap cache to disk.
@@ -189,6 +209,24 @@ mod tests {
             assert!(cache.peers().is_empty());
         }
 
+        #[test]
+        fn recently_verified() {
+            let cache = Cache::new(None);
+            let peer1 = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 4, 4000));
+            let peer2 = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 5, 5000));
+            cache.put(peer1);
+            cache.mark_verified(peer2);
+
+            assert_eq!(
+                cache.recently_verified(Duration::from_secs(60)),
+                vec![peer2]
+            );
+            assert_eq!(cache.peers().len(), 1);
+
+            cache.remove(&peer2);
+            assert!(cache.recently_verified(Duration::from_secs(60)).is_empty());
+        }
+
         mod commit {
             use super::*;
 
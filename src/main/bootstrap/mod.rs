@@ -13,11 +13,15 @@ mod try_peer;
 pub use self::cache::Cache;
 use self::try_peer::TryPeer;
 use crate::common::{
-    BootstrapDenyReason, CoreTimer, CrustUser, ExternalReachability, NameHash, PeerInfo, State, Uid,
+    BootstrapDenyReason, CoreTimer, CrustUser, ExternalReachability, Identity, NameHash, PeerInfo,
+    ProtocolInfo, State, Uid,
 };
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{ActiveConnection, ConnectionMap, CrustConfig, CrustError, Event, EventLoopCore};
-use crate::service_discovery::ServiceDiscovery;
+use crate::main::{
+    ActiveConnection, BootstrapReport, ConnectionMap, ContactResult, CrustConfig, CrustError,
+    Event, EventLoopCore,
+};
+use crate::service_discovery::{LanPeerChange, ServiceDiscovery};
 use mio::{Poll, Token};
 use mio_extras::timer::Timeout;
 use rand;
@@ -26,39 +30,56 @@ use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
 use socket_collection::TcpSock;
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
-use std::sync::mpsc::{self, Receiver};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const BOOTSTRAP_TIMEOUT_SEC: u64 = 10;
 const SERVICE_DISCOVERY_TIMEOUT_SEC: u64 = 1;
 const BOOTSTRAP_TIMER_ID: u8 = 0;
 const SERVICE_DISCOVERY_TIMER_ID: u8 = BOOTSTRAP_TIMER_ID + 1;
 const MAX_CONTACTS_EXPECTED: usize = 1500;
+/// Maximum number of peers we try on account of being redirected by the peers denying us, so that
+/// they can't keep us busy forever.
+const MAX_REDIRECTED_PEERS: usize = 20;
 
 /// Connection bootstrap state that
 ///
-/// 1. attempts service discovery,
-/// 2. if no peers are found, tries cached ones,
-/// 3. if no success again, tries peers hard coded in the config.
+/// 1. tries the peers service discovery has found on our LAN, as well as those it finds while we
+///    bootstrap,
+/// 2. tries cached peers,
+/// 3. tries peers hard coded in the config.
 pub struct Bootstrap<UID: Uid> {
     token: Token,
     cm: ConnectionMap<UID>,
+    config: CrustConfig,
     peers: Vec<PeerInfo>,
+    /// Addresses we tried or must not try, so that redirects don't make us try them again.
+    attempted: HashSet<SocketAddr>,
+    redirects_left: usize,
+    /// Peers we were redirected to. They are cached once we bootstrap off them.
+    redirects: HashSet<PeerInfo>,
     name_hash: NameHash,
     ext_reachability: ExternalReachability,
     our_uid: UID,
     event_tx: crate::CrustEventSender<UID>,
-    sd_meta: Option<ServiceDiscMeta>,
+    /// Set while we give service discovery time to find the first peers on our LAN.
+    lan_timeout: Option<Timeout>,
     bs_timer: CoreTimer,
     bs_timeout: Timeout,
-    children: HashSet<Token>,
+    /// The peers we are still trying, by the token of their `TryPeer` state.
+    children: HashMap<Token, PeerInfo>,
+    /// The peers we tried without success and how each attempt failed.
+    contacts: Vec<(PeerInfo, ContactResult)>,
+    started: Instant,
+    service_discovery: bool,
     self_weak: Weak<RefCell<Bootstrap<UID>>>,
     our_pk: PublicEncryptKey,
     our_sk: SecretEncryptKey,
+    identity: Option<Arc<Identity<UID>>>,
 }
 
 impl<UID: Uid> Bootstrap<UID> {
@@ -76,105 +97,206 @@ impl<UID: Uid> Bootstrap<UID> {
         event_tx: crate::CrustEventSender<UID>,
         our_pk: PublicEncryptKey,
         our_sk: &SecretEncryptKey,
+        identity: Option<Arc<Identity<UID>>>,
     ) -> crate::Res<()> {
         let bs_timer = CoreTimer::new(token, BOOTSTRAP_TIMER_ID);
         let bs_timeout = core.set_timeout(Duration::from_secs(BOOTSTRAP_TIMEOUT_SEC), bs_timer);
-        let sd_meta = match seek_peers(core, service_discovery_token, token) {
-            Ok((rx, timeout)) => Some(ServiceDiscMeta { rx, timeout }),
-            Err(CrustError::ServiceDiscNotEnabled) => None,
-            Err(e) => {
-                warn!("Failed to seek peers using service discovery: {:?}", e);
-                return Err(e);
-            }
-        };
 
-        let peers = shuffled_bootstrap_peers(core.user_data().peers(), config.clone(), blacklist);
+        let peers =
+            shuffled_bootstrap_peers(core.user_data().peers(), config.clone(), blacklist.clone());
         let state = Rc::new(RefCell::new(Self {
             token,
             cm,
+            config,
             peers,
+            attempted: blacklist,
+            redirects_left: MAX_REDIRECTED_PEERS,
+            redirects: HashSet::new(),
             name_hash,
             ext_reachability,
             our_uid,
             event_tx,
-            sd_meta,
+            lan_timeout: None,
             bs_timer,
             bs_timeout,
-            children: HashSet::with_capacity(MAX_CONTACTS_EXPECTED),
+            children: HashMap::with_capacity(MAX_CONTACTS_EXPECTED),
+            contacts: Vec::new(),
+            started: Instant::now(),
+            service_discovery: false,
             self_weak: Weak::new(),
             our_pk,
             our_sk: our_sk.clone(),
+            identity,
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
 
         let _ = core.insert_state(token, state.clone());
 
-        if state.borrow().sd_meta.is_none() {
-            state.borrow_mut().begin_bootstrap(core, poll);
+        let mut bootstrap = state.borrow_mut();
+        let lan_peers = watch_lan_peers(core, poll, service_discovery_token, Rc::downgrade(&state));
+        bootstrap.service_discovery = match lan_peers {
+            Err(CrustError::ServiceDiscNotEnabled) => false,
+            _ => true,
+        };
+        match lan_peers {
+            Ok(ref lan_peers) if lan_peers.is_empty() => {
+                bootstrap.lan_timeout = Some(core.set_timeout(
+                    Duration::from_secs(SERVICE_DISCOVERY_TIMEOUT_SEC),
+                    CoreTimer::new(token, SERVICE_DISCOVERY_TIMER_ID),
+                ));
+            }
+            Ok(lan_peers) => {
+                let lan_peers: Vec<_> = lan_peers
+                    .into_iter()
+                    .filter(|peer| bootstrap.may_try(peer))
+                    .collect();
+                let _ = bootstrap.peers.splice(0..0, lan_peers);
+            }
+            Err(CrustError::ServiceDiscNotEnabled) => (),
+            Err(e) => {
+                warn!("Failed to seek peers using service discovery: {:?}", e);
+                bootstrap.fail_with(core, poll, Some(e.to_string()));
+                return Ok(());
+            }
         }
+        bootstrap.begin_bootstrap(core, poll);
 
         Ok(())
     }
 
     fn begin_bootstrap(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         let peers = mem::replace(&mut self.peers, Vec::new());
-        if peers.is_empty() {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
+        if peers.is_empty() && self.lan_timeout.is_none() {
+            return self.fail(core, poll);
         }
 
         for peer in peers {
-            let self_weak = self.self_weak.clone();
-            let finish = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
-                if let Some(self_rc) = self_weak.upgrade() {
-                    self_rc.borrow_mut().handle_result(core, poll, child, res)
-                }
-            };
+            self.try_peer(core, poll, peer);
+        }
+        self.maybe_terminate(core, poll);
+    }
 
-            if let Ok(child) = TryPeer::start(
-                core,
-                poll,
-                peer,
-                self.our_uid,
-                self.name_hash,
-                self.ext_reachability.clone(),
-                self.our_pk,
-                &self.our_sk,
-                Box::new(finish),
-            ) {
-                let _ = self.children.insert(child);
+    /// Tries the peer service discovery found on our LAN while we bootstrap.
+    fn handle_lan_peer_found(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        listeners: &[PeerInfo],
+    ) {
+        let mut tried = false;
+        for peer in listeners {
+            if self.may_try(peer) {
+                self.try_peer(core, poll, *peer);
+                tried = true;
             }
         }
+        // Keep waiting for other LAN peers if none of these could be tried.
+        if !tried {
+            return;
+        }
+        if let Some(timeout) = self.lan_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
+        }
         self.maybe_terminate(core, poll);
     }
 
+    /// Whether the peer is neither tried already, blacklisted nor blocked by our access lists.
+    fn may_try(&self, peer: &PeerInfo) -> bool {
+        !self.attempted.contains(&peer.addr)
+            && !unwrap!(self.config.lock())
+                .access_lists
+                .is_blocked(&peer.addr.ip())
+    }
+
+    fn try_peer(&mut self, core: &mut EventLoopCore, poll: &Poll, peer: PeerInfo) {
+        let _ = self.attempted.insert(peer.addr);
+
+        let self_weak = self.self_weak.clone();
+        let finish = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
+            if let Some(self_rc) = self_weak.upgrade() {
+                self_rc.borrow_mut().handle_result(core, poll, child, res)
+            }
+        };
+
+        match TryPeer::start(
+            core,
+            poll,
+            peer,
+            self.our_uid,
+            self.name_hash,
+            self.ext_reachability.clone(),
+            self.our_pk,
+            &self.our_sk,
+            unwrap!(self.config.lock()).protocol_info(),
+            self.identity.clone(),
+            Box::new(finish),
+        ) {
+            Ok(child) => {
+                let _ = self.children.insert(child, peer);
+            }
+            Err(e) => self
+                .contacts
+                .push((peer, ContactResult::Failed(format!("{:?}", e)))),
+        }
+    }
+
+    /// Tries the peers the denying peer pointed us to, unless we tried them already.
+    fn follow_redirects(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        redirects: Vec<PeerInfo>,
+    ) {
+        for peer in redirects {
+            if self.redirects_left == 0 {
+                debug!("Too many redirects - ignoring the rest of them.");
+                return;
+            }
+            if !self.may_try(&peer) {
+                continue;
+            }
+            self.redirects_left -= 1;
+            let _ = self.redirects.insert(peer);
+            self.try_peer(core, poll, peer);
+        }
+    }
+
     fn handle_result(
         &mut self,
         core: &mut EventLoopCore,
         poll: &Poll,
         child: Token,
-        res: Result<(TcpSock, PeerInfo, UID), (PeerInfo, Option<BootstrapDenyReason>)>,
+        res: Result<
+            (TcpSock, PeerInfo, UID, ProtocolInfo),
+            (PeerInfo, ContactResult, Vec<PeerInfo>),
+        >,
     ) {
         let _ = self.children.remove(&child);
         match res {
-            Ok((socket, peer_info, peer_id)) => {
+            Ok((socket, peer_info, peer_id, protocol)) => {
                 self.terminate(core, poll);
+                if self.redirects.contains(&peer_info) {
+                    cache_peer_info(core, peer_info, &self.config);
+                }
+                core.user_data().mark_verified(peer_info);
                 return ActiveConnection::start(
                     core,
                     poll,
                     child,
                     socket,
                     self.cm.clone(),
+                    self.config.clone(),
                     self.our_uid,
                     peer_id,
                     // Note; We bootstrap only to Nodes
                     CrustUser::Node,
-                    Event::BootstrapConnect(peer_id, peer_info.addr),
+                    protocol,
+                    move |handle| Event::BootstrapConnect(peer_id, peer_info.addr, handle),
                     self.event_tx.clone(),
                 );
             }
-            Err((bad_peer, opt_reason)) => {
+            Err((bad_peer, contact_res, redirects)) => {
                 {
                     let bootstrap_cache = core.user_data_mut();
                     bootstrap_cache.remove(&bad_peer);
@@ -183,8 +305,8 @@ impl<UID: Uid> Bootstrap<UID> {
                     }
                 }
 
-                if let Some(reason) = opt_reason {
-                    let (err_msg, is_err_fatal) = match reason {
+                if let ContactResult::Denied(ref reason) = contact_res {
+                    let (err_msg, is_err_fatal) = match *reason {
                         BootstrapDenyReason::InvalidNameHash => ("Network name mismatch.", false),
                         BootstrapDenyReason::FailedExternalReachability => (
                             "Bootstrappee node could not establish connection to us.",
@@ -196,12 +318,23 @@ impl<UID: Uid> Bootstrap<UID> {
                         BootstrapDenyReason::ClientNotWhitelisted => {
                             ("Our Client is not whitelisted", false)
                         }
+                        BootstrapDenyReason::IncompatibleVersion => {
+                            ("Peer speaks an incompatible protocol version", false)
+                        }
+                        BootstrapDenyReason::InvalidIdentity => {
+                            ("Peer did not accept our identity", false)
+                        }
+                        BootstrapDenyReason::TooManyConnections => {
+                            ("Peer has no room for more connections", false)
+                        }
+                        BootstrapDenyReason::ReachabilityCheckThrottled => {
+                            ("Peer is busy checking our reachability", false)
+                        }
                     };
                     if is_err_fatal {
                         error!("Failed to Bootstrap: ({:?}) {}", reason, err_msg);
-                        self.terminate(core, poll);
-                        let _ = self.event_tx.send(Event::BootstrapFailed);
-                        return;
+                        self.contacts.push((bad_peer, contact_res));
+                        return self.fail(core, poll);
                     } else {
                         info!(
                             "Failed to Bootstrap with {:?}: ({:?}) {}",
@@ -209,21 +342,47 @@ impl<UID: Uid> Bootstrap<UID> {
                         );
                     }
                 }
+                self.contacts.push((bad_peer, contact_res));
+                self.follow_redirects(core, poll, redirects);
             }
         }
         self.maybe_terminate(core, poll);
     }
 
     fn maybe_terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        if self.children.is_empty() {
+        if self.children.is_empty() && self.lan_timeout.is_none() {
             error!("Bootstrapper has no active children left - bootstrap has failed");
-            self.terminate(core, poll);
-            let _ = self.event_tx.send(Event::BootstrapFailed);
+            self.fail(core, poll);
         }
     }
 
+    /// Gives up on the peers we are still trying and reports how bootstrapping off each peer
+    /// failed.
+    fn fail(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.fail_with(core, poll, None)
+    }
+
+    /// Like `fail`, but reports the error which kept bootstrapping from getting under way too.
+    fn fail_with(&mut self, core: &mut EventLoopCore, poll: &Poll, error: Option<String>) {
+        let timed_out: Vec<_> = self
+            .children
+            .values()
+            .map(|peer| (*peer, ContactResult::TimedOut))
+            .collect();
+        self.contacts.extend(timed_out);
+        self.terminate(core, poll);
+
+        let report = BootstrapReport {
+            contacts: mem::replace(&mut self.contacts, Vec::new()),
+            elapsed: self.started.elapsed(),
+            service_discovery: self.service_discovery,
+            error,
+        };
+        let _ = self.event_tx.send(Event::BootstrapFailed(report));
+    }
+
     fn terminate_children(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        for child in self.children.drain() {
+        for (child, _) in self.children.drain() {
             let child = match core.get_state(child) {
                 Some(state) => state,
                 None => continue,
@@ -237,23 +396,18 @@ impl<UID: Uid> Bootstrap<UID> {
 impl<UID: Uid> State<BootstrapCache> for Bootstrap<UID> {
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, timer_id: u8) {
         if timer_id == self.bs_timer.timer_id {
-            let _ = self.event_tx.send(Event::BootstrapFailed);
-            return self.terminate(core, poll);
-        }
-
-        let rx = unwrap!(self.sd_meta.take()).rx;
-
-        while let Ok(listeners) = rx.try_recv() {
-            self.peers.extend(listeners);
+            return self.fail(core, poll);
         }
 
-        self.begin_bootstrap(core, poll);
+        // Service discovery found no peers on our LAN in time.
+        self.lan_timeout = None;
+        self.maybe_terminate(core, poll);
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         self.terminate_children(core, poll);
-        if let Some(sd_meta) = self.sd_meta.take() {
-            let _ = core.cancel_timeout(&sd_meta.timeout);
+        if let Some(timeout) = self.lan_timeout.take() {
+            let _ = core.cancel_timeout(&timeout);
         }
         let _ = core.remove_state(self.token);
         let _ = core.cancel_timeout(&self.bs_timeout);
@@ -279,33 +433,39 @@ pub fn cache_peer_info(core: &mut EventLoopCore, peer_info: PeerInfo, config: &C
     }
 }
 
-struct ServiceDiscMeta {
-    rx: Receiver<Vec<PeerInfo>>,
-    timeout: Timeout,
-}
-
-/// Runs service discovery state with a timeout. When timeout happens, `Bootstrap::timeout()`
-/// callback is called.
-fn seek_peers(
+/// Returns the peers service discovery currently knows on our LAN, and has the peers it finds from
+/// now on tried by the bootstrap for as long as it runs. If no peers are known yet, they are
+/// sought right away.
+fn watch_lan_peers<UID: Uid>(
     core: &mut EventLoopCore,
+    poll: &Poll,
     service_discovery_token: Token,
-    token: Token,
-) -> crate::Res<(Receiver<Vec<PeerInfo>>, Timeout)> {
+    bootstrap: Weak<RefCell<Bootstrap<UID>>>,
+) -> crate::Res<Vec<PeerInfo>> {
     if let Some(state) = core.get_state(service_discovery_token) {
         let mut state = state.borrow_mut();
         let state = unwrap!(state
             .as_any()
             .downcast_mut::<ServiceDiscovery<BootstrapCache>>());
 
-        let (obs, rx) = mpsc::channel();
-        state.register_observer(obs);
-        state.seek_peers()?;
-        let timeout = core.set_timeout(
-            Duration::from_secs(SERVICE_DISCOVERY_TIMEOUT_SEC),
-            CoreTimer::new(token, SERVICE_DISCOVERY_TIMER_ID),
-        );
+        state.register_lan_observer(Box::new(move |core, poll, change| {
+            let bootstrap = match bootstrap.upgrade() {
+                Some(bootstrap) => bootstrap,
+                None => return false,
+            };
+            if let LanPeerChange::Found(ref listeners) = *change {
+                bootstrap
+                    .borrow_mut()
+                    .handle_lan_peer_found(core, poll, listeners);
+            }
+            true
+        }));
 
-        Ok((rx, timeout))
+        let lan_peers = state.lan_peers();
+        if lan_peers.is_empty() {
+            state.seek_peers(core, poll)?;
+        }
+        Ok(lan_peers)
     } else {
         Err(CrustError::ServiceDiscNotEnabled)
     }
@@ -328,7 +488,10 @@ fn shuffled_bootstrap_peers(
     hard_coded.shuffle(&mut rng);
     peers.extend(hard_coded);
 
-    peers.retain(|peer| !blacklist.contains(&peer.addr));
+    let config = unwrap!(config.lock());
+    peers.retain(|peer| {
+        !blacklist.contains(&peer.addr) && !config.access_lists.is_blocked(&peer.addr.ip())
+    });
     peers
 }
 
@@ -373,16 +536,18 @@ mod tests {
         }
     }
 
-    mod seek_pers {
+    mod watch_lan_peers {
         use super::*;
+        use crate::tests::utils::UniqueId;
 
         #[test]
         fn it_returns_error_when_service_discovery_token_is_not_registered() {
             let dummy_token = Token(99999);
             let bootstrap_cache = test_bootstrap_cache();
             let mut core = test_core(bootstrap_cache);
+            let poll = unwrap!(Poll::new());
 
-            let res = seek_peers(&mut core, dummy_token, dummy_token);
+            let res = watch_lan_peers::<UniqueId>(&mut core, &poll, dummy_token, Weak::new());
 
             match res {
                 Err(CrustError::ServiceDiscNotEnabled) => (),
@@ -472,7 +637,8 @@ mod tests {
                         dummy_service_discovery_token,
                         event_tx,
                         our_pk,
-                        &our_sk
+                        &our_sk,
+                        None,
                     ));
 
                     let state = unwrap!(core.get_state(token));
@@ -483,7 +649,11 @@ mod tests {
                         &mut core,
                         &poll,
                         Token(2),
-                        Err((peer_info, None)),
+                        Err((
+                            peer_info,
+                            ContactResult::Failed("test".to_owned()),
+                            Vec::new(),
+                        )),
                     );
 
                     let cached_peers = core.user_data().peers();
@@ -522,7 +692,8 @@ mod tests {
                         dummy_service_discovery_token,
                         event_tx,
                         our_pk,
-                        &our_sk
+                        &our_sk,
+                        None,
                     ));
 
                     let state = unwrap!(core.get_state(token));
@@ -533,12 +704,74 @@ mod tests {
                         &mut core,
                         &poll,
                         Token(2),
-                        Err((peer_info, Some(BootstrapDenyReason::InvalidNameHash))),
+                        Err((
+                            peer_info,
+                            ContactResult::Denied(BootstrapDenyReason::InvalidNameHash),
+                            Vec::new(),
+                        )),
                     );
 
                     let state = core.get_state(token);
                     assert!(state.is_some());
                 }
+
+                #[test]
+                fn when_reason_is_fatal_bootstrap_fails_with_report() {
+                    let bootstrap_cache = test_bootstrap_cache();
+                    let peer_info = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 4, 4000));
+                    bootstrap_cache.put(peer_info);
+                    let mut core = test_core(bootstrap_cache);
+                    let poll = unwrap!(Poll::new());
+
+                    let config = Config::default();
+                    let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
+                    let dummy_service_discovery_token = Token(9999);
+
+                    let (our_pk, our_sk) = gen_encrypt_keypair();
+                    let (event_tx, event_rx) = get_event_sender();
+                    let token = Token(1);
+                    let conn_map = Arc::new(Mutex::new(HashMap::new()));
+
+                    unwrap!(Bootstrap::start(
+                        &mut core,
+                        &poll,
+                        [1; 32],
+                        ExternalReachability::NotRequired,
+                        rand_uid(),
+                        conn_map,
+                        config,
+                        HashSet::new(),
+                        token,
+                        dummy_service_discovery_token,
+                        event_tx,
+                        our_pk,
+                        &our_sk,
+                        None,
+                    ));
+
+                    let state = unwrap!(core.get_state(token));
+                    let mut state = state.borrow_mut();
+                    let bootstrap_state =
+                        unwrap!(state.as_any().downcast_mut::<Bootstrap<UniqueId>>());
+                    let child = *unwrap!(bootstrap_state.children.keys().next());
+                    let reason = BootstrapDenyReason::FailedExternalReachability;
+                    bootstrap_state.handle_result(
+                        &mut core,
+                        &poll,
+                        child,
+                        Err((peer_info, ContactResult::Denied(reason.clone()), Vec::new())),
+                    );
+
+                    assert!(core.get_state(token).is_none());
+                    match unwrap!(event_rx.try_recv()) {
+                        Event::BootstrapFailed(report) => {
+                            let denied = ContactResult::Denied(reason);
+                            assert_eq!(report.contacts, vec![(peer_info, denied)]);
+                            assert!(!report.service_discovery);
+                        }
+                        event => panic!("Unexpected event: {:?}", event),
+                    }
+                }
             }
         }
     }
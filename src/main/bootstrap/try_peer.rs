@@ -8,24 +8,26 @@
 // Software.
 
 use crate::common::{
-    BootstrapDenyReason, ExternalReachability, Message, NameHash, PeerInfo, State, Uid,
+    prove_identity, verify_identity, BootstrapDenyReason, ExternalReachability, Freshness,
+    Handshake, Identity, Message, NameHash, PeerInfo, ProtocolInfo, State, Uid,
 };
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::EventLoopCore;
+use crate::main::{ContactResult, EventLoopCore};
 use mio::{Poll, PollOpt, Ready, Token};
-use safe_crypto::{PublicEncryptKey, SecretEncryptKey, SharedSecretKey};
+use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey};
 use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
 use std::mem;
 use std::rc::Rc;
+use std::sync::Arc;
 
 pub type Finish<UID> = Box<
     FnMut(
         &mut EventLoopCore,
         &Poll,
         Token,
-        Result<(TcpSock, PeerInfo, UID), (PeerInfo, Option<BootstrapDenyReason>)>,
+        Result<(TcpSock, PeerInfo, UID, ProtocolInfo), (PeerInfo, ContactResult, Vec<PeerInfo>)>,
     ),
 >;
 
@@ -34,9 +36,12 @@ pub struct TryPeer<UID: Uid> {
     token: Token,
     peer: PeerInfo,
     socket: TcpSock,
-    request: Option<(Message<UID>, Priority)>,
+    request: Option<(Handshake, Priority)>,
     finish: Finish<UID>,
-    shared_key: SharedSecretKey,
+    our_protocol: ProtocolInfo,
+    our_eph_pk: PublicEncryptKey,
+    our_eph_sk: SecretEncryptKey,
+    identity: Option<Arc<Identity<UID>>>,
 }
 
 impl<UID: Uid> TryPeer<UID> {
@@ -49,13 +54,16 @@ impl<UID: Uid> TryPeer<UID> {
         ext_reachability: ExternalReachability,
         our_pk: PublicEncryptKey,
         our_sk: &SecretEncryptKey,
+        our_protocol: ProtocolInfo,
+        identity: Option<Arc<Identity<UID>>>,
         finish: Finish<UID>,
     ) -> crate::Res<Token> {
         let mut socket = TcpSock::connect(&peer.addr)?;
         socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(peer.pub_key))?;
         let shared_key = our_sk.shared_secret(&peer.pub_key);
-        socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone()))?;
+        socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key))?;
         let token = core.get_new_token();
+        let (our_eph_pk, our_eph_sk) = gen_encrypt_keypair();
 
         poll.register(
             &socket,
@@ -64,16 +72,29 @@ impl<UID: Uid> TryPeer<UID> {
             PollOpt::edge(),
         )?;
 
+        let our_proof = prove_identity(&identity, &our_uid, &our_eph_pk, &peer.pub_key);
+        let request = Handshake::request(
+            our_protocol,
+            our_pk,
+            &Message::BootstrapRequest(
+                Freshness::now(),
+                our_uid,
+                name_hash,
+                ext_reachability,
+                our_eph_pk,
+                our_proof,
+            ),
+        )?;
         let state = TryPeer {
             token,
             peer,
             socket,
-            request: Some((
-                Message::BootstrapRequest(our_uid, name_hash, ext_reachability, our_pk),
-                0,
-            )),
+            request: Some((request, 0)),
             finish,
-            shared_key,
+            our_protocol,
+            our_eph_pk,
+            our_eph_sk,
+            identity,
         };
 
         let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
@@ -81,52 +102,79 @@ impl<UID: Uid> TryPeer<UID> {
         Ok(token)
     }
 
-    fn write(
-        &mut self,
-        core: &mut EventLoopCore,
-        poll: &Poll,
-        msg: Option<(Message<UID>, Priority)>,
-    ) {
-        if self.socket.write(msg).is_err() {
-            self.handle_error(core, poll, None);
+    fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, msg: Option<(Handshake, Priority)>) {
+        if let Err(e) = self.socket.write(msg) {
+            self.handle_error(core, poll, ContactResult::Failed(format!("{:?}", e)));
         }
     }
 
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        match self.socket.read::<Message<UID>>() {
-            Ok(Some(Message::BootstrapGranted(peer_uid))) => {
+        let handshake = match self.socket.read::<Handshake>() {
+            Ok(Some(handshake)) => handshake,
+            Ok(None) => return,
+            Err(e) => {
+                return self.handle_error(core, poll, ContactResult::Failed(format!("{:?}", e)))
+            }
+        };
+        let protocol = match self.our_protocol.negotiate(&handshake.protocol) {
+            Some(protocol) => protocol,
+            None => {
+                let reason = BootstrapDenyReason::IncompatibleVersion;
+                return self.handle_error(core, poll, ContactResult::Denied(reason));
+            }
+        };
+
+        match handshake.message::<UID>(protocol.version) {
+            Ok(Message::BootstrapGranted(peer_uid, their_eph_pk, their_proof)) => {
+                if !verify_identity(
+                    &self.identity,
+                    &their_proof,
+                    &peer_uid,
+                    &their_eph_pk,
+                    &self.our_eph_pk,
+                ) {
+                    debug!("Peer {:?} failed to prove its identity", peer_uid);
+                    let res = ContactResult::Failed("Peer failed to prove its identity".to_owned());
+                    return self.handle_error(core, poll, res);
+                }
                 let _ = core.remove_state(self.token);
                 let token = self.token;
 
                 let mut socket = mem::replace(&mut self.socket, Default::default());
-                match socket.set_encrypt_ctx(EncryptContext::authenticated(self.shared_key.clone()))
-                {
-                    Ok(_) => {
-                        let data = (socket, self.peer, peer_uid);
+                // Everything after the handshake is encrypted with the key derived from the
+                // ephemeral keys, so that leaking the static keys later doesn't expose it.
+                let session_key = self.our_eph_sk.shared_secret(&their_eph_pk);
+                match (
+                    socket.set_encrypt_ctx(EncryptContext::authenticated(session_key.clone())),
+                    socket.set_decrypt_ctx(DecryptContext::authenticated(session_key)),
+                ) {
+                    (Ok(_), Ok(_)) => {
+                        let data = (socket, self.peer, peer_uid, protocol);
                         (*self.finish)(core, poll, token, Ok(data));
                     }
-                    Err(e) => {
-                        warn!("Failed to set socket encrypt context: {}", e);
-                        self.handle_error(core, poll, None);
+                    res => {
+                        warn!("Failed to set socket encrypt/decrypt context: {:?}", res);
+                        let res = ContactResult::Failed(format!("{:?}", res));
+                        self.handle_error(core, poll, res);
                     }
                 }
             }
-            Ok(Some(Message::BootstrapDenied(reason))) => {
-                self.handle_error(core, poll, Some(reason))
+            Ok(Message::BootstrapDenied(reason, redirects)) => {
+                self.terminate(core, poll);
+                let res = Err((self.peer, ContactResult::Denied(reason), redirects));
+                (*self.finish)(core, poll, self.token, res);
             }
-            Ok(None) => (),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll, None),
+            Ok(_) => {
+                let res = ContactResult::Failed("Unexpected message".to_owned());
+                self.handle_error(core, poll, res);
+            }
+            Err(e) => self.handle_error(core, poll, ContactResult::Failed(format!("{:?}", e))),
         }
     }
 
-    fn handle_error(
-        &mut self,
-        core: &mut EventLoopCore,
-        poll: &Poll,
-        reason: Option<BootstrapDenyReason>,
-    ) {
+    fn handle_error(&mut self, core: &mut EventLoopCore, poll: &Poll, res: ContactResult) {
         self.terminate(core, poll);
-        (*self.finish)(core, poll, self.token, Err((self.peer, reason)));
+        (*self.finish)(core, poll, self.token, Err((self.peer, res, Vec::new())));
     }
 }
 
@@ -147,7 +195,8 @@ impl<UID: Uid> State<BootstrapCache> for TryPeer<UID> {
             "Considering the following event to indicate dirupted connection: {:?}",
             kind
         );
-        self.handle_error(core, poll, None);
+        let res = ContactResult::Failed("Connection disrupted".to_owned());
+        self.handle_error(core, poll, res);
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
@@ -8,6 +8,7 @@
 // Software.
 
 use crate::common::PeerInfo;
+use crate::main::{AccessLists, IpRange};
 use config_file_handler::{self, FileHandler};
 use std::collections::HashSet;
 use std::ffi::OsString;
@@ -40,21 +41,158 @@ pub struct Config {
     /// useful when you want to run multiple instances of Crust on the same machine.
     /// By default it will use the same as `service_discovery_port` value.
     pub service_discovery_listener_port: Option<u16>,
+    /// How peers on the local network are discovered. Defaults to `DiscoveryMethod::Broadcast`.
+    pub service_discovery_method: Option<DiscoveryMethod>,
     /// File for bootstrap cache
     pub bootstrap_cache_name: Option<OsString>,
     /// Whitelisted nodes who are allowed to bootstrap off us or to connect to us
     pub whitelisted_node_ips: Option<HashSet<IpAddr>>,
     /// Whitelisted clients who are allowed to bootstrap off us
     pub whitelisted_client_ips: Option<HashSet<IpAddr>>,
+    /// IP ranges peers are allowed or refused from. Can be changed at runtime via `Service`.
+    pub access_lists: Option<AccessLists>,
+    /// File access lists changed at runtime are persisted to. They take precedence over
+    /// `access_lists` on start. Defaults to executable file + '.access_lists' suffix.
+    pub access_lists_file_name: Option<OsString>,
     /// Network ID
     ///
     /// This is a mechanism to prevent nodes from different decentralized
     /// networks to connect to each other (issue #209)
     pub network_name: Option<String>,
+    /// Bandwidth limits. If not given, traffic is not limited.
+    pub rate_limits: Option<RateLimits>,
+    /// Data messages bigger than this many bytes are compressed, provided the peer supports
+    /// compression too. If not given, compression is disabled and not offered to peers.
+    pub compression_threshold_bytes: Option<usize>,
+    /// Connections derive a fresh session key after transferring this many bytes. Defaults to
+    /// 1 GiB.
+    pub rekey_after_bytes: Option<u64>,
+    /// Connections derive a fresh session key after being open this many seconds. Defaults to one
+    /// hour.
+    pub rekey_interval_secs: Option<u64>,
+    /// Limits protecting the TCP listener against connection floods. If not given, defaults apply.
+    pub listener_limits: Option<ListenerLimits>,
+    /// Limits on the number of established connections. If not given, they are not limited.
+    pub connection_limits: Option<ConnectionLimits>,
+    /// If `true`, we share contacts of peers we lately connected to directly with our peers and
+    /// learn new bootstrap contacts from them. Defaults to `false`.
+    pub peer_exchange: Option<bool>,
+    /// If given, we relay sessions between our peers which can't connect to each other directly.
+    pub relay: Option<RelayConfig>,
+    /// If `true`, a second connection with each Node, over a different pair of addresses, is kept
+    /// on standby and takes over when the active one fails. Only used with peers which enable it
+    /// too. Defaults to `false`.
+    pub standby_connections: Option<bool>,
+    /// Our network interfaces are checked for changes this often, in seconds. When they change,
+    /// our listeners are mapped anew. Defaults to 60.
+    pub interface_scan_interval_secs: Option<u64>,
+    /// If `true`, connections are checked as soon as our network interfaces change: ones over
+    /// addresses we no longer have are dropped and the others send a heartbeat, so that broken
+    /// ones fail fast. Defaults to `false`.
+    pub revalidate_connections: Option<bool>,
+    /// Our external addresses are refreshed this often, in seconds. Defaults to 5 minutes.
+    pub external_addr_refresh_interval_secs: Option<u64>,
     /// Optional developer configuration
     pub dev: Option<DevConfig>,
 }
 
+/// Token bucket based bandwidth limits for connection traffic.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RateLimits {
+    /// Limits shared by all connections together.
+    pub global: BandwidthLimit,
+    /// Limits applied to each connection individually.
+    pub per_peer: BandwidthLimit,
+    /// Overrides `per_peer` limits for connections with Nodes.
+    pub per_node: Option<BandwidthLimit>,
+    /// Overrides `per_peer` limits for connections with Clients.
+    pub per_client: Option<BandwidthLimit>,
+}
+
+/// Upload and download limits in bytes per second. `None` means unlimited.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct BandwidthLimit {
+    /// Maximum rate we send data at.
+    pub upload_bytes_per_sec: Option<u64>,
+    /// Maximum rate we receive data at.
+    pub download_bytes_per_sec: Option<u64>,
+}
+
+/// Limits on incoming connections which haven't completed their handshake yet. `None` fields
+/// take default values.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ListenerLimits {
+    /// Maximum number of handshakes in progress at once. Defaults to 512.
+    pub max_handshakes: Option<usize>,
+    /// Maximum number of handshakes in progress with a single IP. Defaults to 16.
+    pub max_handshakes_per_ip: Option<usize>,
+    /// Number of connections accepted from a single IP per second. Defaults to 10.
+    pub accepts_per_sec_per_ip: Option<u64>,
+    /// Seconds a peer has to complete its handshake in. Defaults to 30.
+    pub handshake_timeout_secs: Option<u64>,
+    /// Number of failed handshakes in a row after which an IP gets banned. Defaults to 10.
+    pub max_failures_per_ip: Option<u32>,
+    /// Seconds a banned IP is refused connections for. Defaults to 10 minutes.
+    pub ban_secs: Option<u64>,
+    /// Maximum number of listeners checked for external reachability per bootstrap request.
+    /// Defaults to 4.
+    pub max_reachability_checks: Option<usize>,
+    /// Number of reachability checks made on behalf of a single IP per second. Defaults to 1.
+    pub reachability_checks_per_sec_per_ip: Option<u64>,
+    /// If `true`, bootstrappers' listeners are checked for reachability even if their IP differs
+    /// from the one the bootstrapper connects from. Needed when nodes reach us through a different
+    /// NAT than the one they accept connections through. Defaults to `false`.
+    pub reachability_check_any_ip: Option<bool>,
+}
+
+/// Maximum numbers of established connections, enforced when peers bootstrap off or connect to
+/// us, directly or through a relay. Relayed sessions count as connections too. `None` fields mean
+/// no limit.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ConnectionLimits {
+    /// Maximum number of connections with Nodes.
+    pub max_nodes: Option<usize>,
+    /// Maximum number of connections with Clients.
+    pub max_clients: Option<usize>,
+    /// Peers within these ranges are privileged: their connections are never evicted.
+    pub privileged_ranges: Option<Vec<IpRange>>,
+    /// If given, a privileged peer finding no room evicts an unprivileged connection of the same
+    /// kind picked by this policy. Otherwise it is refused like any other peer.
+    pub eviction: Option<EvictionPolicy>,
+}
+
+/// Picks which connection gets evicted to make room for a privileged one.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum EvictionPolicy {
+    /// Connection which didn't exchange data for the longest time.
+    OldestIdle,
+    /// Connection which exchanged the fewest bytes.
+    LowestTraffic,
+}
+
+/// Way requests of service discovery reach peers on the local network.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum DiscoveryMethod {
+    /// UDP broadcasts to `255.255.255.255`. Limited to our IPv4 subnet.
+    Broadcast,
+    /// UDP multicast to an administratively scoped IPv4 group and a site-local IPv6 group. Works
+    /// on networks filtering broadcasts and can reach other subnets if multicast is routed.
+    Multicast,
+}
+
+/// Quotas on the sessions we relay. Sessions exceeding them are closed. `None` fields take default
+/// values.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RelayConfig {
+    /// Maximum number of sessions relayed at once. Defaults to 32.
+    pub max_sessions: Option<usize>,
+    /// Maximum number of sessions relayed at once from or to a single peer. Defaults to 4.
+    pub max_sessions_per_peer: Option<usize>,
+    /// Maximum number of payload bytes relayed per session. Defaults to 64 MiB. Peers whose session
+    /// ran out of it may not open another one for 10 minutes.
+    pub max_bytes_per_session: Option<u64>,
+}
+
 /// Developer options
 #[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone, Default)]
 pub struct DevConfig {
@@ -70,10 +208,25 @@ impl Default for Config {
             force_acceptor_port_in_ext_ep: false,
             service_discovery_port: None,
             service_discovery_listener_port: None,
+            service_discovery_method: None,
             bootstrap_cache_name: None,
             whitelisted_node_ips: None,
             whitelisted_client_ips: None,
+            access_lists: None,
+            access_lists_file_name: None,
             network_name: None,
+            rate_limits: None,
+            compression_threshold_bytes: None,
+            rekey_after_bytes: None,
+            rekey_interval_secs: None,
+            listener_limits: None,
+            connection_limits: None,
+            peer_exchange: None,
+            relay: None,
+            standby_connections: None,
+            interface_scan_interval_secs: None,
+            revalidate_connections: None,
+            external_addr_refresh_interval_secs: None,
             dev: None,
         }
     }
@@ -7,9 +7,12 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{CoreTimer, CrustUser, State, Uid};
+use crate::common::{CoreTimer, State, Uid};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{read_config_file, ActiveConnection, ConnectionMap, CrustConfig, EventLoopCore};
+use crate::main::{
+    read_config_file, AccessLists, ActiveConnection, ConnectionMap, CrustConfig, DisconnectReason,
+    EventLoopCore, RateLimits,
+};
 use mio::{Poll, Token};
 use mio_extras::timer::Timeout;
 use std::any::Any;
@@ -25,6 +28,11 @@ pub struct ConfigRefresher<UID: Uid> {
     timeout: Timeout,
     cm: ConnectionMap<UID>,
     config: CrustConfig,
+    /// Rate limits last read from the config file. Runtime changes made via `Service` are only
+    /// overridden when the file changes.
+    file_rate_limits: Option<RateLimits>,
+    /// Access lists last read from the config file. Same as with `file_rate_limits`.
+    file_access_lists: Option<AccessLists>,
 }
 
 impl<UID: Uid> ConfigRefresher<UID> {
@@ -38,6 +46,13 @@ impl<UID: Uid> ConfigRefresher<UID> {
 
         let timer = CoreTimer::new(token, 0);
         let timeout = core.set_timeout(Duration::from_secs(REFRESH_INTERVAL_SEC), timer);
+        let (file_rate_limits, file_access_lists) = {
+            let config = unwrap!(config.lock());
+            (
+                config.cfg.rate_limits.clone(),
+                config.cfg.access_lists.clone(),
+            )
+        };
 
         let state = Rc::new(RefCell::new(ConfigRefresher {
             token,
@@ -45,6 +60,8 @@ impl<UID: Uid> ConfigRefresher<UID> {
             timeout,
             cm,
             config,
+            file_rate_limits,
+            file_access_lists,
         }));
         let _ = core.insert_state(token, state);
 
@@ -72,67 +89,49 @@ impl<UID: Uid> State<BootstrapCache> for ConfigRefresher<UID> {
             }
         };
 
-        let whitelisted_node_ips = config.whitelisted_node_ips.clone();
-        let whitelisted_client_ips = config.whitelisted_client_ips.clone();
+        if config.rate_limits != self.file_rate_limits {
+            trace!("Rate limits in Crust config have changed - applying them.");
+            self.file_rate_limits = config.rate_limits.clone();
+            unwrap!(self.config.lock())
+                .bandwidth
+                .set_limits(config.rate_limits.clone().unwrap_or_default());
+        }
 
-        if !unwrap!(self.config.lock()).check_for_refresh_and_reset_modified(config)
-            || (whitelisted_node_ips.is_none() && whitelisted_client_ips.is_none())
+        let mut purge = false;
         {
-            return;
+            let mut wrapper = unwrap!(self.config.lock());
+            if config.access_lists != self.file_access_lists {
+                trace!("Access lists in Crust config have changed - applying them.");
+                self.file_access_lists = config.access_lists.clone();
+                wrapper.access_lists = config.access_lists.clone().unwrap_or_default();
+                if let Err(e) = wrapper
+                    .access_lists
+                    .commit(wrapper.cfg.access_lists_file_name.as_ref())
+                {
+                    info!("Failed to write access lists file: {}", e);
+                }
+                purge = true;
+            }
+            if wrapper.access_lists.remove_expired() {
+                trace!("Some access list entries have expired.");
+                purge = true;
+            }
         }
 
-        trace!(
-            "Crust config has been updated - going to purge any nodes or clients that are no \
-             longer whitelisted"
-        );
-
-        // Peers collected to avoid keeping the mutex lock alive which might lead to deadlock
-        let peers_to_terminate: Vec<_> = unwrap!(self.cm.lock())
-            .values()
-            .filter_map(|cid| {
-                cid.active_connection
-                    .and_then(|token| core.get_state(token))
-                    .and_then(|peer| {
-                        let should_drop = {
-                            let mut state = peer.borrow_mut();
-                            let ac = match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
-                                Some(ac) => ac,
-                                None => {
-                                    warn!(
-                                        "Token reserved for ActiveConnection has something else."
-                                    );
-                                    return None;
-                                }
-                            };
-                            match ac.peer_addr() {
-                                Err(e) => {
-                                    debug!(
-                                        "Could not obtain Peer IP: {:?} - dropping this peer.",
-                                        e
-                                    );
-                                    true
-                                }
-                                Ok(s) => match ac.peer_kind() {
-                                    CrustUser::Node => whitelisted_node_ips
-                                        .as_ref()
-                                        .map_or(false, |ips| !ips.contains(&s.ip())),
-                                    CrustUser::Client => whitelisted_client_ips
-                                        .as_ref()
-                                        .map_or(false, |ips| !ips.contains(&s.ip())),
-                                },
-                            }
-                        };
-                        if should_drop {
-                            Some(peer)
-                        } else {
-                            None
-                        }
-                    })
-            })
-            .collect();
+        let has_whitelists =
+            config.whitelisted_node_ips.is_some() || config.whitelisted_client_ips.is_some();
+        if unwrap!(self.config.lock()).check_for_refresh_and_reset_modified(config)
+            && has_whitelists
+        {
+            purge = true;
+        }
 
-        for peer in peers_to_terminate {
-            peer.borrow_mut().terminate(core, poll);
+        if purge {
+            trace!(
+                "Crust config has been updated - going to purge any nodes or clients that are no \
+                 longer allowed"
+            );
+            drop_disallowed_peers(core, poll, &self.cm, &self.config);
         }
     }
 
@@ -140,3 +139,57 @@ impl<UID: Uid> State<BootstrapCache> for ConfigRefresher<UID> {
         self
     }
 }
+
+/// Terminates connections with peers which aren't allowed according to the current whitelists
+/// and access lists.
+pub fn drop_disallowed_peers<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    config: &CrustConfig,
+) {
+    // Peers collected to avoid keeping the mutex lock alive which might lead to deadlock. Standby
+    // connections are checked on their own: if only the active one is disallowed, the standby one
+    // takes over.
+    let tokens: Vec<_> = unwrap!(cm.lock())
+        .values()
+        .flat_map(|cid| cid.connections())
+        .collect();
+    let peers: Vec<_> = tokens
+        .into_iter()
+        .filter_map(|token| {
+            core.get_state(token).and_then(|peer| {
+                let (peer_addr, peer_kind) = {
+                    let mut state = peer.borrow_mut();
+                    // Relayed connections aren't `ActiveConnection`s. They go down together
+                    // with the connection to their relay.
+                    let ac = state.as_any().downcast_mut::<ActiveConnection<UID>>()?;
+                    (ac.peer_addr(), ac.peer_kind())
+                };
+                Some((peer, peer_addr, peer_kind))
+            })
+        })
+        .collect();
+
+    let peers_to_terminate: Vec<_> = {
+        let config = unwrap!(config.lock());
+        peers
+            .into_iter()
+            .filter(|&(_, ref peer_addr, peer_kind)| match *peer_addr {
+                Err(ref e) => {
+                    debug!("Could not obtain Peer IP: {:?} - dropping this peer.", e);
+                    true
+                }
+                Ok(s) => !config.is_ip_allowed(&s.ip(), peer_kind),
+            })
+            .map(|(peer, _, _)| peer)
+            .collect()
+    };
+
+    for peer in peers_to_terminate {
+        let mut state = peer.borrow_mut();
+        if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+            ac.disconnect(core, poll, DisconnectReason::NotAllowed);
+        }
+    }
+}
@@ -7,21 +7,27 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{Message, NameHash, State, Uid};
+use crate::common::{
+    prove_identity, verify_identity, Freshness, Handshake, Identity, Message, NameHash,
+    ProtocolInfo, State, Uid,
+};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{ConnectionId, ConnectionMap, EventLoopCore};
+use crate::main::{AddrFailure, ConnectionId, ConnectionMap, EventLoopCore};
 use mio::{Poll, PollOpt, Ready, Token};
-use safe_crypto::{PublicEncryptKey, SharedSecretKey};
-use socket_collection::{EncryptContext, Priority, TcpSock};
+use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey};
+use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::mem;
 use std::rc::Rc;
+use std::sync::Arc;
 
 /// When connection messages are exchanged a callback is called with these parameters.
-/// A new mio `Token` is assigned to the given socket.
-pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, Option<TcpSock>)>;
+/// A new mio `Token` is assigned to the given socket. On success, the socket is passed along with
+/// the negotiated protocol, otherwise how the handshake failed.
+pub type Finish =
+    Box<FnMut(&mut EventLoopCore, &Poll, Token, Result<(TcpSock, ProtocolInfo), AddrFailure>)>;
 
 /// Exchanges connect messages.
 pub struct ExchangeMsg<UID: Uid> {
@@ -30,8 +36,11 @@ pub struct ExchangeMsg<UID: Uid> {
     expected_nh: NameHash,
     socket: TcpSock,
     cm: ConnectionMap<UID>,
-    msg: Option<(Message<UID>, Priority)>,
-    shared_key: SharedSecretKey,
+    msg: Option<(Handshake, Priority)>,
+    our_protocol: ProtocolInfo,
+    our_eph_pk: PublicEncryptKey,
+    our_eph_sk: SecretEncryptKey,
+    identity: Option<Arc<Identity<UID>>>,
     finish: Finish,
 }
 
@@ -45,10 +54,13 @@ impl<UID: Uid> ExchangeMsg<UID> {
         name_hash: NameHash,
         cm: ConnectionMap<UID>,
         our_pk: PublicEncryptKey,
-        shared_key: SharedSecretKey,
+        their_pk: PublicEncryptKey,
+        our_protocol: ProtocolInfo,
+        identity: Option<Arc<Identity<UID>>>,
         finish: Finish,
     ) -> crate::Res<Token> {
         let token = core.get_new_token();
+        let (our_eph_pk, our_eph_sk) = gen_encrypt_keypair();
 
         poll.register(
             &socket,
@@ -64,6 +76,8 @@ impl<UID: Uid> ExchangeMsg<UID> {
                 .or_insert(ConnectionId {
                     active_connection: None,
                     currently_handshaking: 0,
+                    standby: None,
+                    generation: 0,
                 })
                 .currently_handshaking += 1;
             trace!(
@@ -73,14 +87,23 @@ impl<UID: Uid> ExchangeMsg<UID> {
             );
         }
 
+        let our_proof = prove_identity(&identity, &our_id, &our_eph_pk, &their_pk);
+        let request = Handshake::request(
+            our_protocol,
+            our_pk,
+            &Message::Connect(Freshness::now(), our_id, name_hash, our_eph_pk, our_proof),
+        )?;
         let state = Self {
             token,
             expected_id,
             expected_nh: name_hash,
             socket,
             cm,
-            msg: Some((Message::Connect(our_id, name_hash, our_pk), 0)),
-            shared_key,
+            msg: Some((request, 0)),
+            our_protocol,
+            our_eph_pk,
+            our_eph_sk,
+            identity,
             finish,
         };
 
@@ -89,45 +112,79 @@ impl<UID: Uid> ExchangeMsg<UID> {
         Ok(token)
     }
 
-    fn write(
-        &mut self,
-        core: &mut EventLoopCore,
-        poll: &Poll,
-        msg: Option<(Message<UID>, Priority)>,
-    ) {
+    fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, msg: Option<(Handshake, Priority)>) {
         if self.socket.write(msg).is_err() {
             self.handle_error(core, poll);
         }
     }
 
     fn receive_response(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        match self.socket.read::<Message<UID>>() {
-            Ok(Some(Message::Connect(their_uid, name_hash, _their_pk))) => {
+        let handshake = match self.socket.read::<Handshake>() {
+            Ok(Some(handshake)) => handshake,
+            Ok(None) => return,
+            Err(_) => return self.handle_error(core, poll),
+        };
+        let protocol = match self.our_protocol.negotiate(&handshake.protocol) {
+            Some(protocol) => protocol,
+            None => {
+                debug!(
+                    "Peer speaks an incompatible protocol: {:?}",
+                    handshake.protocol
+                );
+                return self.handle_error(core, poll);
+            }
+        };
+
+        match handshake.message::<UID>(protocol.version) {
+            Ok(Message::Connect(_, their_uid, name_hash, their_eph_pk, their_proof)) => {
                 if their_uid != self.expected_id || name_hash != self.expected_nh {
                     return self.handle_error(core, poll);
                 }
+                if !verify_identity(
+                    &self.identity,
+                    &their_proof,
+                    &their_uid,
+                    &their_eph_pk,
+                    &self.our_eph_pk,
+                ) {
+                    debug!("Peer {:?} failed to prove its identity", their_uid);
+                    return self.handle_error(core, poll);
+                }
                 let _ = core.remove_state(self.token);
                 let token = self.token;
 
                 let mut socket = mem::replace(&mut self.socket, Default::default());
-                match socket.set_encrypt_ctx(EncryptContext::authenticated(self.shared_key.clone()))
-                {
-                    Ok(_) => (*self.finish)(core, poll, token, Some(socket)),
-                    Err(e) => {
-                        warn!("Failed to set socket encrypt context: {}", e);
+                let session_key = self.our_eph_sk.shared_secret(&their_eph_pk);
+                match (
+                    socket.set_encrypt_ctx(EncryptContext::authenticated(session_key.clone())),
+                    socket.set_decrypt_ctx(DecryptContext::authenticated(session_key)),
+                ) {
+                    (Ok(_), Ok(_)) => (*self.finish)(core, poll, token, Ok((socket, protocol))),
+                    res => {
+                        warn!("Failed to set socket encrypt/decrypt context: {:?}", res);
                         self.handle_error(core, poll);
                     }
                 }
             }
-            Ok(None) => (),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll),
+            Ok(Message::ConnectDenied(reason)) => {
+                debug!(
+                    "Peer {:?} denied connection: {:?}",
+                    self.expected_id, reason
+                );
+                self.fail(core, poll, AddrFailure::Denied(reason));
+            }
+            Ok(_) | Err(_) => self.handle_error(core, poll),
         }
     }
 
     fn handle_error(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.fail(core, poll, AddrFailure::Handshake);
+    }
+
+    fn fail(&mut self, core: &mut EventLoopCore, poll: &Poll, failure: AddrFailure) {
         self.terminate(core, poll);
         let token = self.token;
-        (*self.finish)(core, poll, token, None);
+        (*self.finish)(core, poll, token, Err(failure));
     }
 }
 
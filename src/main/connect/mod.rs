@@ -10,25 +10,35 @@
 mod exchange_msg;
 
 use self::exchange_msg::ExchangeMsg;
-use crate::common::{CoreTimer, CrustUser, NameHash, PeerInfo, State, Uid};
+use crate::common::{CoreTimer, CrustUser, Identity, NameHash, PeerInfo, ProtocolInfo, State, Uid};
 use crate::main::bootstrap;
+use crate::main::relay::Endpoint;
 use crate::main::{
-    ActiveConnection, ConnectionCandidate, ConnectionMap, CrustConfig, CrustError, Event,
-    EventLoopCore, PrivConnectionInfo, PubConnectionInfo,
+    ActiveConnection, AddrFailure, ConnectFailureReason, ConnectionCandidate, ConnectionMap,
+    CrustConfig, CrustError, Event, EventLoopCore, PrivConnectionInfo, PubConnectionInfo,
+    RelayedConnection,
 };
+use crate::nat::connect_from_port;
 use mio::{Poll, Token};
 use mio_extras::timer::Timeout;
-use safe_crypto::{PublicEncryptKey, SecretEncryptKey, SharedSecretKey};
+use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
 use socket_collection::{DecryptContext, EncryptContext, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::HashSet;
+use std::mem;
+use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
+use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(not(test))]
 const TIMEOUT_SEC: u64 = 60;
+#[cfg(test)]
+const TIMEOUT_SEC: u64 = 5;
 
-/// Atempts multiple connections to remote peer, but yields the first successful one.
+/// Atempts multiple connections to remote peer, but yields the first successful one. If none of
+/// them succeeds, falls back to sessions relayed through the nodes the peer suggested.
 pub struct Connect<UID: Uid> {
     token: Token,
     timeout: Timeout,
@@ -41,6 +51,17 @@ pub struct Connect<UID: Uid> {
     event_tx: crate::CrustEventSender<UID>,
     our_pk: PublicEncryptKey,
     config: CrustConfig,
+    identity: Option<Arc<Identity<UID>>>,
+    their_pk: PublicEncryptKey,
+    /// Relays yet to try.
+    their_relays: Vec<UID>,
+    /// Whether the user learns about failures. Attempts to upgrade a relayed session fail quietly.
+    report_failure: bool,
+    /// How the attempt to connect to each of the peer's addresses went so far.
+    attempts: Vec<(SocketAddr, AddrFailure)>,
+    /// How the attempt through each relay tried so far went.
+    relay_attempts: Vec<(UID, AddrFailure)>,
+    timed_out: bool,
 }
 
 impl<UID: Uid> Connect<UID> {
@@ -55,12 +76,93 @@ impl<UID: Uid> Connect<UID> {
         our_pk: PublicEncryptKey,
         our_sk: &SecretEncryptKey,
         config: CrustConfig,
+        identity: Option<Arc<Identity<UID>>>,
     ) -> crate::Res<()> {
-        let their_id = their_ci.id;
-        let their_direct = their_ci.for_direct;
+        Self::attempt(
+            core, poll, our_ci, their_ci, cm, our_nh, event_tx, our_pk, our_sk, config, identity,
+            true, None,
+        )
+    }
 
+    /// Tries to connect directly to a peer we have a relayed session with. If that succeeds, the
+    /// direct connection takes the session over. Connecting from the port of our listener lets us
+    /// through the holes the peer punched in its NAT for the address the listener is mapped to.
+    pub fn upgrade(
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        endpoint: &Endpoint<UID>,
+        their_id: UID,
+        their_pk: PublicEncryptKey,
+        their_direct: Vec<SocketAddr>,
+        local_port: Option<u16>,
+    ) -> crate::Res<()> {
+        let their_direct = {
+            let config = unwrap!(endpoint.config.lock());
+            their_direct
+                .into_iter()
+                .filter(|addr| config.is_ip_allowed(&addr.ip(), CrustUser::Node))
+                .collect::<Vec<_>>()
+        };
         if their_direct.is_empty() {
-            let _ = event_tx.send(Event::ConnectFailure(their_id));
+            return Err(CrustError::InsufficientConnectionInfo);
+        }
+        trace!("Trying to connect directly to relayed peer {:?}", their_id);
+
+        let our_ci = PrivConnectionInfo {
+            id: endpoint.our_id,
+            for_direct: vec![],
+            for_relay: vec![],
+            our_pk: endpoint.our_pk,
+        };
+        let their_ci = PubConnectionInfo {
+            id: their_id,
+            for_direct: their_direct,
+            for_relay: vec![],
+            our_pk: their_pk,
+        };
+        Self::attempt(
+            core,
+            poll,
+            our_ci,
+            their_ci,
+            endpoint.cm.clone(),
+            endpoint.name_hash,
+            endpoint.event_tx.clone(),
+            endpoint.our_pk,
+            &endpoint.our_sk,
+            endpoint.config.clone(),
+            endpoint.identity.clone(),
+            false,
+            local_port,
+        )
+    }
+
+    fn attempt(
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        our_ci: PrivConnectionInfo<UID>,
+        their_ci: PubConnectionInfo<UID>,
+        cm: ConnectionMap<UID>,
+        our_nh: NameHash,
+        event_tx: crate::CrustEventSender<UID>,
+        our_pk: PublicEncryptKey,
+        our_sk: &SecretEncryptKey,
+        config: CrustConfig,
+        identity: Option<Arc<Identity<UID>>>,
+        report_failure: bool,
+        local_port: Option<u16>,
+    ) -> crate::Res<()> {
+        let their_id = their_ci.id;
+        let their_direct = their_ci.for_direct;
+        let mut their_relays = their_ci.for_relay;
+        // Relays are tried last to first.
+        their_relays.reverse();
+
+        if their_direct.is_empty() && their_relays.is_empty() {
+            if report_failure {
+                let reason = ConnectFailureReason::InsufficientConnectionInfo;
+                let _ = event_tx.send(Event::ConnectFailure(their_id, reason));
+            }
             return Err(CrustError::InsufficientConnectionInfo);
         }
 
@@ -78,33 +180,54 @@ impl<UID: Uid> Connect<UID> {
             event_tx,
             our_pk,
             config,
+            identity,
+            their_pk: their_ci.our_pk,
+            their_relays,
+            report_failure,
+            attempts: their_direct
+                .iter()
+                .map(|addr| (*addr, AddrFailure::Pending))
+                .collect(),
+            relay_attempts: Vec::new(),
+            timed_out: false,
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
+        let _ = core.insert_state(token, state.clone());
 
         let their_pk = their_ci.our_pk;
-        let sockets = their_direct
-            .into_iter()
-            .filter_map(|addr| {
-                let info = PeerInfo::new(addr, their_pk);
-                TcpSock::connect(&addr).map(|sock| (sock, info)).ok()
-            })
-            .collect::<Vec<_>>();
-
-        for (mut socket, peer_info) in sockets {
+        for addr in their_direct {
+            let socket = match local_port {
+                Some(port) => connect_from_port(port, &addr)
+                    .map(TcpSock::wrap)
+                    .map_err(|e| format!("{:?}", e)),
+                None => TcpSock::connect(&addr).map_err(|e| format!("{:?}", e)),
+            };
+            let mut socket = match socket {
+                Ok(socket) => socket,
+                Err(e) => {
+                    let failure = AddrFailure::Connect(e);
+                    state.borrow_mut().record_failure(addr, failure);
+                    continue;
+                }
+            };
+            let peer_info = PeerInfo::new(addr, their_pk);
             let shared_key = our_sk.shared_secret(&their_ci.our_pk);
             match (
                 socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(their_ci.our_pk)),
-                socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone())),
+                socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key)),
             ) {
                 (Ok(_), Ok(_)) => state
                     .borrow_mut()
-                    .exchange_msg(core, poll, socket, peer_info, shared_key),
-                res => warn!("Failed to set encrypt/decrypt context: {:?}", res),
+                    .exchange_msg(core, poll, socket, peer_info),
+                res => {
+                    warn!("Failed to set encrypt/decrypt context: {:?}", res);
+                    let failure = AddrFailure::Connect(format!("{:?}", res));
+                    state.borrow_mut().record_failure(addr, failure);
+                }
             }
         }
-
-        let _ = core.insert_state(token, state);
+        state.borrow_mut().maybe_terminate(core, poll);
 
         Ok(())
     }
@@ -115,7 +238,6 @@ impl<UID: Uid> Connect<UID> {
         poll: &Poll,
         socket: TcpSock,
         peer_info: PeerInfo,
-        shared_key: SharedSecretKey,
     ) {
         let self_weak = self.self_weak.clone();
         let handler = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
@@ -135,12 +257,13 @@ impl<UID: Uid> Connect<UID> {
             self.our_nh,
             self.cm.clone(),
             self.our_pk,
-            shared_key,
+            peer_info.pub_key,
+            unwrap!(self.config.lock()).protocol_info(),
+            self.identity.clone(),
             Box::new(handler),
         ) {
             let _ = self.children.insert(child);
         }
-        self.maybe_terminate(core, poll);
     }
 
     fn handle_exchange_msg(
@@ -148,35 +271,44 @@ impl<UID: Uid> Connect<UID> {
         core: &mut EventLoopCore,
         poll: &Poll,
         child: Token,
-        res: Option<TcpSock>,
+        res: Result<(TcpSock, ProtocolInfo), AddrFailure>,
         peer_info: PeerInfo,
     ) {
         let _ = self.children.remove(&child);
-        if let Some(socket) = res {
-            bootstrap::cache_peer_info(core, peer_info, &self.config);
-            let self_weak = self.self_weak.clone();
-            let handler = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
-                if let Some(self_rc) = self_weak.upgrade() {
-                    self_rc
-                        .borrow_mut()
-                        .handle_connection_candidate(core, poll, child, res);
+        match res {
+            Ok((socket, protocol)) => {
+                bootstrap::cache_peer_info(core, peer_info, &self.config);
+                core.user_data().mark_verified(peer_info);
+                let self_weak = self.self_weak.clone();
+                let addr = peer_info.addr;
+                let handler = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
+                    if let Some(self_rc) = self_weak.upgrade() {
+                        self_rc
+                            .borrow_mut()
+                            .handle_connection_candidate(core, poll, child, res, protocol, addr);
+                    }
+                };
+
+                if let Ok(child) = ConnectionCandidate::start(
+                    core,
+                    poll,
+                    child,
+                    socket,
+                    self.cm.clone(),
+                    self.config.clone(),
+                    self.our_id,
+                    self.their_id,
+                    Box::new(handler),
+                ) {
+                    let _ = self.children.insert(child);
                 }
-            };
-
-            if let Ok(child) = ConnectionCandidate::start(
-                core,
-                poll,
-                child,
-                socket,
-                self.cm.clone(),
-                self.our_id,
-                self.their_id,
-                Box::new(handler),
-            ) {
-                let _ = self.children.insert(child);
             }
-        } else {
-            self.remove_peer_from_cache(core, &peer_info);
+            // The peer is genuine, it just won't have us.
+            Err(failure @ AddrFailure::Denied(_)) => self.record_failure(peer_info.addr, failure),
+            Err(failure) => {
+                self.record_failure(peer_info.addr, failure);
+                self.remove_peer_from_cache(core, &peer_info);
+            }
         }
         self.maybe_terminate(core, poll);
     }
@@ -186,28 +318,119 @@ impl<UID: Uid> Connect<UID> {
         core: &mut EventLoopCore,
         poll: &Poll,
         child: Token,
-        res: Option<TcpSock>,
+        res: Result<TcpSock, AddrFailure>,
+        protocol: ProtocolInfo,
+        addr: SocketAddr,
     ) {
         let _ = self.children.remove(&child);
-        if let Some(socket) = res {
-            self.terminate(core, poll);
-            return ActiveConnection::start(
-                core,
-                poll,
-                child,
-                socket,
-                self.cm.clone(),
-                self.our_id,
-                self.their_id,
-                // Note; We connect only to Nodes
-                CrustUser::Node,
-                Event::ConnectSuccess(self.their_id),
-                self.event_tx.clone(),
-            );
+        match res {
+            Err(failure) => self.record_failure(addr, failure),
+            Ok(socket) => {
+                // The other attempts may still make it as a standby connection.
+                let keep_trying = unwrap!(self.config.lock()).cfg.standby_connections == Some(true);
+                if !keep_trying {
+                    self.terminate(core, poll);
+                }
+                let their_id = self.their_id;
+                ActiveConnection::start(
+                    core,
+                    poll,
+                    child,
+                    socket,
+                    self.cm.clone(),
+                    self.config.clone(),
+                    self.our_id,
+                    self.their_id,
+                    // Note; We connect only to Nodes
+                    CrustUser::Node,
+                    protocol,
+                    move |handle| Event::ConnectSuccess(their_id, handle),
+                    self.event_tx.clone(),
+                );
+                if !keep_trying {
+                    return;
+                }
+            }
+        }
+        self.maybe_terminate(core, poll);
+    }
+
+    /// Returns whether the session is being opened.
+    fn open_relayed_session(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        relay_id: UID,
+    ) -> bool {
+        let self_weak = self.self_weak.clone();
+        let handler = move |core: &mut EventLoopCore, poll: &Poll, child, connected| {
+            if let Some(self_rc) = self_weak.upgrade() {
+                self_rc
+                    .borrow_mut()
+                    .handle_relayed_connection(core, poll, child, relay_id, connected);
+            }
+        };
+
+        match RelayedConnection::open(
+            core,
+            poll,
+            relay_id,
+            self.their_id,
+            self.their_pk,
+            Box::new(handler),
+        ) {
+            Ok(child) => {
+                let _ = self.children.insert(child);
+                self.relay_attempts.push((relay_id, AddrFailure::Pending));
+                true
+            }
+            Err(e) => {
+                debug!(
+                    "Failed to open relayed session through {:?}: {:?}",
+                    relay_id, e
+                );
+                let failure = AddrFailure::Connect(format!("{:?}", e));
+                self.relay_attempts.push((relay_id, failure));
+                false
+            }
+        }
+    }
+
+    fn handle_relayed_connection(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        child: Token,
+        relay_id: UID,
+        connected: bool,
+    ) {
+        let _ = self.children.remove(&child);
+        if connected {
+            return self.terminate(core, poll);
+        }
+        if let Some(attempt) = self
+            .relay_attempts
+            .iter_mut()
+            .find(|attempt| attempt.0 == relay_id)
+        {
+            attempt.1 = AddrFailure::Handshake;
         }
         self.maybe_terminate(core, poll);
     }
 
+    fn is_connected(&self) -> bool {
+        unwrap!(self.cm.lock())
+            .get(&self.their_id)
+            .map_or(false, |cid| cid.active_connection.is_some())
+    }
+
+    /// Records how the attempt to connect to `addr` failed.
+    fn record_failure(&mut self, addr: SocketAddr, failure: AddrFailure) {
+        if let Some(attempt) = self.attempts.iter_mut().find(|attempt| attempt.0 == addr) {
+            attempt.1 = failure;
+        }
+    }
+
     fn remove_peer_from_cache(&self, core: &mut EventLoopCore, peer_info: &PeerInfo) {
         let bootstrap_cache = core.user_data_mut();
         bootstrap_cache.remove(peer_info);
@@ -217,9 +440,17 @@ impl<UID: Uid> Connect<UID> {
     }
 
     fn maybe_terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        if self.children.is_empty() {
-            self.terminate(core, poll);
+        if !self.children.is_empty() {
+            return;
         }
+        if !self.is_connected() {
+            while let Some(relay_id) = self.their_relays.pop() {
+                if self.open_relayed_session(core, poll, relay_id) {
+                    return;
+                }
+            }
+        }
+        self.terminate(core, poll);
     }
 
     fn terminate_children(&mut self, core: &mut EventLoopCore, poll: &Poll) {
@@ -237,6 +468,7 @@ impl<UID: Uid> Connect<UID> {
 impl<UID: Uid> State<bootstrap::Cache> for Connect<UID> {
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
         debug!("Connect to peer {:?} timed out", self.their_id);
+        self.timed_out = true;
         self.terminate(core, poll);
     }
 
@@ -246,8 +478,18 @@ impl<UID: Uid> State<bootstrap::Cache> for Connect<UID> {
         let _ = core.cancel_timeout(&self.timeout);
         let _ = core.remove_state(self.token);
 
-        if !unwrap!(self.cm.lock()).contains_key(&self.their_id) {
-            let _ = self.event_tx.send(Event::ConnectFailure(self.their_id));
+        if self.report_failure && !unwrap!(self.cm.lock()).contains_key(&self.their_id) {
+            let attempts = mem::replace(&mut self.attempts, Vec::new());
+            let relay_attempts = mem::replace(&mut self.relay_attempts, Vec::new());
+            let reason = if self.timed_out {
+                ConnectFailureReason::Timeout(attempts, relay_attempts)
+            } else {
+                ConnectFailureReason::Failed(attempts, relay_attempts)
+            };
+            debug!("Failed to connect to {:?}: {:?}", self.their_id, reason);
+            let _ = self
+                .event_tx
+                .send(Event::ConnectFailure(self.their_id, reason));
         }
     }
 
@@ -278,6 +520,7 @@ mod tests {
             let conn_info = PrivConnectionInfo {
                 id: rand_uid(),
                 for_direct: vec![ipv4_addr(1, 2, 3, 4, 4000)],
+                for_relay: vec![],
                 our_pk: pk,
             };
             (conn_info, sk)
@@ -311,6 +554,7 @@ mod tests {
                 our_pk,
                 &our_sk,
                 config,
+                None,
             ));
 
             let connect_state_token = Token(0);
@@ -323,5 +567,45 @@ mod tests {
             let cached_peers = core.user_data().peers();
             assert!(cached_peers.is_empty());
         }
+
+        #[test]
+        fn it_reports_insufficient_connection_info() {
+            let mut core = test_core(test_bootstrap_cache());
+            let poll = unwrap!(Poll::new());
+
+            let (our_ci, our_sk) = test_priv_conn_info();
+            let our_pk = our_ci.our_pk;
+            let (their_ci, _) = test_priv_conn_info();
+            let mut their_ci = their_ci.to_pub_connection_info();
+            their_ci.for_direct.clear();
+            let their_id = their_ci.id;
+            let config = Arc::new(Mutex::new(ConfigWrapper::new(Config::default())));
+
+            let (event_tx, event_rx) = get_event_sender();
+            let res = Connect::start(
+                &mut core,
+                &poll,
+                our_ci,
+                their_ci,
+                Arc::new(Mutex::new(HashMap::new())),
+                [1; 32],
+                event_tx,
+                our_pk,
+                &our_sk,
+                config,
+                None,
+            );
+
+            match res {
+                Err(CrustError::InsufficientConnectionInfo) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+            match unwrap!(event_rx.try_recv()) {
+                Event::ConnectFailure(id, ConnectFailureReason::InsufficientConnectionInfo) => {
+                    assert_eq!(id, their_id)
+                }
+                event => panic!("Unexpected event: {:?}", event),
+            }
+        }
     }
 }
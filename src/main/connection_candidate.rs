@@ -9,7 +9,9 @@
 
 use crate::common::{Message, State, Uid};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{ConnectionId, ConnectionMap, EventLoopCore};
+use crate::main::{
+    can_stand_by, can_upgrade, AddrFailure, ConnectionMap, CrustConfig, EventLoopCore,
+};
 use mio::{Poll, PollOpt, Ready, Token};
 use socket_collection::{Priority, TcpSock};
 use std::any::Any;
@@ -18,12 +20,13 @@ use std::collections::hash_map::Entry;
 use std::mem;
 use std::rc::Rc;
 
-pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, Option<TcpSock>)>;
+pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, Result<TcpSock, AddrFailure>)>;
 
 /// Exchanges `ConnectionChoose` message with remote peer and transitions to next state.
 pub struct ConnectionCandidate<UID: Uid> {
     token: Token,
     cm: ConnectionMap<UID>,
+    config: CrustConfig,
     socket: TcpSock,
     our_id: UID,
     their_id: UID,
@@ -38,6 +41,7 @@ impl<UID: Uid> ConnectionCandidate<UID> {
         token: Token,
         socket: TcpSock,
         cm: ConnectionMap<UID>,
+        config: CrustConfig,
         our_id: UID,
         their_id: UID,
         finish: Finish,
@@ -45,6 +49,7 @@ impl<UID: Uid> ConnectionCandidate<UID> {
         let state = Rc::new(RefCell::new(ConnectionCandidate {
             token,
             cm,
+            config,
             socket,
             our_id,
             their_id,
@@ -70,7 +75,7 @@ impl<UID: Uid> ConnectionCandidate<UID> {
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         match self.socket.read::<Message<UID>>() {
             Ok(Some(Message::ChooseConnection)) => self.done(core, poll),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll),
+            Ok(Some(_)) | Err(_) => self.handle_error(core, poll, AddrFailure::Handshake),
             Ok(None) => (),
         }
     }
@@ -81,28 +86,31 @@ impl<UID: Uid> ConnectionCandidate<UID> {
         poll: &Poll,
         msg: Option<(Message<UID>, Priority)>,
     ) {
-        let terminate = match unwrap!(self.cm.lock()).get(&self.their_id) {
-            Some(&ConnectionId {
-                active_connection: Some(_),
-                ..
-            }) => true,
-            _ => false,
+        let (active, standby) = match unwrap!(self.cm.lock()).get(&self.their_id) {
+            Some(cid) => (cid.active_connection, cid.standby),
+            None => (None, None),
         };
-        if terminate {
-            return self.handle_error(core, poll);
+        // A direct connection may still take a relayed session over or stand by for a direct one.
+        let wanted = active.map_or(true, |token| {
+            can_upgrade::<UID>(core, token)
+                || (standby.is_none()
+                    && can_stand_by::<UID>(core, &self.config, token, &self.socket))
+        });
+        if !wanted {
+            return self.handle_error(core, poll, AddrFailure::Duplicate);
         }
 
         if self.our_id > self.their_id {
             match self.socket.write(msg) {
                 Ok(true) => self.done(core, poll),
                 Ok(false) => (),
-                Err(_) => self.handle_error(core, poll),
+                Err(_) => self.handle_error(core, poll, AddrFailure::Handshake),
             }
         } else if let Err(e) =
             poll.reregister(&self.socket, self.token, Ready::readable(), PollOpt::edge())
         {
             debug!("Error in re-registeration: {:?}", e);
-            self.handle_error(core, poll);
+            self.handle_error(core, poll, AddrFailure::Handshake);
         } else {
             self.read(core, poll)
         }
@@ -114,13 +122,13 @@ impl<UID: Uid> ConnectionCandidate<UID> {
         let socket = mem::replace(&mut self.socket, Default::default());
         let _ = poll.reregister(&socket, token, Ready::readable(), PollOpt::edge());
 
-        (*self.finish)(core, poll, token, Some(socket));
+        (*self.finish)(core, poll, token, Ok(socket));
     }
 
-    fn handle_error(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+    fn handle_error(&mut self, core: &mut EventLoopCore, poll: &Poll, failure: AddrFailure) {
         self.terminate(core, poll);
         let token = self.token;
-        (*self.finish)(core, poll, token, None);
+        (*self.finish)(core, poll, token, Err(failure));
     }
 }
 
@@ -0,0 +1,247 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CrustUser, Uid};
+use crate::main::{
+    disconnect, ActiveConnection, ConnectionLimits, ConnectionMap, CrustConfig, DisconnectReason,
+    EventLoopCore, EvictionPolicy, RelayedConnection,
+};
+use mio::{Poll, Token};
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// An established connection which could be evicted to make room for a new one.
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    token: Token,
+    privileged: bool,
+    last_activity: Instant,
+    bytes_transferred: u64,
+}
+
+/// Returns whether one more connection with a peer of given kind fits within the configured
+/// limits, counting `handshaking` connections which were let in but aren't established yet. If
+/// the new peer is privileged, connections `make_room` could evict for it count as free. Peers
+/// reached through a relay have no IP and are never privileged.
+pub fn has_room<UID: Uid>(
+    core: &EventLoopCore,
+    cm: &ConnectionMap<UID>,
+    config: &CrustConfig,
+    peer_kind: CrustUser,
+    peer_ip: Option<&IpAddr>,
+    handshaking: usize,
+) -> bool {
+    let (limits, max_connections) = match limits_of_kind(config, peer_kind) {
+        Some(limits) => limits,
+        None => return true,
+    };
+    let may_evict = limits.eviction.is_some()
+        && peer_ip.map_or(false, |peer_ip| is_privileged(&limits, peer_ip));
+    let candidates = connections_of_kind(core, cm, &limits, peer_kind);
+    fits(&candidates, handshaking, max_connections, may_evict)
+}
+
+/// Makes sure an established connection with a peer of given kind, which passed `has_room`
+/// before, fits within the configured limits. If it doesn't, but the new peer is privileged,
+/// evicts unprivileged connections of the same kind according to the configured policy. Returns
+/// whether the new connection may proceed.
+pub fn make_room<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    config: &CrustConfig,
+    peer_kind: CrustUser,
+    peer_ip: &IpAddr,
+) -> bool {
+    let (limits, max_connections) = match limits_of_kind(config, peer_kind) {
+        Some(limits) => limits,
+        None => return true,
+    };
+    let mut candidates = connections_of_kind(core, cm, &limits, peer_kind);
+    while candidates.len() >= max_connections {
+        let policy = match limits.eviction {
+            Some(policy) if is_privileged(&limits, peer_ip) => policy,
+            _ => return false,
+        };
+        let token = match pick_victim(policy, &candidates) {
+            Some(token) => token,
+            None => return false,
+        };
+        trace!(
+            "Too many {:?} connections - evicting {:?}.",
+            peer_kind,
+            token
+        );
+        // The standby connection goes first, so that it doesn't take over.
+        let tokens = unwrap!(cm.lock())
+            .values()
+            .find(|cid| cid.active_connection == Some(token))
+            .map(|cid| cid.connections())
+            .unwrap_or_default();
+        for token in tokens {
+            disconnect::<UID>(core, poll, token, DisconnectReason::Evicted);
+        }
+        candidates.retain(|c| c.token != token);
+    }
+    true
+}
+
+fn limits_of_kind(config: &CrustConfig, peer_kind: CrustUser) -> Option<(ConnectionLimits, usize)> {
+    let limits = unwrap!(config.lock()).cfg.connection_limits.clone()?;
+    let max_connections = match peer_kind {
+        CrustUser::Node => limits.max_nodes,
+        CrustUser::Client => limits.max_clients,
+    }?;
+    Some((limits, max_connections))
+}
+
+/// Returns whether another connection fits next to `candidates` and `handshaking` ones, possibly
+/// by evicting unprivileged candidates.
+fn fits(
+    candidates: &[Candidate],
+    handshaking: usize,
+    max_connections: usize,
+    may_evict: bool,
+) -> bool {
+    let taken = candidates.len() + handshaking;
+    if taken < max_connections {
+        return true;
+    }
+    may_evict && candidates.iter().filter(|c| !c.privileged).count() > taken - max_connections
+}
+
+fn connections_of_kind<UID: Uid>(
+    core: &EventLoopCore,
+    cm: &ConnectionMap<UID>,
+    limits: &ConnectionLimits,
+    peer_kind: CrustUser,
+) -> Vec<Candidate> {
+    unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| {
+            let token = cid.active_connection?;
+            let state = core.get_state(token)?;
+            // Skips the connection which called us, if any.
+            let mut state = state.try_borrow_mut().ok()?;
+            let state = state.as_any();
+            if let Some(ac) = state.downcast_mut::<ActiveConnection<UID>>() {
+                if ac.peer_kind() != peer_kind {
+                    return None;
+                }
+                let privileged = ac
+                    .peer_addr()
+                    .map(|addr| is_privileged(limits, &addr.ip()))
+                    .unwrap_or(false);
+                return Some(Candidate {
+                    token,
+                    privileged,
+                    last_activity: ac.last_activity(),
+                    bytes_transferred: ac.bytes_transferred(),
+                });
+            }
+            let session = state.downcast_mut::<RelayedConnection<UID>>()?;
+            if session.peer_kind() != peer_kind {
+                return None;
+            }
+            Some(Candidate {
+                token,
+                privileged: false,
+                last_activity: session.last_activity(),
+                bytes_transferred: session.bytes_transferred(),
+            })
+        })
+        .collect()
+}
+
+fn is_privileged(limits: &ConnectionLimits, ip: &IpAddr) -> bool {
+    limits.privileged_ranges.as_ref().map_or(false, |ranges| {
+        ranges.iter().any(|range| range.contains(ip))
+    })
+}
+
+/// Picks the unprivileged connection to evict, if there is any.
+fn pick_victim(policy: EvictionPolicy, candidates: &[Candidate]) -> Option<Token> {
+    let unprivileged = candidates.iter().filter(|c| !c.privileged);
+    let victim = match policy {
+        EvictionPolicy::OldestIdle => unprivileged.min_by_key(|c| c.last_activity),
+        EvictionPolicy::LowestTraffic => unprivileged.min_by_key(|c| c.bytes_transferred),
+    };
+    victim.map(|c| c.token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn candidate(token: usize, privileged: bool) -> Candidate {
+        Candidate {
+            token: Token(token),
+            privileged,
+            last_activity: Instant::now(),
+            bytes_transferred: 0,
+        }
+    }
+
+    #[test]
+    fn handshaking_connections_take_up_room() {
+        let candidates = vec![candidate(1, false)];
+        assert!(fits(&candidates, 0, 2, false));
+        assert!(!fits(&candidates, 1, 2, false));
+        assert!(!fits(&[], 2, 2, false));
+    }
+
+    #[test]
+    fn privileged_peers_fit_while_there_is_enough_to_evict() {
+        let candidates = vec![candidate(1, true), candidate(2, false)];
+        assert!(!fits(&candidates, 0, 2, false));
+        assert!(fits(&candidates, 0, 2, true));
+        // The only unprivileged connection is promised to another handshake already.
+        assert!(!fits(&candidates, 1, 2, true));
+        assert!(!fits(&candidates[..1], 0, 1, true));
+    }
+
+    #[test]
+    fn privileged_connections_are_never_evicted() {
+        let now = Instant::now();
+        let candidates = vec![
+            Candidate {
+                token: Token(1),
+                privileged: true,
+                last_activity: now,
+                bytes_transferred: 0,
+            },
+            Candidate {
+                token: Token(2),
+                privileged: false,
+                last_activity: now + Duration::from_secs(1),
+                bytes_transferred: 100,
+            },
+            Candidate {
+                token: Token(3),
+                privileged: false,
+                last_activity: now + Duration::from_secs(2),
+                bytes_transferred: 10,
+            },
+        ];
+
+        assert_eq!(
+            pick_victim(EvictionPolicy::OldestIdle, &candidates),
+            Some(Token(2))
+        );
+        assert_eq!(
+            pick_victim(EvictionPolicy::LowestTraffic, &candidates),
+            Some(Token(3))
+        );
+        assert_eq!(
+            pick_victim(EvictionPolicy::OldestIdle, &candidates[..1]),
+            None
+        );
+    }
+}
@@ -8,28 +8,37 @@
 // Software.
 
 use super::check_reachability::CheckReachability;
+use super::guard::{HandshakeOutcome, ListenerGuard};
+use super::replay_cache::ReplayCache;
 use crate::common::{
-    BootstrapDenyReason, CoreTimer, CrustUser, ExternalReachability, Message, NameHash, State, Uid,
+    prove_identity, verify_identity, BootstrapDenyReason, ConnectDenyReason, CoreTimer, CrustUser,
+    ExternalReachability, Freshness, Handshake, Identity, IdentityProof, Message, NameHash,
+    ProtocolInfo, State, Uid,
 };
 use crate::main::bootstrap::Cache as BootstrapCache;
 use crate::main::{
-    read_config_file, ActiveConnection, ConnectionCandidate, ConnectionId, ConnectionMap,
-    CrustConfig, Event, EventLoopCore,
+    has_room, make_room, peers_to_share, read_config_file, ActiveConnection, ConnectionCandidate,
+    ConnectionId, ConnectionMap, CrustConfig, Event, EventLoopCore,
 };
-use crate::nat::ip_addr_is_global;
 use mio::{Poll, PollOpt, Ready, Token};
 use mio_extras::timer::Timeout;
-use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
+use rand;
+use rand::seq::SliceRandom;
+use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey, SharedSecretKey};
 use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::collections::HashSet;
 use std::mem;
+use std::net::IpAddr;
 use std::rc::{Rc, Weak};
+use std::sync::Arc;
 use std::time::Duration;
 
-pub const EXCHANGE_MSG_TIMEOUT_SEC: u64 = 10 * 60;
+pub const EXCHANGE_MSG_TIMEOUT_SEC: u64 = 30;
+/// Maximum number of other nodes a denied bootstrapper is pointed to.
+const MAX_REDIRECTS: usize = 5;
 
 pub struct ExchangeMsg<UID: Uid> {
     token: Token,
@@ -47,6 +56,22 @@ pub struct ExchangeMsg<UID: Uid> {
     self_weak: Weak<RefCell<ExchangeMsg<UID>>>,
     our_pk: PublicEncryptKey,
     our_sk: SecretEncryptKey,
+    our_eph_pk: PublicEncryptKey,
+    our_eph_sk: SecretEncryptKey,
+    /// Key derived from ephemeral keys of both sides, used once the handshake is done.
+    session_key: Option<SharedSecretKey>,
+    protocol: Option<ProtocolInfo>,
+    identity: Option<Arc<Identity<UID>>>,
+    /// Proof of our identity for the peer, to be sent in our response.
+    our_proof: Option<IdentityProof>,
+    replay_cache: Rc<RefCell<ReplayCache<IpAddr>>>,
+    /// The peer's request, recorded with the replay cache once we grant it.
+    freshness: Option<Freshness>,
+    guard: Rc<RefCell<ListenerGuard>>,
+    /// IP the handshake is accounted for with the guard, until we report its outcome.
+    peer_ip: Option<IpAddr>,
+    /// Kind of the connection slot reserved with the guard, until the connection is established.
+    reserved: Option<CrustUser>,
 }
 
 impl<UID: Uid> ExchangeMsg<UID> {
@@ -63,8 +88,13 @@ impl<UID: Uid> ExchangeMsg<UID> {
         event_tx: crate::CrustEventSender<UID>,
         our_pk: PublicEncryptKey,
         our_sk: &SecretEncryptKey,
+        identity: Option<Arc<Identity<UID>>>,
+        replay_cache: Rc<RefCell<ReplayCache<IpAddr>>>,
+        guard: Rc<RefCell<ListenerGuard>>,
+        peer_ip: IpAddr,
     ) -> crate::Res<()> {
         let token = core.get_new_token();
+        let (our_eph_pk, our_eph_sk) = gen_encrypt_keypair();
 
         let kind = Ready::readable();
         poll.register(&socket, token, kind, PollOpt::edge())?;
@@ -100,6 +130,17 @@ impl<UID: Uid> ExchangeMsg<UID> {
             self_weak: Default::default(),
             our_pk,
             our_sk: our_sk.clone(),
+            our_eph_pk,
+            our_eph_sk,
+            session_key: None,
+            protocol: None,
+            identity,
+            our_proof: None,
+            replay_cache,
+            freshness: None,
+            guard,
+            peer_ip: Some(peer_ip),
+            reserved: None,
         }));
 
         state.borrow_mut().self_weak = Rc::downgrade(&state);
@@ -110,17 +151,48 @@ impl<UID: Uid> ExchangeMsg<UID> {
     }
 
     fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
-        match self.socket.read::<Message<UID>>() {
-            Ok(Some(Message::BootstrapRequest(
+        let handshake = match self.socket.read::<Handshake>() {
+            Ok(Some(handshake)) => handshake,
+            Ok(None) => return,
+            Err(e) => {
+                trace!("Failed to read from socket: {:?}", e);
+                return self.terminate(core, poll);
+            }
+        };
+
+        if !self.use_authed_encryption(handshake.pub_key) {
+            trace!("Failed to set authenticated encryption context.");
+            return self.terminate(core, poll);
+        }
+
+        self.try_update_crust_config();
+
+        if !self.negotiate_protocol(&handshake.protocol) {
+            trace!(
+                "Peer speaks an incompatible protocol: {:?}",
+                handshake.protocol
+            );
+            let our_protocol = unwrap!(self.config.lock()).protocol_info();
+            let reply = Handshake::incompatible(our_protocol, self.our_pk);
+            return self.write(core, poll, Some((reply, 0)));
+        }
+
+        match handshake.request_message::<UID>() {
+            Ok(Message::BootstrapRequest(
+                freshness,
                 their_uid,
                 name_hash,
                 ext_reachability,
-                their_pk,
-            ))) => {
+                their_eph_pk,
+                their_proof,
+            )) => {
                 if !self.accept_bootstrap {
                     trace!("Bootstrapping off us is not allowed");
                     return self.terminate(core, poll);
                 }
+                if !self.is_fresh(freshness) {
+                    return self.terminate(core, poll);
+                }
 
                 match self.validate_peer_uid(their_uid) {
                     Ok(their_uid) => self.handle_bootstrap_req(
@@ -129,27 +201,35 @@ impl<UID: Uid> ExchangeMsg<UID> {
                         their_uid,
                         name_hash,
                         ext_reachability,
-                        their_pk,
+                        their_eph_pk,
+                        &their_proof,
                     ),
                     Err(()) => self.terminate(core, poll),
                 }
             }
-            Ok(Some(Message::Connect(their_uid, name_hash, their_pk))) => match self
-                .validate_peer_uid(their_uid)
-            {
-                Ok(their_uid) => self.handle_connect(core, poll, their_uid, name_hash, their_pk),
-                Err(()) => self.terminate(core, poll),
-            },
-            Ok(Some(Message::EchoAddrReq(their_pk))) => {
-                self.handle_echo_addr_req(core, poll, their_pk)
+            Ok(Message::Connect(freshness, their_uid, name_hash, their_eph_pk, their_proof)) => {
+                if !self.is_fresh(freshness) {
+                    return self.terminate(core, poll);
+                }
+                match self.validate_peer_uid(their_uid) {
+                    Ok(their_uid) => self.handle_connect(
+                        core,
+                        poll,
+                        their_uid,
+                        name_hash,
+                        their_eph_pk,
+                        &their_proof,
+                    ),
+                    Err(()) => self.terminate(core, poll),
+                }
             }
-            Ok(Some(message)) => {
+            Ok(Message::EchoAddrReq) => self.handle_echo_addr_req(core, poll),
+            Ok(message) => {
                 trace!("Unexpected message in direct connect: {:?}", message);
                 self.terminate(core, poll)
             }
-            Ok(None) => (),
             Err(e) => {
-                trace!("Failed to read from socket: {:?}", e);
+                trace!("Failed to decode handshake: {:?}", e);
                 self.terminate(core, poll);
             }
         }
@@ -162,33 +242,26 @@ impl<UID: Uid> ExchangeMsg<UID> {
         their_uid: UID,
         name_hash: NameHash,
         ext_reachability: ExternalReachability,
-        their_pk: PublicEncryptKey,
+        their_eph_pk: PublicEncryptKey,
+        their_proof: &Option<IdentityProof>,
     ) {
         if !self.is_valid_name_hash(name_hash) {
             trace!("Rejecting Bootstrapper with an invalid name hash.");
-            return self.write(
-                core,
-                poll,
-                Some((
-                    Message::BootstrapDenied(BootstrapDenyReason::InvalidNameHash),
-                    0,
-                )),
-            );
+            return self.deny_bootstrap(core, poll, BootstrapDenyReason::InvalidNameHash);
         }
 
-        if !self.use_authed_encryption(their_pk) {
-            trace!("Failed to set authenticated encryption context.");
-            return self.terminate(core, poll);
+        if !self.check_identity(their_uid, their_eph_pk, their_proof) {
+            trace!("Rejecting Bootstrapper with an invalid identity.");
+            let reason = BootstrapDenyReason::InvalidIdentity;
+            return self.deny_bootstrap(core, poll, reason);
         }
 
-        self.try_update_crust_config();
-
         match ext_reachability {
             ExternalReachability::Required { direct_listeners } => {
                 if !self.is_peer_whitelisted(CrustUser::Node) {
                     trace!("Bootstrapper Node is not whitelisted. Denying bootstrap.");
                     let reason = BootstrapDenyReason::NodeNotWhitelisted;
-                    return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+                    return self.deny_bootstrap(core, poll, reason);
                 }
 
                 if !self.require_reachability {
@@ -196,10 +269,36 @@ impl<UID: Uid> ExchangeMsg<UID> {
                     return self.send_bootstrap_grant(core, poll, their_uid, CrustUser::Node);
                 }
 
-                for their_listener in direct_listeners
-                    .into_iter()
-                    .filter(|addr| ip_addr_is_global(&addr.ip()))
-                {
+                // Only check a few listeners on the IP the peer connects from, so bootstrap
+                // requests can't turn us into a port scanner for arbitrary hosts.
+                let targets = match self.socket.peer_addr() {
+                    Ok(peer_addr) => {
+                        let limits = unwrap!(self.config.lock())
+                            .cfg
+                            .listener_limits
+                            .clone()
+                            .unwrap_or_default();
+                        self.guard.borrow_mut().reachability_targets(
+                            peer_addr.ip(),
+                            direct_listeners,
+                            &limits,
+                        )
+                    }
+                    Err(e) => {
+                        debug!("Could not obtain Peer IP: {:?}", e);
+                        Some(Vec::new())
+                    }
+                };
+                let targets = match targets {
+                    Some(targets) => targets,
+                    None => {
+                        trace!("Bootstrapper asked for too many reachability checks lately.");
+                        let reason = BootstrapDenyReason::ReachabilityCheckThrottled;
+                        return self.deny_bootstrap(core, poll, reason);
+                    }
+                };
+
+                for their_listener in targets {
                     let self_weak = self.self_weak.clone();
                     let finish = move |core: &mut EventLoopCore, poll: &Poll, child, res| {
                         if let Some(self_rc) = self_weak.upgrade() {
@@ -225,14 +324,14 @@ impl<UID: Uid> ExchangeMsg<UID> {
                          recheability. Denying bootstrap."
                     );
                     let reason = BootstrapDenyReason::FailedExternalReachability;
-                    self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+                    self.deny_bootstrap(core, poll, reason);
                 }
             }
             ExternalReachability::NotRequired => {
                 if !self.is_peer_whitelisted(CrustUser::Client) {
                     trace!("Bootstrapper Client is not whitelisted. Denying bootstrap.");
                     let reason = BootstrapDenyReason::ClientNotWhitelisted;
-                    return self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+                    return self.deny_bootstrap(core, poll, reason);
                 }
 
                 self.send_bootstrap_grant(core, poll, their_uid, CrustUser::Client)
@@ -252,18 +351,7 @@ impl<UID: Uid> ExchangeMsg<UID> {
             }
         };
 
-        let res = match peer_kind {
-            CrustUser::Node => unwrap!(self.config.lock())
-                .cfg
-                .whitelisted_node_ips
-                .as_ref()
-                .map_or(true, |ips| ips.contains(&peer_ip)),
-            CrustUser::Client => unwrap!(self.config.lock())
-                .cfg
-                .whitelisted_client_ips
-                .as_ref()
-                .map_or(true, |ips| ips.contains(&peer_ip)),
-        };
+        let res = unwrap!(self.config.lock()).is_ip_allowed(&peer_ip, peer_kind);
 
         if !res {
             trace!("IP: {} is not whitelisted.", peer_ip);
@@ -290,10 +378,42 @@ impl<UID: Uid> ExchangeMsg<UID> {
                  Denying bootstrap."
             );
             let reason = BootstrapDenyReason::FailedExternalReachability;
-            self.write(core, poll, Some((Message::BootstrapDenied(reason), 0)));
+            self.deny_bootstrap(core, poll, reason);
         }
     }
 
+    /// Denies bootstrap. If other nodes might still accept the bootstrapper, points it to a few of
+    /// the nodes we connected to lately and the ones in our bootstrap cache.
+    fn deny_bootstrap(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        reason: BootstrapDenyReason,
+    ) {
+        let redirects = match reason {
+            BootstrapDenyReason::TooManyConnections
+            | BootstrapDenyReason::NodeNotWhitelisted
+            | BootstrapDenyReason::ClientNotWhitelisted => {
+                let peer_addr = self.socket.peer_addr().ok();
+                // Nodes we connected to lately go first, as they are the likeliest to be up.
+                let mut peers = peers_to_share(core, None);
+                let mut cached: Vec<_> = core
+                    .user_data()
+                    .peers()
+                    .into_iter()
+                    .filter(|peer| !peers.contains(peer))
+                    .collect();
+                cached.shuffle(&mut rand::thread_rng());
+                peers.extend(cached);
+                peers.retain(|peer| Some(peer.addr) != peer_addr);
+                peers.truncate(MAX_REDIRECTS);
+                peers
+            }
+            _ => Vec::new(),
+        };
+        self.reply(core, poll, &Message::BootstrapDenied(reason, redirects))
+    }
+
     fn send_bootstrap_grant(
         &mut self,
         core: &mut EventLoopCore,
@@ -301,11 +421,21 @@ impl<UID: Uid> ExchangeMsg<UID> {
         their_uid: UID,
         peer_kind: CrustUser,
     ) {
+        if !self.record_request() {
+            return self.terminate(core, poll);
+        }
+        if !self.reserve_slot(core, peer_kind) {
+            trace!("No room for another {:?}. Denying bootstrap.", peer_kind);
+            let reason = BootstrapDenyReason::TooManyConnections;
+            return self.deny_bootstrap(core, poll, reason);
+        }
+
         self.enter_handshaking_mode(their_uid);
 
-        let our_uid = self.our_uid;
+        let our_proof = self.our_proof.take();
         self.next_state = NextState::ActiveConnection(their_uid, peer_kind);
-        self.write(core, poll, Some((Message::BootstrapGranted(our_uid), 0)))
+        let msg = Message::BootstrapGranted(self.our_uid, self.our_eph_pk, our_proof);
+        self.reply(core, poll, &msg)
     }
 
     fn handle_connect(
@@ -314,47 +444,55 @@ impl<UID: Uid> ExchangeMsg<UID> {
         poll: &Poll,
         their_uid: UID,
         name_hash: NameHash,
-        their_pk: PublicEncryptKey,
+        their_eph_pk: PublicEncryptKey,
+        their_proof: &Option<IdentityProof>,
     ) {
         if !self.is_valid_name_hash(name_hash) {
             trace!("Invalid name hash given. Denying connection.");
             return self.terminate(core, poll);
         }
 
-        self.try_update_crust_config();
+        if !self.check_identity(their_uid, their_eph_pk, their_proof) {
+            trace!("Invalid identity given. Denying connection.");
+            return self.terminate(core, poll);
+        }
 
         if !self.is_peer_whitelisted(CrustUser::Node) {
             trace!("Connecting Node is not whitelisted. Denying connection.");
-            return self.terminate(core, poll);
+            let reason = ConnectDenyReason::NodeNotWhitelisted;
+            return self.reply(core, poll, &Message::ConnectDenied(reason));
         }
 
-        if !self.use_authed_encryption(their_pk) {
-            trace!("Failed to set authenticated encryption context.");
+        if !self.record_request() {
             return self.terminate(core, poll);
         }
+        if !self.reserve_slot(core, CrustUser::Node) {
+            trace!("No room for another Node. Denying connection.");
+            let reason = ConnectDenyReason::TooManyConnections;
+            return self.reply(core, poll, &Message::ConnectDenied(reason));
+        }
 
         self.enter_handshaking_mode(their_uid);
 
-        let msg = Message::Connect(self.our_uid, self.name_hash, self.our_pk);
+        let msg = Message::Connect(
+            Freshness::now(),
+            self.our_uid,
+            self.name_hash,
+            self.our_eph_pk,
+            self.our_proof.take(),
+        );
         self.next_state = NextState::ConnectionCandidate(their_uid);
-        self.write(core, poll, Some((msg, 0)));
+        self.reply(core, poll, &msg);
     }
 
-    fn handle_echo_addr_req(
-        &mut self,
-        core: &mut EventLoopCore,
-        poll: &Poll,
-        their_pk: PublicEncryptKey,
-    ) {
+    fn handle_echo_addr_req(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         self.next_state = NextState::None;
-        match (
-            self.use_authed_encryption(their_pk),
-            self.socket.peer_addr(),
-        ) {
-            (true, Ok(peer_addr)) => {
-                self.write(core, poll, Some((Message::EchoAddrResp(peer_addr), 0)));
+        match self.socket.peer_addr() {
+            Ok(peer_addr) => {
+                self.report(HandshakeOutcome::Success);
+                self.reply(core, poll, &Message::EchoAddrResp(peer_addr));
             }
-            _ => self.terminate(core, poll),
+            Err(_) => self.terminate(core, poll),
         }
     }
 
@@ -362,6 +500,19 @@ impl<UID: Uid> ExchangeMsg<UID> {
     /// Returns false on failure.
     fn use_authed_encryption(&mut self, their_pk: PublicEncryptKey) -> bool {
         let shared_key = self.our_sk.shared_secret(&their_pk);
+        self.use_key(shared_key)
+    }
+
+    /// Switches the socket to the session key once our handshake response has been sent.
+    /// Returns false on failure.
+    fn use_session_key(&mut self) -> bool {
+        match self.session_key.take() {
+            Some(session_key) => self.use_key(session_key),
+            None => false,
+        }
+    }
+
+    fn use_key(&mut self, shared_key: SharedSecretKey) -> bool {
         match (
             self.socket
                 .set_encrypt_ctx(EncryptContext::authenticated(shared_key.clone())),
@@ -376,6 +527,91 @@ impl<UID: Uid> ExchangeMsg<UID> {
         }
     }
 
+    /// Returns false if we can't speak any protocol version the peer speaks.
+    fn negotiate_protocol(&mut self, their_protocol: &ProtocolInfo) -> bool {
+        let our_protocol = unwrap!(self.config.lock()).protocol_info();
+        self.protocol = our_protocol.negotiate(their_protocol);
+        self.protocol.is_some()
+    }
+
+    /// Verifies the peer's identity and prepares the proof of ours in return, along with the
+    /// session key.
+    fn check_identity(
+        &mut self,
+        their_uid: UID,
+        their_eph_pk: PublicEncryptKey,
+        their_proof: &Option<IdentityProof>,
+    ) -> bool {
+        if !verify_identity(
+            &self.identity,
+            their_proof,
+            &their_uid,
+            &their_eph_pk,
+            &self.our_pk,
+        ) {
+            return false;
+        }
+        self.our_proof = prove_identity(
+            &self.identity,
+            &self.our_uid,
+            &self.our_eph_pk,
+            &their_eph_pk,
+        );
+        self.session_key = Some(self.our_eph_sk.shared_secret(&their_eph_pk));
+        true
+    }
+
+    /// Reserves a connection slot of given kind with the guard, so that concurrent handshakes
+    /// can't get past the connection limits together. Returns false if there's no room.
+    fn reserve_slot(&mut self, core: &EventLoopCore, peer_kind: CrustUser) -> bool {
+        let peer_ip = match self.socket.peer_addr() {
+            Ok(peer_addr) => peer_addr.ip(),
+            Err(e) => {
+                debug!("Could not obtain Peer IP: {:?}", e);
+                return false;
+            }
+        };
+        let handshaking = self.guard.borrow().reserved_slots(peer_kind);
+        if !has_room(
+            core,
+            &self.cm,
+            &self.config,
+            peer_kind,
+            Some(&peer_ip),
+            handshaking,
+        ) {
+            return false;
+        }
+        self.guard.borrow_mut().reserve_slot(peer_kind);
+        self.reserved = Some(peer_kind);
+        true
+    }
+
+    fn release_slot(&mut self) {
+        if let Some(peer_kind) = self.reserved.take() {
+            self.guard.borrow_mut().release_slot(peer_kind);
+        }
+    }
+
+    /// Evicts connections if needed to fit the now verified peer in. Returns false if there's no
+    /// room for it after all.
+    fn make_room(&self, core: &mut EventLoopCore, poll: &Poll, peer_kind: CrustUser) -> bool {
+        match self.socket.peer_addr() {
+            Ok(peer_addr) => make_room(
+                core,
+                poll,
+                &self.cm,
+                &self.config,
+                peer_kind,
+                &peer_addr.ip(),
+            ),
+            Err(e) => {
+                debug!("Could not obtain Peer IP: {:?}", e);
+                false
+            }
+        }
+    }
+
     fn enter_handshaking_mode(&self, their_uid: UID) {
         let mut guard = unwrap!(self.cm.lock());
         guard
@@ -383,6 +619,8 @@ impl<UID: Uid> ExchangeMsg<UID> {
             .or_insert(ConnectionId {
                 active_connection: None,
                 currently_handshaking: 0,
+                standby: None,
+                generation: 0,
             })
             .currently_handshaking += 1;
         trace!(
@@ -396,6 +634,33 @@ impl<UID: Uid> ExchangeMsg<UID> {
         self.name_hash == name_hash
     }
 
+    /// Returns false if the request is stale or replayed. Otherwise keeps it to be recorded once
+    /// it passed validation.
+    fn is_fresh(&mut self, freshness: Freshness) -> bool {
+        if !self.replay_cache.borrow().is_fresh(freshness) {
+            debug!(
+                "Ignoring stale or replayed handshake request: {:?}",
+                freshness
+            );
+            return false;
+        }
+        self.freshness = Some(freshness);
+        true
+    }
+
+    /// Records the request we are about to grant with the replay cache. Returns false if it was
+    /// replayed meanwhile or its source sent too many requests lately.
+    fn record_request(&mut self) -> bool {
+        let source = match self.socket.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return false,
+        };
+        match self.freshness.take() {
+            Some(freshness) => self.replay_cache.borrow_mut().record(freshness, source),
+            None => false,
+        }
+    }
+
     fn validate_peer_uid(&self, their_uid: UID) -> Result<UID, ()> {
         if self.our_uid == their_uid {
             debug!("Accepted connection from ourselves");
@@ -412,12 +677,20 @@ impl<UID: Uid> ExchangeMsg<UID> {
         }
     }
 
-    fn write(
-        &mut self,
-        core: &mut EventLoopCore,
-        poll: &Poll,
-        msg: Option<(Message<UID>, Priority)>,
-    ) {
+    /// Sends our reply, encoded in the format of the negotiated protocol version.
+    fn reply(&mut self, core: &mut EventLoopCore, poll: &Poll, msg: &Message<UID>) {
+        let protocol = unwrap!(self.protocol);
+        let our_protocol = unwrap!(self.config.lock()).protocol_info();
+        match Handshake::new(our_protocol, self.our_pk, protocol.version, msg) {
+            Ok(reply) => self.write(core, poll, Some((reply, 0))),
+            Err(e) => {
+                debug!("Failed to encode handshake reply: {:?}", e);
+                self.terminate(core, poll)
+            }
+        }
+    }
+
+    fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, msg: Option<(Handshake, Priority)>) {
         // Do not accept multiple bootstraps from same peer
         if let NextState::ActiveConnection(their_uid, _) = self.next_state {
             let terminate = match unwrap!(self.cm.lock()).get(&their_uid).cloned() {
@@ -428,6 +701,7 @@ impl<UID: Uid> ExchangeMsg<UID> {
                 _ => false,
             };
             if terminate {
+                self.report(HandshakeOutcome::Denied);
                 return self.terminate(core, poll);
             }
         }
@@ -443,6 +717,35 @@ impl<UID: Uid> ExchangeMsg<UID> {
     }
 
     fn done(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        match self.next_state {
+            NextState::ActiveConnection(_, peer_kind) => {
+                if !self.use_session_key() {
+                    return self.terminate(core, poll);
+                }
+                self.release_slot();
+                if !self.make_room(core, poll, peer_kind) {
+                    trace!("No room left for another {:?}.", peer_kind);
+                    self.report(HandshakeOutcome::Denied);
+                    return self.terminate(core, poll);
+                }
+                self.report(HandshakeOutcome::Success);
+            }
+            NextState::ConnectionCandidate(_) => {
+                if !self.use_session_key() {
+                    return self.terminate(core, poll);
+                }
+                // The slot stays reserved until the connection candidate is done.
+                if !self.make_room(core, poll, CrustUser::Node) {
+                    trace!("No room left for another Node.");
+                    self.report(HandshakeOutcome::Denied);
+                    return self.terminate(core, poll);
+                }
+                self.report(HandshakeOutcome::Success);
+            }
+            // We sent a denial, unless we served an echo request, which was reported already.
+            NextState::None => self.report(HandshakeOutcome::Denied),
+        }
+
         let _ = core.remove_state(self.token);
         let _ = core.cancel_timeout(&self.timeout);
 
@@ -451,6 +754,7 @@ impl<UID: Uid> ExchangeMsg<UID> {
 
         match self.next_state {
             NextState::ActiveConnection(their_uid, peer_kind) => {
+                let protocol = unwrap!(self.protocol);
                 let socket = mem::replace(&mut self.socket, Default::default());
                 ActiveConnection::start(
                     core,
@@ -458,50 +762,82 @@ impl<UID: Uid> ExchangeMsg<UID> {
                     self.token,
                     socket,
                     self.cm.clone(),
+                    self.config.clone(),
                     our_uid,
                     their_uid,
                     peer_kind,
-                    Event::BootstrapAccept(their_uid, peer_kind),
+                    protocol,
+                    move |handle| Event::BootstrapAccept(their_uid, peer_kind, handle),
                     event_tx,
                 );
             }
             NextState::ConnectionCandidate(their_uid) => {
+                let protocol = unwrap!(self.protocol);
                 let cm = self.cm.clone();
+                let config = self.config.clone();
+                let guard = self.guard.clone();
+                let reserved = self.reserved.take();
                 let handler = move |core: &mut EventLoopCore, poll: &Poll, token, res| {
-                    if let Some(socket) = res {
+                    if let Some(peer_kind) = reserved {
+                        guard.borrow_mut().release_slot(peer_kind);
+                    }
+                    if let Ok(socket) = res {
                         ActiveConnection::start(
                             core,
                             poll,
                             token,
                             socket,
                             cm.clone(),
+                            config.clone(),
                             our_uid,
                             their_uid,
                             // Note; We enter ConnectionCandidate only with
                             //       Nodes
                             CrustUser::Node,
-                            Event::ConnectSuccess(their_uid),
+                            protocol,
+                            move |handle| Event::ConnectSuccess(their_uid, handle),
                             event_tx.clone(),
                         );
                     }
                 };
 
                 let socket = mem::replace(&mut self.socket, Default::default());
-                let _ = ConnectionCandidate::start(
+                if ConnectionCandidate::start(
                     core,
                     poll,
                     self.token,
                     socket,
                     self.cm.clone(),
+                    self.config.clone(),
                     our_uid,
                     their_uid,
                     Box::new(handler),
-                );
+                )
+                .is_err()
+                {
+                    if let Some(peer_kind) = reserved {
+                        self.guard.borrow_mut().release_slot(peer_kind);
+                    }
+                }
             }
             NextState::None => self.terminate(core, poll),
         }
     }
 
+    /// Lets the guard know how the handshake ended. Only the first report counts.
+    fn report(&mut self, outcome: HandshakeOutcome) {
+        if let Some(peer_ip) = self.peer_ip.take() {
+            let limits = unwrap!(self.config.lock())
+                .cfg
+                .listener_limits
+                .clone()
+                .unwrap_or_default();
+            self.guard
+                .borrow_mut()
+                .handshake_finished(peer_ip, outcome, &limits);
+        }
+    }
+
     fn terminate_childern(&mut self, core: &mut EventLoopCore, poll: &Poll) {
         for child in self.reachability_children.drain() {
             core.get_state(child)
@@ -521,6 +857,8 @@ impl<UID: Uid> State<BootstrapCache> for ExchangeMsg<UID> {
     }
 
     fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.report(HandshakeOutcome::Failure);
+        self.release_slot();
         self.terminate_childern(core, poll);
         let _ = core.remove_state(self.token);
 
@@ -549,6 +887,7 @@ impl<UID: Uid> State<BootstrapCache> for ExchangeMsg<UID> {
 
     fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
         debug!("Exchange message timed out. Terminating direct connection request.");
+        self.report(HandshakeOutcome::Timeout);
         self.terminate(core, poll)
     }
 
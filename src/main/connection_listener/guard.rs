@@ -0,0 +1,541 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::CrustUser;
+use crate::main::bandwidth::TokenBucket;
+use crate::main::ListenerLimits;
+use crate::nat::ip_addr_is_global;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_HANDSHAKES: usize = 512;
+pub const DEFAULT_MAX_HANDSHAKES_PER_IP: usize = 16;
+pub const DEFAULT_ACCEPTS_PER_SEC_PER_IP: u64 = 10;
+pub const DEFAULT_MAX_FAILURES_PER_IP: u32 = 10;
+pub const DEFAULT_BAN_SEC: u64 = 10 * 60;
+pub const DEFAULT_MAX_REACHABILITY_CHECKS: usize = 4;
+pub const DEFAULT_REACHABILITY_CHECKS_PER_SEC_PER_IP: u64 = 1;
+
+/// Upper bound for the number of IPs we keep rate limit and failure records for, so that
+/// attackers with many addresses can't make us use arbitrary amounts of memory.
+const MAX_TRACKED_IPS: usize = 4096;
+
+/// Counters of the listener's flood protection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ListenerStats {
+    /// Handshakes currently in progress.
+    pub handshakes_in_progress: usize,
+    /// IPs currently banned.
+    pub banned_ips: usize,
+    /// Connections refused because too many handshakes were in progress.
+    pub rejected_max_handshakes: u64,
+    /// Connections refused because too many handshakes with the same IP were in progress.
+    pub rejected_max_handshakes_per_ip: u64,
+    /// Connections refused because their IP connected too often.
+    pub rejected_rate_limited: u64,
+    /// Connections refused because their IP was banned.
+    pub rejected_banned: u64,
+    /// Handshakes which didn't complete in time.
+    pub handshake_timeouts: u64,
+    /// Handshakes which failed for any other reason.
+    pub handshake_failures: u64,
+    /// Handshakes we ended by turning the peer down, e.g. because it's not whitelisted.
+    pub handshake_denials: u64,
+    /// Number of times an IP got banned.
+    pub bans: u64,
+    /// Bootstrapper listeners not checked for reachability, because they were over the limit or
+    /// on an IP the bootstrapper didn't connect from.
+    pub skipped_reachability_checks: u64,
+    /// Bootstrap requests denied because their IP asked for reachability checks too often.
+    pub throttled_reachability_checks: u64,
+}
+
+/// How a handshake accepted by the listener ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeOutcome {
+    Success,
+    Failure,
+    Timeout,
+    /// We turned down a peer which played by the rules. Doesn't count towards a ban.
+    Denied,
+}
+
+/// Decides which incoming connections the listener handles and keeps track of their handshakes.
+#[derive(Default)]
+pub struct ListenerGuard {
+    handshakes: HashMap<IpAddr, usize>,
+    accept_buckets: HashMap<IpAddr, TokenBucket>,
+    reachability_buckets: HashMap<IpAddr, TokenBucket>,
+    /// Failed handshakes of IPs along with the time of their latest failure.
+    failures: HashMap<IpAddr, (u32, Instant)>,
+    /// Banned IPs along with the time their ban ends.
+    bans: HashMap<IpAddr, Instant>,
+    /// Handshakes which were given room for a connection, but aren't established yet.
+    reserved_nodes: usize,
+    reserved_clients: usize,
+    stats: ListenerStats,
+}
+
+impl ListenerGuard {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns `true` if a connection from `ip` may start a handshake, accounting for it.
+    pub fn admit(&mut self, ip: IpAddr, limits: &ListenerLimits) -> bool {
+        self.admit_at(ip, limits, Instant::now())
+    }
+
+    /// Must be called exactly once for every admitted connection.
+    pub fn handshake_finished(
+        &mut self,
+        ip: IpAddr,
+        outcome: HandshakeOutcome,
+        limits: &ListenerLimits,
+    ) {
+        self.handshake_finished_at(ip, outcome, limits, Instant::now())
+    }
+
+    /// Picks which of the bootstrapper's listeners to check for reachability. Returns `None` if
+    /// `peer_ip` asked for too many checks lately.
+    pub fn reachability_targets(
+        &mut self,
+        peer_ip: IpAddr,
+        listeners: Vec<SocketAddr>,
+        limits: &ListenerLimits,
+    ) -> Option<Vec<SocketAddr>> {
+        self.reachability_targets_at(peer_ip, listeners, limits, Instant::now())
+    }
+
+    /// Accounts for a handshake which will take up a connection slot of given kind once done.
+    pub fn reserve_slot(&mut self, peer_kind: CrustUser) {
+        *self.reserved_mut(peer_kind) += 1;
+    }
+
+    /// Must be called exactly once for every reserved slot, whether the connection made it or not.
+    pub fn release_slot(&mut self, peer_kind: CrustUser) {
+        let reserved = self.reserved_mut(peer_kind);
+        *reserved = reserved.saturating_sub(1);
+    }
+
+    /// Number of handshakes holding a slot of given kind.
+    pub fn reserved_slots(&self, peer_kind: CrustUser) -> usize {
+        match peer_kind {
+            CrustUser::Node => self.reserved_nodes,
+            CrustUser::Client => self.reserved_clients,
+        }
+    }
+
+    pub fn stats(&mut self) -> ListenerStats {
+        let now = Instant::now();
+        self.bans.retain(|_, until| *until > now);
+        ListenerStats {
+            banned_ips: self.bans.len(),
+            ..self.stats
+        }
+    }
+
+    fn reserved_mut(&mut self, peer_kind: CrustUser) -> &mut usize {
+        match peer_kind {
+            CrustUser::Node => &mut self.reserved_nodes,
+            CrustUser::Client => &mut self.reserved_clients,
+        }
+    }
+
+    fn admit_at(&mut self, ip: IpAddr, limits: &ListenerLimits, now: Instant) -> bool {
+        if let Entry::Occupied(ban) = self.bans.entry(ip) {
+            if *ban.get() > now {
+                self.stats.rejected_banned += 1;
+                return false;
+            }
+            let _ = ban.remove();
+        }
+
+        let max_handshakes = limits.max_handshakes.unwrap_or(DEFAULT_MAX_HANDSHAKES);
+        if self.stats.handshakes_in_progress >= max_handshakes {
+            self.stats.rejected_max_handshakes += 1;
+            return false;
+        }
+
+        let max_per_ip = limits
+            .max_handshakes_per_ip
+            .unwrap_or(DEFAULT_MAX_HANDSHAKES_PER_IP);
+        if self.handshakes.get(&ip).map_or(false, |n| *n >= max_per_ip) {
+            self.stats.rejected_max_handshakes_per_ip += 1;
+            return false;
+        }
+
+        let rate = limits
+            .accepts_per_sec_per_ip
+            .unwrap_or(DEFAULT_ACCEPTS_PER_SEC_PER_IP);
+        if !take_tokens(&mut self.accept_buckets, ip, rate, 1, now) {
+            self.stats.rejected_rate_limited += 1;
+            return false;
+        }
+
+        *self.handshakes.entry(ip).or_insert(0) += 1;
+        self.stats.handshakes_in_progress += 1;
+        true
+    }
+
+    fn reachability_targets_at(
+        &mut self,
+        peer_ip: IpAddr,
+        listeners: Vec<SocketAddr>,
+        limits: &ListenerLimits,
+        now: Instant,
+    ) -> Option<Vec<SocketAddr>> {
+        let any_ip = limits.reachability_check_any_ip.unwrap_or(false);
+        let max_checks = limits
+            .max_reachability_checks
+            .unwrap_or(DEFAULT_MAX_REACHABILITY_CHECKS);
+
+        let total = listeners.len();
+        let mut targets = Vec::with_capacity(max_checks.min(total));
+        for addr in listeners {
+            if targets.len() == max_checks {
+                break;
+            }
+            if ip_addr_is_global(&addr.ip())
+                && (any_ip || addr.ip() == peer_ip)
+                && !targets.contains(&addr)
+            {
+                targets.push(addr);
+            }
+        }
+        self.stats.skipped_reachability_checks += (total - targets.len()) as u64;
+
+        if targets.is_empty() {
+            return Some(targets);
+        }
+        let rate = limits
+            .reachability_checks_per_sec_per_ip
+            .unwrap_or(DEFAULT_REACHABILITY_CHECKS_PER_SEC_PER_IP);
+        if !take_tokens(
+            &mut self.reachability_buckets,
+            peer_ip,
+            rate,
+            targets.len(),
+            now,
+        ) {
+            self.stats.throttled_reachability_checks += 1;
+            return None;
+        }
+        Some(targets)
+    }
+
+    fn handshake_finished_at(
+        &mut self,
+        ip: IpAddr,
+        outcome: HandshakeOutcome,
+        limits: &ListenerLimits,
+        now: Instant,
+    ) {
+        if let Entry::Occupied(mut count) = self.handshakes.entry(ip) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                let _ = count.remove();
+            }
+        }
+        self.stats.handshakes_in_progress = self.stats.handshakes_in_progress.saturating_sub(1);
+
+        match outcome {
+            HandshakeOutcome::Success => {
+                let _ = self.failures.remove(&ip);
+                return;
+            }
+            HandshakeOutcome::Denied => {
+                self.stats.handshake_denials += 1;
+                return;
+            }
+            HandshakeOutcome::Failure => self.stats.handshake_failures += 1,
+            HandshakeOutcome::Timeout => self.stats.handshake_timeouts += 1,
+        }
+
+        if self.failures.len() >= MAX_TRACKED_IPS && !self.failures.contains_key(&ip) {
+            // Forget the IP which failed longest ago, so that attackers with many IPs can't wipe
+            // the records of the ones failing right now.
+            let oldest = self
+                .failures
+                .iter()
+                .min_by_key(|&(_, &(_, last_failure))| last_failure)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                let _ = self.failures.remove(&oldest);
+            }
+        }
+        let failures = {
+            let failures = self.failures.entry(ip).or_insert((0, now));
+            failures.0 += 1;
+            failures.1 = now;
+            failures.0
+        };
+        if failures
+            >= limits
+                .max_failures_per_ip
+                .unwrap_or(DEFAULT_MAX_FAILURES_PER_IP)
+        {
+            debug!("Banning {} after {} failed handshakes", ip, failures);
+            let _ = self.failures.remove(&ip);
+            let ban = Duration::from_secs(limits.ban_secs.unwrap_or(DEFAULT_BAN_SEC));
+            let _ = self.bans.insert(ip, now + ban);
+            self.stats.bans += 1;
+        }
+    }
+}
+
+/// Takes tokens out of the IP's bucket, unless the bucket is in debt already.
+fn take_tokens(
+    buckets: &mut HashMap<IpAddr, TokenBucket>,
+    ip: IpAddr,
+    rate: u64,
+    tokens: usize,
+    now: Instant,
+) -> bool {
+    if buckets.len() >= MAX_TRACKED_IPS && !buckets.contains_key(&ip) {
+        // Keep only the IPs which are being held back right now.
+        buckets.retain(|_, bucket| bucket.delay_at(now).is_some());
+    }
+    let bucket = buckets
+        .entry(ip)
+        .or_insert_with(|| TokenBucket::new_at(rate, now));
+    if bucket.delay_at(now).is_some() {
+        return false;
+    }
+    bucket.consume_at(tokens, now);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+
+    fn ip(last: u8) -> IpAddr {
+        ipv4_addr(10, 0, 0, last, 0).ip()
+    }
+
+    #[test]
+    fn reachability_checks_are_limited_to_source_ip() {
+        let mut guard = ListenerGuard::new();
+        let now = Instant::now();
+        let peer_ip = ipv4_addr(1, 2, 3, 4, 0).ip();
+        let listeners = vec![
+            ipv4_addr(1, 2, 3, 4, 5000),
+            ipv4_addr(5, 6, 7, 8, 5000),
+            ipv4_addr(192, 168, 0, 1, 5000),
+            ipv4_addr(1, 2, 3, 4, 5000),
+            ipv4_addr(1, 2, 3, 4, 5001),
+        ];
+
+        let targets = unwrap!(guard.reachability_targets_at(
+            peer_ip,
+            listeners.clone(),
+            &Default::default(),
+            now
+        ));
+        assert_eq!(
+            targets,
+            vec![ipv4_addr(1, 2, 3, 4, 5000), ipv4_addr(1, 2, 3, 4, 5001)]
+        );
+        assert_eq!(guard.stats().skipped_reachability_checks, 3);
+
+        let limits = ListenerLimits {
+            reachability_check_any_ip: Some(true),
+            max_reachability_checks: Some(2),
+            ..Default::default()
+        };
+        let targets = unwrap!(guard.reachability_targets_at(
+            peer_ip,
+            listeners,
+            &limits,
+            now + Duration::from_secs(10),
+        ));
+        assert_eq!(
+            targets,
+            vec![ipv4_addr(1, 2, 3, 4, 5000), ipv4_addr(5, 6, 7, 8, 5000)]
+        );
+    }
+
+    #[test]
+    fn reachability_checks_are_throttled_per_ip() {
+        let mut guard = ListenerGuard::new();
+        let now = Instant::now();
+        let peer_ip = ipv4_addr(1, 2, 3, 4, 0).ip();
+        let listeners = vec![ipv4_addr(1, 2, 3, 4, 5000), ipv4_addr(1, 2, 3, 4, 5001)];
+        let limits = Default::default();
+
+        assert_eq!(
+            guard
+                .reachability_targets_at(peer_ip, listeners.clone(), &limits, now)
+                .map(|targets| targets.len()),
+            Some(2)
+        );
+        assert!(guard
+            .reachability_targets_at(peer_ip, listeners.clone(), &limits, now)
+            .is_none());
+        assert_eq!(guard.stats().throttled_reachability_checks, 1);
+
+        let later = now + Duration::from_secs(2);
+        assert_eq!(
+            guard
+                .reachability_targets_at(peer_ip, listeners, &limits, later)
+                .map(|targets| targets.len()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn handshakes_are_capped_globally_and_per_ip() {
+        let limits = ListenerLimits {
+            max_handshakes: Some(3),
+            max_handshakes_per_ip: Some(2),
+            ..Default::default()
+        };
+        let mut guard = ListenerGuard::new();
+        let now = Instant::now();
+
+        assert!(guard.admit_at(ip(1), &limits, now));
+        assert!(guard.admit_at(ip(1), &limits, now));
+        assert!(!guard.admit_at(ip(1), &limits, now));
+        assert!(guard.admit_at(ip(2), &limits, now));
+        assert!(!guard.admit_at(ip(3), &limits, now));
+
+        guard.handshake_finished_at(ip(1), HandshakeOutcome::Success, &limits, now);
+        assert!(guard.admit_at(ip(3), &limits, now));
+
+        let stats = guard.stats();
+        assert_eq!(stats.handshakes_in_progress, 3);
+        assert_eq!(stats.rejected_max_handshakes_per_ip, 1);
+        assert_eq!(stats.rejected_max_handshakes, 1);
+    }
+
+    #[test]
+    fn accepts_are_rate_limited_per_ip() {
+        let limits = ListenerLimits {
+            accepts_per_sec_per_ip: Some(2),
+            ..Default::default()
+        };
+        let mut guard = ListenerGuard::new();
+        let now = Instant::now();
+
+        let admitted = (0..10)
+            .filter(|_| guard.admit_at(ip(1), &limits, now))
+            .count();
+        assert_eq!(admitted, 3);
+        assert!(guard.admit_at(ip(2), &limits, now));
+        assert!(guard.admit_at(ip(1), &limits, now + Duration::from_secs(2)));
+        assert_eq!(guard.stats().rejected_rate_limited, 7);
+    }
+
+    #[test]
+    fn repeated_failures_get_ip_banned() {
+        let limits = ListenerLimits {
+            max_failures_per_ip: Some(2),
+            ban_secs: Some(60),
+            ..Default::default()
+        };
+        let mut guard = ListenerGuard::new();
+        let now = Instant::now();
+
+        for outcome in &[HandshakeOutcome::Failure, HandshakeOutcome::Timeout] {
+            assert!(guard.admit_at(ip(1), &limits, now));
+            guard.handshake_finished_at(ip(1), *outcome, &limits, now);
+        }
+
+        assert!(!guard.admit_at(ip(1), &limits, now + Duration::from_secs(59)));
+        assert!(guard.admit_at(ip(2), &limits, now));
+        assert!(guard.admit_at(ip(1), &limits, now + Duration::from_secs(61)));
+
+        let stats = guard.stats();
+        assert_eq!(stats.bans, 1);
+        assert_eq!(stats.rejected_banned, 1);
+        assert_eq!(stats.handshake_failures, 1);
+        assert_eq!(stats.handshake_timeouts, 1);
+    }
+
+    #[test]
+    fn successful_handshake_resets_failures() {
+        let limits = ListenerLimits {
+            max_failures_per_ip: Some(2),
+            ..Default::default()
+        };
+        let mut guard = ListenerGuard::new();
+        let now = Instant::now();
+
+        for outcome in &[
+            HandshakeOutcome::Failure,
+            HandshakeOutcome::Success,
+            HandshakeOutcome::Failure,
+        ] {
+            assert!(guard.admit_at(ip(1), &limits, now));
+            guard.handshake_finished_at(ip(1), *outcome, &limits, now);
+        }
+
+        assert!(guard.admit_at(ip(1), &limits, now));
+        assert_eq!(guard.stats().bans, 0);
+    }
+
+    #[test]
+    fn denials_dont_count_towards_bans() {
+        let limits = ListenerLimits {
+            max_failures_per_ip: Some(2),
+            ..Default::default()
+        };
+        let mut guard = ListenerGuard::new();
+        let now = Instant::now();
+
+        for outcome in &[
+            HandshakeOutcome::Failure,
+            HandshakeOutcome::Denied,
+            HandshakeOutcome::Denied,
+        ] {
+            assert!(guard.admit_at(ip(1), &limits, now));
+            guard.handshake_finished_at(ip(1), *outcome, &limits, now);
+        }
+
+        let stats = guard.stats();
+        assert_eq!(stats.bans, 0);
+        assert_eq!(stats.handshake_denials, 2);
+
+        // Denials don't wipe failures either.
+        assert!(guard.admit_at(ip(1), &limits, now));
+        guard.handshake_finished_at(ip(1), HandshakeOutcome::Failure, &limits, now);
+        assert_eq!(guard.stats().bans, 1);
+    }
+
+    #[test]
+    fn failures_of_many_ips_dont_wipe_recent_ones() {
+        let limits = ListenerLimits {
+            max_failures_per_ip: Some(2),
+            ..Default::default()
+        };
+        let mut guard = ListenerGuard::new();
+        let now = Instant::now();
+        let attacker_ip = |i: usize| ipv4_addr(1, (i >> 16) as u8, (i >> 8) as u8, i as u8, 0).ip();
+
+        for i in 0..MAX_TRACKED_IPS {
+            assert!(guard.admit_at(attacker_ip(i), &limits, now));
+            guard.handshake_finished_at(attacker_ip(i), HandshakeOutcome::Failure, &limits, now);
+        }
+        let later = now + Duration::from_secs(1);
+        assert!(guard.admit_at(ip(1), &limits, later));
+        guard.handshake_finished_at(ip(1), HandshakeOutcome::Failure, &limits, later);
+        let attacker = attacker_ip(MAX_TRACKED_IPS);
+        assert!(guard.admit_at(attacker, &limits, later));
+        guard.handshake_finished_at(attacker, HandshakeOutcome::Failure, &limits, later);
+
+        assert!(guard.admit_at(ip(1), &limits, later));
+        guard.handshake_finished_at(ip(1), HandshakeOutcome::Failure, &limits, later);
+        assert_eq!(guard.stats().bans, 1);
+        assert!(!guard.admit_at(ip(1), &limits, later));
+    }
+}
@@ -9,11 +9,17 @@
 
 mod check_reachability;
 mod exchange_msg;
+mod guard;
+mod replay_cache;
+
+pub use self::guard::ListenerStats;
+pub use self::replay_cache::{ReplayCache, REPLAY_CACHE_CAPACITY};
 
 use self::exchange_msg::ExchangeMsg;
-use crate::common::{NameHash, PeerInfo, State, Uid};
+use self::guard::{HandshakeOutcome, ListenerGuard};
+use crate::common::{Identity, NameHash, PeerInfo, State, Uid};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::{ConnectionMap, CrustConfig, Event, EventLoopCore};
+use crate::main::{ConnectionMap, CrustConfig, Event, EventLoopCore, EventToken};
 use crate::nat::ip_addr_is_global;
 use crate::nat::{MappedTcpSocket, MappingContext};
 use mio::net::TcpListener;
@@ -24,7 +30,7 @@ use socket_collection::{DecryptContext, TcpSock};
 use std::any::Any;
 use std::cell::RefCell;
 use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
@@ -37,12 +43,18 @@ pub struct ConnectionListener<UID: Uid> {
     config: CrustConfig,
     event_tx: crate::CrustEventSender<UID>,
     listener: TcpListener,
+    port: u16,
+    force_include_port: bool,
+    our_listeners: Arc<Mutex<Vec<PeerInfo>>>,
     name_hash: NameHash,
     our_uid: UID,
     timeout_sec: Option<u64>,
     accept_bootstrap: bool,
     our_pk: PublicEncryptKey,
     our_sk: SecretEncryptKey,
+    identity: Option<Arc<Identity<UID>>>,
+    replay_cache: Rc<RefCell<ReplayCache<IpAddr>>>,
+    guard: Rc<RefCell<ListenerGuard>>,
 }
 
 impl<UID: Uid> ConnectionListener<UID> {
@@ -62,50 +74,36 @@ impl<UID: Uid> ConnectionListener<UID> {
         event_tx: crate::CrustEventSender<UID>,
         our_pk: PublicEncryptKey,
         our_sk: SecretEncryptKey,
+        identity: Option<Arc<Identity<UID>>>,
     ) {
         let event_tx_0 = event_tx.clone();
         let our_sk2 = our_sk.clone();
 
-        let finish = move |core: &mut EventLoopCore,
-                           poll: &Poll,
-                           socket,
-                           mut mapped_addrs: Vec<SocketAddr>| {
-            let checker = |s: &SocketAddr| ip_addr_is_global(&s.ip()) && s.port() == port;
-            if force_include_port && port != 0 && !mapped_addrs.iter().any(checker) {
-                let global_addrs: Vec<_> = mapped_addrs
-                    .iter()
-                    .filter_map(|s| {
-                        if ip_addr_is_global(&s.ip()) {
-                            let mut s = *s;
-                            s.set_port(port);
-                            Some(s)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-                mapped_addrs.extend(global_addrs);
-            }
-            if let Err(e) = Self::handle_mapped_socket(
-                core,
-                poll,
-                handshake_timeout_sec,
-                socket,
-                mapped_addrs,
-                our_uid,
-                name_hash,
-                cm,
-                config,
-                our_listeners,
-                token,
-                event_tx.clone(),
-                our_pk,
-                our_sk,
-            ) {
-                error!("TCP Listener failed to handle mapped socket: {:?}", e);
-                let _ = event_tx.send(Event::ListenerFailed);
-            }
-        };
+        let finish =
+            move |core: &mut EventLoopCore, poll: &Poll, socket, mapped_addrs: Vec<SocketAddr>| {
+                if let Err(e) = Self::handle_mapped_socket(
+                    core,
+                    poll,
+                    handshake_timeout_sec,
+                    socket,
+                    mapped_addrs,
+                    port,
+                    force_include_port,
+                    our_uid,
+                    name_hash,
+                    cm,
+                    config,
+                    our_listeners,
+                    token,
+                    event_tx.clone(),
+                    our_pk,
+                    our_sk,
+                    identity,
+                ) {
+                    error!("TCP Listener failed to handle mapped socket: {:?}", e);
+                    let _ = event_tx.send(Event::ListenerFailed);
+                }
+            };
 
         if let Err(e) =
             MappedTcpSocket::<_, UID, _>::start(core, poll, port, &mc, our_pk, &our_sk2, finish)
@@ -119,12 +117,91 @@ impl<UID: Uid> ConnectionListener<UID> {
         self.accept_bootstrap = accept;
     }
 
+    /// Maps the listener anew, e.g. after our network interfaces changed, and updates our
+    /// listeners with the result.
+    pub fn remap(core: &mut EventLoopCore, poll: &Poll, token: Token, mc: &MappingContext) {
+        let (local_port, port, force_include_port, our_pk, our_sk) = {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.borrow_mut();
+            let listener = match state.as_any().downcast_mut::<Self>() {
+                Some(listener) => listener,
+                None => return,
+            };
+            let local_port = match listener.listener.local_addr() {
+                Ok(addr) => addr.port(),
+                Err(e) => {
+                    debug!("Failed to get TCP listener address: {:?}", e);
+                    return;
+                }
+            };
+            (
+                local_port,
+                listener.port,
+                listener.force_include_port,
+                listener.our_pk,
+                listener.our_sk.clone(),
+            )
+        };
+
+        let finish = move |core: &mut EventLoopCore,
+                           _poll: &Poll,
+                           _socket: TcpBuilder,
+                           mapped_addrs: Vec<SocketAddr>| {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.borrow_mut();
+            if let Some(listener) = state.as_any().downcast_mut::<Self>() {
+                listener.update_listeners(with_forced_port(mapped_addrs, port, force_include_port));
+            }
+        };
+
+        if let Err(e) =
+            MappedTcpSocket::<_, UID, _>::start(core, poll, local_port, mc, our_pk, &our_sk, finish)
+        {
+            debug!("Failed to remap TCP listener: {:?}", e);
+        }
+    }
+
+    /// Replaces our listeners with the given addresses and lets the user know if they or our
+    /// external addresses changed.
+    fn update_listeners(&self, mapped_addrs: Vec<SocketAddr>) {
+        let mut our_listeners = unwrap!(self.our_listeners.lock());
+        let mut old_addrs: Vec<_> = our_listeners.iter().map(|peer| peer.addr).collect();
+        let mut new_addrs = mapped_addrs.clone();
+        old_addrs.sort();
+        new_addrs.sort();
+        if old_addrs == new_addrs {
+            return;
+        }
+
+        trace!("Our listeners have changed to {:?}", mapped_addrs);
+        let old_external = external_addrs(&our_listeners);
+        *our_listeners = mapped_addrs
+            .iter()
+            .map(|addr| PeerInfo::new(*addr, self.our_pk))
+            .collect();
+        let new_external = external_addrs(&our_listeners);
+        let _ = self.event_tx.send(Event::ListenersChanged(mapped_addrs));
+        if new_external != old_external {
+            let _ = self
+                .event_tx
+                .send(Event::ExternalAddrsChanged(new_external));
+        }
+    }
+
     fn handle_mapped_socket(
         core: &mut EventLoopCore,
         poll: &Poll,
         timeout_sec: Option<u64>,
         socket: TcpBuilder,
         mapped_addrs: Vec<SocketAddr>,
+        port: u16,
+        force_include_port: bool,
         our_uid: UID,
         name_hash: NameHash,
         cm: ConnectionMap<UID>,
@@ -134,6 +211,7 @@ impl<UID: Uid> ConnectionListener<UID> {
         event_tx: crate::CrustEventSender<UID>,
         our_pk: PublicEncryptKey,
         our_sk: SecretEncryptKey,
+        identity: Option<Arc<Identity<UID>>>,
     ) -> crate::Res<()> {
         let listener = socket.listen(LISTENER_BACKLOG)?;
         let local_addr = listener.local_addr()?;
@@ -141,7 +219,7 @@ impl<UID: Uid> ConnectionListener<UID> {
         let listener = TcpListener::from_std(listener)?;
         poll.register(&listener, token, Ready::readable(), PollOpt::edge())?;
 
-        *unwrap!(our_listeners.lock()) = mapped_addrs
+        *unwrap!(our_listeners.lock()) = with_forced_port(mapped_addrs, port, force_include_port)
             .into_iter()
             .map(|addr| PeerInfo::new(addr, our_pk))
             .collect();
@@ -152,12 +230,18 @@ impl<UID: Uid> ConnectionListener<UID> {
             config,
             event_tx: event_tx.clone(),
             listener,
+            port,
+            force_include_port,
+            our_listeners,
             name_hash,
             our_uid,
             timeout_sec,
             accept_bootstrap: false,
             our_pk,
             our_sk,
+            identity,
+            replay_cache: Rc::new(RefCell::new(ReplayCache::new(REPLAY_CACHE_CAPACITY))),
+            guard: Rc::new(RefCell::new(ListenerGuard::new())),
         };
 
         let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
@@ -166,22 +250,48 @@ impl<UID: Uid> ConnectionListener<UID> {
         Ok(())
     }
 
+    /// Counters of the flood protection.
+    pub fn stats(&self) -> ListenerStats {
+        self.guard.borrow_mut().stats()
+    }
+
+    /// Local port the listener is bound to.
+    pub fn local_port(&self) -> Option<u16> {
+        self.listener.local_addr().ok().map(|addr| addr.port())
+    }
+
     fn accept(&self, core: &mut EventLoopCore, poll: &Poll) {
         loop {
             match self.listener.accept() {
-                Ok((socket, _)) => {
+                Ok((socket, peer_addr)) => {
+                    let limits = unwrap!(self.config.lock())
+                        .cfg
+                        .listener_limits
+                        .clone()
+                        .unwrap_or_default();
+                    let peer_ip = peer_addr.ip();
+                    if !self.guard.borrow_mut().admit(peer_ip, &limits) {
+                        trace!("Refusing connection from {}", peer_addr);
+                        continue;
+                    }
+
                     let mut socket = TcpSock::wrap(socket);
                     if let Err(e) = socket.set_decrypt_ctx(DecryptContext::anonymous_decrypt(
                         self.our_pk,
                         self.our_sk.clone(),
                     )) {
                         warn!("Failed to set decryption context: {}", e);
+                        self.guard.borrow_mut().handshake_finished(
+                            peer_ip,
+                            HandshakeOutcome::Failure,
+                            &limits,
+                        );
                         continue;
                     }
                     if let Err(e) = ExchangeMsg::start(
                         core,
                         poll,
-                        self.timeout_sec,
+                        self.timeout_sec.or(limits.handshake_timeout_secs),
                         socket,
                         self.accept_bootstrap,
                         self.our_uid,
@@ -191,8 +301,17 @@ impl<UID: Uid> ConnectionListener<UID> {
                         self.event_tx.clone(),
                         self.our_pk,
                         &self.our_sk,
+                        self.identity.clone(),
+                        self.replay_cache.clone(),
+                        self.guard.clone(),
+                        peer_ip,
                     ) {
                         debug!("Error accepting direct connection: {:?}", e);
+                        self.guard.borrow_mut().handshake_finished(
+                            peer_ip,
+                            HandshakeOutcome::Failure,
+                            &limits,
+                        );
                     }
                 }
                 Err(ref e)
@@ -226,17 +345,69 @@ impl<UID: Uid> State<BootstrapCache> for ConnectionListener<UID> {
     }
 }
 
+/// Returns the local port of our listener, if it's running.
+pub fn listener_port<UID: Uid>(core: &EventLoopCore) -> Option<u16> {
+    let state = core.get_state(EventToken::Listener.into())?;
+    let mut state = state.borrow_mut();
+    state
+        .as_any()
+        .downcast_mut::<ConnectionListener<UID>>()?
+        .local_port()
+}
+
+/// Addresses of our listeners reachable from the internet, sorted and without duplicates.
+pub fn external_addrs(our_listeners: &[PeerInfo]) -> Vec<SocketAddr> {
+    let mut addrs: Vec<_> = our_listeners
+        .iter()
+        .map(|peer| peer.addr)
+        .filter(|addr| ip_addr_is_global(&addr.ip()))
+        .collect();
+    addrs.sort();
+    addrs.dedup();
+    addrs
+}
+
+/// Adds our acceptor port to our global addresses if we are configured to force it and it isn't
+/// among them yet. See `Config::force_acceptor_port_in_ext_ep`.
+fn with_forced_port(
+    mut mapped_addrs: Vec<SocketAddr>,
+    port: u16,
+    force_include_port: bool,
+) -> Vec<SocketAddr> {
+    let checker = |s: &SocketAddr| ip_addr_is_global(&s.ip()) && s.port() == port;
+    if force_include_port && port != 0 && !mapped_addrs.iter().any(checker) {
+        let global_addrs: Vec<_> = mapped_addrs
+            .iter()
+            .filter_map(|s| {
+                if ip_addr_is_global(&s.ip()) {
+                    let mut s = *s;
+                    s.set_port(port);
+                    Some(s)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        mapped_addrs.extend(global_addrs);
+    }
+    mapped_addrs
+}
+
 #[cfg(test)]
 mod tests {
     use super::exchange_msg::EXCHANGE_MSG_TIMEOUT_SEC;
+    use super::replay_cache::MAX_CLOCK_SKEW_SECS;
     use super::*;
     use crate::common::{
-        self, CoreMessage, CrustUser, ExternalReachability, Message, NameHash, HASH_SIZE,
+        self, ipv4_addr, unix_timestamp, BootstrapDenyReason, Capabilities, ConnectDenyReason,
+        CoreMessage, CrustUser, ExternalReachability, Freshness, Handshake, Message, NameHash,
+        ProtocolInfo, HASH_SIZE, PROTOCOL_VERSION,
     };
     use crate::main::bootstrap::Cache as BootstrapCache;
-    use crate::main::{Event, EventLoop};
+    use crate::main::{ConfigWrapper, ConnectionLimits, Event, EventLoop, ListenerLimits};
     use crate::nat::MappingContext;
     use crate::tests::UniqueId;
+    use crate::Config;
     use maidsafe_utilities::event_sender::MaidSafeEventCategory;
     use mio::Events;
     use mio::Token;
@@ -244,7 +415,7 @@ mod tests {
     use safe_crypto::gen_encrypt_keypair;
     use socket_collection::{EncryptContext, SocketError};
     use std::collections::HashMap;
-    use std::io::Read;
+    use std::io::{Read, Write};
     use std::net::SocketAddr as StdSocketAddr;
     use std::net::TcpStream;
     use std::sync::mpsc;
@@ -261,14 +432,19 @@ mod tests {
     const NAME_HASH_2: NameHash = [2; HASH_SIZE];
 
     struct Listener {
-        _el: EventLoop,
+        el: EventLoop,
         uid: UniqueId,
         addr: SocketAddr,
         event_rx: mpsc::Receiver<Event<UniqueId>>,
         pub_key: PublicEncryptKey,
+        listeners: Arc<Mutex<Vec<PeerInfo>>>,
     }
 
     fn start_listener(accept_bootstrap: bool) -> Listener {
+        start_listener_with_config(accept_bootstrap, Config::default())
+    }
+
+    fn start_listener_with_config(accept_bootstrap: bool, config: Config) -> Listener {
         let el = unwrap!(common::spawn_event_loop(
             LISTENER_TOKEN + 1,
             Some("Connection Listener Test"),
@@ -281,7 +457,7 @@ mod tests {
 
         let cm = Arc::new(Mutex::new(HashMap::new()));
         let mc = Arc::new(unwrap!(MappingContext::try_new(), "Could not get MC"));
-        let config = Arc::new(Mutex::new(Default::default()));
+        let config = Arc::new(Mutex::new(ConfigWrapper::new(config)));
         let listeners = Arc::new(Mutex::new(Vec::with_capacity(5)));
         let (our_pk, our_sk) = gen_encrypt_keypair();
 
@@ -305,6 +481,7 @@ mod tests {
                     crust_sender,
                     our_pk,
                     our_sk,
+                    None,
                 );
             })),
             "Could not send to tx"
@@ -336,16 +513,31 @@ mod tests {
         );
         unwrap!(rx.recv());
 
-        let listen_info = &unwrap!(listeners.lock())[0];
+        let addr = unwrap!(listeners.lock())[0].addr;
         Listener {
-            _el: el,
+            el,
             uid,
-            addr: listen_info.addr,
+            addr,
             event_rx,
             pub_key: our_pk,
+            listeners,
         }
     }
 
+    fn listener_stats(listener: &Listener) -> ListenerStats {
+        let (tx, rx) = mpsc::channel();
+        unwrap!(
+            listener.el.send(CoreMessage::new(move |core, _| {
+                let state = unwrap!(core.get_state(Token(LISTENER_TOKEN)));
+                let mut state = state.borrow_mut();
+                let listener = unwrap!(state.as_any().downcast_mut::<ConnectionListener>());
+                unwrap!(tx.send(listener.stats()));
+            })),
+            "Could not send to tx"
+        );
+        unwrap!(rx.recv())
+    }
+
     fn connect_to_listener(listener: &Listener) -> TcpStream {
         let listener_addr = StdSocketAddr::new(listener.addr.ip(), listener.addr.port());
         let stream = unwrap!(
@@ -366,24 +558,94 @@ mod tests {
         our_uid: UniqueId,
         listener: &Listener,
     ) {
+        let expected_kind = match ext_reachability {
+            ExternalReachability::NotRequired => CrustUser::Client,
+            ExternalReachability::Required { .. } => CrustUser::Node,
+        };
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+
+        let reply =
+            send_bootstrap_request(protocol, name_hash, ext_reachability, our_uid, listener);
+        match reply_message(protocol, &reply) {
+            Message::BootstrapGranted(peer_uid, _, _) => assert_eq!(peer_uid, listener.uid),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
+            Event::BootstrapAccept(peer_id, peer_kind, _) => {
+                assert_eq!(peer_id, our_uid);
+                assert_eq!(peer_kind, expected_kind);
+            }
+            event => panic!("Unexpected event notification: {:?}", event),
+        }
+    }
+
+    /// Sends bootstrap request to the listener and returns its response.
+    fn send_bootstrap_request(
+        protocol: ProtocolInfo,
+        name_hash: NameHash,
+        ext_reachability: ExternalReachability,
+        our_uid: UniqueId,
+        listener: &Listener,
+    ) -> Handshake {
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let message = bootstrap_request(
+            protocol,
+            Freshness::now(),
+            name_hash,
+            ext_reachability,
+            our_uid,
+            our_pk,
+        );
+        unwrap!(unwrap!(send_handshake_request(&message, &our_sk, listener)))
+    }
+
+    fn bootstrap_request(
+        protocol: ProtocolInfo,
+        freshness: Freshness,
+        name_hash: NameHash,
+        ext_reachability: ExternalReachability,
+        our_uid: UniqueId,
+        our_pk: PublicEncryptKey,
+    ) -> Handshake {
+        let (our_eph_pk, _) = gen_encrypt_keypair();
+        let msg = Message::BootstrapRequest(
+            freshness,
+            our_uid,
+            name_hash,
+            ext_reachability,
+            our_eph_pk,
+            None,
+        );
+        // Encoded in our format whatever versions `protocol` claims, as the listener shouldn't
+        // need to look at it.
+        unwrap!(Handshake::new(protocol, our_pk, PROTOCOL_VERSION, &msg))
+    }
+
+    /// Decodes the listener's reply to a request made with the given protocol.
+    fn reply_message(protocol: ProtocolInfo, reply: &Handshake) -> Message<UniqueId> {
+        let negotiated = unwrap!(protocol.negotiate(&reply.protocol));
+        unwrap!(reply.message(negotiated.version))
+    }
+
+    /// Sends handshake request to the listener over a new connection and returns the result of
+    /// reading its response.
+    fn send_handshake_request(
+        message: &Handshake,
+        our_sk: &SecretEncryptKey,
+        listener: &Listener,
+    ) -> ::std::result::Result<Option<Handshake>, SocketError> {
         const SOCKET_TOKEN: Token = Token(0);
         let el = unwrap!(Poll::new());
 
-        let (our_pk, our_sk) = gen_encrypt_keypair();
         let mut sock = unwrap!(TcpSock::connect(&listener.addr));
         unwrap!(sock.set_encrypt_ctx(EncryptContext::anonymous_encrypt(listener.pub_key)));
         let shared_key = our_sk.shared_secret(&listener.pub_key);
         unwrap!(sock.set_decrypt_ctx(DecryptContext::authenticated(shared_key)));
         unwrap!(el.register(&sock, SOCKET_TOKEN, Ready::writable(), PollOpt::edge(),));
 
-        let expected_kind = match ext_reachability {
-            ExternalReachability::NotRequired => CrustUser::Client,
-            ExternalReachability::Required { .. } => CrustUser::Node,
-        };
-        let message = Message::BootstrapRequest(our_uid, name_hash, ext_reachability, our_pk);
-
         let mut events = Events::with_capacity(16);
-        let msg = 'event_loop: loop {
+        'event_loop: loop {
             let _ = unwrap!(el.poll(&mut events, None));
             for ev in events.iter() {
                 match ev.token() {
@@ -399,30 +661,60 @@ mod tests {
                             ));
                         }
                         if ev.readiness().is_readable() {
-                            let msg: Message<UniqueId> = unwrap!(unwrap!(sock.read()));
-                            break 'event_loop msg;
+                            break 'event_loop sock.read();
                         }
                     }
                     _ => panic!("Unexpected event"),
                 }
             }
-        };
+        }
+    }
 
-        match msg {
-            Message::BootstrapGranted(peer_uid) => assert_eq!(peer_uid, listener.uid),
-            msg => panic!("Unexpected message: {:?}", msg),
+    fn connect(name_hash: NameHash, our_uid: UniqueId, listener: &Listener) {
+        let (mut sock, their_uid) = send_connect_request(name_hash, our_uid, listener);
+        if our_uid > their_uid {
+            let message = Message::ChooseConnection::<UniqueId>;
+            let sent = unwrap!(sock.write(Some((message, 0))));
+            assert!(sent);
         }
 
         match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
-            Event::BootstrapAccept(peer_id, peer_kind) => {
-                assert_eq!(peer_id, our_uid);
-                assert_eq!(peer_kind, expected_kind);
-            }
+            Event::ConnectSuccess(id, _) => assert_eq!(id, our_uid),
             event => panic!("Unexpected event notification: {:?}", event),
         }
     }
 
-    fn connect(name_hash: NameHash, our_uid: UniqueId, listener: &Listener) {
+    /// Sends connect request to the listener and completes the handshake, up to choosing the
+    /// connection. Returns the socket along with the listener's UID.
+    fn send_connect_request(
+        name_hash: NameHash,
+        our_uid: UniqueId,
+        listener: &Listener,
+    ) -> (TcpSock, UniqueId) {
+        let (mut sock, reply, our_eph_sk) = send_connect_request_raw(name_hash, our_uid, listener);
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+        assert_eq!(reply.pub_key, listener.pub_key);
+        match reply_message(protocol, &reply) {
+            Message::Connect(_, peer_uid, peer_hash, their_eph_pk, _) => {
+                assert_eq!(peer_uid, listener.uid);
+                assert_eq!(peer_hash, NAME_HASH);
+
+                let session_key = our_eph_sk.shared_secret(&their_eph_pk);
+                unwrap!(sock.set_encrypt_ctx(EncryptContext::authenticated(session_key.clone())));
+                unwrap!(sock.set_decrypt_ctx(DecryptContext::authenticated(session_key)));
+                (sock, peer_uid)
+            }
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+    }
+
+    /// Sends connect request to the listener and returns the socket, the listener's response and
+    /// our ephemeral secret key.
+    fn send_connect_request_raw(
+        name_hash: NameHash,
+        our_uid: UniqueId,
+        listener: &Listener,
+    ) -> (TcpSock, Handshake, SecretEncryptKey) {
         const SOCKET_TOKEN: Token = Token(0);
         let el = unwrap!(Poll::new());
 
@@ -430,10 +722,16 @@ mod tests {
         let mut sock = unwrap!(TcpSock::connect(&listener.addr));
         unwrap!(sock.set_encrypt_ctx(EncryptContext::anonymous_encrypt(listener.pub_key)));
         let shared_key = our_sk.shared_secret(&listener.pub_key);
-        unwrap!(sock.set_decrypt_ctx(DecryptContext::authenticated(shared_key.clone())));
+        unwrap!(sock.set_decrypt_ctx(DecryptContext::authenticated(shared_key)));
         unwrap!(el.register(&sock, SOCKET_TOKEN, Ready::writable(), PollOpt::edge()));
 
-        let message = Message::Connect(our_uid, name_hash, our_pk);
+        let (our_eph_pk, our_eph_sk) = gen_encrypt_keypair();
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+        let message = unwrap!(Handshake::request(
+            protocol,
+            our_pk,
+            &Message::Connect(Freshness::now(), our_uid, name_hash, our_eph_pk, None),
+        ));
 
         let mut events = Events::with_capacity(16);
         'event_loop: loop {
@@ -452,37 +750,14 @@ mod tests {
                             ));
                         }
                         if ev.readiness().is_readable() {
-                            let msg: Message<UniqueId> = unwrap!(unwrap!(sock.read()));
-                            let their_uid = match msg {
-                                Message::Connect(peer_uid, peer_hash, their_pk) => {
-                                    assert_eq!(peer_uid, listener.uid);
-                                    assert_eq!(peer_hash, NAME_HASH);
-                                    assert_eq!(their_pk, listener.pub_key);
-
-                                    unwrap!(sock.set_encrypt_ctx(EncryptContext::authenticated(
-                                        shared_key
-                                    )));
-                                    peer_uid
-                                }
-                                msg => panic!("Unexpected message: {:?}", msg),
-                            };
-                            if our_uid > their_uid {
-                                let message = Message::ChooseConnection::<UniqueId>;
-                                let sent = unwrap!(sock.write(Some((message, 0))));
-                                assert!(sent);
-                            }
-                            break 'event_loop;
+                            let reply: Handshake = unwrap!(unwrap!(sock.read()));
+                            break 'event_loop (sock, reply, our_eph_sk);
                         }
                     }
                     _ => panic!("Unexpected event"),
                 }
             }
         }
-
-        match unwrap!(listener.event_rx.recv(), "Could not read event channel") {
-            Event::ConnectSuccess(id) => assert_eq!(id, our_uid),
-            event => panic!("Unexpected event notification: {:?}", event),
-        }
     }
 
     #[test]
@@ -535,6 +810,181 @@ mod tests {
         connect(NAME_HASH_2, uid, &listener);
     }
 
+    #[test]
+    fn bootstrap_with_incompatible_version() {
+        let listener = start_listener(true);
+        let protocol = ProtocolInfo {
+            version: PROTOCOL_VERSION + 2,
+            min_version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::empty(),
+        };
+
+        let reply = send_bootstrap_request(
+            protocol,
+            NAME_HASH,
+            ExternalReachability::NotRequired,
+            rand::random(),
+            &listener,
+        );
+
+        // The reply carries the listener's protocol info, which we can tell we're incompatible
+        // with without decoding anything else.
+        assert!(protocol.negotiate(&reply.protocol).is_none());
+    }
+
+    #[test]
+    fn replayed_bootstrap_request_is_ignored() {
+        let listener = start_listener(true);
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+        let message = bootstrap_request(
+            protocol,
+            Freshness::now(),
+            NAME_HASH,
+            ExternalReachability::NotRequired,
+            rand::random(),
+            our_pk,
+        );
+
+        let reply = unwrap!(unwrap!(send_handshake_request(
+            &message, &our_sk, &listener
+        )));
+        match reply_message(protocol, &reply) {
+            Message::BootstrapGranted(..) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        match send_handshake_request(&message, &our_sk, &listener) {
+            Err(SocketError::ZeroByteRead) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn stale_bootstrap_request_is_ignored() {
+        let listener = start_listener(true);
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let freshness = Freshness {
+            timestamp: unix_timestamp() - 2 * MAX_CLOCK_SKEW_SECS,
+            nonce: rand::random(),
+        };
+        let message = bootstrap_request(
+            ProtocolInfo::new(Capabilities::empty()),
+            freshness,
+            NAME_HASH,
+            ExternalReachability::NotRequired,
+            rand::random(),
+            our_pk,
+        );
+
+        match send_handshake_request(&message, &our_sk, &listener) {
+            Err(SocketError::ZeroByteRead) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn bootstrap_over_connection_limit_is_denied() {
+        let mut config = Config::default();
+        config.connection_limits = Some(ConnectionLimits {
+            max_clients: Some(0),
+            ..Default::default()
+        });
+        let listener = start_listener_with_config(true, config);
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+
+        let reply = send_bootstrap_request(
+            protocol,
+            NAME_HASH,
+            ExternalReachability::NotRequired,
+            rand::random(),
+            &listener,
+        );
+
+        match reply_message(protocol, &reply) {
+            Message::BootstrapDenied(BootstrapDenyReason::TooManyConnections, _) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        let stats = listener_stats(&listener);
+        assert_eq!(stats.handshake_denials, 1);
+        assert_eq!(stats.handshake_failures, 0);
+    }
+
+    #[test]
+    fn connect_over_connection_limit_is_denied() {
+        let mut config = Config::default();
+        config.connection_limits = Some(ConnectionLimits {
+            max_nodes: Some(0),
+            ..Default::default()
+        });
+        let listener = start_listener_with_config(false, config);
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+
+        let (_sock, reply, _) = send_connect_request_raw(NAME_HASH, rand::random(), &listener);
+        match reply_message(protocol, &reply) {
+            Message::ConnectDenied(ConnectDenyReason::TooManyConnections) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        let stats = listener_stats(&listener);
+        assert_eq!(stats.handshake_denials, 1);
+        assert_eq!(stats.handshake_failures, 0);
+    }
+
+    #[test]
+    fn handshaking_connects_count_towards_connection_limit() {
+        let mut config = Config::default();
+        config.connection_limits = Some(ConnectionLimits {
+            max_nodes: Some(1),
+            ..Default::default()
+        });
+        let listener = start_listener_with_config(false, config);
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+
+        // Waiting for us to choose the connection, the first handshake isn't done yet.
+        let first_uid = loop {
+            let uid: UniqueId = rand::random();
+            if uid > listener.uid {
+                break uid;
+            }
+        };
+        let (mut first, _) = send_connect_request(NAME_HASH, first_uid, &listener);
+
+        let (_second, reply, _) = send_connect_request_raw(NAME_HASH, rand::random(), &listener);
+        match reply_message(protocol, &reply) {
+            Message::ConnectDenied(ConnectDenyReason::TooManyConnections) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+
+        let sent = unwrap!(first.write(Some((Message::ChooseConnection::<UniqueId>, 0))));
+        assert!(sent);
+        match unwrap!(listener.event_rx.recv()) {
+            Event::ConnectSuccess(id, _) => assert_eq!(id, first_uid),
+            event => panic!("Unexpected event notification: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn foreign_listeners_are_not_checked_for_reachability() {
+        let listener = start_listener(true);
+        let ext_reachability = ExternalReachability::Required {
+            direct_listeners: vec![ipv4_addr(8, 8, 8, 8, 5483)],
+        };
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+
+        let reply = send_bootstrap_request(
+            protocol,
+            NAME_HASH,
+            ext_reachability,
+            rand::random(),
+            &listener,
+        );
+
+        match reply_message(protocol, &reply) {
+            Message::BootstrapDenied(BootstrapDenyReason::FailedExternalReachability, _) => (),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        assert_eq!(listener_stats(&listener).skipped_reachability_checks, 1);
+    }
+
     #[test]
     #[should_panic]
     fn bootstrap_with_invalid_pub_key() {
@@ -609,6 +1059,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn handshakes_over_per_ip_cap_are_refused() {
+        let mut config = Config::default();
+        config.listener_limits = Some(ListenerLimits {
+            max_handshakes_per_ip: Some(1),
+            ..Default::default()
+        });
+        let listener = start_listener_with_config(true, config);
+
+        let _handshaking = connect_to_listener(&listener);
+        let mut refused = connect_to_listener(&listener);
+        let mut buf = [0; 512];
+        assert_eq!(0, unwrap!(refused.read(&mut buf)));
+
+        let stats = listener_stats(&listener);
+        assert_eq!(stats.handshakes_in_progress, 1);
+        assert_eq!(stats.rejected_max_handshakes_per_ip, 1);
+    }
+
+    #[test]
+    fn ip_sending_garbage_gets_banned() {
+        let mut config = Config::default();
+        config.listener_limits = Some(ListenerLimits {
+            max_failures_per_ip: Some(1),
+            ..Default::default()
+        });
+        let listener = start_listener_with_config(true, config);
+        let mut buf = [0; 512];
+
+        let mut garbage = connect_to_listener(&listener);
+        unwrap!(garbage.write_all(&[0xff; 64]));
+        assert_eq!(0, unwrap!(garbage.read(&mut buf)));
+
+        let mut banned = connect_to_listener(&listener);
+        assert_eq!(0, unwrap!(banned.read(&mut buf)));
+
+        let stats = listener_stats(&listener);
+        assert_eq!(stats.handshake_failures, 1);
+        assert_eq!(stats.bans, 1);
+        assert_eq!(stats.banned_ips, 1);
+        assert_eq!(stats.rejected_banned, 1);
+        assert_eq!(stats.handshakes_in_progress, 0);
+    }
+
     #[test]
     fn stun_service() {
         // TODO(povilas): use GetExtAddr for this test.
@@ -622,7 +1116,12 @@ mod tests {
         unwrap!(el.register(&sock, SOCKET_TOKEN, Ready::writable(), PollOpt::edge(),));
 
         let (our_pk, our_sk) = gen_encrypt_keypair();
-        let message = Message::EchoAddrReq::<UniqueId>(our_pk);
+        let protocol = ProtocolInfo::new(Capabilities::empty());
+        let message = unwrap!(Handshake::request(
+            protocol,
+            our_pk,
+            &Message::EchoAddrReq::<UniqueId>,
+        ));
 
         let shared_key = our_sk.shared_secret(&listener.pub_key);
         let dec_ctx = DecryptContext::authenticated(shared_key);
@@ -645,8 +1144,8 @@ mod tests {
                             ));
                         }
                         if ev.readiness().is_readable() {
-                            let msg: Message<UniqueId> = unwrap!(unwrap!(sock.read()));
-                            break 'event_loop msg;
+                            let reply: Handshake = unwrap!(unwrap!(sock.read()));
+                            break 'event_loop reply_message(protocol, &reply);
                         }
                     }
                     _ => panic!("Unexpected event"),
@@ -671,4 +1170,58 @@ mod tests {
             r => panic!("Unexpected result: {:?}", r),
         }
     }
+
+    #[test]
+    fn remapping_updates_listeners() {
+        let listener = start_listener(false);
+        let remap = |mc: MappingContext| {
+            unwrap!(listener.el.send(CoreMessage::new(move |core, poll| {
+                ConnectionListener::remap(core, poll, Token(LISTENER_TOKEN), &mc);
+            })));
+        };
+        // Hosts with global addresses get their external addresses reported too.
+        let next_listeners = |timeout| loop {
+            match listener.event_rx.recv_timeout(timeout) {
+                Ok(Event::ListenersChanged(addrs)) => return Some(addrs),
+                Ok(Event::ExternalAddrsChanged(_)) => (),
+                Ok(event) => panic!("Unexpected event: {:?}", event),
+                Err(_) => return None,
+            }
+        };
+
+        // All interfaces gone.
+        remap(MappingContext::empty());
+        assert_eq!(unwrap!(next_listeners(Duration::from_secs(5))), vec![]);
+        assert!(unwrap!(listener.listeners.lock()).is_empty());
+
+        // And back again.
+        remap(unwrap!(MappingContext::try_new()));
+        assert!(unwrap!(next_listeners(Duration::from_secs(5))).contains(&listener.addr));
+        assert!(unwrap!(listener.listeners.lock())
+            .iter()
+            .any(|peer| peer.addr == listener.addr));
+
+        // Nothing changed, so no event.
+        remap(unwrap!(MappingContext::try_new()));
+        assert!(next_listeners(Duration::from_secs(1)).is_none());
+    }
+
+    #[test]
+    fn external_addrs_are_global_listeners() {
+        let (pk, _) = gen_encrypt_keypair();
+        let listeners: Vec<_> = vec![
+            ipv4_addr(192, 168, 0, 2, 5000),
+            ipv4_addr(1, 2, 3, 4, 5000),
+            ipv4_addr(127, 0, 0, 1, 5000),
+            ipv4_addr(1, 2, 3, 4, 5000),
+        ]
+        .into_iter()
+        .map(|addr| PeerInfo::new(addr, pk))
+        .collect();
+
+        assert_eq!(
+            external_addrs(&listeners),
+            vec![ipv4_addr(1, 2, 3, 4, 5000)]
+        );
+    }
 }
@@ -0,0 +1,207 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{unix_timestamp, Freshness};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// Handshake requests whose timestamp differs from our clock by more than this are rejected.
+pub const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+/// Maximum number of handshake requests remembered.
+pub const REPLAY_CACHE_CAPACITY: usize = 10_000;
+/// Maximum number of handshake requests remembered per source.
+pub const MAX_REQUESTS_PER_SOURCE: usize = 100;
+
+/// Remembers handshake requests we granted within the accepted clock skew, so that each of them is
+/// handled at most once. Once full, the oldest request is forgotten and no request as old as
+/// that is accepted any more. Requests are accounted for per source, e.g. the IP they came from.
+pub struct ReplayCache<S> {
+    /// Seen requests by timestamp and nonce, along with their source.
+    seen: BTreeMap<(u64, u64), S>,
+    /// Number of seen requests per source.
+    sources: HashMap<S, usize>,
+    /// Timestamp of the latest request forgotten before it expired.
+    watermark: Option<u64>,
+    capacity: usize,
+}
+
+impl<S: Copy + Debug + Eq + Hash> ReplayCache<S> {
+    pub fn new(capacity: usize) -> Self {
+        ReplayCache {
+            seen: BTreeMap::new(),
+            sources: HashMap::new(),
+            watermark: None,
+            capacity,
+        }
+    }
+
+    /// Returns `true` if the request is fresh and wasn't seen before.
+    pub fn is_fresh(&self, freshness: Freshness) -> bool {
+        self.is_fresh_at(freshness, unix_timestamp())
+    }
+
+    /// Records the request as seen, once it passed validation. Returns `false` if it isn't fresh
+    /// any more or its source sent too many requests lately.
+    pub fn record(&mut self, freshness: Freshness, source: S) -> bool {
+        self.record_at(freshness, source, unix_timestamp())
+    }
+
+    fn is_fresh_at(&self, freshness: Freshness, now: u64) -> bool {
+        let skew = if freshness.timestamp > now {
+            freshness.timestamp - now
+        } else {
+            now - freshness.timestamp
+        };
+        skew <= MAX_CLOCK_SKEW_SECS
+            && self
+                .watermark
+                .map_or(true, |watermark| freshness.timestamp > watermark)
+            && !self.seen.contains_key(&key(freshness))
+    }
+
+    fn record_at(&mut self, freshness: Freshness, source: S, now: u64) -> bool {
+        if !self.is_fresh_at(freshness, now) {
+            return false;
+        }
+
+        // Requests which are too old to be accepted anyway don't need to be remembered.
+        while let Some(oldest) = self.seen.keys().next().cloned() {
+            if oldest.0 + MAX_CLOCK_SKEW_SECS >= now {
+                break;
+            }
+            self.forget(oldest);
+        }
+
+        if self.sources.get(&source).cloned().unwrap_or(0) >= MAX_REQUESTS_PER_SOURCE {
+            debug!("Too many handshake requests from {:?} lately.", source);
+            return false;
+        }
+        if self.seen.len() >= self.capacity {
+            if let Some(oldest) = self.seen.keys().next().cloned() {
+                self.forget(oldest);
+                self.watermark = Some(oldest.0);
+            }
+        }
+
+        let _ = self.seen.insert(key(freshness), source);
+        *self.sources.entry(source).or_insert(0) += 1;
+        true
+    }
+
+    fn forget(&mut self, key: (u64, u64)) {
+        let source = match self.seen.remove(&key) {
+            Some(source) => source,
+            None => return,
+        };
+        let remove = match self.sources.get_mut(&source) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if remove {
+            let _ = self.sources.remove(&source);
+        }
+    }
+}
+
+fn key(freshness: Freshness) -> (u64, u64) {
+    (freshness.timestamp, freshness.nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn freshness(timestamp: u64, nonce: u64) -> Freshness {
+        Freshness { timestamp, nonce }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn requests_are_accepted_once() {
+        let mut cache = ReplayCache::new(REPLAY_CACHE_CAPACITY);
+        let now = 1_000_000;
+
+        assert!(cache.record_at(freshness(now, 1), ip(1), now));
+        assert!(cache.record_at(freshness(now, 2), ip(1), now));
+        assert!(!cache.is_fresh_at(freshness(now, 1), now + 1));
+        assert!(!cache.record_at(freshness(now, 1), ip(1), now + 1));
+    }
+
+    #[test]
+    fn checking_requests_does_not_record_them() {
+        let mut cache = ReplayCache::new(REPLAY_CACHE_CAPACITY);
+        let now = 1_000_000;
+
+        assert!(cache.is_fresh_at(freshness(now, 1), now));
+        assert!(cache.is_fresh_at(freshness(now, 1), now));
+        assert!(cache.record_at(freshness(now, 1), ip(1), now));
+    }
+
+    #[test]
+    fn stale_and_future_requests_are_rejected() {
+        let mut cache = ReplayCache::new(REPLAY_CACHE_CAPACITY);
+        let now = 1_000_000;
+
+        assert!(!cache.is_fresh_at(freshness(now - MAX_CLOCK_SKEW_SECS - 1, 1), now));
+        assert!(!cache.is_fresh_at(freshness(now + MAX_CLOCK_SKEW_SECS + 1, 2), now));
+        assert!(cache.record_at(freshness(now - MAX_CLOCK_SKEW_SECS, 3), ip(1), now));
+    }
+
+    #[test]
+    fn flood_from_one_source_does_not_block_others() {
+        let mut cache = ReplayCache::new(REPLAY_CACHE_CAPACITY);
+        let now = 1_000_000;
+
+        let future = now + MAX_CLOCK_SKEW_SECS;
+        let accepted = (0..REPLAY_CACHE_CAPACITY as u64)
+            .filter(|nonce| cache.record_at(freshness(future, *nonce), ip(1), now))
+            .count();
+        assert_eq!(accepted, MAX_REQUESTS_PER_SOURCE);
+
+        assert!(cache.is_fresh_at(freshness(now, 0), now));
+        assert!(cache.record_at(freshness(now, 0), ip(2), now));
+    }
+
+    #[test]
+    fn full_cache_forgets_the_oldest_request() {
+        let mut cache = ReplayCache::new(2);
+        let now = 1_000_000;
+
+        assert!(cache.record_at(freshness(now, 1), ip(1), now));
+        assert!(cache.record_at(freshness(now - 1, 2), ip(2), now));
+        assert!(cache.record_at(freshness(now, 3), ip(3), now));
+        assert_eq!(cache.seen.len(), 2);
+
+        // Requests as old as the forgotten one can't be replayed either.
+        assert!(!cache.is_fresh_at(freshness(now - 1, 2), now));
+        assert!(!cache.is_fresh_at(freshness(now - 1, 4), now));
+        assert!(!cache.is_fresh_at(freshness(now, 1), now));
+        assert!(cache.record_at(freshness(now, 4), ip(4), now));
+    }
+
+    #[test]
+    fn expired_requests_are_forgotten() {
+        let mut cache = ReplayCache::new(REPLAY_CACHE_CAPACITY);
+        let now = 1_000_000;
+
+        assert!(cache.record_at(freshness(now, 1), ip(1), now));
+        let later = now + MAX_CLOCK_SKEW_SECS + 1;
+        assert!(cache.record_at(freshness(later, 2), ip(1), later));
+        assert_eq!(cache.seen.len(), 1);
+        assert_eq!(cache.sources.get(&ip(1)), Some(&1));
+    }
+}
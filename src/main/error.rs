@@ -15,6 +15,7 @@ use maidsafe_utilities::serialisation::SerialisationError;
 use safe_crypto;
 use socket_collection::SocketError;
 use std::io;
+use std::net::IpAddr;
 use std::sync::mpsc;
 
 quick_error! {
@@ -73,6 +74,14 @@ quick_error! {
         PeerNotFound {
             description("Peer not found")
         }
+        /// The connection a `PeerHandle` refers to was replaced by a newer one
+        ConnectionReplaced {
+            description("Connection was replaced by a newer one")
+        }
+        /// The operation is not available while the peer is reached through a relay
+        RelayedSession {
+            description("Not available over a relayed session")
+        }
         /// Serialisation error
         Serialisation(e: SerialisationError) {
             description("Serialisation error")
@@ -102,5 +111,10 @@ quick_error! {
             cause(e)
             from()
         }
+        /// IP range prefix is longer than the address.
+        InvalidIpRange(addr: IpAddr, prefix_len: u8) {
+            description("Invalid IP range")
+            display("Invalid IP range: {}/{}", addr, prefix_len)
+        }
     }
 }
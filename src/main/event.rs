@@ -7,36 +7,140 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use super::ConnectionInfoResult;
+use super::{ConnectionInfoResult, PeerHandle};
 
-use crate::common::{CrustUser, Uid};
+use crate::common::{
+    BootstrapDenyReason, ConnectDenyReason, CrustUser, GoodbyeReason, PeerInfo, Uid,
+};
 use std::net::SocketAddr;
+use std::time::Duration;
 
 /// Enum representing different events that will be sent over the asynchronous channel to the user
 /// of this module.
 #[derive(Debug)]
 pub enum Event<UID: Uid> {
     /// Invoked when a bootstrap peer connects to us
-    BootstrapAccept(UID, CrustUser),
+    BootstrapAccept(UID, CrustUser, PeerHandle<UID>),
     /// Invoked when we bootstrap to a new peer.
-    BootstrapConnect(UID, SocketAddr),
-    /// Invoked when we failed to connect to all bootstrap contacts.
-    BootstrapFailed,
+    BootstrapConnect(UID, SocketAddr, PeerHandle<UID>),
+    /// Invoked when we failed to connect to all bootstrap contacts. Contains what happened to each
+    /// of them.
+    BootstrapFailed(BootstrapReport),
     /// Invoked when we are ready to listen for incomming connection. Contains
     /// the listening port.
     ListenerStarted(u16),
     /// Invoked when listener failed to start.
     ListenerFailed,
+    /// Invoked when our listener addresses changed after our network interfaces did. Contains the
+    /// new addresses.
+    ListenersChanged(Vec<SocketAddr>),
+    /// Invoked when our addresses as seen from the internet changed, see
+    /// `Service::our_external_addrs`. Contains the new addresses.
+    ExternalAddrsChanged(Vec<SocketAddr>),
+    /// Invoked when service discovery found a peer of our network on the LAN, or the peer's
+    /// listeners changed. Contains its listeners.
+    LanPeerFound(Vec<PeerInfo>),
+    /// Invoked when a peer on the LAN stopped answering service discovery. Contains its last known
+    /// listeners.
+    LanPeerLost(Vec<PeerInfo>),
+    /// Invoked when a peer shared with us via peer exchange turned out genuine and was added to
+    /// the bootstrap cache.
+    PeerLearned(PeerInfo),
     /// Invoked as a result to the call of `Service::prepare_contact_info`.
     ConnectionInfoPrepared(ConnectionInfoResult<UID>),
     /// Invoked when connection to a new peer has been established.
-    ConnectSuccess(UID),
+    ConnectSuccess(UID, PeerHandle<UID>),
     /// Invoked when connection to a new peer has failed.
-    ConnectFailure(UID),
+    ConnectFailure(UID, ConnectFailureReason<UID>),
     /// Invoked when a peer disconnects or can no longer be contacted.
-    LostPeer(UID),
+    LostPeer(UID, DisconnectReason),
     /// Invoked when a new message is received. Passes the message.
     NewMessage(UID, CrustUser, Vec<u8>),
     /// Invoked when trying to sending a too large data.
     WriteMsgSizeProhibitive(UID, Vec<u8>),
 }
+
+/// Why bootstrapping failed, see `Event::BootstrapFailed`.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct BootstrapReport {
+    /// Every contact we tried, in the order they finished, with how it went.
+    pub contacts: Vec<(PeerInfo, ContactResult)>,
+    /// How long bootstrapping ran before it failed.
+    pub elapsed: Duration,
+    /// Whether service discovery was running to find contacts on our LAN.
+    pub service_discovery: bool,
+    /// The error which kept bootstrapping from getting under way, if any. Without one, every
+    /// contact failed or there were none.
+    pub error: Option<String>,
+}
+
+/// How bootstrapping off one contact failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContactResult {
+    /// The contact refused us, saying why.
+    Denied(BootstrapDenyReason),
+    /// Connecting to the contact or the handshake with it failed. Contains the error.
+    Failed(String),
+    /// The contact didn't answer before bootstrapping gave up on it.
+    TimedOut,
+}
+
+/// Why we lost a peer, see `Event::LostPeer`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The peer closed the connection, saying why.
+    Goodbye(GoodbyeReason),
+    /// We heard nothing from the peer for too long.
+    Inactivity,
+    /// Reading from the connection failed. Contains the error.
+    ReadError(String),
+    /// Writing to the connection failed. Contains the error.
+    WriteError(String),
+    /// Setting the connection up or keeping it going failed on our side. Contains the error.
+    LocalError(String),
+    /// The peer sent something the protocol doesn't allow.
+    ProtocolViolation,
+    /// The peer is no longer allowed by our whitelists or access lists.
+    NotAllowed,
+    /// We closed the connection, e.g. with `Service::disconnect`.
+    Disconnected,
+    /// We dropped the peer to make room for others, see `ConnectionLimits`.
+    Evicted,
+    /// Our local address of the connection went away with a network interface.
+    InterfaceGone,
+    /// We shut down, see `Service::shutdown`.
+    Shutdown,
+    /// The relayed session ended because the peer closed it or we lost the relay.
+    RelayLost,
+    /// Another connection to the peer replaced this one. An event announcing the new connection
+    /// follows.
+    Duplicate,
+}
+
+/// Why connecting to a peer failed, see `Event::ConnectFailure`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectFailureReason<UID> {
+    /// The peer's connection info had no addresses or relays we may use.
+    InsufficientConnectionInfo,
+    /// No attempt succeeded in time. Contains how each of the peer's addresses failed, and how
+    /// each relay tried so far failed.
+    Timeout(Vec<(SocketAddr, AddrFailure)>, Vec<(UID, AddrFailure)>),
+    /// Every attempt failed. Contains how each of the peer's addresses and each of its relays
+    /// failed.
+    Failed(Vec<(SocketAddr, AddrFailure)>, Vec<(UID, AddrFailure)>),
+}
+
+/// How connecting to one of the peer's addresses, or through one of its relays, failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AddrFailure {
+    /// Opening the connection or relayed session failed. Contains the error.
+    Connect(String),
+    /// The handshake failed, or the peer refused it without giving a reason.
+    Handshake,
+    /// The peer refused the connection for given reason.
+    Denied(ConnectDenyReason),
+    /// We kept another connection to the peer instead.
+    Duplicate,
+    /// The attempt was still under way when we gave up.
+    Pending,
+}
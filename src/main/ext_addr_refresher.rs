@@ -0,0 +1,111 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CoreTimer, State, Uid};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{ConnectionListener, CrustConfig, EventLoopCore, EventToken};
+use crate::nat::{self, MappingContext};
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use rand;
+use rand::seq::SliceRandom;
+use std::any::Any;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_REFRESH_INTERVAL_SEC: u64 = 5 * 60;
+/// Maximum number of peers asked to echo our address next to the configured echo servers.
+const MAX_ECHO_PEERS: usize = 8;
+/// Only nodes we connected to directly within this period are asked to echo our address.
+const ECHO_NODE_MAX_AGE_SECS: u64 = 60 * 60;
+
+/// Periodically maps our listener anew, so that our external addresses follow changes made by our
+/// ISP or NAT.
+pub struct ExtAddrRefresher<UID: Uid> {
+    token: Token,
+    timer: CoreTimer,
+    timeout: Timeout,
+    mc: Arc<Mutex<MappingContext>>,
+    config: CrustConfig,
+    phantom: PhantomData<UID>,
+}
+
+impl<UID: Uid> ExtAddrRefresher<UID> {
+    pub fn start(
+        core: &mut EventLoopCore,
+        token: Token,
+        mc: Arc<Mutex<MappingContext>>,
+        config: CrustConfig,
+    ) -> crate::Res<()> {
+        trace!("Entered state ExtAddrRefresher");
+
+        let timer = CoreTimer::new(token, 0);
+        let timeout = core.set_timeout(refresh_interval(&config), timer);
+
+        let state = Rc::new(RefCell::new(ExtAddrRefresher {
+            token,
+            timer,
+            timeout,
+            mc,
+            config,
+            phantom: PhantomData,
+        }));
+        let _ = core.insert_state(token, state);
+
+        Ok(())
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for ExtAddrRefresher<UID> {
+    fn terminate(&mut self, core: &mut EventLoopCore, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        self.timeout = core.set_timeout(refresh_interval(&self.config), self.timer);
+
+        let mut mc = unwrap!(self.mc.lock()).clone();
+        add_echo_peers(core, &mut mc);
+        ConnectionListener::<UID>::remap(core, poll, EventToken::Listener.into(), &mc);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Adds nodes we lately connected to directly as peers to ask to echo our address next to the
+/// configured echo servers of `mc`. Mapping takes the addresses several of them agree on.
+pub fn add_echo_peers(core: &EventLoopCore, mc: &mut MappingContext) {
+    let mut nodes = core
+        .user_data()
+        .recently_verified(Duration::from_secs(ECHO_NODE_MAX_AGE_SECS));
+    nodes.shuffle(&mut rand::thread_rng());
+
+    let peers: Vec<_> = nodes
+        .into_iter()
+        .filter(|peer| nat::ip_addr_is_global(&peer.addr.ip()))
+        .filter(|peer| !mc.peer_stuns().contains(peer))
+        .take(MAX_ECHO_PEERS)
+        .collect();
+    mc.add_echo_peers(peers);
+}
+
+fn refresh_interval(config: &CrustConfig) -> Duration {
+    Duration::from_secs(
+        unwrap!(config.lock())
+            .cfg
+            .external_addr_refresh_interval_secs
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL_SEC),
+    )
+}
@@ -0,0 +1,174 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CoreMessage, CoreTimer, State, Uid};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{
+    add_echo_peers, ActiveConnection, ConnectionListener, ConnectionMap, CrustConfig,
+    EventLoopCore, EventToken,
+};
+use crate::nat::{self, MappingContext, NatError};
+use maidsafe_utilities::thread;
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_SCAN_INTERVAL_SEC: u64 = 60;
+
+/// Periodically checks our network interfaces. When they change, the mapping context is created
+/// anew, off the event loop as looking for IGD gateways blocks, and our listener is mapped again.
+pub struct InterfaceWatcher<UID: Uid> {
+    token: Token,
+    timer: CoreTimer,
+    timeout: Timeout,
+    mc: Arc<Mutex<MappingContext>>,
+    cm: ConnectionMap<UID>,
+    config: CrustConfig,
+    our_ips: Vec<IpAddr>,
+    remapping: bool,
+}
+
+impl<UID: Uid> InterfaceWatcher<UID> {
+    pub fn start(
+        core: &mut EventLoopCore,
+        token: Token,
+        mc: Arc<Mutex<MappingContext>>,
+        cm: ConnectionMap<UID>,
+        config: CrustConfig,
+    ) -> crate::Res<()> {
+        trace!("Entered state InterfaceWatcher");
+
+        let timer = CoreTimer::new(token, 0);
+        let timeout = core.set_timeout(scan_interval(&config), timer);
+        let our_ips = unwrap!(mc.lock()).ips();
+
+        let state = Rc::new(RefCell::new(InterfaceWatcher {
+            token,
+            timer,
+            timeout,
+            mc,
+            cm,
+            config,
+            our_ips,
+            remapping: false,
+        }));
+        let _ = core.insert_state(token, state);
+
+        Ok(())
+    }
+
+    fn remap(&mut self, core: &mut EventLoopCore) {
+        self.remapping = true;
+        let peer_stuns = unwrap!(self.mc.lock()).peer_stuns().clone();
+        let tx = core.sender().clone();
+        let token = self.token;
+        let _ = thread::named("Interface-Remapping", move || {
+            let res = MappingContext::try_new().map(|mut mc| {
+                mc.add_peer_stuns(peer_stuns);
+                mc
+            });
+            let _ = tx.send(CoreMessage::new(move |core, poll| {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => return,
+                };
+                let mut state = state.borrow_mut();
+                if let Some(watcher) = state.as_any().downcast_mut::<InterfaceWatcher<UID>>() {
+                    watcher.handle_mapping_context(core, poll, res);
+                }
+            }));
+        });
+    }
+
+    fn handle_mapping_context(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        res: Result<MappingContext, NatError>,
+    ) {
+        self.remapping = false;
+        let mut mc = match res {
+            Ok(mc) => mc,
+            Err(e) => {
+                debug!("Failed to create mapping context: {:?}", e);
+                // Try again on the next scan.
+                self.our_ips.clear();
+                return;
+            }
+        };
+        self.our_ips = mc.ips();
+        *unwrap!(self.mc.lock()) = mc.clone();
+
+        add_echo_peers(core, &mut mc);
+        ConnectionListener::<UID>::remap(core, poll, EventToken::Listener.into(), &mc);
+
+        if unwrap!(self.config.lock()).cfg.revalidate_connections == Some(true) {
+            self.revalidate_connections(core, poll);
+        }
+    }
+
+    fn revalidate_connections(&self, core: &mut EventLoopCore, poll: &Poll) {
+        // Relayed connections depend on the connection to their relay, which is checked here.
+        let tokens: Vec<_> = unwrap!(self.cm.lock())
+            .values()
+            .flat_map(|cid| cid.connections())
+            .collect();
+        for token in tokens {
+            let state = match core.get_state(token) {
+                Some(state) => state,
+                None => continue,
+            };
+            let mut state = state.borrow_mut();
+            if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                ac.revalidate(core, poll, &self.our_ips);
+            }
+        }
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for InterfaceWatcher<UID> {
+    fn terminate(&mut self, core: &mut EventLoopCore, _poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+    }
+
+    fn timeout(&mut self, core: &mut EventLoopCore, _poll: &Poll, _timer_id: u8) {
+        self.timeout = core.set_timeout(scan_interval(&self.config), self.timer);
+        if self.remapping {
+            return;
+        }
+
+        match nat::interface_ips() {
+            Ok(ref ips) if *ips == self.our_ips => (),
+            Ok(ips) => {
+                trace!("Network interfaces have changed to {:?} - remapping.", ips);
+                self.remap(core);
+            }
+            Err(e) => debug!("Failed to list network interfaces: {:?}", e),
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn scan_interval(config: &CrustConfig) -> Duration {
+    Duration::from_secs(
+        unwrap!(config.lock())
+            .cfg
+            .interface_scan_interval_secs
+            .unwrap_or(DEFAULT_SCAN_INTERVAL_SEC),
+    )
+}
@@ -7,18 +7,40 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-pub use self::active_connection::{ActiveConnection, INACTIVITY_TIMEOUT_MS};
+pub use self::access_list::{AccessListEntry, AccessLists, IpRange};
+pub use self::active_connection::{
+    can_stand_by, disconnect, queue_by_priority, ActiveConnection, Heartbeat, HeartbeatAction,
+    INACTIVITY_TIMEOUT_MS, RECV_HEARTBEAT_TIMER_ID, SEND_HEARTBEAT_TIMER_ID,
+};
+pub use self::bandwidth::{BandwidthLimiter, Direction, PeerBandwidth};
 pub use self::bootstrap::Bootstrap;
 #[cfg(test)]
 pub use self::bootstrap::Cache as BootstrapCache;
-pub use self::config_handler::{Config, DevConfig};
-pub use self::config_refresher::ConfigRefresher;
+pub use self::config_handler::{
+    BandwidthLimit, Config, ConnectionLimits, DevConfig, DiscoveryMethod, EvictionPolicy,
+    ListenerLimits, RateLimits, RelayConfig,
+};
+pub use self::config_refresher::{drop_disallowed_peers, ConfigRefresher};
 pub use self::connect::Connect;
 pub use self::connection_candidate::ConnectionCandidate;
-pub use self::connection_listener::ConnectionListener;
+pub use self::connection_limits::{has_room, make_room};
+pub use self::connection_listener::{
+    external_addrs, listener_port, ConnectionListener, ListenerStats, ReplayCache,
+    REPLAY_CACHE_CAPACITY,
+};
 pub use self::error::CrustError;
-pub use self::event::Event;
-pub use self::service::Service;
+pub use self::event::{
+    AddrFailure, BootstrapReport, ConnectFailureReason, ContactResult, DisconnectReason, Event,
+};
+pub use self::ext_addr_refresher::{add_echo_peers, ExtAddrRefresher};
+pub use self::interface_watcher::InterfaceWatcher;
+pub use self::peer_exchange::{learn_peers, peers_to_share};
+pub use self::peer_handle::{ConnectionStats, PeerHandle};
+pub use self::relay::{
+    can_upgrade, handle_lost_peer, handle_relay, handle_relay_closed, handle_relayed,
+    retire_session, Relay, RelayedConnection,
+};
+pub use self::service::{EventToken, Service};
 pub use self::types::{
     ConfigWrapper, ConnectionId, ConnectionInfoResult, EventLoop, EventLoopCore,
     PrivConnectionInfo, PubConnectionInfo,
@@ -29,15 +51,23 @@ use std::sync::{Arc, Mutex};
 pub type ConnectionMap<UID> = Arc<Mutex<HashMap<UID, ConnectionId>>>;
 pub type CrustConfig = Arc<Mutex<ConfigWrapper>>;
 
+mod access_list;
 mod active_connection;
+mod bandwidth;
 mod bootstrap;
 mod config_handler;
 mod config_refresher;
 mod connect;
 mod connection_candidate;
+mod connection_limits;
 mod connection_listener;
 mod error;
 mod event;
+mod ext_addr_refresher;
+mod interface_watcher;
+mod peer_exchange;
+mod peer_handle;
+mod relay;
 mod service;
 mod types;
 
@@ -0,0 +1,223 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{
+    Capabilities, CoreTimer, Handshake, Message, PeerInfo, ProtocolInfo, State, Uid,
+};
+use crate::main::bootstrap::{self, Cache as BootstrapCache};
+use crate::main::{ConfigWrapper, CrustConfig, Event, EventLoopCore};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio_extras::timer::Timeout;
+use rand;
+use rand::seq::SliceRandom;
+use safe_crypto::gen_encrypt_keypair;
+use socket_collection::{DecryptContext, EncryptContext, Priority, TcpSock};
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Maximum number of peers shared in one go. Any extra ones we are sent are ignored.
+pub const MAX_SHARED_PEERS: usize = 10;
+/// Only peers we connected to directly within this period are shared.
+const VERIFIED_PEER_MAX_AGE_SECS: u64 = 60 * 60;
+/// Peers shared with us are only cached while the cache is smaller than this.
+const MAX_CACHE_SIZE: usize = 256;
+/// Maximum number of cached peers on a single IP, so that one host can't fill the cache.
+const MAX_CACHED_PEERS_PER_IP: usize = 2;
+const VALIDATE_PEER_TIMEOUT_SEC: u64 = 10;
+
+/// Returns a sample of peers we connected to directly lately, leaving out the ones on `their_ip`.
+pub fn peers_to_share(core: &EventLoopCore, their_ip: Option<IpAddr>) -> Vec<PeerInfo> {
+    let max_age = Duration::from_secs(VERIFIED_PEER_MAX_AGE_SECS);
+    let mut peers: Vec<_> = core
+        .user_data()
+        .recently_verified(max_age)
+        .into_iter()
+        .filter(|peer| Some(peer.addr.ip()) != their_ip)
+        .collect();
+    peers.shuffle(&mut rand::thread_rng());
+    peers.truncate(MAX_SHARED_PEERS);
+    peers
+}
+
+/// Checks peers shared with us and caches the ones which turn out to be reachable and to own
+/// their public keys, reporting each with `Event::PeerLearned`.
+pub fn learn_peers<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    config: &CrustConfig,
+    event_tx: &crate::CrustEventSender<UID>,
+    peers: Vec<PeerInfo>,
+) {
+    for peer in peers.into_iter().take(MAX_SHARED_PEERS) {
+        if !is_worth_learning(core.user_data(), &unwrap!(config.lock()), &peer) {
+            continue;
+        }
+        if let Err(e) = ValidatePeer::start(core, poll, peer, config.clone(), event_tx.clone()) {
+            debug!("Failed to validate shared peer {:?}: {:?}", peer, e);
+        }
+    }
+}
+
+fn is_worth_learning(cache: &BootstrapCache, config: &ConfigWrapper, peer: &PeerInfo) -> bool {
+    let ip = peer.addr.ip();
+    if peer.addr.port() == 0
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || config.access_lists.is_blocked(&ip)
+    {
+        return false;
+    }
+
+    let cached = cache.peers();
+    cached.len() < MAX_CACHE_SIZE
+        && !cached.contains(peer)
+        && cached.iter().filter(|p| p.addr.ip() == ip).count() < MAX_CACHED_PEERS_PER_IP
+}
+
+/// Asks a shared peer to echo our address, which it can only do if it listens on the given
+/// address and holds the secret key matching the given public key.
+struct ValidatePeer<UID> {
+    token: Token,
+    socket: TcpSock,
+    timeout: Timeout,
+    request: Option<(Handshake, Priority)>,
+    our_protocol: ProtocolInfo,
+    peer: PeerInfo,
+    config: CrustConfig,
+    event_tx: crate::CrustEventSender<UID>,
+}
+
+impl<UID: Uid> ValidatePeer<UID> {
+    fn start(
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        peer: PeerInfo,
+        config: CrustConfig,
+        event_tx: crate::CrustEventSender<UID>,
+    ) -> crate::Res<()> {
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let our_protocol = ProtocolInfo::new(Capabilities::empty());
+        let request = Handshake::request(our_protocol, our_pk, &Message::EchoAddrReq::<UID>)?;
+        let mut socket = TcpSock::connect(&peer.addr)?;
+        socket.set_encrypt_ctx(EncryptContext::anonymous_encrypt(peer.pub_key))?;
+        let shared_key = our_sk.shared_secret(&peer.pub_key);
+        socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key))?;
+
+        let token = core.get_new_token();
+        poll.register(
+            &socket,
+            token,
+            Ready::writable() | Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        let timeout = core.set_timeout(
+            Duration::from_secs(VALIDATE_PEER_TIMEOUT_SEC),
+            CoreTimer::new(token, 0),
+        );
+
+        let state = ValidatePeer {
+            token,
+            socket,
+            timeout,
+            request: Some((request, 0)),
+            our_protocol,
+            peer,
+            config,
+            event_tx,
+        };
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(())
+    }
+
+    fn read(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let handshake = match self.socket.read::<Handshake>() {
+            Ok(Some(handshake)) => handshake,
+            Ok(None) => return,
+            Err(_) => return self.terminate(core, poll),
+        };
+        let msg = self
+            .our_protocol
+            .negotiate(&handshake.protocol)
+            .and_then(|protocol| handshake.message::<UID>(protocol.version).ok());
+        match msg {
+            Some(Message::EchoAddrResp(_)) => {
+                self.terminate(core, poll);
+                // Other validations might have filled the cache in the meantime.
+                if is_worth_learning(core.user_data(), &unwrap!(self.config.lock()), &self.peer) {
+                    trace!("Learnt new peer {:?}", self.peer);
+                    bootstrap::cache_peer_info(core, self.peer, &self.config);
+                    core.user_data().mark_verified(self.peer);
+                    let _ = self.event_tx.send(Event::PeerLearned(self.peer));
+                }
+            }
+            _ => self.terminate(core, poll),
+        }
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for ValidatePeer<UID> {
+    fn ready(&mut self, core: &mut EventLoopCore, poll: &Poll, kind: Ready) {
+        if kind.is_writable() {
+            let req = self.request.take();
+            if self.socket.write(req).is_err() {
+                return self.terminate(core, poll);
+            }
+        }
+        if kind.is_readable() {
+            self.read(core, poll)
+        }
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        let _ = core.cancel_timeout(&self.timeout);
+        let _ = core.remove_state(self.token);
+        let _ = poll.deregister(&self.socket);
+    }
+
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, _timer_id: u8) {
+        debug!("Shared peer {:?} did not respond in time.", self.peer);
+        self.terminate(core, poll)
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+    use crate::tests::utils::peer_info_with_rand_key;
+    use crate::Config;
+
+    #[test]
+    fn peers_on_crowded_ips_are_not_learnt() {
+        let cache = BootstrapCache::new(None);
+        let config = ConfigWrapper::new(Config::default());
+
+        let peer = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 4, 4000));
+        assert!(is_worth_learning(&cache, &config, &peer));
+        cache.put(peer);
+        assert!(!is_worth_learning(&cache, &config, &peer));
+
+        cache.put(peer_info_with_rand_key(ipv4_addr(1, 2, 3, 4, 4001)));
+        let peer = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 4, 4002));
+        assert!(!is_worth_learning(&cache, &config, &peer));
+
+        let peer = peer_info_with_rand_key(ipv4_addr(0, 0, 0, 0, 4000));
+        assert!(!is_worth_learning(&cache, &config, &peer));
+        let peer = peer_info_with_rand_key(ipv4_addr(1, 2, 3, 5, 0));
+        assert!(!is_worth_learning(&cache, &config, &peer));
+    }
+}
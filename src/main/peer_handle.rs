@@ -0,0 +1,169 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::common::{CoreMessage, Uid};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{
+    disconnect, ActiveConnection, ConnectionMap, CrustError, DisconnectReason, EventLoopCore,
+    RelayedConnection,
+};
+use mio::{Poll, Token};
+use mio_extras::channel::Sender;
+use socket_collection::Priority;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Handle to the connection with a peer announced by `Event::BootstrapAccept`,
+/// `Event::BootstrapConnect` or `Event::ConnectSuccess`.
+///
+/// The handle stays valid while the connection is moved onto another path, e.g. when a relayed
+/// session is upgraded or a standby connection takes over. Once the peer is lost, or the
+/// connection is replaced by a new one announced with a new handle, its methods fail with
+/// `CrustError::PeerNotFound` or `CrustError::ConnectionReplaced`.
+#[derive(Clone)]
+pub struct PeerHandle<UID: Uid> {
+    id: UID,
+    /// Generation of the connection we announced along with this handle, see `ConnectionId`.
+    generation: u64,
+    cm: ConnectionMap<UID>,
+    core_tx: Sender<CoreMessage<BootstrapCache>>,
+}
+
+impl<UID: Uid> PeerHandle<UID> {
+    #[doc(hidden)]
+    pub fn new(core: &EventLoopCore, id: UID, generation: u64, cm: ConnectionMap<UID>) -> Self {
+        Self {
+            id,
+            generation,
+            cm,
+            core_tx: core.sender().clone(),
+        }
+    }
+
+    /// Returns the peer's ID.
+    pub fn id(&self) -> UID {
+        self.id
+    }
+
+    /// Returns the generation of the connection. Handles to later connections with the same peer
+    /// have higher generations.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Send data to the peer.
+    pub fn send(&self, msg: Vec<u8>, priority: Priority) -> crate::Res<()> {
+        let token = self.active_connection()?;
+        self.post(move |core, poll| {
+            if let Some(state) = core.get_state(token) {
+                state.borrow_mut().write(core, poll, msg, priority);
+            }
+        })
+    }
+
+    /// Disconnects from the peer and returns whether this handle's connection was still up.
+    pub fn disconnect(&self) -> bool {
+        let tokens = match self.active_connection() {
+            Ok(_) => unwrap!(self.cm.lock())
+                .get(&self.id)
+                .map_or_else(Vec::new, |cid| cid.connections()),
+            Err(_) => return false,
+        };
+
+        let _ = self.post(move |core, poll| {
+            for token in tokens {
+                disconnect::<UID>(core, poll, token, DisconnectReason::Disconnected);
+            }
+        });
+
+        true
+    }
+
+    /// Returns traffic statistics of the connection. Fails with `CrustError::RelayedSession` while
+    /// the peer is reached through a relay.
+    pub fn stats(&self) -> crate::Res<ConnectionStats> {
+        self.with_active_connection(|active_connection| active_connection.stats())
+    }
+
+    /// Returns the peer's address of the connection. Fails with `CrustError::RelayedSession` while
+    /// the peer is reached through a relay.
+    pub fn peer_addr(&self) -> crate::Res<SocketAddr> {
+        self.with_active_connection(|active_connection| active_connection.peer_addr())?
+    }
+
+    /// Returns the token of the connection currently carrying this handle's one.
+    fn active_connection(&self) -> crate::Res<Token> {
+        match unwrap!(self.cm.lock()).get(&self.id) {
+            Some(cid) if cid.generation != self.generation => Err(CrustError::ConnectionReplaced),
+            Some(cid) => cid.active_connection.ok_or(CrustError::PeerNotFound),
+            None => Err(CrustError::PeerNotFound),
+        }
+    }
+
+    /// Runs the given function on the event loop against the connection and returns its result.
+    fn with_active_connection<T, F>(&self, f: F) -> crate::Res<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ActiveConnection<UID>) -> T + Send + 'static,
+    {
+        let token = self.active_connection()?;
+        let (tx, rx) = mpsc::channel();
+
+        self.post(move |core, _| {
+            let res = core.get_state(token).and_then(|state| {
+                let mut state = state.borrow_mut();
+                let any = state.as_any();
+                if any.is::<RelayedConnection<UID>>() {
+                    return Some(Err(()));
+                }
+                any.downcast_mut::<ActiveConnection<UID>>()
+                    .map(|active_connection| Ok(f(active_connection)))
+            });
+            let _ = tx.send(res);
+        })?;
+
+        match rx.recv() {
+            Ok(Some(Ok(res))) => Ok(res),
+            Ok(Some(Err(()))) => Err(CrustError::RelayedSession),
+            Ok(None) => Err(CrustError::PeerNotFound),
+            Err(e) => Err(CrustError::ChannelRecv(e)),
+        }
+    }
+
+    fn post<F>(&self, f: F) -> crate::Res<()>
+    where
+        F: FnOnce(&mut EventLoopCore, &Poll) + Send + 'static,
+    {
+        self.core_tx
+            .send(CoreMessage::new(f))
+            .map_err(|_| CrustError::CoreMsgTx)
+    }
+}
+
+impl<UID: Uid> fmt::Debug for PeerHandle<UID> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PeerHandle")
+            .field("id", &self.id)
+            .field("generation", &self.generation())
+            .finish()
+    }
+}
+
+/// Traffic statistics of a connection, see `PeerHandle::stats`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Data bytes sent and received so far.
+    pub bytes_transferred: u64,
+    /// How long ago data was last sent or received.
+    pub idle: Duration,
+    /// Number of messages held back by our upload limits.
+    pub pending_writes: usize,
+}
@@ -0,0 +1,741 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+mod relayed_connection;
+
+pub use self::relayed_connection::{Endpoint, RelayedConnection};
+
+use crate::common::{
+    Capabilities, CrustUser, Freshness, Identity, Message, RelayMessage, State, Uid,
+};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{
+    ActiveConnection, ConnectionMap, CrustConfig, Direction, EventLoopCore, EventToken,
+    RelayConfig, ReplayCache, REPLAY_CACHE_CAPACITY,
+};
+use mio::{Poll, Token};
+use socket_collection::Priority;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Maximum number of relays listed in our connection info.
+const MAX_RELAY_CANDIDATES: usize = 8;
+const DEFAULT_MAX_SESSIONS: usize = 32;
+const DEFAULT_MAX_SESSIONS_PER_PEER: usize = 4;
+const DEFAULT_MAX_BYTES_PER_SESSION: u64 = 64 * 1024 * 1024;
+/// Peers whose session ran out of its quota may not open another one for this long.
+const EXHAUSTED_SESSION_COOLDOWN_SECS: u64 = 10 * 60;
+
+/// Relays sessions between our peers and keeps track of the sessions we open through other relays.
+///
+/// Lives at a reserved token. Its methods never call into other states, so that those can call
+/// back into it.
+pub struct Relay<UID: Uid> {
+    token: Token,
+    endpoint: Endpoint<UID>,
+    /// Sessions we relay, keyed by the peer which opened them and the peer they were opened to,
+    /// along with the number of payload bytes relayed so far.
+    sessions: HashMap<(UID, UID), u64>,
+    /// Sessions which ran out of their quota, along with when that happened.
+    exhausted: HashMap<(UID, UID), Instant>,
+    /// Sessions we are opening through relays, keyed by relay and peer.
+    opening: HashMap<(UID, UID), Token>,
+    /// Sessions which gave way to direct connections but still deliver the messages on their way
+    /// through the relay, keyed by relay and peer.
+    retired: HashMap<(UID, UID), Token>,
+    /// Requests to open sessions with us seen lately, by the relay they came through, so that none
+    /// of them is accepted twice.
+    replay_cache: ReplayCache<UID>,
+}
+
+/// What the relay does with a message one peer sent to another.
+enum Route {
+    Forward,
+    Refuse,
+    /// The session ran out of its quota and was closed.
+    Exhausted,
+    Drop,
+}
+
+impl<UID: Uid> Relay<UID> {
+    pub fn start(
+        core: &mut EventLoopCore,
+        token: Token,
+        endpoint: Endpoint<UID>,
+    ) -> crate::Res<()> {
+        trace!("Entered state Relay");
+
+        let state = Rc::new(RefCell::new(Relay {
+            token,
+            endpoint,
+            sessions: HashMap::new(),
+            exhausted: HashMap::new(),
+            opening: HashMap::new(),
+            retired: HashMap::new(),
+            replay_cache: ReplayCache::new(REPLAY_CACHE_CAPACITY),
+        }));
+        let _ = core.insert_state(token, state);
+
+        Ok(())
+    }
+
+    fn route(
+        &mut self,
+        limits: &RelayConfig,
+        source: UID,
+        target: UID,
+        target_connected: bool,
+        msg: &RelayMessage,
+    ) -> Route {
+        let session = if self.sessions.contains_key(&(source, target)) {
+            Some((source, target))
+        } else if self.sessions.contains_key(&(target, source)) {
+            Some((target, source))
+        } else {
+            None
+        };
+
+        if !target_connected {
+            if let Some(session) = session {
+                let _ = self.sessions.remove(&session);
+            }
+            return Route::Refuse;
+        }
+
+        match (msg, session) {
+            (&RelayMessage::Connect(..), None) => {
+                if self.is_cooling_down(source, target) {
+                    return Route::Refuse;
+                }
+                let max_sessions = limits.max_sessions.unwrap_or(DEFAULT_MAX_SESSIONS);
+                let max_sessions_per_peer = limits
+                    .max_sessions_per_peer
+                    .unwrap_or(DEFAULT_MAX_SESSIONS_PER_PEER);
+                if self.sessions.len() >= max_sessions
+                    || self.sessions_of(source) >= max_sessions_per_peer
+                    || self.sessions_of(target) >= max_sessions_per_peer
+                {
+                    return Route::Refuse;
+                }
+                let _ = self.sessions.insert((source, target), 0);
+                self.consume_quota(limits, (source, target), msg.payload_len())
+            }
+            (&RelayMessage::Connect(..), Some(session))
+            | (&RelayMessage::Accept(..), Some(session))
+            | (&RelayMessage::Data(..), Some(session))
+            | (&RelayMessage::Upgrade(..), Some(session)) => {
+                self.consume_quota(limits, session, msg.payload_len())
+            }
+            (&RelayMessage::Upgraded, Some(_)) | (&RelayMessage::Heartbeat, Some(_)) => {
+                Route::Forward
+            }
+            (&RelayMessage::Close, Some(session)) => {
+                let _ = self.sessions.remove(&session);
+                Route::Forward
+            }
+            (&RelayMessage::Close, None) => Route::Drop,
+            (_, None) => Route::Refuse,
+        }
+    }
+
+    /// Number of sessions we relay from or to the peer.
+    fn sessions_of(&self, peer: UID) -> usize {
+        self.sessions
+            .keys()
+            .filter(|&&(from, to)| from == peer || to == peer)
+            .count()
+    }
+
+    /// Returns whether a session between the peers ran out of its quota lately.
+    fn is_cooling_down(&mut self, source: UID, target: UID) -> bool {
+        let cooldown = Duration::from_secs(EXHAUSTED_SESSION_COOLDOWN_SECS);
+        self.exhausted.retain(|_, at| at.elapsed() < cooldown);
+        self.exhausted.contains_key(&(source, target))
+            || self.exhausted.contains_key(&(target, source))
+    }
+
+    /// Accounts for payload relayed in the session. Closes it if it ran out of its quota.
+    fn consume_quota(&mut self, limits: &RelayConfig, session: (UID, UID), bytes: usize) -> Route {
+        let max_bytes = limits
+            .max_bytes_per_session
+            .unwrap_or(DEFAULT_MAX_BYTES_PER_SESSION);
+        let relayed = self.sessions.entry(session).or_insert(0);
+        *relayed += bytes as u64;
+        if *relayed > max_bytes {
+            let _ = self.sessions.remove(&session);
+            let _ = self.exhausted.insert(session, Instant::now());
+            return Route::Exhausted;
+        }
+        Route::Forward
+    }
+
+    /// Forgets the sessions we relayed for the lost peer and the ones we were opening or retiring
+    /// through it. Returns the other ends of the former and the tokens of the latter.
+    fn forget_peer(&mut self, lost: UID) -> (Vec<UID>, Vec<Token>) {
+        let mut peers = Vec::new();
+        self.sessions.retain(|&(from, to), _| {
+            if from == lost {
+                peers.push(to);
+            } else if to == lost {
+                peers.push(from);
+            } else {
+                return true;
+            }
+            false
+        });
+
+        let mut tokens = Vec::new();
+        for sessions in &mut [&mut self.opening, &mut self.retired] {
+            sessions.retain(|&(relay_id, _), token| {
+                if relay_id == lost {
+                    tokens.push(*token);
+                    return false;
+                }
+                true
+            });
+        }
+
+        (peers, tokens)
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for Relay<UID> {
+    fn terminate(&mut self, core: &mut EventLoopCore, _poll: &Poll) {
+        let _ = core.remove_state(self.token);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+/// Returns peers we could be reached through by peers we can't connect to directly.
+pub fn relay_candidates<UID: Uid>(core: &EventLoopCore, cm: &ConnectionMap<UID>) -> Vec<UID> {
+    let peers: Vec<UID> = unwrap!(cm.lock()).keys().cloned().collect();
+    peers
+        .into_iter()
+        .filter(
+            |peer| match connection_to(core, cm, *peer, Capabilities::RELAY) {
+                Some((_, CrustUser::Node)) => true,
+                _ => false,
+            },
+        )
+        .take(MAX_RELAY_CANDIDATES)
+        .collect()
+}
+
+/// Handles a message `source`, a peer of the given kind, asks us to relay to `target`. Returns the
+/// reply to send back to `source`, if any.
+pub fn handle_relay<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    config: &CrustConfig,
+    (source, source_kind): (UID, CrustUser),
+    target: UID,
+    msg: RelayMessage,
+) -> Option<Message<UID>> {
+    let target_token = if target == source {
+        None
+    } else {
+        connection_to(core, cm, target, Capabilities::RELAYED_SESSIONS).map(|(token, _)| token)
+    };
+    let limits = unwrap!(config.lock()).cfg.relay.clone();
+    let route = match limits {
+        Some(limits) => with_relay(core, |relay: &mut Relay<UID>| {
+            relay.route(&limits, source, target, target_token.is_some(), &msg)
+        })
+        .unwrap_or(Route::Refuse),
+        None => Route::Refuse,
+    };
+
+    match (route, target_token) {
+        (Route::Forward, Some(token)) => {
+            let priority = msg.priority();
+            let bytes = msg.payload_len();
+            let msg = Message::Relayed(source, source_kind, msg);
+            if write_to(core, poll, token, msg, priority, bytes) {
+                return None;
+            }
+            let _ = with_relay(core, |relay: &mut Relay<UID>| {
+                let _ = relay.sessions.remove(&(source, target));
+                let _ = relay.sessions.remove(&(target, source));
+            });
+            Some(Message::RelayClosed(target))
+        }
+        (Route::Exhausted, Some(token)) => {
+            debug!(
+                "Relayed session {:?} -> {:?} ran out of quota",
+                source, target
+            );
+            let _ = send(core, poll, token, Message::RelayClosed(source), 0);
+            Some(Message::RelayClosed(target))
+        }
+        (Route::Drop, _) => None,
+        _ => match msg {
+            RelayMessage::Close => None,
+            _ => Some(Message::RelayClosed(target)),
+        },
+    }
+}
+
+/// Handles a message `source`, a peer of the given kind to the relay, sent us through `relay_id`,
+/// a peer of the given kind to us. Returns the reply to send back through the relay, if any.
+pub fn handle_relayed<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    (relay_id, relay_kind): (UID, CrustUser),
+    (source, source_kind): (UID, CrustUser),
+    msg: RelayMessage,
+) -> Option<RelayMessage> {
+    let session = match msg {
+        RelayMessage::Connect(their_pk, request) => {
+            return RelayedConnection::accept(
+                core,
+                (relay_id, relay_kind),
+                (source, source_kind),
+                their_pk,
+                &request,
+            );
+        }
+        RelayMessage::Accept(..) => opening_session(core, relay_id, source),
+        RelayMessage::Close => {
+            handle_relay_closed(core, poll, cm, relay_id, source);
+            return None;
+        }
+        RelayMessage::Data(..)
+        | RelayMessage::Upgrade(..)
+        | RelayMessage::Upgraded
+        | RelayMessage::Heartbeat => session_with(core, cm, relay_id, source),
+    };
+    let session = match session.and_then(|token| core.get_state(token)) {
+        Some(session) => session,
+        None => return Some(RelayMessage::Close),
+    };
+
+    let mut session = session.borrow_mut();
+    let session = match session.as_any().downcast_mut::<RelayedConnection<UID>>() {
+        Some(session) => session,
+        None => return Some(RelayMessage::Close),
+    };
+    match msg {
+        RelayMessage::Accept(response) => session.handle_accept(core, poll, source_kind, &response),
+        msg => {
+            if !session.reset_receive_heartbeat(core, poll) {
+                return None;
+            }
+            match msg {
+                RelayMessage::Data(_, data) => session.receive(core, poll, &data),
+                RelayMessage::Upgrade(addrs) => session.handle_upgrade(core, poll, &addrs),
+                RelayMessage::Upgraded => session.handle_upgraded(core, poll),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Closes our session with `peer` through `relay_id`, as the peer or the relay ended it.
+pub fn handle_relay_closed<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    relay_id: UID,
+    peer: UID,
+) {
+    // We might have been refused a session we are opening.
+    let session =
+        session_with(core, cm, relay_id, peer).or_else(|| opening_session(core, relay_id, peer));
+    if let Some(token) = session {
+        close_session(core, poll, token);
+    }
+}
+
+/// Cleans up after losing the connection to a peer: tells the other ends of the sessions we relayed
+/// for it that they are closed and closes the sessions it relayed for us.
+pub fn handle_lost_peer<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    cm: &ConnectionMap<UID>,
+    lost: UID,
+) {
+    let (peers, mut sessions) =
+        with_relay(core, |relay: &mut Relay<UID>| relay.forget_peer(lost)).unwrap_or_default();
+
+    for peer in peers {
+        if let Some((token, _)) = connection_to(core, cm, peer, Capabilities::RELAYED_SESSIONS) {
+            let _ = send(core, poll, token, Message::RelayClosed(lost), 0);
+        }
+    }
+
+    let tokens: Vec<Token> = unwrap!(cm.lock())
+        .values()
+        .filter_map(|cid| cid.active_connection)
+        .collect();
+    sessions.extend(
+        tokens
+            .into_iter()
+            .filter(|token| is_relayed_by(core, *token, lost)),
+    );
+    for token in sessions {
+        close_session(core, poll, token);
+    }
+}
+
+/// Passes a message on to the peer we have a direct connection with under `token`. Returns false if
+/// that's not possible.
+pub fn send<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    token: Token,
+    msg: Message<UID>,
+    priority: Priority,
+) -> bool {
+    write_to(core, poll, token, msg, priority, 0)
+}
+
+/// Like `send`, accounting for `bytes` of relayed payload against the connection's upload limits.
+fn write_to<UID: Uid>(
+    core: &mut EventLoopCore,
+    poll: &Poll,
+    token: Token,
+    msg: Message<UID>,
+    priority: Priority,
+    bytes: usize,
+) -> bool {
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return false,
+    };
+    // The connection is busy if it's the one which called us. Those get their replies returned.
+    let mut state = match state.try_borrow_mut() {
+        Ok(state) => state,
+        Err(_) => return false,
+    };
+    match state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+        Some(ac) => {
+            ac.consume_bandwidth(Direction::Upload, bytes);
+            ac.send_message(core, poll, msg, priority)
+        }
+        None => false,
+    }
+}
+
+/// Returns the token and kind of our direct connection with the peer, provided the connection has
+/// the given relay capability: `RELAY` if the peer is to relay for us, `RELAYED_SESSIONS` if we
+/// relay for it.
+pub fn connection_to<UID: Uid>(
+    core: &EventLoopCore,
+    cm: &ConnectionMap<UID>,
+    peer: UID,
+    capability: Capabilities,
+) -> Option<(Token, CrustUser)> {
+    let token = unwrap!(cm.lock()).get(&peer)?.active_connection?;
+    let state = core.get_state(token)?;
+    let mut state = state.try_borrow_mut().ok()?;
+    let ac = state.as_any().downcast_mut::<ActiveConnection<UID>>()?;
+    if ac.protocol().capabilities.contains(capability) {
+        Some((token, ac.peer_kind()))
+    } else {
+        None
+    }
+}
+
+/// Returns what we need to open or accept relayed sessions.
+pub fn endpoint<UID: Uid>(core: &EventLoopCore) -> Option<Endpoint<UID>> {
+    with_relay(core, |relay: &mut Relay<UID>| relay.endpoint.clone())
+}
+
+/// Records the request to open a session with us, which came through the given relay. Returns
+/// false if it is stale or replayed, or the relay passed on too many requests lately.
+pub fn record_request<UID: Uid>(core: &EventLoopCore, freshness: Freshness, relay_id: UID) -> bool {
+    with_relay(core, |relay: &mut Relay<UID>| {
+        relay.replay_cache.record(freshness, relay_id)
+    })
+    .unwrap_or(false)
+}
+
+/// Makes relayed sessions opened or accepted from now on use the given identity.
+pub fn set_identity<UID: Uid>(core: &EventLoopCore, identity: Option<Arc<Identity<UID>>>) {
+    let _ = with_relay(core, |relay: &mut Relay<UID>| {
+        relay.endpoint.identity = identity;
+    });
+}
+
+/// Returns whether we are opening a session with the peer through any relay.
+pub fn is_opening_session<UID: Uid>(core: &EventLoopCore, peer: UID) -> bool {
+    with_relay(core, |relay: &mut Relay<UID>| {
+        relay.opening.keys().any(|&(_, their_id)| their_id == peer)
+    })
+    .unwrap_or(false)
+}
+
+pub fn register_opening_session<UID: Uid>(
+    core: &EventLoopCore,
+    relay_id: UID,
+    their_id: UID,
+    token: Token,
+) {
+    let _ = with_relay(core, |relay: &mut Relay<UID>| {
+        relay.opening.insert((relay_id, their_id), token)
+    });
+}
+
+pub fn unregister_opening_session<UID: Uid>(
+    core: &EventLoopCore,
+    relay_id: UID,
+    their_id: UID,
+    token: Token,
+) {
+    let _ = with_relay(core, |relay: &mut Relay<UID>| {
+        if relay.opening.get(&(relay_id, their_id)) == Some(&token) {
+            let _ = relay.opening.remove(&(relay_id, their_id));
+        }
+    });
+}
+
+pub fn register_retired_session<UID: Uid>(
+    core: &EventLoopCore,
+    relay_id: UID,
+    their_id: UID,
+    token: Token,
+) {
+    let _ = with_relay(core, |relay: &mut Relay<UID>| {
+        relay.retired.insert((relay_id, their_id), token)
+    });
+}
+
+pub fn unregister_retired_session<UID: Uid>(
+    core: &EventLoopCore,
+    relay_id: UID,
+    their_id: UID,
+    token: Token,
+) {
+    let _ = with_relay(core, |relay: &mut Relay<UID>| {
+        if relay.retired.get(&(relay_id, their_id)) == Some(&token) {
+            let _ = relay.retired.remove(&(relay_id, their_id));
+        }
+    });
+}
+
+/// Returns whether the connection under `token` is a relayed session a direct connection may take
+/// over.
+pub fn can_upgrade<UID: Uid>(core: &EventLoopCore, token: Token) -> bool {
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return false,
+    };
+    let mut state = match state.try_borrow_mut() {
+        Ok(state) => state,
+        Err(_) => return false,
+    };
+    state
+        .as_any()
+        .downcast_mut::<RelayedConnection<UID>>()
+        .map_or(false, |session| session.is_upgradable())
+}
+
+/// Retires the relayed session under `token` in favour of a direct connection which took its place
+/// in the connection map. Returns false if there is no such session.
+pub fn retire_session<UID: Uid>(core: &mut EventLoopCore, poll: &Poll, token: Token) -> bool {
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return false,
+    };
+    let mut state = state.borrow_mut();
+    match state.as_any().downcast_mut::<RelayedConnection<UID>>() {
+        Some(session) => {
+            session.retire(core, poll);
+            true
+        }
+        None => false,
+    }
+}
+
+fn opening_session<UID: Uid>(core: &EventLoopCore, relay_id: UID, their_id: UID) -> Option<Token> {
+    with_relay(core, |relay: &mut Relay<UID>| {
+        relay.opening.get(&(relay_id, their_id)).cloned()
+    })
+    .and_then(|token| token)
+}
+
+/// Returns the established or retired session with `their_id` through `relay_id`, if any.
+fn session_with<UID: Uid>(
+    core: &EventLoopCore,
+    cm: &ConnectionMap<UID>,
+    relay_id: UID,
+    their_id: UID,
+) -> Option<Token> {
+    let token = unwrap!(cm.lock())
+        .get(&their_id)
+        .and_then(|cid| cid.active_connection);
+    token
+        .filter(|token| is_relayed_by(core, *token, relay_id))
+        .or_else(|| retired_session(core, relay_id, their_id))
+}
+
+fn retired_session<UID: Uid>(core: &EventLoopCore, relay_id: UID, their_id: UID) -> Option<Token> {
+    with_relay(core, |relay: &mut Relay<UID>| {
+        relay.retired.get(&(relay_id, their_id)).cloned()
+    })
+    .and_then(|token| token)
+}
+
+fn is_relayed_by<UID: Uid>(core: &EventLoopCore, token: Token, relay_id: UID) -> bool {
+    let state = match core.get_state(token) {
+        Some(state) => state,
+        None => return false,
+    };
+    let mut state = match state.try_borrow_mut() {
+        Ok(state) => state,
+        Err(_) => return false,
+    };
+    state
+        .as_any()
+        .downcast_mut::<RelayedConnection<UID>>()
+        .map_or(false, |session| session.relay_id() == relay_id)
+}
+
+fn close_session<UID: Uid>(core: &mut EventLoopCore, poll: &Poll, token: Token) {
+    if let Some(state) = core.get_state(token) {
+        let mut state = state.borrow_mut();
+        if let Some(session) = state.as_any().downcast_mut::<RelayedConnection<UID>>() {
+            session.close(core, poll);
+        }
+    }
+}
+
+fn with_relay<UID, T, F>(core: &EventLoopCore, f: F) -> Option<T>
+where
+    UID: Uid,
+    F: FnOnce(&mut Relay<UID>) -> T,
+{
+    let state = core.get_state(EventToken::Relay.into())?;
+    let mut state = state.borrow_mut();
+    state.as_any().downcast_mut::<Relay<UID>>().map(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::main::{Config, ConfigWrapper};
+    use crate::tests::utils::{get_event_sender, rand_uid, UniqueId};
+    use safe_crypto::gen_encrypt_keypair;
+    use std::sync::Mutex;
+
+    fn relay() -> Relay<UniqueId> {
+        let (our_pk, our_sk) = gen_encrypt_keypair();
+        let (event_tx, _event_rx) = get_event_sender();
+        let endpoint = Endpoint {
+            our_id: rand_uid(),
+            our_pk,
+            our_sk,
+            name_hash: [0; 32],
+            identity: None,
+            our_listeners: Arc::new(Mutex::new(Vec::new())),
+            cm: Arc::new(Mutex::new(HashMap::new())),
+            config: Arc::new(Mutex::new(ConfigWrapper::new(Config::default()))),
+            event_tx,
+        };
+        Relay {
+            token: Token(0),
+            endpoint,
+            sessions: HashMap::new(),
+            exhausted: HashMap::new(),
+            opening: HashMap::new(),
+            retired: HashMap::new(),
+            replay_cache: ReplayCache::new(REPLAY_CACHE_CAPACITY),
+        }
+    }
+
+    fn connect(payload_len: usize) -> RelayMessage {
+        let (pk, _) = gen_encrypt_keypair();
+        RelayMessage::Connect(pk, vec![0; payload_len])
+    }
+
+    fn is_forwarded(route: Route) -> bool {
+        match route {
+            Route::Forward => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn connect_payloads_count_against_the_quota() {
+        let mut relay = relay();
+        let limits = RelayConfig {
+            max_bytes_per_session: Some(100),
+            ..Default::default()
+        };
+        let (a, b) = (rand_uid(), rand_uid());
+
+        assert!(is_forwarded(relay.route(&limits, a, b, true, &connect(60))));
+        match relay.route(&limits, a, b, true, &connect(60)) {
+            Route::Exhausted => (),
+            _ => panic!("Repeated connect requests got past the quota"),
+        }
+    }
+
+    #[test]
+    fn exhausted_peers_cannot_open_another_session() {
+        let mut relay = relay();
+        let limits = RelayConfig {
+            max_bytes_per_session: Some(100),
+            ..Default::default()
+        };
+        let (a, b) = (rand_uid(), rand_uid());
+
+        assert!(is_forwarded(relay.route(&limits, a, b, true, &connect(0))));
+        let data = RelayMessage::Data(0, vec![0; 200]);
+        match relay.route(&limits, b, a, true, &data) {
+            Route::Exhausted => (),
+            _ => panic!("Data got past the quota"),
+        }
+        assert!(!is_forwarded(relay.route(&limits, a, b, true, &connect(0))));
+        assert!(!is_forwarded(relay.route(&limits, b, a, true, &connect(0))));
+        assert!(is_forwarded(relay.route(
+            &limits,
+            a,
+            rand_uid(),
+            true,
+            &connect(0)
+        )));
+    }
+
+    #[test]
+    fn sessions_per_peer_are_capped_for_targets_too() {
+        let mut relay = relay();
+        let limits = RelayConfig {
+            max_sessions_per_peer: Some(2),
+            ..Default::default()
+        };
+        let target = rand_uid();
+
+        for _ in 0..2 {
+            assert!(is_forwarded(relay.route(
+                &limits,
+                rand_uid(),
+                target,
+                true,
+                &connect(0)
+            )));
+        }
+        assert!(!is_forwarded(relay.route(
+            &limits,
+            rand_uid(),
+            target,
+            true,
+            &connect(0)
+        )));
+    }
+}
@@ -0,0 +1,910 @@
+// Copyright 2018 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::{
+    connection_to, endpoint, is_opening_session, record_request, register_opening_session,
+    register_retired_session, send, unregister_opening_session, unregister_retired_session,
+};
+use crate::common::{
+    prove_identity, verify_identity, Capabilities, CoreTimer, CrustUser, Freshness, Handshake,
+    Identity, Message, NameHash, PeerInfo, ProtocolInfo, RelayMessage, State, Uid,
+};
+use crate::main::bootstrap::Cache as BootstrapCache;
+use crate::main::{
+    has_room, listener_port, queue_by_priority, Connect, ConnectionId, ConnectionMap, CrustConfig,
+    CrustError, Direction, DisconnectReason, Event, EventLoopCore, Heartbeat, HeartbeatAction,
+    PeerBandwidth, PeerHandle, RECV_HEARTBEAT_TIMER_ID, SEND_HEARTBEAT_TIMER_ID,
+};
+use crate::nat::punch_hole;
+use mio::{Poll, Token};
+use mio_extras::timer::Timeout;
+use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey, SharedSecretKey};
+use socket_collection::Priority;
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::hash_map::Entry;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const OPEN_TIMEOUT_SEC: u64 = 20;
+/// Established sessions offer the peer our listeners to connect to directly after this delay,
+/// doubling it after every offer up to `MAX_UPGRADE_DELAY_SEC`.
+#[cfg(not(test))]
+const FIRST_UPGRADE_DELAY_MS: u64 = 10_000;
+#[cfg(test)]
+const FIRST_UPGRADE_DELAY_MS: u64 = 500;
+const MAX_UPGRADE_DELAY_SEC: u64 = 10 * 60;
+/// Retired sessions wait this long for the peer to move onto the direct connection too.
+const RETIRE_TIMEOUT_SEC: u64 = 30;
+
+const OPEN_TIMER_ID: u8 = SEND_HEARTBEAT_TIMER_ID + 1;
+const UPGRADE_TIMER_ID: u8 = OPEN_TIMER_ID + 1;
+const RETIRE_TIMER_ID: u8 = UPGRADE_TIMER_ID + 1;
+const WRITE_THROTTLE_TIMER_ID: u8 = RETIRE_TIMER_ID + 1;
+
+/// Called once a session we open is established or fails.
+pub type Finish = Box<FnMut(&mut EventLoopCore, &Poll, Token, bool)>;
+
+/// What we need to know about ourselves to open or accept relayed sessions.
+#[derive(Clone)]
+pub struct Endpoint<UID: Uid> {
+    pub our_id: UID,
+    pub our_pk: PublicEncryptKey,
+    pub our_sk: SecretEncryptKey,
+    pub name_hash: NameHash,
+    pub identity: Option<Arc<Identity<UID>>>,
+    pub our_listeners: Arc<Mutex<Vec<PeerInfo>>>,
+    pub cm: ConnectionMap<UID>,
+    pub config: CrustConfig,
+    pub event_tx: crate::CrustEventSender<UID>,
+}
+
+/// Session we are opening, waiting for the peer to accept it.
+struct Opening<UID: Uid> {
+    static_key: SharedSecretKey,
+    our_eph_pk: PublicEncryptKey,
+    our_eph_sk: SecretEncryptKey,
+    name_hash: NameHash,
+    identity: Option<Arc<Identity<UID>>>,
+    timeout: Timeout,
+    finish: Finish,
+}
+
+/// Connection with a peer through a relay both of us are connected to. Stands in for an
+/// `ActiveConnection` once established, with data encrypted end to end with a session key derived
+/// from ephemeral keys of both ends.
+///
+/// Established sessions exchange heartbeats through the relay and are subject to the bandwidth
+/// limits like direct connections.
+///
+/// Both ends keep offering each other their listeners, so that a direct connection can take the
+/// session over. The session is then retired: it leaves the connection map, but delivers the
+/// messages still on their way through the relay until the peer retires its end too.
+pub struct RelayedConnection<UID: Uid> {
+    token: Token,
+    cm: ConnectionMap<UID>,
+    event_tx: crate::CrustEventSender<UID>,
+    our_listeners: Arc<Mutex<Vec<PeerInfo>>>,
+    relay_id: UID,
+    their_id: UID,
+    /// Kind of peer it is to the relay. Taken for a node until it accepts the session we open.
+    their_kind: CrustUser,
+    their_pk: PublicEncryptKey,
+    protocol: ProtocolInfo,
+    opening: Option<Opening<UID>>,
+    session_key: Option<SharedSecretKey>,
+    /// Running once the session is established and until it is retired.
+    heartbeat: Option<Heartbeat>,
+    config: CrustConfig,
+    bandwidth: PeerBandwidth,
+    pending_writes: VecDeque<(Vec<u8>, Priority)>,
+    write_throttle: Option<Timeout>,
+    /// Next upgrade offer while established, end of the grace period once retired.
+    timer: Option<Timeout>,
+    upgrade_delay: Duration,
+    retired: bool,
+    /// Whether the peer moved onto a direct connection already.
+    peer_upgraded: bool,
+    /// Why the session is being shut down, if known.
+    disconnect_reason: Option<DisconnectReason>,
+    /// When data was last sent or received.
+    last_activity: Instant,
+    /// Data bytes sent and received so far.
+    bytes_transferred: u64,
+}
+
+impl<UID: Uid> RelayedConnection<UID> {
+    /// Asks the peer through the relay to open a session with us.
+    pub fn open(
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        relay_id: UID,
+        their_id: UID,
+        their_pk: PublicEncryptKey,
+        finish: Finish,
+    ) -> crate::Res<Token> {
+        let endpoint = endpoint::<UID>(core).ok_or(CrustError::PeerNotFound)?;
+        let (relay_token, _) = connection_to(core, &endpoint.cm, relay_id, Capabilities::RELAY)
+            .ok_or(CrustError::PeerNotFound)?;
+
+        let (our_eph_pk, our_eph_sk) = gen_encrypt_keypair();
+        let static_key = endpoint.our_sk.shared_secret(&their_pk);
+        let our_protocol = unwrap!(endpoint.config.lock()).protocol_info();
+        let our_proof =
+            prove_identity(&endpoint.identity, &endpoint.our_id, &our_eph_pk, &their_pk);
+        let request = static_key.encrypt(&Handshake::request(
+            our_protocol,
+            endpoint.our_pk,
+            &Message::Connect(
+                Freshness::now(),
+                endpoint.our_id,
+                endpoint.name_hash,
+                our_eph_pk,
+                our_proof,
+            ),
+        )?)?;
+        let msg = Message::Relay(their_id, RelayMessage::Connect(endpoint.our_pk, request));
+        if !send(core, poll, relay_token, msg, 0) {
+            return Err(CrustError::PeerNotFound);
+        }
+
+        let token = core.get_new_token();
+        register_opening_session(core, relay_id, their_id, token);
+        {
+            let mut guard = unwrap!(endpoint.cm.lock());
+            guard
+                .entry(their_id)
+                .or_insert(ConnectionId {
+                    active_connection: None,
+                    currently_handshaking: 0,
+                    standby: None,
+                    generation: 0,
+                })
+                .currently_handshaking += 1;
+        }
+
+        let timeout = core.set_timeout(
+            Duration::from_secs(OPEN_TIMEOUT_SEC),
+            CoreTimer::new(token, OPEN_TIMER_ID),
+        );
+        let bandwidth =
+            PeerBandwidth::new(&unwrap!(endpoint.config.lock()).bandwidth, CrustUser::Node);
+        let state = RelayedConnection {
+            token,
+            cm: endpoint.cm,
+            event_tx: endpoint.event_tx,
+            our_listeners: endpoint.our_listeners,
+            relay_id,
+            their_id,
+            their_kind: CrustUser::Node,
+            their_pk,
+            protocol: our_protocol,
+            opening: Some(Opening {
+                static_key,
+                our_eph_pk,
+                our_eph_sk,
+                name_hash: endpoint.name_hash,
+                identity: endpoint.identity,
+                timeout,
+                finish,
+            }),
+            session_key: None,
+            heartbeat: None,
+            config: endpoint.config,
+            bandwidth,
+            pending_writes: VecDeque::new(),
+            write_throttle: None,
+            timer: None,
+            upgrade_delay: Duration::from_millis(FIRST_UPGRADE_DELAY_MS),
+            retired: false,
+            peer_upgraded: false,
+            disconnect_reason: None,
+            last_activity: Instant::now(),
+            bytes_transferred: 0,
+        };
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Ok(token)
+    }
+
+    /// Accepts the session the peer, of the given kind to the relay, asks to open through the
+    /// relay, a peer of the given kind to us. Returns the reply for the peer.
+    pub fn accept(
+        core: &mut EventLoopCore,
+        (relay_id, relay_kind): (UID, CrustUser),
+        (their_id, their_kind): (UID, CrustUser),
+        their_pk: PublicEncryptKey,
+        request: &[u8],
+    ) -> Option<RelayMessage> {
+        let endpoint = endpoint::<UID>(core)?;
+        // When both ends open a session at once, the one opened by the higher ID wins.
+        if endpoint.our_id > their_id && is_opening_session(core, their_id) {
+            return Some(RelayMessage::Close);
+        }
+        if is_connected(&endpoint.cm, their_id) {
+            return Some(RelayMessage::Close);
+        }
+
+        let static_key = endpoint.our_sk.shared_secret(&their_pk);
+        let handshake = match static_key.decrypt::<Handshake>(request) {
+            Ok(ref handshake) if handshake.pub_key != their_pk => {
+                debug!(
+                    "Relayed session request with mismatched key from {:?}",
+                    their_id
+                );
+                return Some(RelayMessage::Close);
+            }
+            Ok(handshake) => handshake,
+            Err(e) => {
+                debug!("Invalid relayed session request: {:?}", e);
+                return Some(RelayMessage::Close);
+            }
+        };
+        let our_protocol = unwrap!(endpoint.config.lock()).protocol_info();
+        let protocol = match our_protocol.negotiate(&handshake.protocol) {
+            Some(protocol) => protocol,
+            None => {
+                debug!(
+                    "Peer speaks an incompatible protocol: {:?}",
+                    handshake.protocol
+                );
+                return Some(RelayMessage::Close);
+            }
+        };
+        let their_eph_pk = match handshake.request_message::<UID>() {
+            Ok(Message::Connect(freshness, uid, name_hash, their_eph_pk, their_proof)) => {
+                if uid != their_id
+                    || name_hash != endpoint.name_hash
+                    || !verify_identity(
+                        &endpoint.identity,
+                        &their_proof,
+                        &uid,
+                        &their_eph_pk,
+                        &endpoint.our_pk,
+                    )
+                {
+                    debug!("Refusing relayed session with {:?}", their_id);
+                    return Some(RelayMessage::Close);
+                }
+                if !record_request(core, freshness, relay_id) {
+                    debug!(
+                        "Ignoring stale or replayed relayed session request: {:?}",
+                        freshness
+                    );
+                    return Some(RelayMessage::Close);
+                }
+                // The connection to the relay is busy passing the request on, so `has_room`
+                // can't see it.
+                let busy = if relay_kind == their_kind { 1 } else { 0 };
+                if !has_room(core, &endpoint.cm, &endpoint.config, their_kind, None, busy) {
+                    debug!(
+                        "No room for another {:?}. Refusing relayed session.",
+                        their_kind
+                    );
+                    return Some(RelayMessage::Close);
+                }
+                their_eph_pk
+            }
+            res => {
+                debug!("Invalid relayed session request: {:?}", res);
+                return Some(RelayMessage::Close);
+            }
+        };
+
+        let (our_eph_pk, our_eph_sk) = gen_encrypt_keypair();
+        let our_proof = prove_identity(
+            &endpoint.identity,
+            &endpoint.our_id,
+            &our_eph_pk,
+            &their_eph_pk,
+        );
+        let msg = Message::Connect(
+            Freshness::now(),
+            endpoint.our_id,
+            endpoint.name_hash,
+            our_eph_pk,
+            our_proof,
+        );
+        let response = Handshake::new(our_protocol, endpoint.our_pk, protocol.version, &msg)
+            .map_err(CrustError::from)
+            .and_then(|handshake| static_key.encrypt(&handshake).map_err(CrustError::from));
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("Failed to encrypt relayed session response: {:?}", e);
+                return Some(RelayMessage::Close);
+            }
+        };
+
+        let token = core.get_new_token();
+        let heartbeat = match Heartbeat::try_new(core, token) {
+            Ok(heartbeat) => heartbeat,
+            Err(e) => {
+                debug!("Failed to start relayed session heartbeat: {:?}", e);
+                return Some(RelayMessage::Close);
+            }
+        };
+        let bandwidth = PeerBandwidth::new(&unwrap!(endpoint.config.lock()).bandwidth, their_kind);
+        let mut state = RelayedConnection {
+            token,
+            cm: endpoint.cm,
+            event_tx: endpoint.event_tx,
+            our_listeners: endpoint.our_listeners,
+            relay_id,
+            their_id,
+            their_kind,
+            their_pk,
+            protocol,
+            opening: None,
+            session_key: Some(our_eph_sk.shared_secret(&their_eph_pk)),
+            heartbeat: Some(heartbeat),
+            config: endpoint.config,
+            bandwidth,
+            pending_writes: VecDeque::new(),
+            write_throttle: None,
+            timer: None,
+            upgrade_delay: Duration::from_millis(FIRST_UPGRADE_DELAY_MS),
+            retired: false,
+            peer_upgraded: false,
+            disconnect_reason: None,
+            last_activity: Instant::now(),
+            bytes_transferred: 0,
+        };
+        let generation = state.enter_connection_map();
+        state.schedule_upgrade_offer(core);
+        let handle = PeerHandle::new(core, their_id, generation, state.cm.clone());
+        let _ = state.event_tx.send(Event::ConnectSuccess(their_id, handle));
+        let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
+
+        Some(RelayMessage::Accept(response))
+    }
+
+    /// Completes opening the session once the peer, of the given kind to the relay, accepted it.
+    /// Returns the reply for the peer.
+    pub fn handle_accept(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        their_kind: CrustUser,
+        response: &[u8],
+    ) -> Option<RelayMessage> {
+        let session_key = match self.opening {
+            Some(ref opening) => match opening
+                .static_key
+                .decrypt::<Handshake>(response)
+                .ok()
+                .and_then(|handshake| {
+                    let protocol = self.protocol.negotiate(&handshake.protocol)?;
+                    Some((protocol, handshake.message::<UID>(protocol.version).ok()?))
+                }) {
+                Some((protocol, Message::Connect(_, uid, name_hash, their_eph_pk, proof))) => {
+                    if uid != self.their_id
+                        || name_hash != opening.name_hash
+                        || !verify_identity(
+                            &opening.identity,
+                            &proof,
+                            &uid,
+                            &their_eph_pk,
+                            &opening.our_eph_pk,
+                        )
+                        || is_connected(&self.cm, self.their_id)
+                    {
+                        None
+                    } else {
+                        self.protocol = protocol;
+                        Some(opening.our_eph_sk.shared_secret(&their_eph_pk))
+                    }
+                }
+                _ => None,
+            },
+            None => {
+                debug!(
+                    "Unexpected relayed session response from {:?}",
+                    self.their_id
+                );
+                return None;
+            }
+        };
+        let session_key = match session_key {
+            Some(session_key) => session_key,
+            None => {
+                debug!("Invalid relayed session response from {:?}", self.their_id);
+                self.close(core, poll);
+                return Some(RelayMessage::Close);
+            }
+        };
+        let heartbeat = match Heartbeat::try_new(core, self.token) {
+            Ok(heartbeat) => heartbeat,
+            Err(e) => {
+                debug!("Failed to start relayed session heartbeat: {:?}", e);
+                self.close(core, poll);
+                return Some(RelayMessage::Close);
+            }
+        };
+
+        let mut opening = unwrap!(self.opening.take());
+        let _ = core.cancel_timeout(&opening.timeout);
+        unregister_opening_session(core, self.relay_id, self.their_id, self.token);
+        self.session_key = Some(session_key);
+        self.heartbeat = Some(heartbeat);
+        self.their_kind = their_kind;
+        self.bandwidth = PeerBandwidth::new(&unwrap!(self.config.lock()).bandwidth, their_kind);
+        {
+            let mut guard = unwrap!(self.cm.lock());
+            if let Some(cid) = guard.get_mut(&self.their_id) {
+                cid.currently_handshaking -= 1;
+            }
+        }
+        let generation = self.enter_connection_map();
+        self.schedule_upgrade_offer(core);
+        let handle = PeerHandle::new(core, self.their_id, generation, self.cm.clone());
+        let _ = self
+            .event_tx
+            .send(Event::ConnectSuccess(self.their_id, handle));
+        let token = self.token;
+        (*opening.finish)(core, poll, token, true);
+        None
+    }
+
+    /// Handles data the peer sent us. Returns the reply for the peer, if any.
+    pub fn receive(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        data: &[u8],
+    ) -> Option<RelayMessage> {
+        self.consume_bandwidth(Direction::Download, data.len());
+        let data = match self.session_key.as_ref().map(|key| key.decrypt_bytes(data)) {
+            Some(Ok(data)) => data,
+            res => {
+                debug!("Failed to decrypt relayed data: {:?}", res);
+                self.close(core, poll);
+                return Some(RelayMessage::Close);
+            }
+        };
+        self.last_activity = Instant::now();
+        self.bytes_transferred += data.len() as u64;
+        let _ = self
+            .event_tx
+            .send(Event::NewMessage(self.their_id, self.their_kind, data));
+        None
+    }
+
+    /// Notes that the peer is alive. Returns false if the session was closed instead.
+    pub fn reset_receive_heartbeat(&mut self, core: &mut EventLoopCore, poll: &Poll) -> bool {
+        let res = match self.heartbeat {
+            Some(ref mut heartbeat) => heartbeat.reset_receive(core),
+            None => return true,
+        };
+        if let Err(e) = res {
+            debug!("Failed to reset heartbeat: {:?}", e);
+            self.disconnect(core, poll, DisconnectReason::LocalError(format!("{:?}", e)));
+            return false;
+        }
+        true
+    }
+
+    /// Tries to connect to the listeners the peer offered us. Returns the reply for the peer, if
+    /// any.
+    pub fn handle_upgrade(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        payload: &[u8],
+    ) -> Option<RelayMessage> {
+        if self.retired {
+            return None;
+        }
+        let addrs = match self
+            .session_key
+            .as_ref()
+            .map(|key| key.decrypt::<Vec<SocketAddr>>(payload))
+        {
+            Some(Ok(addrs)) => addrs,
+            res => {
+                debug!("Invalid upgrade offer from {:?}: {:?}", self.their_id, res);
+                return None;
+            }
+        };
+        // Don't pile up attempts while one is still under way.
+        let handshaking = unwrap!(self.cm.lock())
+            .get(&self.their_id)
+            .map_or(false, |cid| cid.currently_handshaking > 0);
+        if handshaking {
+            return None;
+        }
+        let endpoint = endpoint::<UID>(core)?;
+        let listener_port = listener_port::<UID>(core);
+        // The end with the higher ID punches holes in its NAT for the other end to connect through
+        // from the port of its listener. Both ends still connect straight to each other.
+        let punch = endpoint.our_id > self.their_id;
+        if punch {
+            if let Some(port) = listener_port {
+                for addr in &addrs {
+                    if let Err(e) = punch_hole(port, addr) {
+                        debug!("Failed to punch hole for {}: {:?}", addr, e);
+                    }
+                }
+            }
+        }
+        let local_port = if punch { None } else { listener_port };
+        if let Err(e) = Connect::upgrade(
+            core,
+            poll,
+            &endpoint,
+            self.their_id,
+            self.their_pk,
+            addrs,
+            local_port,
+        ) {
+            debug!("Failed to connect directly to {:?}: {:?}", self.their_id, e);
+        }
+        // Tell the peer to connect while the holes are open.
+        if punch && listener_port.is_some() {
+            self.upgrade_offer()
+        } else {
+            None
+        }
+    }
+
+    /// Notes that the peer moved onto a direct connection. Returns the reply for the peer, if any.
+    pub fn handle_upgraded(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+    ) -> Option<RelayMessage> {
+        if self.retired {
+            // Both ends moved on, so the relay can let go of the session.
+            self.shut_down(core, poll, false, false);
+            return Some(RelayMessage::Close);
+        }
+        self.peer_upgraded = true;
+        if let Some(timer) = self.timer.take() {
+            let _ = core.cancel_timeout(&timer);
+        }
+        None
+    }
+
+    /// Steps aside for a direct connection which took the session's place in the connection map.
+    pub fn retire(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        trace!("Relayed session with {:?} upgraded", self.their_id);
+        self.retired = true;
+        if let Some(timer) = self.timer.take() {
+            let _ = core.cancel_timeout(&timer);
+        }
+        // The direct connection keeps the peer alive from now on.
+        if let Some(mut heartbeat) = self.heartbeat.take() {
+            heartbeat.terminate(core);
+        }
+        if self.peer_upgraded {
+            return self.shut_down(core, poll, true, false);
+        }
+
+        let msg = Message::Relay(self.their_id, RelayMessage::Upgraded);
+        let _ = self.send_to_relay(core, poll, msg, 0);
+        register_retired_session(core, self.relay_id, self.their_id, self.token);
+        self.timer = Some(core.set_timeout(
+            Duration::from_secs(RETIRE_TIMEOUT_SEC),
+            CoreTimer::new(self.token, RETIRE_TIMER_ID),
+        ));
+    }
+
+    /// Returns whether a direct connection may take the session over.
+    pub fn is_upgradable(&self) -> bool {
+        self.opening.is_none()
+            && !self.retired
+            && self.protocol.capabilities.contains(Capabilities::UPGRADE)
+    }
+
+    /// The peer relaying this connection.
+    pub fn relay_id(&self) -> UID {
+        self.relay_id
+    }
+
+    /// Protocol version and capabilities negotiated with the peer.
+    pub fn protocol(&self) -> ProtocolInfo {
+        self.protocol
+    }
+
+    /// Kind of peer it is to the relay.
+    pub fn peer_kind(&self) -> CrustUser {
+        self.their_kind
+    }
+
+    /// When data was last sent or received.
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    /// Data bytes sent and received through the session so far.
+    pub fn bytes_transferred(&self) -> u64 {
+        self.bytes_transferred
+    }
+
+    /// Ends the session without telling the peer, e.g. because it closed it.
+    pub fn close(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.disconnect_reason = Some(DisconnectReason::RelayLost);
+        self.shut_down(core, poll, false, true)
+    }
+
+    /// Ends the session, telling the peer, and the user why if the peer is lost with it.
+    pub fn disconnect(&mut self, core: &mut EventLoopCore, poll: &Poll, reason: DisconnectReason) {
+        self.disconnect_reason = Some(reason);
+        self.shut_down(core, poll, true, false)
+    }
+
+    /// Ends the session, telling the peer through the relay if `notify_peer` is set. If we were
+    /// still opening it, reports the failure if `report` is set.
+    fn shut_down(
+        &mut self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        notify_peer: bool,
+        report: bool,
+    ) {
+        let _ = core.remove_state(self.token);
+        if let Some(timer) = self.timer.take() {
+            let _ = core.cancel_timeout(&timer);
+        }
+        if let Some(timer) = self.write_throttle.take() {
+            let _ = core.cancel_timeout(&timer);
+        }
+        if let Some(mut heartbeat) = self.heartbeat.take() {
+            heartbeat.terminate(core);
+        }
+        if self.retired {
+            unregister_retired_session(core, self.relay_id, self.their_id, self.token);
+        }
+        if notify_peer {
+            let msg = Message::Relay(self.their_id, RelayMessage::Close);
+            let _ = self.send_to_relay(core, poll, msg, 0);
+        }
+
+        let opening = self.opening.take();
+        {
+            let mut guard = unwrap!(self.cm.lock());
+            if let Entry::Occupied(mut oe) = guard.entry(self.their_id) {
+                if opening.is_some() {
+                    oe.get_mut().currently_handshaking -= 1;
+                } else if oe.get().active_connection == Some(self.token) {
+                    oe.get_mut().active_connection = None;
+                }
+                if oe.get().currently_handshaking == 0 && oe.get().active_connection.is_none() {
+                    let _ = oe.remove();
+                }
+            }
+        }
+
+        match opening {
+            Some(mut opening) => {
+                let _ = core.cancel_timeout(&opening.timeout);
+                unregister_opening_session(core, self.relay_id, self.their_id, self.token);
+                if report {
+                    let token = self.token;
+                    (*opening.finish)(core, poll, token, false);
+                }
+            }
+            // The direct connection which took a retired session over carries on.
+            None if self.retired => (),
+            None => {
+                let reason = self
+                    .disconnect_reason
+                    .take()
+                    .unwrap_or(DisconnectReason::Disconnected);
+                let _ = self.event_tx.send(Event::LostPeer(self.their_id, reason));
+            }
+        }
+    }
+
+    fn schedule_upgrade_offer(&mut self, core: &mut EventLoopCore) {
+        if !self.protocol.capabilities.contains(Capabilities::UPGRADE) {
+            return;
+        }
+        self.timer = Some(core.set_timeout(
+            self.upgrade_delay,
+            CoreTimer::new(self.token, UPGRADE_TIMER_ID),
+        ));
+    }
+
+    /// Offers the peer our listeners to connect to directly.
+    fn offer_upgrade(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.timer = None;
+        if let Some(offer) = self.upgrade_offer() {
+            let msg = Message::Relay(self.their_id, offer);
+            let _ = self.send_to_relay(core, poll, msg, 0);
+        }
+
+        self.upgrade_delay = cmp::min(
+            self.upgrade_delay * 2,
+            Duration::from_secs(MAX_UPGRADE_DELAY_SEC),
+        );
+        self.schedule_upgrade_offer(core);
+    }
+
+    /// Our listeners for the peer to connect to directly, unless we have none.
+    fn upgrade_offer(&self) -> Option<RelayMessage> {
+        let addrs: Vec<SocketAddr> = unwrap!(self.our_listeners.lock())
+            .iter()
+            .map(|listener| listener.addr)
+            .collect();
+        if addrs.is_empty() {
+            return None;
+        }
+        match self.session_key.as_ref().map(|key| key.encrypt(&addrs)) {
+            Some(Ok(payload)) => Some(RelayMessage::Upgrade(payload)),
+            res => {
+                debug!("Failed to encrypt upgrade offer: {:?}", res);
+                None
+            }
+        }
+    }
+
+    /// Returns the generation of the session announced to the user.
+    fn enter_connection_map(&self) -> u64 {
+        let mut guard = unwrap!(self.cm.lock());
+        let generation = {
+            let cid = guard.entry(self.their_id).or_insert(ConnectionId {
+                active_connection: None,
+                currently_handshaking: 0,
+                standby: None,
+                generation: 0,
+            });
+            cid.active_connection = Some(self.token);
+            cid.announce()
+        };
+        trace!(
+            "Connection Map inserted: {:?} -> {:?}",
+            self.their_id,
+            guard.get(&self.their_id)
+        );
+        generation
+    }
+
+    /// Sends data held back because of upload limits for as long as limits allow it.
+    fn flush_pending_writes(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        if self.write_throttle.is_some() {
+            return;
+        }
+
+        while !self.pending_writes.is_empty() {
+            let delay = {
+                let mut config = unwrap!(self.config.lock());
+                self.bandwidth
+                    .delay(&mut config.bandwidth, Direction::Upload)
+            };
+            if let Some(delay) = delay {
+                let timer = CoreTimer::new(self.token, WRITE_THROTTLE_TIMER_ID);
+                self.write_throttle = Some(core.set_timeout(delay, timer));
+                return;
+            }
+
+            let (data, priority) = unwrap!(self.pending_writes.pop_front());
+            let len = data.len() as u64;
+            let data = match self
+                .session_key
+                .as_ref()
+                .map(|key| key.encrypt_bytes(&data))
+            {
+                Some(Ok(data)) => data,
+                res => {
+                    debug!("Failed to encrypt relayed data: {:?}", res);
+                    continue;
+                }
+            };
+            self.consume_bandwidth(Direction::Upload, data.len());
+            let msg = Message::Relay(self.their_id, RelayMessage::Data(priority, data));
+            if !self.send_to_relay(core, poll, msg, priority) {
+                debug!("Lost relay {:?} to {:?}", self.relay_id, self.their_id);
+                return self.close(core, poll);
+            }
+            self.last_activity = Instant::now();
+            self.bytes_transferred += len;
+            // Only data actually sent spares us the heartbeat.
+            if let Some(ref mut heartbeat) = self.heartbeat {
+                let _ = heartbeat.reset_send(core);
+            }
+        }
+    }
+
+    fn consume_bandwidth(&mut self, direction: Direction, bytes: usize) {
+        let mut config = unwrap!(self.config.lock());
+        self.bandwidth
+            .consume(&mut config.bandwidth, direction, bytes);
+    }
+
+    fn send_to_relay(
+        &self,
+        core: &mut EventLoopCore,
+        poll: &Poll,
+        msg: Message<UID>,
+        priority: Priority,
+    ) -> bool {
+        match connection_to(core, &self.cm, self.relay_id, Capabilities::RELAY) {
+            Some((token, _)) => send(core, poll, token, msg, priority),
+            None => false,
+        }
+    }
+}
+
+impl<UID: Uid> State<BootstrapCache> for RelayedConnection<UID> {
+    fn write(&mut self, core: &mut EventLoopCore, poll: &Poll, data: Vec<u8>, priority: Priority) {
+        if self.session_key.is_none() {
+            return;
+        }
+        if !queue_by_priority(&mut self.pending_writes, data, priority) {
+            debug!(
+                "Too much data held back for {:?}, dropping some of it",
+                self.their_id
+            );
+        }
+        self.flush_pending_writes(core, poll);
+    }
+
+    fn terminate(&mut self, core: &mut EventLoopCore, poll: &Poll) {
+        self.shut_down(core, poll, true, false);
+    }
+
+    fn timeout(&mut self, core: &mut EventLoopCore, poll: &Poll, timer_id: u8) {
+        match timer_id {
+            RECV_HEARTBEAT_TIMER_ID | SEND_HEARTBEAT_TIMER_ID => {
+                let action = match self.heartbeat {
+                    Some(ref mut heartbeat) => heartbeat.timeout(core, timer_id),
+                    None => return,
+                };
+                match action {
+                    HeartbeatAction::Send => {
+                        let msg = Message::Relay(self.their_id, RelayMessage::Heartbeat);
+                        let _ = self.send_to_relay(core, poll, msg, 0);
+                    }
+                    HeartbeatAction::Terminate => {
+                        debug!(
+                            "Dropping relayed session with {:?} due to peer inactivity",
+                            self.their_id
+                        );
+                        self.disconnect(core, poll, DisconnectReason::Inactivity);
+                    }
+                }
+            }
+            WRITE_THROTTLE_TIMER_ID => {
+                self.write_throttle = None;
+                self.flush_pending_writes(core, poll);
+            }
+            UPGRADE_TIMER_ID => self.offer_upgrade(core, poll),
+            RETIRE_TIMER_ID => {
+                debug!(
+                    "{:?} did not retire its relayed session in time",
+                    self.their_id
+                );
+                self.shut_down(core, poll, true, false);
+            }
+            _ => {
+                debug!("Relayed session with {:?} timed out", self.their_id);
+                self.shut_down(core, poll, true, true);
+            }
+        }
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn is_connected<UID: Uid>(cm: &ConnectionMap<UID>, peer: UID) -> bool {
+    match unwrap!(cm.lock()).get(&peer) {
+        Some(&ConnectionId {
+            active_connection: Some(_),
+            ..
+        }) => true,
+        _ => false,
+    }
+}
@@ -8,17 +8,22 @@
 // Software.
 
 use crate::common::{
-    self, CoreMessage, CrustUser, ExternalReachability, NameHash, PeerInfo, Uid, HASH_SIZE,
+    self, unix_timestamp, CoreMessage, CrustUser, ExternalReachability, GoodbyeReason, Identity,
+    NameHash, PeerInfo, ProtocolInfo, Uid, HASH_SIZE,
 };
 use crate::main::bootstrap::Cache as BootstrapCache;
 use crate::main::config_handler::{self, Config};
+use crate::main::relay;
 use crate::main::{
-    ActiveConnection, Bootstrap, ConfigRefresher, ConfigWrapper, Connect, ConnectionId,
-    ConnectionInfoResult, ConnectionListener, ConnectionMap, CrustConfig, CrustError, Event,
-    EventLoop, EventLoopCore, PrivConnectionInfo, PubConnectionInfo,
+    add_echo_peers, disconnect, drop_disallowed_peers, external_addrs, AccessLists,
+    ActiveConnection, Bootstrap, BootstrapReport, ConfigRefresher, ConfigWrapper, Connect,
+    ConnectionId, ConnectionInfoResult, ConnectionListener, ConnectionMap, CrustConfig, CrustError,
+    DisconnectReason, DiscoveryMethod, Event, EventLoop, EventLoopCore, ExtAddrRefresher,
+    InterfaceWatcher, IpRange, ListenerStats, PrivConnectionInfo, PubConnectionInfo, RateLimits,
+    Relay, RelayedConnection,
 };
 use crate::nat::{MappedTcpSocket, MappingContext};
-use crate::service_discovery::ServiceDiscovery;
+use crate::service_discovery::{LanPeerChange, ServiceDiscovery};
 use mio::{Poll, Token};
 use safe_crypto::{self, gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey};
 use socket_collection::Priority;
@@ -26,16 +31,21 @@ use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// Reserved mio `Token` values for Crust speficic events.
 #[derive(Debug, PartialEq)]
 #[repr(usize)]
 #[allow(unused)]
-enum EventToken {
+pub enum EventToken {
     Bootstrap,
     ServiceDiscovery,
     Listener,
     ConfigRefresher,
+    Relay,
+    InterfaceWatcher,
+    ExtAddrRefresher,
     Unreserved,
 }
 
@@ -55,13 +65,14 @@ pub struct Service<UID: Uid> {
     config: CrustConfig,
     cm: ConnectionMap<UID>,
     event_tx: crate::CrustEventSender<UID>,
-    mc: Arc<MappingContext>,
+    mc: Arc<Mutex<MappingContext>>,
     el: EventLoop,
     name_hash: NameHash,
     our_uid: UID,
     our_listeners: Arc<Mutex<Vec<PeerInfo>>>,
     our_pk: PublicEncryptKey,
     our_sk: SecretEncryptKey,
+    identity: Option<Arc<Identity<UID>>>,
 }
 
 impl<UID: Uid> Service<UID> {
@@ -102,20 +113,30 @@ impl<UID: Uid> Service<UID> {
 
         // TODO(povilas): get from constructor params
         let (our_pk, our_sk) = gen_encrypt_keypair();
+        let mut config = ConfigWrapper::new(config);
+        match AccessLists::read_file(config.cfg.access_lists_file_name.as_ref()) {
+            Ok(Some(access_lists)) => config.access_lists = access_lists,
+            Ok(None) => (),
+            Err(e) => info!("Failed to read access lists file: {}", e),
+        }
         let service = Service {
             cm: Arc::new(Mutex::new(HashMap::new())),
-            config: Arc::new(Mutex::new(ConfigWrapper::new(config))),
+            config: Arc::new(Mutex::new(config)),
             event_tx,
-            mc: Arc::new(mc),
+            mc: Arc::new(Mutex::new(mc)),
             el,
             name_hash,
             our_uid,
             our_listeners,
             our_pk,
             our_sk,
+            identity: None,
         };
 
         service.start_config_refresher()?;
+        service.start_relay()?;
+        service.start_interface_watcher()?;
+        service.start_ext_addr_refresher()?;
 
         Ok(service)
     }
@@ -125,15 +146,73 @@ impl<UID: Uid> Service<UID> {
         let config = self.config.clone();
         let cm = self.cm.clone();
         self.post(move |core, _| {
-            if core.get_state(EventToken::ConfigRefresher.into()).is_none() {
-                let _ = tx.send(ConfigRefresher::start(
+            let res = if core.get_state(EventToken::ConfigRefresher.into()).is_none() {
+                ConfigRefresher::start(core, EventToken::ConfigRefresher.into(), cm, config)
+            } else {
+                Ok(())
+            };
+            let _ = tx.send(res);
+        })?;
+        rx.recv()?
+    }
+
+    fn start_relay(&self) -> crate::Res<()> {
+        let (tx, rx) = mpsc::channel();
+        let endpoint = relay::Endpoint {
+            our_id: self.our_uid,
+            our_pk: self.our_pk,
+            our_sk: self.our_sk.clone(),
+            name_hash: self.name_hash,
+            identity: self.identity.clone(),
+            our_listeners: self.our_listeners.clone(),
+            cm: self.cm.clone(),
+            config: self.config.clone(),
+            event_tx: self.event_tx.clone(),
+        };
+        self.post(move |core, _| {
+            let _ = tx.send(Relay::start(core, EventToken::Relay.into(), endpoint));
+        })?;
+        rx.recv()?
+    }
+
+    fn start_interface_watcher(&self) -> crate::Res<()> {
+        let (tx, rx) = mpsc::channel();
+        let mc = self.mc.clone();
+        let cm = self.cm.clone();
+        let config = self.config.clone();
+        self.post(move |core, _| {
+            let res = if core
+                .get_state(EventToken::InterfaceWatcher.into())
+                .is_none()
+            {
+                InterfaceWatcher::start(core, EventToken::InterfaceWatcher.into(), mc, cm, config)
+            } else {
+                Ok(())
+            };
+            let _ = tx.send(res);
+        })?;
+        rx.recv()?
+    }
+
+    fn start_ext_addr_refresher(&self) -> crate::Res<()> {
+        let (tx, rx) = mpsc::channel();
+        let mc = self.mc.clone();
+        let config = self.config.clone();
+        self.post(move |core, _| {
+            let res = if core
+                .get_state(EventToken::ExtAddrRefresher.into())
+                .is_none()
+            {
+                ExtAddrRefresher::<UID>::start(
                     core,
-                    EventToken::ConfigRefresher.into(),
-                    cm,
+                    EventToken::ExtAddrRefresher.into(),
+                    mc,
                     config,
-                ));
-            }
-            let _ = tx.send(Ok(()));
+                )
+            } else {
+                Ok(())
+            };
+            let _ = tx.send(res);
         })?;
         rx.recv()?
     }
@@ -164,8 +243,125 @@ impl<UID: Uid> Service<UID> {
         rx.recv()?
     }
 
+    /// Returns the counters of the TCP listener's flood protection.
+    pub fn listener_stats(&self) -> crate::Res<ListenerStats> {
+        let (tx, rx) = mpsc::channel();
+        let _ = self.post(move |core, _| {
+            let state = match core.get_state(EventToken::Listener.into()) {
+                Some(state) => state,
+                None => {
+                    let _ = tx.send(Err(CrustError::ListenerNotIntialised));
+                    return;
+                }
+            };
+            let mut state = state.borrow_mut();
+            match state.as_any().downcast_mut::<ConnectionListener<UID>>() {
+                Some(listener) => {
+                    let _ = tx.send(Ok(listener.stats()));
+                }
+                None => {
+                    warn!("Token reserved for ConnectionListener has something else.");
+                    let _ = tx.send(Err(CrustError::ListenerNotIntialised));
+                }
+            }
+        });
+
+        rx.recv()?
+    }
+
+    /// Changes bandwidth limits of all connections. New limits take effect immediately and stay
+    /// in effect until changed again or until `rate_limits` in the config file are modified.
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        unwrap!(self.config.lock()).bandwidth.set_limits(limits);
+    }
+
+    /// Returns bandwidth limits currently in effect.
+    pub fn rate_limits(&self) -> RateLimits {
+        unwrap!(self.config.lock()).bandwidth.limits().clone()
+    }
+
+    /// Allows peers of given kind within `range`, optionally for a limited time only. Once an
+    /// allowlist has an entry, peers of that kind outside of it are refused and disconnected.
+    ///
+    /// Like all access list changes, this takes effect immediately and stays in effect until
+    /// changed again or until `access_lists` in the config file are modified. Changes are
+    /// persisted to `access_lists_file_name`, so they survive a restart.
+    pub fn allow_ip_range(
+        &self,
+        peer_kind: CrustUser,
+        range: IpRange,
+        ttl: Option<Duration>,
+    ) -> crate::Res<()> {
+        let expires_at = expiry(ttl);
+        self.update_access_lists(move |lists| lists.allow(peer_kind, range, expires_at))
+    }
+
+    /// Removes `range` from the allowlist of given peer kind. Returns whether it was there.
+    pub fn remove_allowed_ip_range(
+        &self,
+        peer_kind: CrustUser,
+        range: &IpRange,
+    ) -> crate::Res<bool> {
+        self.update_access_lists(|lists| lists.remove_allowed(peer_kind, range))
+    }
+
+    /// Refuses and disconnects peers within `range`, optionally for a limited time only.
+    pub fn block_ip_range(&self, range: IpRange, ttl: Option<Duration>) -> crate::Res<()> {
+        let expires_at = expiry(ttl);
+        self.update_access_lists(move |lists| lists.block(range, expires_at))
+    }
+
+    /// Removes `range` from the blocklist. Returns whether it was there.
+    pub fn unblock_ip_range(&self, range: &IpRange) -> crate::Res<bool> {
+        self.update_access_lists(|lists| lists.unblock(range))
+    }
+
+    /// Replaces all access lists, e.g. with ones persisted from a previous run.
+    pub fn set_access_lists(&self, access_lists: AccessLists) -> crate::Res<()> {
+        self.update_access_lists(move |lists| *lists = access_lists)
+    }
+
+    /// Returns access lists currently in effect.
+    pub fn access_lists(&self) -> AccessLists {
+        let mut access_lists = unwrap!(self.config.lock()).access_lists.clone();
+        let _ = access_lists.remove_expired();
+        access_lists
+    }
+
+    fn update_access_lists<F, T>(&self, f: F) -> crate::Res<T>
+    where
+        F: FnOnce(&mut AccessLists) -> T,
+    {
+        let res = {
+            let mut config = unwrap!(self.config.lock());
+            let res = f(&mut config.access_lists);
+            if let Err(e) = config
+                .access_lists
+                .commit(config.cfg.access_lists_file_name.as_ref())
+            {
+                info!("Failed to write access lists file: {}", e);
+            }
+            res
+        };
+        let cm = self.cm.clone();
+        let config = self.config.clone();
+        self.post(move |core, poll| drop_disallowed_peers(core, poll, &cm, &config))?;
+        Ok(res)
+    }
+
+    /// Turns on identity mode. From now on we prove the ownership of our UID in every handshake
+    /// and only accept peers which do the same to the satisfaction of the identity's verifier.
+    ///
+    /// Only affects bootstraps, connects, listeners and relayed sessions started after this call.
+    pub fn set_identity(&mut self, identity: Identity<UID>) {
+        self.identity = Some(Arc::new(identity));
+        let identity = self.identity.clone();
+        let _ = self.post(move |core, _| relay::set_identity(core, identity));
+    }
+
     /// Initialises Service Discovery module and starts listening for responses to our beacon
-    /// broadcasts.
+    /// broadcasts. Peers found on the LAN are reported with `Event::LanPeerFound` and
+    /// `Event::LanPeerLost`, see also `lan_peers`.
     pub fn start_service_discovery(&mut self) {
         let our_listeners = self.our_listeners.clone();
         let remote_port = unwrap!(self.config.lock())
@@ -176,8 +372,15 @@ impl<UID: Uid> Service<UID> {
             .cfg
             .service_discovery_listener_port
             .unwrap_or(remote_port);
+        let method = unwrap!(self.config.lock())
+            .cfg
+            .service_discovery_method
+            .unwrap_or(DiscoveryMethod::Broadcast);
 
+        let name_hash = self.name_hash;
         let our_pk = self.our_pk;
+        let our_sk = self.our_sk.clone();
+        let event_tx = self.event_tx.clone();
         let _ = self.post(move |core, poll| {
             if core
                 .get_state(EventToken::ServiceDiscovery.into())
@@ -190,10 +393,29 @@ impl<UID: Uid> Service<UID> {
                     EventToken::ServiceDiscovery.into(),
                     listener_port,
                     remote_port,
+                    method,
+                    name_hash,
                     our_pk,
+                    our_sk,
                 ) {
                     debug!("Could not start ServiceDiscovery: {:?}", e);
+                    return;
                 }
+
+                let state = unwrap!(core.get_state(EventToken::ServiceDiscovery.into()));
+                let mut state = state.borrow_mut();
+                let service_discovery = unwrap!(state
+                    .as_any()
+                    .downcast_mut::<ServiceDiscovery<BootstrapCache>>());
+                service_discovery.register_lan_observer(Box::new(move |_, _, change| {
+                    let event = match *change {
+                        LanPeerChange::Found(ref listeners) => {
+                            Event::LanPeerFound(listeners.clone())
+                        }
+                        LanPeerChange::Lost(ref listeners) => Event::LanPeerLost(listeners.clone()),
+                    };
+                    event_tx.send(event).is_ok()
+                }));
             }
         });
     }
@@ -222,6 +444,21 @@ impl<UID: Uid> Service<UID> {
     }
 
     fn get_peer_socket_addr(&self, peer_uid: &UID) -> crate::Res<SocketAddr> {
+        self.with_active_connection(peer_uid, |active_connection| active_connection.peer_addr())?
+    }
+
+    /// Return the protocol version and capabilities negotiated with the peer.
+    pub fn peer_protocol(&self, peer_uid: &UID) -> crate::Res<ProtocolInfo> {
+        self.with_active_connection(peer_uid, |active_connection| active_connection.protocol())
+    }
+
+    /// Runs the given function on the event loop against the active connection to the peer and
+    /// returns its result.
+    fn with_active_connection<T, F>(&self, peer_uid: &UID, f: F) -> crate::Res<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut ActiveConnection<UID>) -> T + Send + 'static,
+    {
         let token = match unwrap!(self.cm.lock()).get(peer_uid) {
             Some(&ConnectionId {
                 active_connection: Some(token),
@@ -246,7 +483,7 @@ impl<UID: Uid> Service<UID> {
                 .downcast_mut::<ActiveConnection<UID>>()
             {
                 Some(active_connection) => {
-                    let _ = tx.send(Some(active_connection.peer_addr()));
+                    let _ = tx.send(Some(f(active_connection)));
                 }
                 None => {
                     debug!("Expected token {:?} to be ActiveConnection", token);
@@ -256,7 +493,32 @@ impl<UID: Uid> Service<UID> {
         });
 
         match rx.recv() {
-            Ok(Some(ip)) => ip,
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(CrustError::PeerNotFound),
+            Err(e) => Err(CrustError::ChannelRecv(e)),
+        }
+    }
+
+    /// Returns whether our connection to the peer is relayed through another node.
+    pub fn is_relayed(&self, peer_uid: &UID) -> crate::Res<bool> {
+        let token = match unwrap!(self.cm.lock()).get(peer_uid) {
+            Some(&ConnectionId {
+                active_connection: Some(token),
+                ..
+            }) => token,
+            _ => return Err(CrustError::PeerNotFound),
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let _ = self.post(move |core, _| {
+            let relayed = core
+                .get_state(token)
+                .map(|state| state.borrow_mut().as_any().is::<RelayedConnection<UID>>());
+            let _ = tx.send(relayed);
+        });
+
+        match rx.recv() {
+            Ok(Some(relayed)) => Ok(relayed),
             Ok(None) => Err(CrustError::PeerNotFound),
             Err(e) => Err(CrustError::ChannelRecv(e)),
         }
@@ -285,35 +547,35 @@ impl<UID: Uid> Service<UID> {
         }
     }
 
-    // TODO temp remove
     /// Check if we have peers on LAN
     pub fn has_peers_on_lan(&self) -> bool {
-        use std::thread;
-        use std::time::Duration;
+        self.lan_peers()
+            .map(|peers| !peers.is_empty())
+            .unwrap_or(false)
+    }
 
-        let (obs, rx) = mpsc::channel();
+    /// Returns the listeners of the peers service discovery currently knows on our LAN. Service
+    /// discovery keeps seeking peers periodically and forgets those which stop answering.
+    pub fn lan_peers(&self) -> crate::Res<Vec<PeerInfo>> {
+        let (tx, rx) = mpsc::channel();
         let _ = self.post(move |core, _| {
             let state = match core.get_state(EventToken::ServiceDiscovery.into()) {
                 Some(state) => state,
-                None => return,
-            };
-            let mut state = state.borrow_mut();
-            let service_discovery = match state
-                .as_any()
-                .downcast_mut::<ServiceDiscovery<BootstrapCache>>()
-            {
-                Some(sd) => sd,
                 None => {
-                    warn!("Token reserved for ServiceDiscovery has something else.");
+                    let _ = tx.send(None);
                     return;
                 }
             };
-            service_discovery.register_observer(obs);
-            let _ = service_discovery.seek_peers();
+            let mut state = state.borrow_mut();
+            let lan_peers = state
+                .as_any()
+                .downcast_mut::<ServiceDiscovery<BootstrapCache>>()
+                .map(|sd| sd.lan_peers());
+            let _ = tx.send(lan_peers);
         });
-
-        thread::sleep(Duration::from_secs(1));
-        rx.try_recv().is_ok()
+        rx.recv()
+            .map_err(CrustError::ChannelRecv)?
+            .ok_or(CrustError::ServiceDiscNotEnabled)
     }
 
     /// Start the bootstrapping procedure. It will auto terminate after indicating success or
@@ -328,6 +590,7 @@ impl<UID: Uid> Service<UID> {
         let name_hash = self.name_hash;
         let our_pk = self.our_pk;
         let our_sk = self.our_sk.clone();
+        let identity = self.identity.clone();
         let cm = self.cm.clone();
         let event_tx = self.event_tx.clone();
         let ext_reachability = match crust_user {
@@ -356,9 +619,17 @@ impl<UID: Uid> Service<UID> {
                     event_tx.clone(),
                     our_pk,
                     &our_sk,
+                    identity,
                 ) {
                     error!("Could not bootstrap: {:?}", e);
-                    let _ = event_tx.send(Event::BootstrapFailed);
+                    let report = BootstrapReport {
+                        service_discovery: core
+                            .get_state(EventToken::ServiceDiscovery.into())
+                            .is_some(),
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    };
+                    let _ = event_tx.send(Event::BootstrapFailed(report));
                 }
             }
         })
@@ -377,7 +648,7 @@ impl<UID: Uid> Service<UID> {
     /// explicitly.
     pub fn start_listening_tcp(&mut self) -> crate::Res<()> {
         let cm = self.cm.clone();
-        let mc = self.mc.clone();
+        let mut mc = unwrap!(self.mc.lock()).clone();
         let config = self.config.clone();
         let port = unwrap!(self.config.lock())
             .cfg
@@ -393,8 +664,10 @@ impl<UID: Uid> Service<UID> {
 
         let our_pk = self.our_pk;
         let our_sk = self.our_sk.clone();
+        let identity = self.identity.clone();
         self.post(move |core, poll| {
             if core.get_state(EventToken::Listener.into()).is_none() {
+                add_echo_peers(core, &mut mc);
                 ConnectionListener::start(
                     core,
                     poll,
@@ -405,12 +678,13 @@ impl<UID: Uid> Service<UID> {
                     name_hash,
                     cm,
                     config,
-                    mc,
+                    Arc::new(mc),
                     our_listeners,
                     EventToken::Listener.into(),
                     event_tx,
                     our_pk,
                     our_sk,
+                    identity,
                 );
             }
         })
@@ -452,16 +726,17 @@ impl<UID: Uid> Service<UID> {
             return Ok(());
         }
 
+        their_ci
+            .for_relay
+            .retain(|relay_id| *relay_id != self.our_uid);
         {
             let guard = unwrap!(self.config.lock());
-            if let Some(ref whitelisted_node_ips) = guard.cfg.whitelisted_node_ips {
-                let their_direct = their_ci
-                    .for_direct
-                    .drain(..)
-                    .filter(|s| whitelisted_node_ips.contains(&s.ip()))
-                    .collect();
-                their_ci.for_direct = their_direct;
-            }
+            let their_direct = their_ci
+                .for_direct
+                .drain(..)
+                .filter(|s| guard.is_ip_allowed(&s.ip(), CrustUser::Node))
+                .collect();
+            their_ci.for_direct = their_direct;
         }
 
         let event_tx = self.event_tx.clone();
@@ -470,10 +745,12 @@ impl<UID: Uid> Service<UID> {
         let our_pk = self.our_pk;
         let our_sk = self.our_sk.clone();
         let config = self.config.clone();
+        let identity = self.identity.clone();
 
         self.post(move |core, poll| {
             let _ = Connect::start(
                 core, poll, our_ci, their_ci, cm, our_nh, event_tx, our_pk, &our_sk, config,
+                identity,
             );
         })?;
 
@@ -482,23 +759,108 @@ impl<UID: Uid> Service<UID> {
 
     /// Disconnect from the given peer and returns whether there was a connection at all.
     pub fn disconnect(&self, peer_uid: &UID) -> bool {
-        let token = match unwrap!(self.cm.lock()).get(peer_uid) {
-            Some(&ConnectionId {
-                active_connection: Some(token),
-                ..
-            }) => token,
+        let tokens = match unwrap!(self.cm.lock()).get(peer_uid) {
+            Some(cid) if cid.active_connection.is_some() => cid.connections(),
             _ => return false,
         };
 
         let _ = self.post(move |core, poll| {
-            if let Some(state) = core.get_state(token) {
-                state.borrow_mut().terminate(core, poll);
+            for token in tokens {
+                disconnect::<UID>(core, poll, token, DisconnectReason::Disconnected);
             }
         });
 
         true
     }
 
+    /// Shuts the service down gracefully. Stops accepting connections, says goodbye to every
+    /// connected peer once the data we have yet to send it is out, and persists the bootstrap
+    /// cache. Connections not closed within `timeout` are dropped.
+    pub fn shutdown(self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let _ = self.post(move |core, poll| {
+            // Everything but established connections stops, so that no handshake completes
+            // while they are closed.
+            for token in core.tokens() {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                if state
+                    .as_any()
+                    .downcast_mut::<ActiveConnection<UID>>()
+                    .is_none()
+                {
+                    state.terminate(core, poll);
+                }
+            }
+        });
+
+        // Standby connections are closed first, so that closing the active ones doesn't fail
+        // over to them.
+        self.close_connections(|cid| cid.standby);
+        while Instant::now() < deadline && self.has_any_standby() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.close_connections(|cid| cid.active_connection);
+        while Instant::now() < deadline && self.is_connected_to_any() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let _ = self.post(move |core, _| {
+            if let Err(e) = core.user_data().commit() {
+                info!("Failed to write bootstrap cache to disk: {}", e);
+            }
+            let _ = tx.send(());
+        });
+        let _ = rx.recv();
+    }
+
+    /// Says goodbye on the given connection with each peer.
+    fn close_connections(&self, connection: fn(&ConnectionId) -> Option<Token>) {
+        let tokens: Vec<_> = unwrap!(self.cm.lock())
+            .values()
+            .filter_map(connection)
+            .collect();
+        let _ = self.post(move |core, poll| {
+            for token in tokens {
+                let state = match core.get_state(token) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                let mut state = state.borrow_mut();
+                if let Some(ac) = state.as_any().downcast_mut::<ActiveConnection<UID>>() {
+                    ac.close(core, poll, GoodbyeReason::Shutdown);
+                }
+            }
+        });
+    }
+
+    fn has_any_standby(&self) -> bool {
+        unwrap!(self.cm.lock())
+            .values()
+            .any(|cid| cid.standby.is_some())
+    }
+
+    fn is_connected_to_any(&self) -> bool {
+        unwrap!(self.cm.lock())
+            .values()
+            .any(|cid| !cid.connections().is_empty())
+    }
+
+    /// Returns whether a standby connection with the peer is ready to take over if the active one
+    /// fails. See `Config::standby_connections`.
+    pub fn has_standby_connection(&self, peer_uid: &UID) -> bool {
+        match unwrap!(self.cm.lock()).get(peer_uid) {
+            Some(&ConnectionId {
+                standby: Some(_), ..
+            }) => true,
+            _ => false,
+        }
+    }
+
     /// Send data to a peer.
     pub fn send(&self, peer_uid: &UID, msg: Vec<u8>, priority: Priority) -> crate::Res<()> {
         let token = match unwrap!(self.cm.lock()).get(peer_uid) {
@@ -527,22 +889,26 @@ impl<UID: Uid> Service<UID> {
             .collect();
         let our_pk = self.our_pk;
         let our_sk = self.our_sk.clone();
+        let our_uid = self.our_uid;
+        let cm = self.cm.clone();
+        let event_tx = self.event_tx.clone();
 
-        if DISABLE_NAT {
-            let event = Event::ConnectionInfoPrepared(ConnectionInfoResult {
-                result_token,
-                result: Ok(PrivConnectionInfo {
-                    id: self.our_uid,
-                    for_direct: our_listeners,
-                    our_pk,
-                }),
-            });
-            let _ = self.event_tx.send(event);
+        let res = if DISABLE_NAT {
+            self.post(move |core, _| {
+                let event = Event::ConnectionInfoPrepared(ConnectionInfoResult {
+                    result_token,
+                    result: Ok(PrivConnectionInfo {
+                        id: our_uid,
+                        for_direct: our_listeners,
+                        for_relay: relay::relay_candidates(core, &cm),
+                        our_pk,
+                    }),
+                });
+                let _ = event_tx.send(event);
+            })
         } else {
-            let event_tx = self.event_tx.clone();
-            let our_uid = self.our_uid;
-            let mc = self.mc.clone();
-            if let Err(e) = self.post(move |core, poll| {
+            let mc = unwrap!(self.mc.lock()).clone();
+            self.post(move |core, poll| {
                 let event_tx_clone = event_tx.clone();
                 match MappedTcpSocket::<_, UID, _>::start(
                     core,
@@ -551,13 +917,14 @@ impl<UID: Uid> Service<UID> {
                     &mc,
                     our_pk,
                     &our_sk,
-                    move |_, _, _socket, _addrs| {
+                    move |core, _, _socket, _addrs| {
                         let event_tx = event_tx_clone;
                         let event = Event::ConnectionInfoPrepared(ConnectionInfoResult {
                             result_token,
                             result: Ok(PrivConnectionInfo {
                                 id: our_uid,
                                 for_direct: our_listeners,
+                                for_relay: relay::relay_candidates(core, &cm),
                                 our_pk,
                             }),
                         });
@@ -574,14 +941,15 @@ impl<UID: Uid> Service<UID> {
                             }));
                     }
                 };
-            }) {
-                let _ = self
-                    .event_tx
-                    .send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
-                        result_token,
-                        result: Err(e),
-                    }));
-            }
+            })
+        };
+        if let Err(e) = res {
+            let _ = self
+                .event_tx
+                .send(Event::ConnectionInfoPrepared(ConnectionInfoResult {
+                    result_token,
+                    result: Err(e),
+                }));
         }
     }
 
@@ -606,6 +974,13 @@ impl<UID: Uid> Service<UID> {
         self.our_pk
     }
 
+    /// Returns our listener addresses reachable from the internet, as learnt from IGD gateways
+    /// and from peers echoing them. They are refreshed periodically and
+    /// `Event::ExternalAddrsChanged` is sent when they change.
+    pub fn our_external_addrs(&self) -> Vec<SocketAddr> {
+        external_addrs(&unwrap!(self.our_listeners.lock()))
+    }
+
     /// Returns a list of peers stored in bootstrap cache.
     pub fn bootstrap_cached_peers(&self) -> crate::Res<HashSet<PeerInfo>> {
         let (tx, rx) = mpsc::channel();
@@ -625,6 +1000,11 @@ impl<UID: Uid> Service<UID> {
     }
 }
 
+/// Returns the unix timestamp an access list entry living for `ttl` expires at.
+fn expiry(ttl: Option<Duration>) -> Option<u64> {
+    ttl.map(|ttl| unix_timestamp().saturating_add(ttl.as_secs()))
+}
+
 /// Returns a hash of the network name.
 fn name_hash(network_name: &Option<String>) -> NameHash {
     trace!("Network name: {:?}", network_name);
@@ -637,7 +1017,7 @@ fn name_hash(network_name: &Option<String>) -> NameHash {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::CrustUser;
+    use crate::common::{Compression, CrustUser, Message};
     use crate::main::{self, Event};
     use crate::tests::{get_event_sender, timebomb, UniqueId};
     use crate::CrustError;
@@ -699,6 +1079,44 @@ mod tests {
         })
     }
 
+    #[test]
+    fn compressed_data_is_rejected_unless_negotiated() {
+        timebomb(Duration::from_secs(30), || {
+            let (event_tx_0, event_rx_0) = get_event_sender();
+            let mut service_0 = unwrap!(Service::try_new(event_tx_0, rand::random()));
+
+            unwrap!(service_0.start_listening_tcp());
+            expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+            let (event_tx_1, event_rx_1) = get_event_sender();
+            let mut service_1 = unwrap!(Service::try_new(event_tx_1, rand::random()));
+
+            unwrap!(service_1.start_listening_tcp());
+            expect_event!(event_rx_1, Event::ListenerStarted(_));
+
+            connect(&service_0, &event_rx_0, &service_1, &event_rx_1);
+            let protocol = unwrap!(service_0.peer_protocol(&service_1.id()));
+            assert!(protocol.capabilities.compression().is_none());
+
+            let token = unwrap!(unwrap!(service_0.cm.lock())
+                .get(&service_1.id())
+                .and_then(|cid| cid.active_connection));
+            unwrap!(service_0.post(move |core, poll| {
+                let state = unwrap!(core.get_state(token));
+                let mut state = state.borrow_mut();
+                let active_connection =
+                    unwrap!(state.as_any().downcast_mut::<ActiveConnection<UniqueId>>());
+                let compressed = unwrap!(Compression::Lz4.compress(&[7; 4096]));
+                let msg = Message::CompressedData(Compression::Lz4, compressed);
+                let _ = active_connection.send_message(core, poll, msg, 0);
+            }));
+
+            expect_event!(event_rx_1, Event::LostPeer(id, DisconnectReason::ProtocolViolation) => {
+                assert_eq!(id, service_0.id());
+            });
+        })
+    }
+
     #[test]
     #[ignore]
     fn rendezvous_connect_two_peers() {
@@ -739,8 +1157,8 @@ mod tests {
         unwrap!(service_0.connect(priv_info_0, pub_info_1));
         unwrap!(service_1.connect(priv_info_1, pub_info_0));
 
-        expect_event!(event_rx_0, Event::ConnectSuccess(id) => assert_eq!(id, service_1.id()));
-        expect_event!(event_rx_1, Event::ConnectSuccess(id) => assert_eq!(id, service_0.id()));
+        expect_event!(event_rx_0, Event::ConnectSuccess(id, _) => assert_eq!(id, service_1.id()));
+        expect_event!(event_rx_1, Event::ConnectSuccess(id, _) => assert_eq!(id, service_0.id()));
     }
 
     fn exchange_messages(
@@ -860,7 +1278,7 @@ mod tests {
                     let mut their_ids = HashMap::new();
                     for _ in 0..NUM_SERVICES - 1 {
                         let their_id = match unwrap!(self.event_rx.recv()) {
-                            Event::ConnectSuccess(their_id) => their_id,
+                            Event::ConnectSuccess(their_id, _) => their_id,
                             m => panic!("Expected ConnectSuccess message. Got message {:?}", m),
                         };
                         if their_ids.insert(their_id, 0u32).is_some() {
@@ -7,12 +7,15 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{self, Core, Uid};
+use crate::common::{self, Capabilities, Core, CrustUser, ProtocolInfo, Uid};
 use crate::main::bootstrap::Cache as BootstrapCache;
-use crate::main::Config;
+use crate::main::{AccessLists, BandwidthLimiter, Config};
 use mio::Token;
 use safe_crypto::PublicEncryptKey;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static LAST_GENERATION: AtomicU64 = AtomicU64::new(0);
 
 // ========================================================================================
 //                                     ConnectionId
@@ -21,6 +24,30 @@ use std::net::SocketAddr;
 pub struct ConnectionId {
     pub active_connection: Option<Token>,
     pub currently_handshaking: usize,
+    /// Second connection, over a different path, which takes over if the active one fails.
+    pub standby: Option<Token>,
+    /// Generation of the connection we last announced to the user, which identifies valid
+    /// `PeerHandle`s. Zero until one is announced.
+    pub generation: u64,
+}
+
+impl ConnectionId {
+    /// Connections with the peer, the standby one first. Terminating them in this order drops the
+    /// peer instead of failing over.
+    pub fn connections(&self) -> Vec<Token> {
+        self.standby
+            .iter()
+            .chain(self.active_connection.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Moves on to the next generation, for a connection about to be announced to the user.
+    /// Generations keep growing when the peer reconnects, so stale handles never become valid.
+    pub fn announce(&mut self) -> u64 {
+        self.generation = LAST_GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+        self.generation
+    }
 }
 
 // ========================================================================================
@@ -46,6 +73,8 @@ pub struct PrivConnectionInfo<UID> {
     #[doc(hidden)]
     pub for_direct: Vec<SocketAddr>,
     #[doc(hidden)]
+    pub for_relay: Vec<UID>,
+    #[doc(hidden)]
     pub our_pk: PublicEncryptKey,
 }
 
@@ -55,6 +84,7 @@ impl<UID: Uid> PrivConnectionInfo<UID> {
     pub fn to_pub_connection_info(&self) -> PubConnectionInfo<UID> {
         PubConnectionInfo {
             for_direct: self.for_direct.clone(),
+            for_relay: self.for_relay.clone(),
             id: self.id,
             our_pk: self.our_pk,
         }
@@ -72,6 +102,8 @@ pub struct PubConnectionInfo<UID> {
     #[doc(hidden)]
     pub for_direct: Vec<SocketAddr>,
     #[doc(hidden)]
+    pub for_relay: Vec<UID>,
+    #[doc(hidden)]
     pub our_pk: PublicEncryptKey,
 }
 
@@ -89,13 +121,53 @@ impl<UID: Uid> PubConnectionInfo<UID> {
 pub struct ConfigWrapper {
     pub cfg: Config,
     pub is_modified_for_next_refresh: bool,
+    /// Bandwidth limits currently in effect. They start off from `cfg.rate_limits` but can be
+    /// changed at runtime independently of the config file.
+    pub bandwidth: BandwidthLimiter,
+    /// IP access lists currently in effect. Like `bandwidth`, they start off from
+    /// `cfg.access_lists` and can be changed at runtime.
+    pub access_lists: AccessLists,
 }
 impl ConfigWrapper {
     pub fn new(cfg: Config) -> Self {
+        let bandwidth = BandwidthLimiter::new(cfg.rate_limits.clone().unwrap_or_default());
+        let access_lists = cfg.access_lists.clone().unwrap_or_default();
         Self {
             cfg,
             is_modified_for_next_refresh: false,
+            bandwidth,
+            access_lists,
+        }
+    }
+
+    /// Returns `true` if a peer of given kind may be connected with on given IP, according to
+    /// both the whitelists and the access lists.
+    pub fn is_ip_allowed(&self, ip: &IpAddr, peer_kind: CrustUser) -> bool {
+        let whitelist = match peer_kind {
+            CrustUser::Node => &self.cfg.whitelisted_node_ips,
+            CrustUser::Client => &self.cfg.whitelisted_client_ips,
+        };
+        whitelist.as_ref().map_or(true, |ips| ips.contains(ip))
+            && self.access_lists.allows(ip, peer_kind)
+    }
+
+    /// Protocol version and capabilities we advertise to peers during handshake.
+    pub fn protocol_info(&self) -> ProtocolInfo {
+        let mut capabilities =
+            Capabilities::RELAYED_SESSIONS | Capabilities::UPGRADE | Capabilities::GOODBYE;
+        if self.cfg.relay.is_some() {
+            capabilities = capabilities | Capabilities::RELAY;
+        }
+        if self.cfg.standby_connections == Some(true) {
+            capabilities = capabilities | Capabilities::STANDBY;
+        }
+        if self.cfg.compression_threshold_bytes.is_some() {
+            capabilities = capabilities | Capabilities::LZ4_COMPRESSION;
+        }
+        if self.cfg.peer_exchange == Some(true) {
+            capabilities = capabilities | Capabilities::PEER_EXCHANGE;
         }
+        ProtocolInfo::new(capabilities)
     }
 
     pub fn check_for_update_and_mark_modified(&mut self, new_cfg: Config) {
@@ -7,7 +7,7 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::common::{Core, Message, PeerInfo, State, Uid};
+use crate::common::{Capabilities, Core, Handshake, Message, PeerInfo, ProtocolInfo, State, Uid};
 use crate::nat::{util, NatError};
 use mio::net::TcpStream;
 use mio::{Poll, PollOpt, Ready, Token};
@@ -23,7 +23,8 @@ pub type Finish<T> = Box<FnMut(&mut Core<T>, &Poll, Token, Result<SocketAddr, ()
 pub struct GetExtAddr<UID: Uid, T> {
     token: Token,
     socket: TcpSock,
-    request: Option<(Message<UID>, Priority)>,
+    request: Option<(Handshake, Priority)>,
+    our_protocol: ProtocolInfo,
     finish: Finish<T>,
 }
 
@@ -46,11 +47,15 @@ impl<UID: Uid, T: 'static> GetExtAddr<UID, T> {
         let shared_key = our_sk.shared_secret(&peer_stun.pub_key);
         socket.set_decrypt_ctx(DecryptContext::authenticated(shared_key))?;
 
+        let our_protocol = ProtocolInfo::new(Capabilities::empty());
+        let request = Handshake::request(our_protocol, our_pk, &Message::EchoAddrReq::<UID>)?;
+
         let token = core.get_new_token();
         let state = Self {
             token,
             socket,
-            request: Some((Message::EchoAddrReq(our_pk), 0)),
+            request: Some((request, 0)),
+            our_protocol,
             finish,
         };
 
@@ -66,21 +71,29 @@ impl<UID: Uid, T: 'static> GetExtAddr<UID, T> {
         Ok(token)
     }
 
-    fn write(&mut self, core: &mut Core<T>, poll: &Poll, msg: Option<(Message<UID>, Priority)>) {
+    fn write(&mut self, core: &mut Core<T>, poll: &Poll, msg: Option<(Handshake, Priority)>) {
         if self.socket.write(msg).is_err() {
             self.handle_error(core, poll);
         }
     }
 
     fn receive_response(&mut self, core: &mut Core<T>, poll: &Poll) {
-        match self.socket.read::<Message<UID>>() {
-            Ok(Some(Message::EchoAddrResp(ext_addr))) => {
+        let handshake = match self.socket.read::<Handshake>() {
+            Ok(Some(handshake)) => handshake,
+            Ok(None) => return,
+            Err(_) => return self.handle_error(core, poll),
+        };
+        let msg = self
+            .our_protocol
+            .negotiate(&handshake.protocol)
+            .and_then(|protocol| handshake.message::<UID>(protocol.version).ok());
+        match msg {
+            Some(Message::EchoAddrResp(ext_addr)) => {
                 self.terminate(core, poll);
                 let token = self.token;
                 (*self.finish)(core, poll, token, Ok(ext_addr))
             }
-            Ok(None) => (),
-            Ok(Some(_)) | Err(_) => self.handle_error(core, poll),
+            _ => self.handle_error(core, poll),
         }
     }
 
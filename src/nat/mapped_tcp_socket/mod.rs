@@ -18,7 +18,7 @@ use net2::TcpBuilder;
 use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
 use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::rc::Rc;
@@ -27,13 +27,23 @@ use std::time::Duration;
 mod get_ext_addr;
 
 const TIMEOUT_SEC: u64 = 3;
+/// Number of echo servers on distinct IPs which have to agree on an address of ours before we
+/// take it, so that a single lying server can't misdirect us. If they agree only on our IP, as
+/// they do behind a symmetric NAT, all the addresses with that IP are taken. Without any such
+/// agreement only the answers of the configured echo servers are taken.
+const MIN_VOTES: usize = 2;
 
 /// A state which represents the in-progress mapping of a tcp socket.
 pub struct MappedTcpSocket<F, UID, T> {
     token: Token,
     socket: Option<TcpBuilder>,
     igd_children: usize,
-    stun_children: HashSet<Token>,
+    /// Echo servers yet to answer, with their IPs.
+    stun_children: HashMap<Token, IpAddr>,
+    /// IPs of the echo servers which reported each address.
+    stun_votes: HashMap<SocketAddr, HashSet<IpAddr>>,
+    /// IPs of the configured echo servers, as opposed to peers asked to echo our address too.
+    configured_stuns: HashSet<IpAddr>,
     mapped_addrs: Vec<SocketAddr>,
     timeout: Timeout,
     finish: Option<F>,
@@ -107,7 +117,9 @@ where
             token,
             socket: Some(socket),
             igd_children,
-            stun_children: HashSet::with_capacity(mc.peer_stuns().len()),
+            stun_children: HashMap::with_capacity(mc.peer_stuns().len() + mc.echo_peers().len()),
+            stun_votes: HashMap::new(),
+            configured_stuns: mc.peer_stuns().iter().map(|stun| stun.addr.ip()).collect(),
             mapped_addrs,
             timeout: core.set_timeout(Duration::from_secs(TIMEOUT_SEC), CoreTimer::new(token, 0)),
             finish: Some(finish),
@@ -115,7 +127,7 @@ where
         }));
 
         // Ask Stuns
-        for stun in mc.peer_stuns() {
+        for stun in mc.peer_stuns().iter().chain(mc.echo_peers()) {
             let self_weak = Rc::downgrade(&state);
             let handler = move |core: &mut Core<T>, poll: &Poll, child_token, res| {
                 if let Some(self_rc) = self_weak.upgrade() {
@@ -134,7 +146,10 @@ where
                 our_sk,
                 Box::new(handler),
             ) {
-                let _ = state.borrow_mut().stun_children.insert(child);
+                let _ = state
+                    .borrow_mut()
+                    .stun_children
+                    .insert(child, stun.addr.ip());
             }
         }
 
@@ -155,9 +170,13 @@ where
         child: Token,
         res: Result<SocketAddr, ()>,
     ) {
-        let _ = self.stun_children.remove(&child);
-        if let Ok(our_ext_addr) = res {
-            self.mapped_addrs.push(our_ext_addr);
+        let stun_ip = self.stun_children.remove(&child);
+        if let (Ok(our_ext_addr), Some(stun_ip)) = (res, stun_ip) {
+            let _ = self
+                .stun_votes
+                .entry(our_ext_addr)
+                .or_insert_with(HashSet::new)
+                .insert(stun_ip);
         }
         if self.stun_children.is_empty() && self.igd_children == 0 {
             self.terminate(core, poll);
@@ -173,7 +192,7 @@ where
     }
 
     fn terminate_children(&mut self, core: &mut Core<T>, poll: &Poll) {
-        for token in self.stun_children.drain() {
+        for (token, _) in self.stun_children.drain() {
             let child = match core.get_state(token) {
                 Some(state) => state,
                 None => continue,
@@ -199,7 +218,8 @@ where
         let _ = core.cancel_timeout(&self.timeout);
 
         let socket = unwrap!(self.socket.take());
-        let mapped_addrs = self.mapped_addrs.drain(..).collect();
+        let mut mapped_addrs: Vec<_> = self.mapped_addrs.drain(..).collect();
+        mapped_addrs.extend(elect(&self.stun_votes, &self.configured_stuns));
         (unwrap!(self.finish.take()))(core, poll, socket, mapped_addrs);
     }
 
@@ -207,3 +227,130 @@ where
         self
     }
 }
+
+/// Returns the addresses enough echo servers agree on, see `MIN_VOTES`. Reports the ones left out
+/// with the servers which sent them.
+fn elect(
+    votes: &HashMap<SocketAddr, HashSet<IpAddr>>,
+    configured: &HashSet<IpAddr>,
+) -> Vec<SocketAddr> {
+    let mut ip_votes: HashMap<IpAddr, HashSet<IpAddr>> = HashMap::new();
+    for (addr, voters) in votes {
+        ip_votes
+            .entry(addr.ip())
+            .or_insert_with(HashSet::new)
+            .extend(voters);
+    }
+
+    let mut elected = Vec::new();
+    for (ip, voters) in &ip_votes {
+        if voters.len() < MIN_VOTES {
+            continue;
+        }
+        let candidates: Vec<_> = votes.iter().filter(|&(addr, _)| addr.ip() == *ip).collect();
+        let agreed: Vec<_> = candidates
+            .iter()
+            .filter(|&&(_, voters)| voters.len() >= MIN_VOTES)
+            .map(|&(addr, _)| *addr)
+            .collect();
+        if agreed.is_empty() {
+            elected.extend(candidates.iter().map(|&(addr, _)| *addr));
+        } else {
+            elected.extend(agreed);
+        }
+    }
+    if elected.is_empty() {
+        elected = votes
+            .iter()
+            .filter(|&(_, voters)| !voters.is_disjoint(configured))
+            .map(|(addr, _)| *addr)
+            .collect();
+    }
+
+    for (addr, voters) in votes {
+        if !elected.contains(addr) {
+            info!(
+                "Ignoring {} as our address, as only echo servers on {:?} reported it",
+                addr, voters
+            );
+        }
+    }
+    elected.sort();
+    elected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ipv4_addr;
+    use std::iter;
+
+    fn vote(votes: &mut HashMap<SocketAddr, HashSet<IpAddr>>, addr: SocketAddr, voter: SocketAddr) {
+        let _ = votes
+            .entry(addr)
+            .or_insert_with(HashSet::new)
+            .insert(voter.ip());
+    }
+
+    #[test]
+    fn single_lying_echo_server_is_outvoted() {
+        let honest = ipv4_addr(1, 2, 3, 4, 5000);
+        let lie = ipv4_addr(6, 6, 6, 6, 5000);
+        let mut votes = HashMap::new();
+        let no_configured = HashSet::new();
+
+        // A sole peer isn't trusted.
+        vote(&mut votes, honest, ipv4_addr(10, 0, 0, 1, 1));
+        assert!(elect(&votes, &no_configured).is_empty());
+
+        vote(&mut votes, lie, ipv4_addr(10, 0, 0, 2, 1));
+        assert!(elect(&votes, &no_configured).is_empty());
+
+        // Without agreement, the configured echo server is trusted.
+        let configured = iter::once(ipv4_addr(10, 0, 0, 1, 1).ip()).collect();
+        assert_eq!(elect(&votes, &configured), vec![honest]);
+
+        vote(&mut votes, honest, ipv4_addr(10, 0, 0, 3, 1));
+        assert_eq!(elect(&votes, &no_configured), vec![honest]);
+
+        // Servers sharing an IP vote once.
+        vote(&mut votes, lie, ipv4_addr(10, 0, 0, 2, 2));
+        assert_eq!(elect(&votes, &no_configured), vec![honest]);
+    }
+
+    #[test]
+    fn echo_servers_behind_symmetric_nat_agree_on_ip() {
+        let mut votes = HashMap::new();
+        vote(
+            &mut votes,
+            ipv4_addr(1, 2, 3, 4, 5000),
+            ipv4_addr(10, 0, 0, 1, 1),
+        );
+        vote(
+            &mut votes,
+            ipv4_addr(1, 2, 3, 4, 5001),
+            ipv4_addr(10, 0, 0, 2, 1),
+        );
+        vote(
+            &mut votes,
+            ipv4_addr(6, 6, 6, 6, 5002),
+            ipv4_addr(10, 0, 0, 3, 1),
+        );
+
+        assert_eq!(
+            elect(&votes, &HashSet::new()),
+            vec![ipv4_addr(1, 2, 3, 4, 5000), ipv4_addr(1, 2, 3, 4, 5001)]
+        );
+
+        // Ports agreed on win over the others.
+        vote(
+            &mut votes,
+            ipv4_addr(1, 2, 3, 4, 5001),
+            ipv4_addr(10, 0, 0, 3, 1),
+        );
+        assert_eq!(
+            elect(&votes, &HashSet::new()),
+            vec![ipv4_addr(1, 2, 3, 4, 5001)]
+        );
+    }
+}
@@ -15,7 +15,7 @@ use crate::nat;
 use crossbeam;
 use get_if_addrs::{self, IfAddr};
 use igd::{self, Gateway};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::Duration;
 
 /// Keeps track of information about external mapping servers
@@ -24,17 +24,17 @@ pub struct MappingContext {
     our_ifv4s: Vec<(Ipv4Addr, Option<Gateway>)>,
     our_ifv6s: Vec<Ipv6Addr>,
     peer_stuns: Vec<PeerInfo>,
+    echo_peers: Vec<PeerInfo>,
 }
 
 impl MappingContext {
     /// Create a new `MappingContext`
     pub fn try_new() -> Result<Self, NatError> {
-        let ifs = get_if_addrs::get_if_addrs()?;
         let (mut ifv4s, mut ifv6s) = (Vec::with_capacity(5), Vec::with_capacity(5));
-        for interface in ifs {
-            match interface.addr {
-                IfAddr::V4(v4_addr) => ifv4s.push((v4_addr.ip, None)),
-                IfAddr::V6(v6_addr) => ifv6s.push(v6_addr.ip),
+        for ip in interface_ips()? {
+            match ip {
+                IpAddr::V4(ip) => ifv4s.push((ip, None)),
+                IpAddr::V6(ip) => ifv6s.push(ip),
             }
         }
 
@@ -54,6 +54,7 @@ impl MappingContext {
             our_ifv4s: ifv4s,
             our_ifv6s: ifv6s,
             peer_stuns: Vec::with_capacity(10),
+            echo_peers: vec![],
         })
     }
 
@@ -66,6 +67,15 @@ impl MappingContext {
         self.peer_stuns.extend(listeners);
     }
 
+    /// Inform the context about peers which may echo our address too. Unlike the configured
+    /// servers, what they report is only taken if others confirm it.
+    pub fn add_echo_peers<A: IntoIterator<Item = PeerInfo>>(&mut self, peers: A) {
+        let peers = peers
+            .into_iter()
+            .filter(|peer| nat::ip_addr_is_global(&peer.addr.ip()));
+        self.echo_peers.extend(peers);
+    }
+
     /// Get v4 interfaces
     pub fn ifv4s(&self) -> &Vec<(Ipv4Addr, Option<Gateway>)> {
         &self.our_ifv4s
@@ -75,6 +85,48 @@ impl MappingContext {
     pub fn peer_stuns(&self) -> &Vec<PeerInfo> {
         &self.peer_stuns
     }
+
+    /// Iterate over the peers added by `add_echo_peers`
+    pub fn echo_peers(&self) -> &Vec<PeerInfo> {
+        &self.echo_peers
+    }
+
+    /// IPs of the interfaces this context was created with, sorted.
+    pub fn ips(&self) -> Vec<IpAddr> {
+        let mut ips: Vec<_> = self
+            .our_ifv4s
+            .iter()
+            .map(|&(ip, _)| IpAddr::V4(ip))
+            .chain(self.our_ifv6s.iter().map(|&ip| IpAddr::V6(ip)))
+            .collect();
+        ips.sort();
+        ips
+    }
+
+    /// Context without any interfaces or servers.
+    #[cfg(test)]
+    pub fn empty() -> Self {
+        MappingContext {
+            our_ifv4s: vec![],
+            our_ifv6s: vec![],
+            peer_stuns: vec![],
+            echo_peers: vec![],
+        }
+    }
+}
+
+/// IPs of our network interfaces, sorted. Unlike `MappingContext::try_new` this doesn't look for
+/// IGD gateways, so it's cheap enough to poll for interface changes.
+pub fn interface_ips() -> Result<Vec<IpAddr>, NatError> {
+    let mut ips: Vec<_> = get_if_addrs::get_if_addrs()?
+        .into_iter()
+        .map(|interface| match interface.addr {
+            IfAddr::V4(v4_addr) => IpAddr::V4(v4_addr.ip),
+            IfAddr::V6(v6_addr) => IpAddr::V6(v6_addr.ip),
+        })
+        .collect();
+    ips.sort();
+    Ok(ips)
 }
 
 #[cfg(test)]
@@ -9,8 +9,8 @@
 
 pub use self::error::NatError;
 pub use self::mapped_tcp_socket::MappedTcpSocket;
-pub use self::mapping_context::MappingContext;
-pub use self::util::ip_addr_is_global;
+pub use self::mapping_context::{interface_ips, MappingContext};
+pub use self::util::{connect_from_port, ip_addr_is_global, punch_hole};
 
 mod error;
 mod mapped_tcp_socket;
@@ -7,10 +7,15 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use mio::net::TcpStream;
 use net2::TcpBuilder;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
+/// Time to live of the packets which open holes in our NAT. Low enough for them to expire before
+/// they reach the peer, whose NAT might otherwise answer with a reset and close the hole again.
+const HOLE_PUNCH_TTL: u32 = 4;
+
 pub fn new_reusably_bound_tcp_socket(local_addr: &SocketAddr) -> io::Result<TcpBuilder> {
     let socket = match local_addr.ip() {
         IpAddr::V4(..) => TcpBuilder::new_v4()?,
@@ -23,6 +28,32 @@ pub fn new_reusably_bound_tcp_socket(local_addr: &SocketAddr) -> io::Result<TcpB
     Ok(socket)
 }
 
+/// Connects to `addr` from the given local port, which may be the port of our listener. The peer then
+/// sees us at the address our NAT maps the listener to.
+pub fn connect_from_port(port: u16, addr: &SocketAddr) -> io::Result<TcpStream> {
+    let socket = new_reusably_bound_tcp_socket(&unspecified_addr(port, addr))?;
+    TcpStream::connect_stream(socket.to_tcp_stream()?, addr)
+}
+
+/// Makes our NAT let connection attempts from `addr` to the given local port through, by sending a
+/// connection request which expires on the way to `addr`.
+pub fn punch_hole(port: u16, addr: &SocketAddr) -> io::Result<()> {
+    let socket = new_reusably_bound_tcp_socket(&unspecified_addr(port, addr))?;
+    socket.ttl(HOLE_PUNCH_TTL)?;
+    // The request is sent right away. Dropping the socket then leaves the port to the listener.
+    let _ = TcpStream::connect_stream(socket.to_tcp_stream()?, addr)?;
+    Ok(())
+}
+
+fn unspecified_addr(port: u16, peer_addr: &SocketAddr) -> SocketAddr {
+    match *peer_addr {
+        SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port),
+        SocketAddr::V6(..) => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), port)
+        }
+    }
+}
+
 #[cfg(target_family = "unix")]
 pub fn enable_so_reuseport(sock: &TcpBuilder) -> io::Result<()> {
     use net2::unix::UnixTcpBuilderExt;
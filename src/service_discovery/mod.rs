@@ -11,48 +11,177 @@ pub use self::errors::ServiceDiscoveryError;
 
 mod errors;
 
-use crate::common::{ipv4_addr, Core, PeerInfo, State};
+use crate::common::{ipv4_addr, Core, CoreTimer, NameHash, PeerInfo, State};
+use crate::main::DiscoveryMethod;
 use mio::net::UdpSocket;
 use mio::{Poll, PollOpt, Ready, Token};
-use safe_crypto::PublicEncryptKey;
+use mio_extras::timer::Timeout;
+use net2::{UdpBuilder, UdpSocketExt};
+use rand;
+use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
 use socket_collection::{Priority, SocketError, UdpSock};
 use std::any::Any;
 use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::marker::PhantomData;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::rc::Rc;
-use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::u16;
 
+/// Number of our latest requests whose responses are still accepted.
+const MAX_PENDING_REQUESTS: usize = 8;
+/// Interval at which we seek peers anew, so that our table of LAN peers stays up to date.
+const SEEK_INTERVAL_SEC: u64 = 10;
+/// Peers which haven't answered us for this long are considered gone from the LAN.
+const LAN_PEER_EXPIRY_SEC: u64 = 3 * SEEK_INTERVAL_SEC + 5;
+/// IPv4 group of multicast discovery. It's administratively scoped, so it may be routed within an
+/// organisation, unlike broadcasts.
+const MULTICAST_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 54, 84);
+/// IPv6 group of multicast discovery, of site-local scope.
+const MULTICAST_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0x5484);
+/// Number of routers multicast discovery messages may cross.
+const MULTICAST_HOPS: u32 = 4;
+/// Maximum number of peers kept in our table of LAN peers. Peers answering while it's full are
+/// ignored until others expire, so that a host answering with ever new keys can't flood us.
+const MAX_LAN_PEERS: usize = 64;
+
 #[derive(Serialize, Deserialize)]
 enum DiscoveryMsg {
-    /// Service discovery request with requestor's public key.
+    /// Service discovery request. Only peers of the network with given name hash answer it.
     Request {
+        name_hash: NameHash,
+        nonce: u64,
+        our_pk: PublicEncryptKey,
+    },
+    /// Response with the responder's public key and its `ResponseBody`, encrypted with the key
+    /// shared by the requester's and the responder's keys. Only the requester can read it and
+    /// only the owner of the public key can have made it.
+    Response {
         our_pk: PublicEncryptKey,
+        body: Vec<u8>,
     },
-    Response(Vec<PeerInfo>),
 }
 
-pub struct ServiceDiscovery<T> {
-    token: Token,
+#[derive(Serialize, Deserialize)]
+struct ResponseBody {
+    name_hash: NameHash,
+    /// Nonce of the request this answers.
+    nonce: u64,
+    listeners: Vec<PeerInfo>,
+}
+
+/// A socket we discover peers over, along with the address our requests are sent to.
+struct Channel {
     socket: UdpSock,
     remote_addr: SocketAddr,
+}
+
+/// Change to our table of peers on the LAN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LanPeerChange {
+    /// A peer was found, or its listeners changed. Contains its listeners.
+    Found(Vec<PeerInfo>),
+    /// A peer stopped answering us. Contains its last known listeners.
+    Lost(Vec<PeerInfo>),
+}
+
+/// Called with every change to our table of peers on the LAN. Once it returns `false` it's not
+/// called anymore.
+pub type LanObserver<T> = Box<FnMut(&mut Core<T>, &Poll, &LanPeerChange) -> bool>;
+
+struct LanPeer {
+    listeners: Vec<PeerInfo>,
+    last_seen: Instant,
+}
+
+/// Peers of our network which answered us on the LAN, by their public keys.
+#[derive(Default)]
+struct LanPeers {
+    peers: HashMap<PublicEncryptKey, LanPeer>,
+}
+
+impl LanPeers {
+    /// Records that the peer answered us with given listeners. Returns the change this makes, if
+    /// the peer is new to us or its listeners are. New peers are ignored once we know of
+    /// `MAX_LAN_PEERS`.
+    fn update(
+        &mut self,
+        their_pk: PublicEncryptKey,
+        listeners: Vec<PeerInfo>,
+        now: Instant,
+    ) -> Option<LanPeerChange> {
+        if let Some(peer) = self.peers.get_mut(&their_pk) {
+            if peer.listeners == listeners {
+                peer.last_seen = now;
+                return None;
+            }
+        } else if self.peers.len() >= MAX_LAN_PEERS {
+            return None;
+        }
+        let _ = self.peers.insert(
+            their_pk,
+            LanPeer {
+                listeners: listeners.clone(),
+                last_seen: now,
+            },
+        );
+        Some(LanPeerChange::Found(listeners))
+    }
+
+    /// Removes the peers which haven't answered us for `LAN_PEER_EXPIRY_SEC`.
+    fn expire(&mut self, now: Instant) -> Vec<LanPeerChange> {
+        let expiry = Duration::from_secs(LAN_PEER_EXPIRY_SEC);
+        let expired: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|&(_, peer)| now.duration_since(peer.last_seen) >= expiry)
+            .map(|(pk, _)| *pk)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|pk| self.peers.remove(&pk))
+            .map(|peer| LanPeerChange::Lost(peer.listeners))
+            .collect()
+    }
+
+    fn listeners(&self) -> Vec<PeerInfo> {
+        self.peers
+            .values()
+            .flat_map(|peer| peer.listeners.iter().cloned())
+            .collect()
+    }
+}
+
+pub struct ServiceDiscovery<T> {
+    token: Token,
+    channels: Vec<Channel>,
     listen: bool,
     our_listeners: Arc<Mutex<Vec<PeerInfo>>>,
-    seek_peers_req: DiscoveryMsg,
-    observers: Vec<Sender<Vec<PeerInfo>>>,
+    /// Nonces of our latest requests.
+    pending_requests: VecDeque<u64>,
+    lan_peers: LanPeers,
+    lan_observers: Vec<LanObserver<T>>,
+    timer: CoreTimer,
+    timeout: Timeout,
+    name_hash: NameHash,
     our_pk: PublicEncryptKey,
+    our_sk: SecretEncryptKey,
     phantom: PhantomData<T>,
 }
 
 impl<T: 'static> ServiceDiscovery<T> {
-    /// Starts service discovery process.
+    /// Starts service discovery process. We seek peers right away and then periodically, keeping
+    /// a table of the peers on our LAN.
     ///
     /// # Args
     ///
     /// - listener_port - port we will be litening for incoming service discovery requests.
     /// - remote_port - port we will broadcasting service discovery requests to.
+    /// - method - whether requests are broadcast or sent to our multicast groups.
+    /// - name_hash - hash of our network name. Peers of other networks are neither answered nor
+    ///   taken from.
     pub fn start(
         core: &mut Core<T>,
         poll: &Poll,
@@ -60,32 +189,52 @@ impl<T: 'static> ServiceDiscovery<T> {
         token: Token,
         listener_port: u16,
         remote_port: u16,
+        method: DiscoveryMethod,
+        name_hash: NameHash,
         our_pk: PublicEncryptKey,
+        our_sk: SecretEncryptKey,
     ) -> Result<(), ServiceDiscoveryError> {
-        let udp_socket = UdpSocket::bind(&ipv4_addr(0, 0, 0, 0, listener_port))?;
-        udp_socket.set_broadcast(true)?;
-        let udp_socket = UdpSock::wrap(udp_socket);
+        let channels = match method {
+            DiscoveryMethod::Broadcast => vec![broadcast_channel(listener_port, remote_port)?],
+            DiscoveryMethod::Multicast => {
+                let mut channels = vec![multicast_v4_channel(listener_port, remote_port)?];
+                // Not every host has IPv6.
+                match multicast_v6_channel(listener_port, remote_port) {
+                    Ok(channel) => channels.push(channel),
+                    Err(e) => debug!("IPv6 multicast discovery unavailable: {:?}", e),
+                }
+                channels
+            }
+        };
+
+        for channel in &channels {
+            poll.register(
+                &channel.socket,
+                token,
+                Ready::readable() | Ready::writable(),
+                PollOpt::edge(),
+            )?;
+        }
 
-        let remote_addr = ipv4_addr(255, 255, 255, 255, remote_port);
+        let timer = CoreTimer::new(token, 0);
+        let timeout = core.set_timeout(Duration::from_secs(SEEK_INTERVAL_SEC), timer);
 
-        let service_discovery = ServiceDiscovery {
+        let mut service_discovery = ServiceDiscovery {
             token,
-            socket: udp_socket,
-            remote_addr,
+            channels,
             listen: false,
             our_listeners,
-            seek_peers_req: DiscoveryMsg::Request { our_pk },
-            observers: Vec::new(),
+            pending_requests: VecDeque::with_capacity(MAX_PENDING_REQUESTS),
+            lan_peers: Default::default(),
+            lan_observers: Vec::new(),
+            timer,
+            timeout,
+            name_hash,
             our_pk,
+            our_sk,
             phantom: PhantomData,
         };
-
-        poll.register(
-            &service_discovery.socket,
-            token,
-            Ready::readable() | Ready::writable(),
-            PollOpt::edge(),
-        )?;
+        service_discovery.seek_peers(core, poll)?;
 
         let _ = core.insert_state(token, Rc::new(RefCell::new(service_discovery)));
 
@@ -98,75 +247,254 @@ impl<T: 'static> ServiceDiscovery<T> {
         self.listen = listen;
     }
 
-    /// Interrogate the network to find peers.
-    pub fn seek_peers(&mut self) -> Result<(), ServiceDiscoveryError> {
-        let _ = self
-            .socket
-            .write_to(Some((&self.seek_peers_req, self.remote_addr, 0)))?;
-        Ok(())
+    /// Interrogate the network to find peers. Channels we fail to send over are dropped; only if
+    /// none is left, service discovery terminates and the last error is returned.
+    pub fn seek_peers(
+        &mut self,
+        core: &mut Core<T>,
+        poll: &Poll,
+    ) -> Result<(), ServiceDiscoveryError> {
+        let nonce = rand::random();
+        if self.pending_requests.len() == MAX_PENDING_REQUESTS {
+            let _ = self.pending_requests.pop_front();
+        }
+        self.pending_requests.push_back(nonce);
+
+        let req = DiscoveryMsg::Request {
+            name_hash: self.name_hash,
+            nonce,
+            our_pk: self.our_pk,
+        };
+        let mut result = Ok(());
+        for channel in (0..self.channels.len()).rev() {
+            let remote_addr = self.channels[channel].remote_addr;
+            if let Err(e) = self.channels[channel]
+                .socket
+                .write_to(Some((&req, remote_addr, 0)))
+            {
+                debug!("Failed to seek peers at {}: {:?}", remote_addr, e);
+                self.drop_channel(core, poll, channel);
+                result = Err(e.into());
+            }
+        }
+        if self.channels.is_empty() {
+            result
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Register observer of changes to our table of peers on the LAN.
+    pub fn register_lan_observer(&mut self, obs: LanObserver<T>) {
+        self.lan_observers.push(obs);
     }
 
-    /// Register service discovery observer
-    pub fn register_observer(&mut self, obs: Sender<Vec<PeerInfo>>) {
-        self.observers.push(obs);
+    /// Listeners of the peers currently on our LAN.
+    pub fn lan_peers(&self) -> Vec<PeerInfo> {
+        self.lan_peers.listeners()
+    }
+
+    fn notify_lan_observers(&mut self, core: &mut Core<T>, poll: &Poll, change: &LanPeerChange) {
+        let mut i = 0;
+        while i < self.lan_observers.len() {
+            if self.lan_observers[i](core, poll, change) {
+                i += 1;
+            } else {
+                let _ = self.lan_observers.swap_remove(i);
+            }
+        }
+    }
+
+    /// Drops a channel which failed, terminating service discovery once no channel is left.
+    /// Channels are visited in reverse order, so that dropping one leaves the indices of those
+    /// still to visit valid.
+    fn drop_channel(&mut self, core: &mut Core<T>, poll: &Poll, channel: usize) {
+        let channel = self.channels.remove(channel);
+        let _ = poll.deregister(&channel.socket);
+        if self.channels.is_empty() {
+            self.terminate(core, poll);
+        }
     }
 
     fn read(&mut self, core: &mut Core<T>, poll: &Poll) {
-        loop {
-            match self.socket.read_frm() {
-                Ok(Some((msg, peer_addr))) => {
-                    self.handle_incoming_msg(core, poll, msg, peer_addr);
-                }
-                Ok(None) => return,
-                Err(e) => {
-                    debug!("ServiceDiscovery error in read: {:?}", e);
-                    match e {
-                        // don't terminate service discovery server, if one message is invalid
-                        SocketError::Serialisation(_) | SocketError::Crypto(_) => (),
-                        _ => {
-                            self.terminate(core, poll);
-                            return;
+        for channel in (0..self.channels.len()).rev() {
+            loop {
+                match self.channels[channel].socket.read_frm() {
+                    Ok(Some((msg, peer_addr))) => {
+                        if !self.handle_incoming_msg(core, poll, channel, msg, peer_addr) {
+                            break;
                         }
                     }
-                }
-            };
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("ServiceDiscovery error in read: {:?}", e);
+                        match e {
+                            // don't drop the channel, if one message is invalid
+                            SocketError::Serialisation(_) | SocketError::Crypto(_) => (),
+                            _ => {
+                                self.drop_channel(core, poll, channel);
+                                break;
+                            }
+                        }
+                    }
+                };
+            }
         }
     }
 
+    /// Returns false if the channel was dropped.
     fn handle_incoming_msg(
         &mut self,
         core: &mut Core<T>,
         poll: &Poll,
+        channel: usize,
         msg: DiscoveryMsg,
         peer_addr: SocketAddr,
-    ) {
+    ) -> bool {
         match msg {
-            DiscoveryMsg::Request { our_pk: their_pk } => {
-                if self.listen && self.our_pk != their_pk {
-                    let our_current_listeners =
-                        unwrap!(self.our_listeners.lock()).iter().cloned().collect();
-                    let resp = (DiscoveryMsg::Response(our_current_listeners), peer_addr, 0);
-                    self.write(core, poll, Some(resp));
+            DiscoveryMsg::Request {
+                name_hash,
+                nonce,
+                our_pk: their_pk,
+            } => {
+                if !self.listen || self.our_pk == their_pk || self.name_hash != name_hash {
+                    return true;
                 }
+                let body = ResponseBody {
+                    name_hash,
+                    nonce,
+                    listeners: unwrap!(self.our_listeners.lock()).clone(),
+                };
+                let body = match self.our_sk.shared_secret(&their_pk).encrypt(&body) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        debug!("Failed to encrypt service discovery response: {:?}", e);
+                        return true;
+                    }
+                };
+                let resp = DiscoveryMsg::Response {
+                    our_pk: self.our_pk,
+                    body,
+                };
+                self.write(core, poll, channel, Some((resp, peer_addr, 0)))
             }
-            DiscoveryMsg::Response(peer_listeners) => {
-                self.observers
-                    .retain(|obs| obs.send(peer_listeners.clone()).is_ok());
+            DiscoveryMsg::Response {
+                our_pk: their_pk,
+                body,
+            } => {
+                let peer_listeners = match open_response(
+                    &self.our_sk,
+                    self.name_hash,
+                    &self.pending_requests,
+                    &their_pk,
+                    &body,
+                ) {
+                    Some(peer_listeners) => peer_listeners,
+                    None => {
+                        debug!("Invalid service discovery response from {}", peer_addr);
+                        return true;
+                    }
+                };
+                // A peer without listeners can't be connected to, so it's left to expire.
+                if peer_listeners.is_empty() {
+                    return true;
+                }
+                if let Some(change) =
+                    self.lan_peers
+                        .update(their_pk, peer_listeners, Instant::now())
+                {
+                    self.notify_lan_observers(core, poll, &change);
+                }
+                true
             }
         }
     }
 
+    /// Returns false if writing failed and the channel was dropped.
     fn write(
         &mut self,
         core: &mut Core<T>,
         poll: &Poll,
+        channel: usize,
         msg: Option<(DiscoveryMsg, SocketAddr, Priority)>,
-    ) {
-        if let Err(e) = self.socket.write_to(msg) {
+    ) -> bool {
+        if let Err(e) = self.channels[channel].socket.write_to(msg) {
             debug!("Failed to send response: {:?}", e);
-            self.terminate(core, poll);
+            self.drop_channel(core, poll, channel);
+            return false;
         }
+        true
+    }
+}
+
+fn broadcast_channel(
+    listener_port: u16,
+    remote_port: u16,
+) -> Result<Channel, ServiceDiscoveryError> {
+    let builder = UdpBuilder::new_v4()?;
+    let _ = builder.reuse_address(true)?;
+    let socket = builder.bind(ipv4_addr(0, 0, 0, 0, listener_port))?;
+    socket.set_broadcast(true)?;
+    Ok(Channel {
+        socket: UdpSock::wrap(UdpSocket::from_socket(socket)?),
+        remote_addr: ipv4_addr(255, 255, 255, 255, remote_port),
+    })
+}
+
+fn multicast_v4_channel(
+    listener_port: u16,
+    remote_port: u16,
+) -> Result<Channel, ServiceDiscoveryError> {
+    let builder = UdpBuilder::new_v4()?;
+    let _ = builder.reuse_address(true)?;
+    let socket = builder.bind(ipv4_addr(0, 0, 0, 0, listener_port))?;
+    socket.join_multicast_v4(&MULTICAST_GROUP_V4, &Ipv4Addr::new(0, 0, 0, 0))?;
+    socket.set_multicast_ttl_v4(MULTICAST_HOPS)?;
+    Ok(Channel {
+        socket: UdpSock::wrap(UdpSocket::from_socket(socket)?),
+        remote_addr: SocketAddr::new(IpAddr::V4(MULTICAST_GROUP_V4), remote_port),
+    })
+}
+
+fn multicast_v6_channel(
+    listener_port: u16,
+    remote_port: u16,
+) -> Result<Channel, ServiceDiscoveryError> {
+    let builder = UdpBuilder::new_v6()?;
+    let _ = builder.only_v6(true)?;
+    let _ = builder.reuse_address(true)?;
+    let socket = builder.bind(SocketAddr::new(
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        listener_port,
+    ))?;
+    socket.join_multicast_v6(&MULTICAST_GROUP_V6, 0)?;
+    socket.set_multicast_hops_v6(MULTICAST_HOPS)?;
+    Ok(Channel {
+        socket: UdpSock::wrap(UdpSocket::from_socket(socket)?),
+        remote_addr: SocketAddr::new(IpAddr::V6(MULTICAST_GROUP_V6), remote_port),
+    })
+}
+
+/// Decrypts a response and returns the listeners in it, provided it answers one of our pending
+/// requests from within our network and lists only listeners of the responder itself.
+fn open_response(
+    our_sk: &SecretEncryptKey,
+    name_hash: NameHash,
+    pending_requests: &VecDeque<u64>,
+    their_pk: &PublicEncryptKey,
+    body: &[u8],
+) -> Option<Vec<PeerInfo>> {
+    let body = our_sk
+        .shared_secret(their_pk)
+        .decrypt::<ResponseBody>(body)
+        .ok()?;
+    if body.name_hash != name_hash
+        || !pending_requests.contains(&body.nonce)
+        || body.listeners.iter().any(|peer| peer.pub_key != *their_pk)
+    {
+        return None;
     }
+    Some(body.listeners)
 }
 
 impl<T: 'static> State<T> for ServiceDiscovery<T> {
@@ -175,12 +503,28 @@ impl<T: 'static> State<T> for ServiceDiscovery<T> {
             self.read(core, poll);
         }
         if kind.is_writable() {
-            self.write(core, poll, None);
+            for channel in (0..self.channels.len()).rev() {
+                let _ = self.write(core, poll, channel, None);
+            }
+        }
+    }
+
+    fn timeout(&mut self, core: &mut Core<T>, poll: &Poll, _timer_id: u8) {
+        self.timeout = core.set_timeout(Duration::from_secs(SEEK_INTERVAL_SEC), self.timer);
+        if let Err(e) = self.seek_peers(core, poll) {
+            debug!("Failed to seek peers: {:?}", e);
+            return;
+        }
+        for change in self.lan_peers.expire(Instant::now()) {
+            self.notify_lan_observers(core, poll, &change);
         }
     }
 
     fn terminate(&mut self, core: &mut Core<T>, poll: &Poll) {
-        let _ = poll.deregister(&self.socket);
+        let _ = core.cancel_timeout(&self.timeout);
+        for channel in &self.channels {
+            let _ = poll.deregister(&channel.socket);
+        }
         let _ = core.remove_state(self.token);
     }
 
@@ -192,7 +536,7 @@ impl<T: 'static> State<T> for ServiceDiscovery<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::{self, CoreMessage};
+    use crate::common::{self, CoreMessage, HASH_SIZE};
     use mio::Token;
     use safe_crypto::gen_encrypt_keypair;
     use std::str::FromStr;
@@ -201,8 +545,19 @@ mod tests {
     use std::time::Duration;
     use std::{net, thread};
 
+    const NAME_HASH: NameHash = [1; HASH_SIZE];
+
     #[test]
     fn service_discovery() {
+        discover_peer(DiscoveryMethod::Broadcast, 65_530);
+    }
+
+    #[test]
+    fn multicast_service_discovery() {
+        discover_peer(DiscoveryMethod::Multicast, 65_529);
+    }
+
+    fn discover_peer(method: DiscoveryMethod, port: u16) {
         const SERVICE_DISCOVERY_TOKEN: usize = 0;
 
         // Poll-0
@@ -211,7 +566,7 @@ mod tests {
             "Could not run el0"
         );
 
-        let (service0_pk, _sk) = gen_encrypt_keypair();
+        let (service0_pk, service0_sk) = gen_encrypt_keypair();
         let addr = unwrap!(net::SocketAddr::from_str("138.139.140.150:54321"));
         let conn_info = PeerInfo::new(addr, service0_pk);
         let listeners_0 = Arc::new(Mutex::new(vec![conn_info]));
@@ -228,9 +583,12 @@ mod tests {
                             poll,
                             listeners_0_clone,
                             token_0,
-                            65_530,
-                            65_530,
+                            port,
+                            port,
+                            method,
+                            NAME_HASH,
                             service0_pk,
+                            service0_sk,
                         ),
                         "Could not spawn ServiceDiscovery_0"
                     );
@@ -260,7 +618,7 @@ mod tests {
         {
             let listeners_1 = Arc::new(Mutex::new(vec![]));
             let token_1 = Token(SERVICE_DISCOVERY_TOKEN);
-            let (our_pk, _our_sk) = gen_encrypt_keypair();
+            let (our_pk, our_sk) = gen_encrypt_keypair();
             unwrap!(
                 el1.send(CoreMessage::new(move |core, poll| {
                     unwrap!(
@@ -270,8 +628,11 @@ mod tests {
                             listeners_1,
                             token_1,
                             0,
-                            65_530,
-                            our_pk
+                            port,
+                            method,
+                            NAME_HASH,
+                            our_pk,
+                            our_sk,
                         ),
                         "Could not spawn ServiceDiscovery_1"
                     );
@@ -284,16 +645,21 @@ mod tests {
                 let state = unwrap!(core.get_state(token_1));
                 let mut inner = state.borrow_mut();
                 unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery<()>>())
-                    .register_observer(tx);
+                    .register_lan_observer(Box::new(move |_, _, change| {
+                        if let LanPeerChange::Found(ref listeners) = *change {
+                            let _ = tx.send(listeners.clone());
+                        }
+                        true
+                    }));
             })));
 
             // Seek peers
             unwrap!(
-                el1.send(CoreMessage::new(move |core, _| {
+                el1.send(CoreMessage::new(move |core, poll| {
                     let state = unwrap!(core.get_state(token_1));
                     let mut inner = state.borrow_mut();
                     let sd = unwrap!(inner.as_any().downcast_mut::<ServiceDiscovery<()>>());
-                    unwrap!(sd.seek_peers());
+                    unwrap!(sd.seek_peers(core, poll));
                 })),
                 "Could not send to el1"
             );
@@ -305,4 +671,114 @@ mod tests {
             *unwrap!(listeners_0.lock())
         );
     }
+
+    #[test]
+    fn only_valid_responses_are_opened() {
+        let (requester_pk, requester_sk) = gen_encrypt_keypair();
+        let (responder_pk, responder_sk) = gen_encrypt_keypair();
+        let addr = unwrap!(net::SocketAddr::from_str("192.168.0.2:5483"));
+        let listeners = vec![PeerInfo::new(addr, responder_pk)];
+        let pending_requests: VecDeque<_> = vec![7].into_iter().collect();
+
+        let respond = |name_hash, nonce, listeners| {
+            let body = ResponseBody {
+                name_hash,
+                nonce,
+                listeners,
+            };
+            unwrap!(responder_sk.shared_secret(&requester_pk).encrypt(&body))
+        };
+        let open = |body: &[u8]| {
+            open_response(
+                &requester_sk,
+                NAME_HASH,
+                &pending_requests,
+                &responder_pk,
+                body,
+            )
+        };
+
+        let body = respond(NAME_HASH, 7, listeners.clone());
+        assert_eq!(unwrap!(open(&body)), listeners);
+
+        // From another network.
+        assert!(open(&respond([2; HASH_SIZE], 7, listeners.clone())).is_none());
+        // Not answering our request.
+        assert!(open(&respond(NAME_HASH, 8, listeners)).is_none());
+        // Listing someone else's listener.
+        let (other_pk, _) = gen_encrypt_keypair();
+        let listeners = vec![PeerInfo::new(addr, other_pk)];
+        assert!(open(&respond(NAME_HASH, 7, listeners)).is_none());
+        // Made by someone not owning the responder's key.
+        let (_, forger_sk) = gen_encrypt_keypair();
+        let body = ResponseBody {
+            name_hash: NAME_HASH,
+            nonce: 7,
+            listeners: vec![],
+        };
+        let body = unwrap!(forger_sk.shared_secret(&requester_pk).encrypt(&body));
+        assert!(open(&body).is_none());
+    }
+
+    #[test]
+    fn lan_peers_are_found_refreshed_and_lost() {
+        let (pk, _) = gen_encrypt_keypair();
+        let addr0 = unwrap!(net::SocketAddr::from_str("192.168.0.2:5483"));
+        let addr1 = unwrap!(net::SocketAddr::from_str("192.168.0.2:5484"));
+        let listeners0 = vec![PeerInfo::new(addr0, pk)];
+        let listeners1 = vec![PeerInfo::new(addr1, pk)];
+        let expiry = Duration::from_secs(LAN_PEER_EXPIRY_SEC);
+        let mut lan_peers = LanPeers::default();
+        let now = Instant::now();
+
+        assert_eq!(
+            lan_peers.update(pk, listeners0.clone(), now),
+            Some(LanPeerChange::Found(listeners0.clone()))
+        );
+        assert_eq!(lan_peers.listeners(), listeners0);
+
+        // Answering again only refreshes the peer.
+        let refreshed = now + expiry / 2;
+        assert_eq!(lan_peers.update(pk, listeners0.clone(), refreshed), None);
+        assert!(lan_peers.expire(now + expiry).is_empty());
+
+        // New listeners are reported.
+        assert_eq!(
+            lan_peers.update(pk, listeners1.clone(), refreshed),
+            Some(LanPeerChange::Found(listeners1.clone()))
+        );
+
+        assert_eq!(
+            lan_peers.expire(refreshed + expiry),
+            vec![LanPeerChange::Lost(listeners1)]
+        );
+        assert!(lan_peers.listeners().is_empty());
+    }
+
+    #[test]
+    fn lan_peers_table_is_capped() {
+        let addr = unwrap!(net::SocketAddr::from_str("192.168.0.2:5483"));
+        let mut lan_peers = LanPeers::default();
+        let now = Instant::now();
+
+        for _ in 0..MAX_LAN_PEERS {
+            let (pk, _) = gen_encrypt_keypair();
+            assert!(lan_peers
+                .update(pk, vec![PeerInfo::new(addr, pk)], now)
+                .is_some());
+        }
+        let (pk, _) = gen_encrypt_keypair();
+        assert_eq!(
+            lan_peers.update(pk, vec![PeerInfo::new(addr, pk)], now),
+            None
+        );
+        assert_eq!(lan_peers.listeners().len(), MAX_LAN_PEERS);
+
+        // Room is made as peers expire.
+        let later = now + Duration::from_secs(LAN_PEER_EXPIRY_SEC);
+        assert_eq!(lan_peers.expire(later).len(), MAX_LAN_PEERS);
+        assert!(lan_peers
+            .update(pk, vec![PeerInfo::new(addr, pk)], later)
+            .is_some());
+    }
 }
@@ -12,18 +12,25 @@ pub mod utils;
 
 pub use self::utils::{gen_config, get_event_sender, timebomb, UniqueId};
 
-use crate::common::{CrustUser, PeerInfo};
-use crate::main::{self, Config, DevConfig, Event};
+use crate::common::{
+    BootstrapDenyReason, Capabilities, CrustUser, GoodbyeReason, Identity, PeerInfo,
+    PROTOCOL_VERSION,
+};
+use crate::main::{
+    self, AddrFailure, Config, ConnectFailureReason, ConnectionLimits, ContactResult, CrustError,
+    DevConfig, DisconnectReason, Event, IpRange, RateLimits, RelayConfig,
+};
+use maidsafe_utilities::serialisation::serialise;
 use mio;
 use rand;
-use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey};
+use safe_crypto::{self, gen_encrypt_keypair, gen_sign_keypair, PublicEncryptKey, PublicSignKey};
 use std::collections::HashSet;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::sync::mpsc::{self, Receiver};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 type Service = main::Service<UniqueId>;
 
@@ -49,6 +56,8 @@ fn test_service() -> (Service, Receiver<Event<UniqueId>>) {
 
 mod connect {
     use super::*;
+    use crate::tests::utils::rand_uid;
+    use std::net::TcpListener;
 
     #[test]
     fn successfully_connected_peer_contacts_are_cached() {
@@ -84,13 +93,97 @@ mod connect {
             .collect();
 
         unwrap!(service2.connect(ci2, pub_ci1));
-        expect_event!(event_rx2, Event::ConnectSuccess(id) => {
+        expect_event!(event_rx2, Event::ConnectSuccess(id, _) => {
             assert_eq!(id, uid1);
         });
 
         let cached_peers = unwrap!(service2.bootstrap_cached_peers());
         assert!(cached_peers.is_subset(&expected_conns));
     }
+
+    /// Has service 2 connect to service 1 at the given addresses and through the given relays.
+    fn connect_at(
+        service1: &Service,
+        event_rx1: &Receiver<Event<UniqueId>>,
+        service2: &Service,
+        event_rx2: &Receiver<Event<UniqueId>>,
+        for_direct: Vec<SocketAddr>,
+        for_relay: Vec<UniqueId>,
+    ) {
+        let token = rand::random();
+        service1.prepare_connection_info(token);
+        let mut pub_ci1 = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+            unwrap!(res.result).to_pub_connection_info()
+        });
+        pub_ci1.for_direct = for_direct;
+        pub_ci1.for_relay = for_relay;
+        service2.prepare_connection_info(token);
+        let ci2 =
+            expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+        unwrap!(service2.connect(ci2, pub_ci1));
+    }
+
+    #[test]
+    fn connect_failure_reports_how_each_address_and_relay_failed() {
+        let (service1, event_rx1) = test_service();
+        let (service2, event_rx2) = test_service();
+        // Nothing listens at this address anymore.
+        let closed_addr = unwrap!(unwrap!(TcpListener::bind("127.0.0.1:0")).local_addr());
+        let relay_id = rand_uid();
+
+        connect_at(
+            &service1,
+            &event_rx1,
+            &service2,
+            &event_rx2,
+            vec![closed_addr],
+            vec![relay_id],
+        );
+
+        expect_event!(event_rx2, Event::ConnectFailure(id, ConnectFailureReason::Failed(attempts, relay_attempts)) => {
+            assert_eq!(id, service1.id());
+            assert_eq!(attempts.len(), 1);
+            assert_eq!(attempts[0].0, closed_addr);
+            match attempts[0].1 {
+                AddrFailure::Connect(_) | AddrFailure::Handshake => (),
+                ref failure => panic!("Unexpected failure: {:?}", failure),
+            }
+            // We aren't connected to the relay.
+            assert_eq!(relay_attempts.len(), 1);
+            assert_eq!(relay_attempts[0].0, relay_id);
+            match relay_attempts[0].1 {
+                AddrFailure::Connect(_) => (),
+                ref failure => panic!("Unexpected failure: {:?}", failure),
+            }
+        });
+    }
+
+    #[test]
+    fn connect_timeout_reports_pending_addresses() {
+        let (service1, event_rx1) = test_service();
+        let (service2, event_rx2) = test_service();
+        // Accepts connections, but never answers.
+        let silent_listener = unwrap!(TcpListener::bind("127.0.0.1:0"));
+        let silent_addr = unwrap!(silent_listener.local_addr());
+
+        connect_at(
+            &service1,
+            &event_rx1,
+            &service2,
+            &event_rx2,
+            vec![silent_addr],
+            vec![],
+        );
+
+        expect_event!(event_rx2, Event::ConnectFailure(id, reason) => {
+            assert_eq!(id, service1.id());
+            assert_eq!(
+                reason,
+                ConnectFailureReason::Timeout(vec![(silent_addr, AddrFailure::Pending)], vec![])
+            );
+        });
+        drop(silent_listener);
+    }
 }
 
 #[test]
@@ -109,11 +202,11 @@ fn bootstrap_two_services_and_exchange_messages() {
 
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
     let peer_id1 = expect_event!(event_rx0,
-                                 Event::BootstrapAccept(peer_id, CrustUser::Client) => peer_id);
+                                 Event::BootstrapAccept(peer_id, CrustUser::Client, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 
     let message0 = b"hello from 0".to_vec();
@@ -133,6 +226,594 @@ fn bootstrap_two_services_and_exchange_messages() {
     });
 }
 
+// Bootstraps a client off a node which limits its uploads to each peer to 1 KiB/s. Returns them
+// along with the client's ID as the node knows it.
+fn services_with_rate_limited_node() -> (ServiceAndEvents, ServiceAndEvents, UniqueId) {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut limits = RateLimits::default();
+    limits.per_peer.upload_bytes_per_sec = Some(1024);
+    service0.set_rate_limits(limits.clone());
+    assert_eq!(service0.rate_limits(), limits);
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let _ = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    ((service0, event_rx0), (service1, event_rx1), peer_id1)
+}
+
+#[test]
+fn rate_limited_messages_are_delivered_in_order() {
+    let ((service0, _event_rx0), (_service1, event_rx1), peer_id1) =
+        services_with_rate_limited_node();
+
+    // More than a second worth of data must be held back and sent later.
+    let start = Instant::now();
+    for i in 0..3u8 {
+        unwrap!(service0.send(&peer_id1, vec![i; 1024], 0));
+    }
+    for i in 0..3u8 {
+        expect_event!(event_rx1, Event::NewMessage(_, CrustUser::Node, data) => {
+            assert_eq!(data, vec![i; 1024]);
+        });
+    }
+    assert!(start.elapsed() >= Duration::from_millis(900));
+}
+
+#[test]
+fn held_back_messages_are_sent_in_priority_order() {
+    let ((service0, _event_rx0), (_service1, event_rx1), peer_id1) =
+        services_with_rate_limited_node();
+
+    // The first two messages use up the allowance, the rest is held back.
+    for &(i, priority) in &[(0u8, 5), (1, 5), (2, 5), (3, 1)] {
+        unwrap!(service0.send(&peer_id1, vec![i; 1024], priority));
+    }
+    for &i in &[0u8, 1, 3, 2] {
+        expect_event!(event_rx1, Event::NewMessage(_, CrustUser::Node, data) => {
+            assert_eq!(data, vec![i; 1024]);
+        });
+    }
+}
+
+#[test]
+fn compressed_and_uncompressed_peers_exchange_messages() {
+    let mut config0 = gen_config();
+    config0.compression_threshold_bytes = Some(64);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    let data = vec![7; 4096];
+
+    for compression_threshold_bytes in vec![Some(64), None] {
+        let mut config1 = gen_config();
+        config1.hard_coded_contacts = contacts.clone();
+        config1.compression_threshold_bytes = compression_threshold_bytes;
+        let (event_tx1, event_rx1) = get_event_sender();
+        let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+        unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+        let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+        let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+        let protocol = unwrap!(service0.peer_protocol(&peer_id1));
+        assert_eq!(protocol, unwrap!(service1.peer_protocol(&peer_id0)));
+        assert_eq!(protocol.version, PROTOCOL_VERSION);
+        assert_eq!(
+            protocol
+                .capabilities
+                .contains(Capabilities::LZ4_COMPRESSION),
+            compression_threshold_bytes.is_some()
+        );
+
+        unwrap!(service0.send(&peer_id1, data.clone(), 0));
+        expect_event!(event_rx1, Event::NewMessage(_, CrustUser::Node, msg) => {
+            assert_eq!(msg, data);
+        });
+        unwrap!(service1.send(&peer_id0, data.clone(), 0));
+        expect_event!(event_rx0, Event::NewMessage(_, CrustUser::Client, msg) => {
+            assert_eq!(msg, data);
+        });
+
+        drop(service1);
+        expect_event!(event_rx0, Event::LostPeer(id, _) => assert_eq!(id, peer_id1));
+    }
+}
+
+#[test]
+fn peers_keep_exchanging_messages_across_rekeys() {
+    let mut config0 = gen_config();
+    config0.rekey_after_bytes = Some(1024);
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    config1.rekey_after_bytes = Some(1024);
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    // Both sides exceed the rekey threshold several times, often simultaneously.
+    for i in 0..10u8 {
+        unwrap!(service0.send(&peer_id1, vec![i; 1000], 0));
+        unwrap!(service1.send(&peer_id0, vec![i; 1000], 0));
+    }
+    for i in 0..10u8 {
+        expect_event!(event_rx1, Event::NewMessage(_, CrustUser::Node, data) => {
+            assert_eq!(data, vec![i; 1000]);
+        });
+        expect_event!(event_rx0, Event::NewMessage(_, CrustUser::Client, data) => {
+            assert_eq!(data, vec![i; 1000]);
+        });
+    }
+}
+
+#[test]
+fn blocking_ip_range_drops_connected_peers() {
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(
+        event_tx0,
+        gen_config(),
+        rand::random()
+    ));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    let localhost = unwrap!(IpRange::new(unwrap!(IpAddr::from_str("127.0.0.0")), 8));
+    unwrap!(service0.block_ip_range(localhost, Some(Duration::from_secs(60))));
+    expect_event!(event_rx0, Event::LostPeer(id, DisconnectReason::NotAllowed) => {
+        assert_eq!(id, peer_id1)
+    });
+    expect_event!(event_rx1, Event::LostPeer(id, _) => assert_eq!(id, peer_id0));
+
+    let access_lists = service0.access_lists();
+    assert_eq!(unwrap!(access_lists.blocklist).len(), 1);
+
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapFailed(report) => {
+        assert!(report.contacts.is_empty())
+    });
+
+    assert!(unwrap!(service0.unblock_ip_range(&localhost)));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => {
+        assert_eq!(peer_id, peer_id0)
+    });
+}
+
+#[test]
+fn access_lists_changed_at_runtime_survive_restart() {
+    let config = gen_config();
+    let localhost = unwrap!(IpRange::new(unwrap!(IpAddr::from_str("127.0.0.0")), 8));
+    {
+        let (event_tx, _event_rx) = get_event_sender();
+        let service = unwrap!(Service::with_config(
+            event_tx,
+            config.clone(),
+            rand::random()
+        ));
+        unwrap!(service.block_ip_range(localhost, None));
+    }
+
+    let (event_tx, _event_rx) = get_event_sender();
+    let service = unwrap!(Service::with_config(event_tx, config, rand::random()));
+    assert!(service
+        .access_lists()
+        .is_blocked(&unwrap!(IpAddr::from_str("127.0.0.1"))));
+}
+
+/// Starts a listening service with given config which accepts bootstraps, along with a second
+/// listening one it can connect to. Returns the first service along with its port, then the
+/// second one.
+fn bootstrappee_and_other_node(config0: Config) -> (ServiceAndEvents, u16, ServiceAndEvents) {
+    let (mut service2, event_rx2) = test_service();
+    unwrap!(service2.start_listening_tcp());
+    expect_event!(event_rx2, Event::ListenerStarted(_port) => ());
+    unwrap!(service2.set_accept_bootstrap(true));
+
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    ((service0, event_rx0), port0, (service2, event_rx2))
+}
+
+/// Has the first service connect to the second one.
+fn connect_services(from: &ServiceAndEvents, to: &ServiceAndEvents) {
+    let token = rand::random();
+    to.0.prepare_connection_info(token);
+    let ci_to = expect_event!(to.1, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+    from.0.prepare_connection_info(token);
+    let ci_from = expect_event!(from.1, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+    unwrap!(from.0.connect(ci_from, ci_to.to_pub_connection_info()));
+    expect_event!(from.1, Event::ConnectSuccess(id, _) => assert_eq!(id, to.0.id()));
+}
+
+#[test]
+fn denied_bootstrapper_is_redirected_to_other_nodes() {
+    // Service 0 is full, but knows service 2.
+    let mut config0 = gen_config();
+    config0.connection_limits = Some(ConnectionLimits {
+        max_clients: Some(0),
+        ..Default::default()
+    });
+    let (node0, port0, node2) = bootstrappee_and_other_node(config0);
+    connect_services(&node0, &node2);
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, node0.0.pub_key())];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => {
+        assert_eq!(peer_id, node2.0.id())
+    });
+    // Having bootstrapped off it, service 1 knows service 2 is genuine.
+    let cached_peers = unwrap!(service1.bootstrap_cached_peers());
+    assert!(cached_peers
+        .iter()
+        .any(|peer| peer.pub_key == node2.0.pub_key()));
+}
+
+#[test]
+fn bootstrapper_learns_peers_of_bootstrappee() {
+    let mut config0 = gen_config();
+    config0.peer_exchange = Some(true);
+    let (node0, port0, node2) = bootstrappee_and_other_node(config0);
+
+    let mut config1 = gen_config();
+    config1.peer_exchange = Some(true);
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, node0.0.pub_key())];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => {
+        assert_eq!(peer_id, node0.0.id())
+    });
+    expect_event!(node0.1, Event::BootstrapAccept(peer_id, _, _) => {
+        assert_eq!(peer_id, service1.id())
+    });
+
+    // Service 0 only gets to know service 2 after the first exchange, so service 1 learns it from
+    // a later one. It's only cached once service 1 verified it is reachable.
+    connect_services(&node0, &node2);
+    let peer = expect_event!(event_rx1, Event::PeerLearned(peer) => peer);
+    assert_eq!(peer.pub_key, node2.0.pub_key());
+    let cached_peers = unwrap!(service1.bootstrap_cached_peers());
+    assert!(cached_peers.contains(&peer));
+}
+
+#[test]
+fn standby_connection_is_kept_over_second_path() {
+    let mut config = gen_config();
+    config.standby_connections = Some(true);
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(
+        event_tx1,
+        config.clone(),
+        rand::random()
+    ));
+    let (event_tx2, event_rx2) = get_event_sender();
+    let service2 = unwrap!(Service::with_config(event_tx2, config, rand::random()));
+
+    unwrap!(service1.start_listening_tcp());
+    let port1 = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
+
+    let token = rand::random();
+    service1.prepare_connection_info(token);
+    let mut pub_ci1 = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+        unwrap!(res.result).to_pub_connection_info()
+    });
+    // Both addresses lead to the same listener over different paths.
+    pub_ci1.for_direct = vec![
+        SocketAddr::new(unwrap!(IpAddr::from_str("127.0.0.1")), port1),
+        SocketAddr::new(unwrap!(IpAddr::from_str("127.0.0.2")), port1),
+    ];
+    service2.prepare_connection_info(token);
+    let ci2 = expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+
+    unwrap!(service2.connect(ci2, pub_ci1));
+    expect_event!(event_rx2, Event::ConnectSuccess(id, _) => assert_eq!(id, service1.id()));
+    expect_event!(event_rx1, Event::ConnectSuccess(id, _) => assert_eq!(id, service2.id()));
+
+    let mut standby = false;
+    for _ in 0..50 {
+        if service1.has_standby_connection(&service2.id())
+            && service2.has_standby_connection(&service1.id())
+        {
+            standby = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(standby);
+
+    let message = b"hello over either path".to_vec();
+    unwrap!(service2.send(&service1.id(), message.clone(), 0));
+    expect_event!(event_rx1, Event::NewMessage(peer_id, CrustUser::Node, data) => {
+        assert_eq!(peer_id, service2.id());
+        assert_eq!(data, message);
+    });
+
+    // Disconnecting drops both connections instead of failing over.
+    assert!(service2.disconnect(&service1.id()));
+    expect_event!(event_rx1, Event::LostPeer(id, _) => assert_eq!(id, service2.id()));
+    assert!(!service1.is_connected(&service2.id()));
+}
+
+#[test]
+fn standby_connection_needs_both_peers_to_enable_it() {
+    let (mut service1, event_rx1) = test_service();
+    let mut config = gen_config();
+    config.standby_connections = Some(true);
+    let (event_tx2, event_rx2) = get_event_sender();
+    let service2 = unwrap!(Service::with_config(event_tx2, config, rand::random()));
+
+    unwrap!(service1.start_listening_tcp());
+    let port1 = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
+
+    let token = rand::random();
+    service1.prepare_connection_info(token);
+    let mut pub_ci1 = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => {
+        unwrap!(res.result).to_pub_connection_info()
+    });
+    pub_ci1.for_direct = vec![
+        SocketAddr::new(unwrap!(IpAddr::from_str("127.0.0.1")), port1),
+        SocketAddr::new(unwrap!(IpAddr::from_str("127.0.0.2")), port1),
+    ];
+    service2.prepare_connection_info(token);
+    let ci2 = expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+
+    unwrap!(service2.connect(ci2, pub_ci1));
+    expect_event!(event_rx2, Event::ConnectSuccess(id, _) => assert_eq!(id, service1.id()));
+    expect_event!(event_rx1, Event::ConnectSuccess(id, _) => assert_eq!(id, service2.id()));
+
+    // The second attempt neither stands by nor disturbs the connection.
+    thread::sleep(Duration::from_secs(2));
+    assert!(!service2.has_standby_connection(&service1.id()));
+    assert!(!service1.has_standby_connection(&service2.id()));
+    let message = b"hello over the only path".to_vec();
+    unwrap!(service2.send(&service1.id(), message.clone(), 0));
+    expect_event!(event_rx1, Event::NewMessage(peer_id, CrustUser::Node, data) => {
+        assert_eq!(peer_id, service2.id());
+        assert_eq!(data, message);
+    });
+    assert!(event_rx2.try_recv().is_err());
+}
+
+type ServiceAndEvents = (Service, Receiver<Event<UniqueId>>);
+
+// Starts a relay and two services, which connect to it and then to each other through it.
+fn services_connected_through_relay() -> (ServiceAndEvents, ServiceAndEvents, ServiceAndEvents) {
+    let (relay, (service1, event_rx1), (service2, event_rx2)) = services_around_relay(gen_config());
+    connect_through_relay(&relay.0, &service1, &event_rx1, &service2, &event_rx2);
+    expect_event!(event_rx1, Event::ConnectSuccess(id, _) => assert_eq!(id, service2.id()));
+    expect_event!(event_rx2, Event::ConnectSuccess(id, _) => assert_eq!(id, service1.id()));
+
+    (relay, (service1, event_rx1), (service2, event_rx2))
+}
+
+// Starts a relay and two services which connect to it, the second one with the given config.
+fn services_around_relay(
+    config2: Config,
+) -> (ServiceAndEvents, ServiceAndEvents, ServiceAndEvents) {
+    let mut config0 = gen_config();
+    config0.relay = Some(RelayConfig::default());
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    unwrap!(service0.start_listening_tcp());
+    expect_event!(event_rx0, Event::ListenerStarted(_port) => ());
+
+    let (service1, event_rx1) = test_service();
+    let (event_tx2, event_rx2) = get_event_sender();
+    let service2 = unwrap!(Service::with_config(event_tx2, config2, rand::random()));
+
+    let token = rand::random();
+    for &(service, event_rx) in &[(&service1, &event_rx1), (&service2, &event_rx2)] {
+        service0.prepare_connection_info(token);
+        let ci0 =
+            expect_event!(event_rx0, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+        service.prepare_connection_info(token);
+        let ci = expect_event!(event_rx, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+        unwrap!(service.connect(ci, ci0.to_pub_connection_info()));
+        expect_event!(event_rx, Event::ConnectSuccess(id, _) => assert_eq!(id, service0.id()));
+        expect_event!(event_rx0, Event::ConnectSuccess(id, _) => assert_eq!(id, service.id()));
+    }
+
+    (
+        (service0, event_rx0),
+        (service1, event_rx1),
+        (service2, event_rx2),
+    )
+}
+
+// Has service 1 connect to service 2, which is only reachable through the relay.
+fn connect_through_relay(
+    relay: &Service,
+    service1: &Service,
+    event_rx1: &Receiver<Event<UniqueId>>,
+    service2: &Service,
+    event_rx2: &Receiver<Event<UniqueId>>,
+) {
+    let token = rand::random();
+    service1.prepare_connection_info(token);
+    let ci1 = expect_event!(event_rx1, Event::ConnectionInfoPrepared(res) => unwrap!(res.result));
+    service2.prepare_connection_info(token);
+    let mut pub_ci2 = expect_event!(event_rx2, Event::ConnectionInfoPrepared(res) => {
+        unwrap!(res.result).to_pub_connection_info()
+    });
+    pub_ci2.for_direct.clear();
+    assert_eq!(pub_ci2.for_relay, vec![relay.id()]);
+
+    unwrap!(service1.connect(ci1, pub_ci2));
+}
+
+#[test]
+fn peers_connect_through_relay() {
+    let ((service0, _event_rx0), (service1, _event_rx1), (service2, event_rx2)) =
+        services_connected_through_relay();
+    assert!(unwrap!(service1.is_relayed(&service2.id())));
+    assert!(unwrap!(service2.is_relayed(&service1.id())));
+    assert!(!unwrap!(service1.is_relayed(&service0.id())));
+
+    let message = b"hello through the relay".to_vec();
+    unwrap!(service1.send(&service2.id(), message.clone(), 0));
+    expect_event!(event_rx2, Event::NewMessage(peer_id, CrustUser::Node, data) => {
+        assert_eq!(peer_id, service1.id());
+        assert_eq!(data, message);
+    });
+}
+
+#[test]
+fn relayed_sessions_are_subject_to_connection_limits() {
+    let mut config2 = gen_config();
+    // The connection to the relay takes the only slot.
+    config2.connection_limits = Some(ConnectionLimits {
+        max_nodes: Some(1),
+        ..Default::default()
+    });
+    let (relay, (service1, event_rx1), (service2, event_rx2)) = services_around_relay(config2);
+
+    connect_through_relay(&relay.0, &service1, &event_rx1, &service2, &event_rx2);
+    expect_event!(event_rx1, Event::ConnectFailure(id, _) => assert_eq!(id, service2.id()));
+    assert!(service2.is_relayed(&service1.id()).is_err());
+}
+
+#[test]
+fn only_peers_with_relay_config_are_offered_as_relays() {
+    let ((service0, _event_rx0), service1, _service2) = services_connected_through_relay();
+
+    let (mut service3, event_rx3) = test_service();
+    unwrap!(service3.start_listening_tcp());
+    expect_event!(event_rx3, Event::ListenerStarted(_port) => ());
+    let service3 = (service3, event_rx3);
+    connect_services(&service1, &service3);
+
+    service1.0.prepare_connection_info(rand::random());
+    let pub_ci1 = expect_event!(service1.1, Event::ConnectionInfoPrepared(res) => {
+        unwrap!(res.result).to_pub_connection_info()
+    });
+    assert_eq!(pub_ci1.for_relay, vec![service0.id()]);
+}
+
+#[test]
+fn relayed_peers_move_onto_direct_connection() {
+    let ((_service0, _event_rx0), (service1, event_rx1), (mut service2, event_rx2)) =
+        services_connected_through_relay();
+
+    // Service 1 can connect to service 2 directly once it learns about its listener.
+    unwrap!(service2.start_listening_tcp());
+    expect_event!(event_rx2, Event::ListenerStarted(_port) => ());
+
+    let mut upgraded = false;
+    for _ in 0..50 {
+        if !unwrap!(service1.is_relayed(&service2.id()))
+            && !unwrap!(service2.is_relayed(&service1.id()))
+        {
+            upgraded = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(upgraded);
+
+    // Neither end noticed the move.
+    let message = b"hello directly".to_vec();
+    unwrap!(service2.send(&service1.id(), message.clone(), 0));
+    expect_event!(event_rx1, Event::NewMessage(peer_id, CrustUser::Node, data) => {
+        assert_eq!(peer_id, service2.id());
+        assert_eq!(data, message);
+    });
+    let message = b"hello back".to_vec();
+    unwrap!(service1.send(&service2.id(), message.clone(), 0));
+    expect_event!(event_rx2, Event::NewMessage(peer_id, CrustUser::Node, data) => {
+        assert_eq!(peer_id, service1.id());
+        assert_eq!(data, message);
+    });
+}
+
+// Derives UID from the signing key, so that nobody else can claim it.
+fn uid_from_sign_pk(sign_pk: &PublicSignKey) -> UniqueId {
+    let hash = safe_crypto::hash(&unwrap!(serialise(sign_pk)));
+    let mut uid = [0; 20];
+    uid.copy_from_slice(&hash[..20]);
+    uid
+}
+
+fn service_with_identity(config: Config) -> (Service, Receiver<Event<UniqueId>>) {
+    let (sign_pk, sign_sk) = gen_sign_keypair();
+    let verifier = |uid: &UniqueId, sign_pk: &PublicSignKey| *uid == uid_from_sign_pk(sign_pk);
+    let (event_tx, event_rx) = get_event_sender();
+    let mut service = unwrap!(Service::with_config(
+        event_tx,
+        config,
+        uid_from_sign_pk(&sign_pk)
+    ));
+    service.set_identity(Identity::new(sign_pk, sign_sk, Box::new(verifier)));
+    (service, event_rx)
+}
+
+#[test]
+fn identity_mode_requires_peers_to_prove_their_uid() {
+    let (mut service0, event_rx0) = service_with_identity(gen_config());
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config = gen_config();
+    config.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+
+    let (mut service1, event_rx1) = service_with_identity(config.clone());
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    assert_eq!(peer_id0, service0.id());
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+    assert_eq!(peer_id1, service1.id());
+
+    // Peer without identity can't bootstrap off us.
+    let contact = config.hard_coded_contacts[0];
+    let (event_tx2, event_rx2) = get_event_sender();
+    let mut service2 = unwrap!(Service::with_config(event_tx2, config, rand::random()));
+    unwrap!(service2.start_bootstrap(HashSet::new(), CrustUser::Client));
+    expect_event!(event_rx2, Event::BootstrapFailed(report) => {
+        let denied = ContactResult::Denied(BootstrapDenyReason::InvalidIdentity);
+        assert_eq!(report.contacts, vec![(contact, denied)]);
+    });
+}
+
 // Note: if this test fails, make sure that a firewall on your system allows UDP broadcasts
 #[test]
 fn bootstrap_two_services_using_service_discovery() {
@@ -162,13 +843,42 @@ fn bootstrap_two_services_using_service_discovery() {
     service1.start_service_discovery();
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 }
 
+#[test]
+fn service_discovery_keeps_table_of_lan_peers() {
+    let mut config0 = gen_config();
+    let service0_discovery_port = gen_service_discovery_port();
+    config0.service_discovery_listener_port = Some(service0_discovery_port);
+
+    let (event_tx0, event_rx0) = get_event_sender();
+    let mut service0 = unwrap!(Service::with_config(event_tx0, config0, rand::random()));
+    service0.start_service_discovery();
+    service0.set_service_discovery_listen(true);
+    unwrap!(service0.start_listening_tcp());
+    expect_event!(event_rx0, Event::ListenerStarted(_port));
+
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut config1 = gen_config();
+    config1.service_discovery_listener_port = Some(gen_service_discovery_port());
+    config1.service_discovery_port = Some(service0_discovery_port);
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    service1.start_service_discovery();
+
+    let listeners = expect_event!(event_rx1, Event::LanPeerFound(listeners) => listeners);
+    assert!(!listeners.is_empty());
+    assert!(listeners
+        .iter()
+        .all(|listener| listener.pub_key == service0.pub_key()));
+    assert_eq!(unwrap!(service1.lan_peers()), listeners);
+    assert!(service1.has_peers_on_lan());
+}
+
 #[test]
 fn bootstrap_with_multiple_contact_endpoints() {
     use std::net::TcpListener;
@@ -197,10 +907,10 @@ fn bootstrap_with_multiple_contact_endpoints() {
     unwrap!(service1.start_listening_tcp());
     let _ = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 }
 
@@ -224,10 +934,10 @@ fn bootstrap_with_skipped_external_reachability_test() {
     let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Node));
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 }
 
@@ -264,10 +974,10 @@ fn bootstrap_with_blacklist() {
     unwrap!(service1.start_listening_tcp());
     let _ = expect_event!(event_rx1, Event::ListenerStarted(port) => port);
 
-    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id0 = expect_event!(event_rx1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
     assert_eq!(peer_id0, service0.id());
 
-    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _) => peer_id);
+    let peer_id1 = expect_event!(event_rx0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
     assert_eq!(peer_id1, service1.id());
 
     let blacklisted_listener = unwrap!(mio::net::TcpListener::from_std(blacklisted_listener));
@@ -295,7 +1005,7 @@ fn bootstrap_fails_only_blacklisted_contact() {
     let _ = blacklist.insert(blacklisted_address.addr);
     unwrap!(service.start_bootstrap(blacklist, CrustUser::Client));
 
-    expect_event!(event_rx, Event::BootstrapFailed);
+    expect_event!(event_rx, Event::BootstrapFailed(_));
 
     let blacklisted_listener = unwrap!(mio::net::TcpListener::from_std(blacklisted_listener));
     thread::sleep(Duration::from_secs(5));
@@ -310,7 +1020,10 @@ fn bootstrap_fails_if_there_are_no_contacts() {
     let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx, Event::BootstrapFailed);
+    expect_event!(event_rx, Event::BootstrapFailed(report) => {
+        assert!(report.contacts.is_empty());
+        assert_eq!(report.error, None);
+    });
 }
 
 #[test]
@@ -328,7 +1041,10 @@ fn bootstrap_timeouts_if_there_are_only_invalid_contacts() {
     let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx, Event::BootstrapFailed);
+    expect_event!(event_rx, Event::BootstrapFailed(report) => {
+        assert_eq!(report.contacts, vec![(address, ContactResult::TimedOut)]);
+        assert!(report.elapsed >= Duration::from_secs(10));
+    });
 }
 
 #[test]
@@ -349,26 +1065,107 @@ fn drop_disconnects() {
 
     unwrap!(service_1.start_bootstrap(HashSet::new(), CrustUser::Client));
 
-    let peer_id_0 = expect_event!(event_rx_1, Event::BootstrapConnect(peer_id, _) => peer_id);
-    expect_event!(event_rx_0, Event::BootstrapAccept(_peer_id, _));
+    let peer_id_0 = expect_event!(event_rx_1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    expect_event!(event_rx_0, Event::BootstrapAccept(_peer_id, _, _));
 
     // Dropping service_0 should make service_1 receive a LostPeer event.
     drop(service_0);
-    expect_event!(event_rx_1, Event::LostPeer(peer_id) => {
+    expect_event!(event_rx_1, Event::LostPeer(peer_id, _) => {
         assert_eq!(peer_id, peer_id_0)
     });
 }
 
+#[test]
+fn shutdown_says_goodbye() {
+    let config_0 = gen_config();
+    let (event_tx_0, event_rx_0) = get_event_sender();
+    let mut service_0 = unwrap!(Service::with_config(event_tx_0, config_0, rand::random()));
+
+    unwrap!(service_0.start_listening_tcp());
+    let port = expect_event!(event_rx_0, Event::ListenerStarted(port) => port);
+    unwrap!(service_0.set_accept_bootstrap(true));
+
+    let mut config_1 = gen_config();
+    config_1.hard_coded_contacts = vec![localhost_contact_info(port, service_0.pub_key())];
+
+    let (event_tx_1, event_rx_1) = get_event_sender();
+    let mut service_1 = unwrap!(Service::with_config(event_tx_1, config_1, rand::random()));
+
+    unwrap!(service_1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let peer_id_0 = expect_event!(event_rx_1, Event::BootstrapConnect(peer_id, _, _) => peer_id);
+    let peer_id_1 = expect_event!(event_rx_0, Event::BootstrapAccept(peer_id, _, _) => peer_id);
+
+    // Data sent before shutting down is flushed before the goodbye.
+    let data = vec![7; 1024];
+    unwrap!(service_0.send(&peer_id_1, data.clone(), 0));
+    service_0.shutdown(Duration::from_secs(5));
+
+    expect_event!(event_rx_1, Event::NewMessage(peer_id, _, msg) => {
+        assert_eq!(peer_id, peer_id_0);
+        assert_eq!(msg, data);
+    });
+    expect_event!(event_rx_1, Event::LostPeer(
+        peer_id,
+        DisconnectReason::Goodbye(GoodbyeReason::Shutdown)
+    ) => {
+        assert_eq!(peer_id, peer_id_0)
+    });
+}
+
+#[test]
+fn peer_handle_fails_once_its_connection_is_replaced() {
+    let (mut service0, event_rx0) = test_service();
+    unwrap!(service0.start_listening_tcp());
+    let port0 = expect_event!(event_rx0, Event::ListenerStarted(port) => port);
+    unwrap!(service0.set_accept_bootstrap(true));
+
+    let mut config1 = gen_config();
+    config1.hard_coded_contacts = vec![localhost_contact_info(port0, service0.pub_key())];
+    let (event_tx1, event_rx1) = get_event_sender();
+    let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+
+    let handle0 = expect_event!(event_rx1, Event::BootstrapConnect(_, _, handle) => handle);
+    let handle1 = expect_event!(event_rx0, Event::BootstrapAccept(_, _, handle) => handle);
+    assert_eq!(handle0.id(), service0.id());
+    assert_eq!(handle1.id(), service1.id());
+
+    let data = vec![7; 1024];
+    unwrap!(handle1.send(data.clone(), 0));
+    expect_event!(event_rx1, Event::NewMessage(_, CrustUser::Node, msg) => {
+        assert_eq!(msg, data);
+    });
+    assert!(unwrap!(handle0.stats()).bytes_transferred >= data.len() as u64);
+    let _ = unwrap!(handle0.peer_addr());
+
+    assert!(handle1.disconnect());
+    expect_event!(event_rx1, Event::LostPeer(_, _));
+    assert!(!handle1.disconnect());
+
+    // Once we reconnect, only the new handle reaches the peer.
+    unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
+    let new_handle0 = expect_event!(event_rx1, Event::BootstrapConnect(_, _, handle) => handle);
+    assert!(new_handle0.generation() > handle0.generation());
+    match handle0.send(data.clone(), 0) {
+        Err(CrustError::ConnectionReplaced) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    unwrap!(new_handle0.send(data, 0));
+}
+
 // This module implements a simulated crust peer which accepts incomming
 // connections but then does nothing. It's purpose is to test that we detect
 // and handle non-responsive peers correctly.
 mod broken_peer {
-    use crate::common::{Core, Message, State};
+    use crate::common::{
+        Capabilities, Core, Handshake, Message, ProtocolInfo, State, PROTOCOL_VERSION,
+    };
     use crate::tests::UniqueId;
     use mio::net::TcpListener;
     use mio::{Poll, PollOpt, Ready, Token};
     use rand;
-    use safe_crypto::{PublicEncryptKey, SecretEncryptKey};
+    use safe_crypto::{gen_encrypt_keypair, PublicEncryptKey, SecretEncryptKey};
     use socket_collection::{DecryptContext, EncryptContext, TcpSock};
     use std::any::Any;
     use std::cell::RefCell;
@@ -413,7 +1210,7 @@ mod broken_peer {
                 self.our_pk,
                 self.our_sk.clone()
             )));
-            Connection::start(core, poll, self.token, socket, &self.our_sk);
+            Connection::start(core, poll, self.token, socket, self.our_pk, &self.our_sk);
         }
 
         fn as_any(&mut self) -> &mut Any {
@@ -424,6 +1221,7 @@ mod broken_peer {
     struct Connection {
         socket: TcpSock,
         token: Token,
+        our_pk: PublicEncryptKey,
         our_sk: SecretEncryptKey,
     }
 
@@ -433,6 +1231,7 @@ mod broken_peer {
             poll: &Poll,
             token: Token,
             socket: TcpSock,
+            our_pk: PublicEncryptKey,
             our_sk: &SecretEncryptKey,
         ) {
             unwrap!(poll.register(&socket, token, Ready::readable(), PollOpt::edge()));
@@ -440,6 +1239,7 @@ mod broken_peer {
             let state = Connection {
                 socket,
                 token,
+                our_pk,
                 our_sk: our_sk.clone(),
             };
             let _ = core.insert_state(token, Rc::new(RefCell::new(state)));
@@ -449,18 +1249,25 @@ mod broken_peer {
     impl State<()> for Connection {
         fn ready(&mut self, core: &mut Core<()>, poll: &Poll, kind: Ready) {
             if kind.is_readable() {
-                match self.socket.read::<Message<UniqueId>>() {
-                    Ok(Some(Message::BootstrapRequest(_, _, _, their_pk))) => {
-                        let shared_key = self.our_sk.shared_secret(&their_pk);
+                match self.socket.read::<Handshake>() {
+                    Ok(Some(handshake)) => {
+                        let shared_key = self.our_sk.shared_secret(&handshake.pub_key);
                         unwrap!(self
                             .socket
                             .set_encrypt_ctx(EncryptContext::authenticated(shared_key)));
                         let public_id: UniqueId = rand::random();
-                        let _ = unwrap!(self
-                            .socket
-                            .write(Some((Message::BootstrapGranted(public_id), 0))));
+                        let protocol = ProtocolInfo::new(Capabilities::empty());
+                        let (eph_pk, _) = gen_encrypt_keypair();
+                        let msg = Message::BootstrapGranted(public_id, eph_pk, None);
+                        let reply = unwrap!(Handshake::new(
+                            protocol,
+                            self.our_pk,
+                            PROTOCOL_VERSION,
+                            &msg
+                        ));
+                        let _ = unwrap!(self.socket.write(Some((reply, 0))));
                     }
-                    Ok(Some(_)) | Ok(None) => (),
+                    Ok(None) => (),
                     Err(_) => self.terminate(core, poll),
                 }
             }
@@ -507,10 +1314,10 @@ fn drop_peer_when_no_message_received_within_inactivity_period() {
     let mut service = unwrap!(Service::with_config(event_tx, config, rand::random()));
 
     unwrap!(service.start_bootstrap(HashSet::new(), CrustUser::Client));
-    let peer_id = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _) => peer_id);
+    let peer_id = expect_event!(event_rx, Event::BootstrapConnect(peer_id, _, _) => peer_id);
 
     // The peer should drop after inactivity.
-    expect_event!(event_rx, Event::LostPeer(lost_peer_id) => {
+    expect_event!(event_rx, Event::LostPeer(lost_peer_id, DisconnectReason::Inactivity) => {
         assert_eq!(lost_peer_id, peer_id)
     });
 }
@@ -536,8 +1343,8 @@ fn do_not_drop_peer_even_when_no_data_messages_are_exchanged_within_inactivity_p
     let mut service1 = unwrap!(Service::with_config(event_tx1, config1, rand::random()));
 
     unwrap!(service1.start_bootstrap(HashSet::new(), CrustUser::Client));
-    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _));
-    expect_event!(event_rx0, Event::BootstrapAccept(_peer_id, _));
+    expect_event!(event_rx1, Event::BootstrapConnect(_peer_id, _, _));
+    expect_event!(event_rx0, Event::BootstrapAccept(_peer_id, _, _));
 
     thread::sleep(Duration::from_millis(2 * INACTIVITY_TIMEOUT_MS));
 
@@ -62,6 +62,7 @@ pub fn get_event_sender() -> (crate::CrustEventSender<UniqueId>, Receiver<Event<
 pub fn gen_config() -> Config {
     let mut config = Config::default();
     config.bootstrap_cache_name = Some(bootstrap_cache_tmp_file().into());
+    config.access_lists_file_name = Some(access_lists_tmp_file().into());
     config
 }
 
@@ -96,6 +97,13 @@ pub fn bootstrap_cache_tmp_file() -> PathBuf {
     path
 }
 
+pub fn access_lists_tmp_file() -> PathBuf {
+    let fname = format!("{:016x}.access_lists", rand::random::<u64>());
+    let mut path = env::temp_dir();
+    path.push(fname);
+    path
+}
+
 /// Creates `Core` for tests with some defaults.
 pub fn test_core(bootstrap_cache: BootstrapCache) -> EventLoopCore {
     let (event_tx, _event_rx) = channel();